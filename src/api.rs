@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::handler::{HttpRequest, HttpResponse};
+use crate::logger;
+use crate::servererror::{Result, ServerError};
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// An error returned by an API route, which is sent as a JSON body of the form
+/// `{"error":"..."}`.
+#[derive(Debug, PartialEq)]
+pub struct ApiError {
+    // The status code as it appears in the status line, e.g. "400 BAD REQUEST".
+    pub status_code: &'static str,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status_code: &'static str, message: &str) -> ApiError {
+        return ApiError { status_code, message: message.into() };
+    }
+
+    pub fn bad_request(message: &str) -> ApiError {
+        return ApiError::new("400 BAD REQUEST", message);
+    }
+
+    pub fn unauthorized() -> ApiError {
+        return ApiError::new("401 UNAUTHORIZED", "Authentication is required.");
+    }
+
+    pub fn payload_too_large(message: &str) -> ApiError {
+        return ApiError::new("413 PAYLOAD TOO LARGE", message);
+    }
+
+    pub fn unsupported_media_type(expected: &str) -> ApiError {
+        return ApiError::new("415 UNSUPPORTED MEDIA TYPE", &format!("Content-Type must be {}.", expected));
+    }
+
+    pub fn service_unavailable(message: &str) -> ApiError {
+        return ApiError::new("503 SERVICE UNAVAILABLE", message);
+    }
+
+    pub fn to_response(&self) -> HttpResponse {
+        let body = serde_json::json!({ "error": self.message });
+        return HttpResponse::new(self.status_code, JSON_CONTENT_TYPE, body.to_string().into());
+    }
+}
+
+/// Internal errors are logged, but their details are not sent to the client.
+impl From<ServerError> for ApiError {
+    fn from(err: ServerError) -> Self {
+        logger::global().error(&ServerError::with_cause("API route failed".into(), err));
+        return ApiError::new("500 INTERNAL SERVER ERROR", "Internal server error.");
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(err: io::Error) -> Self {
+        return ServerError::from(err).into();
+    }
+}
+
+/// The result of an API route.
+pub type ApiResult = std::result::Result<HttpResponse, ApiError>;
+
+impl HttpRequest {
+    /// Parses the body as JSON, into either a `serde_json::Value` or a typed struct. Fails with a
+    /// 415 if the request is not marked as JSON, or a 400 if the body does not parse.
+    pub fn json<T: DeserializeOwned>(&self) -> std::result::Result<T, ApiError> {
+        if self.media_type().as_deref() != Some(JSON_CONTENT_TYPE) {
+            return Err(ApiError::unsupported_media_type(JSON_CONTENT_TYPE));
+        }
+
+        return serde_json::from_slice(self.body())
+            .map_err(|e| ApiError::bad_request(&format!("Malformed JSON: {}", e)));
+    }
+}
+
+impl HttpResponse {
+    /// Creates a response whose body is the value, serialised as JSON.
+    pub fn json<T: Serialize>(status_code: &str, value: &T) -> Result<HttpResponse> {
+        let body = serde_json::to_vec(value)?;
+        return Ok(HttpResponse::new(status_code, JSON_CONTENT_TYPE, body));
+    }
+}
+
+/// Handles requests to an API route.
+pub type ApiHandler = dyn Fn(&HttpRequest) -> ApiResult + Send + Sync;
+
+/// A route served by code rather than by a page.
+pub struct ApiRoute {
+    // The methods the route accepts. Others receive a 405.
+    pub(crate) methods: Vec<String>,
+    pub(crate) handler: Box<ApiHandler>,
+    // Whether the route needs the database, so is unavailable while the database is.
+    pub(crate) requires_database: bool,
+}
+
+impl ApiRoute {
+    /// Handles the request, turning any error into a JSON error response.
+    pub fn respond(&self, request: &HttpRequest) -> HttpResponse {
+        if !self.methods.contains(&request.method) {
+            let mut response = ApiError::new("405 METHOD NOT ALLOWED", &format!("{} is not allowed.", request.method)).to_response();
+            response.set_header("Allow", &self.allowed_methods());
+            return response;
+        }
+
+        return (self.handler)(request).unwrap_or_else(|e| e.to_response());
+    }
+
+    /// The value of the Allow header for the route.
+    pub fn allowed_methods(&self) -> String {
+        return format!("{}, OPTIONS", self.methods.join(", "));
+    }
+}
+
+/// The API routes, keyed by request URI.
+#[derive(Clone, Default)]
+pub struct ApiRoutes {
+    routes: HashMap<String, Arc<ApiRoute>>,
+}
+
+impl ApiRoutes {
+    pub fn new() -> ApiRoutes {
+        return ApiRoutes::default();
+    }
+
+    /// Adds a route accepting the given methods.
+    pub fn add<F: Fn(&HttpRequest) -> ApiResult + Send + Sync + 'static>(&mut self, path: &str, methods: &[&str], handler: F) {
+        self.insert(path, methods, Box::new(handler), false);
+    }
+
+    /// Adds a route accepting the given methods, which responds with a 503 while the database is
+    /// unavailable.
+    pub fn add_database_route<F: Fn(&HttpRequest) -> ApiResult + Send + Sync + 'static>(&mut self, path: &str, methods: &[&str], handler: F) {
+        self.insert(path, methods, Box::new(handler), true);
+    }
+
+    fn insert(&mut self, path: &str, methods: &[&str], handler: Box<ApiHandler>, requires_database: bool) {
+        let methods = methods.iter().map(|method| method.to_string()).collect();
+        self.routes.insert(path.into(), Arc::new(ApiRoute { methods, handler, requires_database }));
+    }
+
+    pub fn get(&self, path: &str) -> Option<&ApiRoute> {
+        return self.routes.get(path).map(|route| route.as_ref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::str::from_utf8;
+
+    use serde::{Deserialize, Serialize};
+    use serde_json::Value;
+
+    use crate::api::{ApiError, ApiRoutes};
+    use crate::handler::{HttpRequest, HttpResponse};
+
+    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    struct Transfer {
+        to: String,
+        amount: u64,
+    }
+
+    fn json_request(method: &str, content_type: Option<&str>, body: &str) -> HttpRequest {
+        let mut headers = HashMap::new();
+        if let Some(content_type) = content_type {
+            headers.insert("content-type".into(), content_type.into());
+        }
+        let mut request = HttpRequest::new(method, "/transfers", "HTTP/1.1", headers);
+        request.body = body.into();
+        return request;
+    }
+
+    fn body_string(response: &HttpResponse) -> &str {
+        return from_utf8(&response.body).unwrap();
+    }
+
+    #[test]
+    fn requests_parse_into_typed_values() {
+        let request = json_request("POST", Some("application/json; charset=utf-8"), "{\"to\":\"bob\",\"amount\":5}");
+
+        assert_eq!(request.json::<Transfer>().unwrap(), Transfer { to: "bob".into(), amount: 5 });
+        assert_eq!(request.json::<Value>().unwrap()["amount"], 5);
+    }
+
+    #[test]
+    fn requests_with_malformed_json_are_rejected() {
+        let malformed_bodies = ["{\"to\":\"bob\"", "{\"to\":\"bob\"}", "{\"to\":\"bob\",\"amount\":-1}"];
+
+        for body in malformed_bodies.iter() {
+            let error = json_request("POST", Some("application/json"), body).json::<Transfer>().unwrap_err();
+
+            assert_eq!(error.status_code, "400 BAD REQUEST");
+        }
+    }
+
+    #[test]
+    fn requests_with_other_media_types_are_rejected() {
+        for content_type in [None, Some("text/plain"), Some("application/jsonp")].iter() {
+            let error = json_request("POST", *content_type, "{}").json::<Value>().unwrap_err();
+
+            assert_eq!(error, ApiError::unsupported_media_type("application/json"));
+        }
+    }
+
+    #[test]
+    fn responses_serialise_typed_values() {
+        let response = HttpResponse::json("201 CREATED", &Transfer { to: "bob".into(), amount: 5 }).unwrap();
+
+        assert_eq!(response.header("Content-Type"), Some("application/json"));
+        assert_eq!(body_string(&response), "{\"to\":\"bob\",\"amount\":5}");
+    }
+
+    #[test]
+    fn api_routes_respond_with_json_errors() {
+        let mut routes = ApiRoutes::new();
+        routes.add("/transfers", &["POST"], |request| {
+            let transfer: Transfer = request.json()?;
+            return Ok(HttpResponse::json("201 CREATED", &transfer)?);
+        });
+        let route = routes.get("/transfers").unwrap();
+
+        let response = route.respond(&json_request("POST", Some("text/plain"), "{}"));
+        assert_eq!(response.status_code, "415 UNSUPPORTED MEDIA TYPE");
+        assert_eq!(body_string(&response), "{\"error\":\"Content-Type must be application/json.\"}");
+
+        let response = route.respond(&json_request("GET", None, ""));
+        assert_eq!(response.status_code, "405 METHOD NOT ALLOWED");
+        assert_eq!(response.header("Allow"), Some("POST, OPTIONS"));
+
+        let response = route.respond(&json_request("POST", Some("application/json"), "{\"to\":\"bob\",\"amount\":5}"));
+        assert_eq!(response.status_code, "201 CREATED");
+    }
+}
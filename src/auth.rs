@@ -0,0 +1,605 @@
+use std::collections::HashMap;
+use std::fs;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac, pbkdf2};
+use serde::{Deserialize, Serialize};
+
+use crate::api::{ApiError, ApiResult};
+use crate::form::{FORM_URLENCODED_CONTENT_TYPE, MULTIPART_CONTENT_TYPE};
+use crate::handler::{ConnectionInfo, HttpRequest, HttpResponse};
+use crate::logger;
+use crate::middleware::{Middleware, Next};
+use crate::servererror::{Result, ServerError};
+
+const ERROR_PAGE_401: &str = "./src/html/401.html";
+const ERROR_PAGE_403: &str = "./src/html/403.html";
+// Identifies the hashing scheme of passwords in the credentials file.
+const PASSWORD_HASH_PREFIX: &str = "$pbkdf2-sha256$";
+// The iterations used when hashing new passwords.
+pub const DEFAULT_PASSWORD_HASH_ITERATIONS: u32 = 100_000;
+const SALT_LENGTH: usize = 16;
+
+/// An authenticated user or client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal {
+    pub name: String,
+}
+
+impl Principal {
+    pub fn new(name: &str) -> Principal {
+        return Principal { name: name.into() };
+    }
+}
+
+/// The outcome of checking a request's credentials.
+#[derive(Debug, PartialEq)]
+pub enum Authentication {
+    // The request carries no credentials of the kind checked.
+    Missing,
+    // The request carries credentials of the kind checked, but they are wrong or expired.
+    Invalid,
+    Authenticated(Principal),
+}
+
+/// A way of checking a request's credentials.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, request: &HttpRequest) -> Authentication;
+
+    // The challenge sent in the WWW-Authenticate header when authentication fails, or an empty
+    // string to send none.
+    fn challenge(&self) -> String;
+
+    // Whether the credentials it accepts were issued by the server, like signed tokens and
+    // sessions. These cannot be swapped for new tokens, or they could be renewed forever.
+    fn accepts_issued_credentials(&self) -> bool {
+        return false;
+    }
+}
+
+/// Returns the credentials in the Authorization header, if it uses the given scheme.
+fn authorization_credentials<'a>(request: &'a HttpRequest, scheme: &str) -> Option<&'a str> {
+    let (request_scheme, credentials) = request.header("Authorization")?.split_once(' ')?;
+    return match request_scheme.eq_ignore_ascii_case(scheme) {
+        true => Some(credentials.trim()),
+        false => None
+    };
+}
+
+/// A PBKDF2-SHA256 password hash, written as `$pbkdf2-sha256$<iterations>$<salt>$<hash>` with
+/// the salt and hash in base64.
+struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    fn parse(encoded: &str) -> Result<PasswordHash> {
+        let malformed = || ServerError::new("Malformed password hash.".into());
+
+        let fields: Vec<&str> = encoded.strip_prefix(PASSWORD_HASH_PREFIX).ok_or_else(malformed)?.split('$').collect();
+        if fields.len() != 3 {
+            return Err(malformed());
+        }
+
+        return Ok(PasswordHash {
+            iterations: NonZeroU32::new(fields[0].parse()?).ok_or_else(malformed)?,
+            salt: STANDARD.decode(fields[1]).map_err(|_| malformed())?,
+            hash: STANDARD.decode(fields[2]).map_err(|_| malformed())?,
+        });
+    }
+
+    /// Checks the password against the hash, in constant time.
+    fn verify(&self, password: &str) -> bool {
+        return pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, self.iterations, &self.salt, password.as_bytes(), &self.hash).is_ok();
+    }
+}
+
+/// Hashes a password with a random salt, for storing in a credentials file.
+pub fn hash_password(password: &str, iterations: u32) -> Result<String> {
+    let iterations = NonZeroU32::new(iterations).ok_or_else(|| ServerError::new("Iterations must be positive.".into()))?;
+    let mut salt = [0u8; SALT_LENGTH];
+    SystemRandom::new().fill(&mut salt).map_err(|_| ServerError::new("Failed to generate a salt.".into()))?;
+
+    let mut hash = [0u8; digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+
+    return Ok(format!("{}{}${}${}", PASSWORD_HASH_PREFIX, iterations, STANDARD.encode(salt), STANDARD.encode(hash)));
+}
+
+/// Authenticates HTTP Basic credentials against hashed passwords.
+pub struct BasicAuthenticator {
+    realm: String,
+    // Keyed by username.
+    users: HashMap<String, PasswordHash>,
+    // Checked against the passwords of unknown usernames, so that they take as long to refuse as
+    // known ones, and usernames cannot be discovered by timing.
+    dummy_hash: PasswordHash,
+}
+
+impl BasicAuthenticator {
+    /// Loads a credentials file with a `username:password_hash` entry per line, as produced by
+    /// `hash_password`. Blank lines and lines starting with `#` are ignored.
+    pub fn from_file(path: &str, realm: &str) -> Result<BasicAuthenticator> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ServerError::with_cause(format!("Failed to read credentials file {}", path), e.into()))?;
+        return BasicAuthenticator::parse(&contents, realm);
+    }
+
+    fn parse(contents: &str, realm: &str) -> Result<BasicAuthenticator> {
+        let mut users = HashMap::new();
+
+        for (index, line) in contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#')) {
+            let malformed = |cause| ServerError::with_cause(format!("Malformed credentials on line {}", index + 1), cause);
+
+            let (username, password_hash) = line.trim().split_once(':')
+                .ok_or_else(|| malformed(ServerError::new("Missing colon.".into())))?;
+            users.insert(username.into(), PasswordHash::parse(password_hash).map_err(malformed)?);
+        }
+
+        // The dummy costs as much as the costliest real hash.
+        let iterations = users.values().map(|password_hash| password_hash.iterations).max()
+            .unwrap_or_else(|| NonZeroU32::new(DEFAULT_PASSWORD_HASH_ITERATIONS).unwrap());
+        let dummy_hash = PasswordHash { iterations, salt: vec![0; SALT_LENGTH], hash: vec![0; digest::SHA256_OUTPUT_LEN] };
+        return Ok(BasicAuthenticator { realm: realm.into(), users, dummy_hash });
+    }
+}
+
+impl Authenticator for BasicAuthenticator {
+    fn authenticate(&self, request: &HttpRequest) -> Authentication {
+        let credentials = match authorization_credentials(request, "Basic") {
+            None => return Authentication::Missing,
+            Some(credentials) => credentials
+        };
+
+        let decoded = STANDARD.decode(credentials).ok().and_then(|decoded| String::from_utf8(decoded).ok());
+        let (username, password) = match decoded.as_ref().and_then(|decoded| decoded.split_once(':')) {
+            None => return Authentication::Invalid,
+            Some(username_and_password) => username_and_password
+        };
+
+        return match self.users.get(username) {
+            Some(password_hash) if password_hash.verify(password) => Authentication::Authenticated(Principal::new(username)),
+            Some(_) => Authentication::Invalid,
+            None => {
+                self.dummy_hash.verify(password);
+                Authentication::Invalid
+            }
+        };
+    }
+
+    fn challenge(&self) -> String {
+        return format!("Basic realm=\"{}\"", self.realm);
+    }
+}
+
+/// Authenticates static tokens, sent either as bearer tokens or in an API key header.
+pub struct TokenAuthenticator {
+    realm: String,
+    api_key_header: String,
+    // Keyed by the SHA-256 digest of the token, so that lookups do not leak the tokens' contents
+    // through timing.
+    tokens: HashMap<Vec<u8>, Principal>,
+}
+
+impl TokenAuthenticator {
+    pub fn new(realm: &str, api_key_header: &str) -> TokenAuthenticator {
+        return TokenAuthenticator { realm: realm.into(), api_key_header: api_key_header.into(), tokens: HashMap::new() };
+    }
+
+    /// Loads a tokens file with a `principal:token` entry per line. Blank lines and lines
+    /// starting with `#` are ignored.
+    pub fn from_file(path: &str, realm: &str, api_key_header: &str) -> Result<TokenAuthenticator> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ServerError::with_cause(format!("Failed to read tokens file {}", path), e.into()))?;
+
+        let mut authenticator = TokenAuthenticator::new(realm, api_key_header);
+        for (index, line) in contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#')) {
+            let (principal, token) = line.trim().split_once(':')
+                .ok_or_else(|| ServerError::new(format!("Malformed token on line {}.", index + 1)))?;
+            authenticator.add_token(token, principal);
+        }
+
+        return Ok(authenticator);
+    }
+
+    pub fn add_token(&mut self, token: &str, principal_name: &str) {
+        self.tokens.insert(digest::digest(&digest::SHA256, token.as_bytes()).as_ref().to_vec(), Principal::new(principal_name));
+    }
+}
+
+impl Authenticator for TokenAuthenticator {
+    fn authenticate(&self, request: &HttpRequest) -> Authentication {
+        let token = match authorization_credentials(request, "Bearer").or_else(|| request.header(&self.api_key_header)) {
+            None => return Authentication::Missing,
+            Some(token) => token
+        };
+
+        return match self.tokens.get(digest::digest(&digest::SHA256, token.as_bytes()).as_ref()) {
+            None => Authentication::Invalid,
+            Some(principal) => Authentication::Authenticated(principal.clone())
+        };
+    }
+
+    fn challenge(&self) -> String {
+        return format!("Bearer realm=\"{}\"", self.realm);
+    }
+}
+
+/// Issues and authenticates bearer tokens of the form `<principal>.<expiry>.<signature>`, where
+/// the principal is base64url-encoded, the expiry is in seconds since the Unix epoch, and the
+/// signature is a base64url-encoded HMAC-SHA256 of the rest.
+pub struct SignedTokenAuthenticator {
+    realm: String,
+    key: hmac::Key,
+}
+
+impl SignedTokenAuthenticator {
+    pub fn new(realm: &str, secret: &[u8]) -> SignedTokenAuthenticator {
+        return SignedTokenAuthenticator { realm: realm.into(), key: hmac::Key::new(hmac::HMAC_SHA256, secret) };
+    }
+
+    /// Issues a token for the principal that expires after the given time.
+    pub fn issue(&self, principal_name: &str, time_to_live: Duration) -> Result<String> {
+        let expiry = SystemTime::now().duration_since(UNIX_EPOCH)? + time_to_live;
+        return Ok(self.issue_until(principal_name, expiry.as_secs()));
+    }
+
+    fn issue_until(&self, principal_name: &str, expiry_secs: u64) -> String {
+        let payload = format!("{}.{}", URL_SAFE_NO_PAD.encode(principal_name), expiry_secs);
+        let signature = hmac::sign(&self.key, payload.as_bytes());
+        return format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(signature.as_ref()));
+    }
+
+    /// Checks the token's signature, and that it has not expired by the given time.
+    fn verify(&self, token: &str, now: SystemTime) -> Authentication {
+        let (payload, signature) = match token.rsplit_once('.') {
+            None => return Authentication::Invalid,
+            Some(payload_and_signature) => payload_and_signature
+        };
+        let signature_is_valid = URL_SAFE_NO_PAD.decode(signature).ok()
+            .map(|signature| hmac::verify(&self.key, payload.as_bytes(), &signature).is_ok())
+            .unwrap_or(false);
+        if !signature_is_valid {
+            return Authentication::Invalid;
+        }
+
+        // The payload is ours, since the signature is valid, but we parse it defensively anyway.
+        let (encoded_principal, expiry_secs) = match payload.split_once('.') {
+            None => return Authentication::Invalid,
+            Some(principal_and_expiry) => principal_and_expiry
+        };
+        let now_secs = now.duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0);
+        let principal_name = URL_SAFE_NO_PAD.decode(encoded_principal).ok().and_then(|name| String::from_utf8(name).ok());
+
+        return match (principal_name, expiry_secs.parse::<u64>()) {
+            (Some(principal_name), Ok(expiry_secs)) if now_secs < expiry_secs => Authentication::Authenticated(Principal::new(&principal_name)),
+            _ => Authentication::Invalid
+        };
+    }
+}
+
+impl Authenticator for SignedTokenAuthenticator {
+    fn authenticate(&self, request: &HttpRequest) -> Authentication {
+        return match authorization_credentials(request, "Bearer") {
+            None => Authentication::Missing,
+            Some(token) => self.verify(token, SystemTime::now())
+        };
+    }
+
+    fn challenge(&self) -> String {
+        return format!("Bearer realm=\"{}\"", self.realm);
+    }
+
+    fn accepts_issued_credentials(&self) -> bool {
+        return true;
+    }
+}
+
+/// The body of a request to the token route.
+#[derive(Deserialize)]
+struct TokenRequest {
+    // How long the token should last. Defaults to, and is capped at, the route's maximum.
+    ttl_seconds: Option<u64>,
+}
+
+/// The body of a response from the token route.
+#[derive(Serialize)]
+struct TokenResponse {
+    token: String,
+    principal: String,
+    expires_in: u64,
+}
+
+/// Returns an API route that issues the authenticated principal a signed token, lasting at most
+/// the given time. The request may be JSON or a form. The route must be protected by an
+/// `AuthLayer` from `AuthLayer::for_token_route`.
+pub fn token_route(authenticator: SignedTokenAuthenticator, max_time_to_live: Duration) -> impl Fn(&HttpRequest) -> ApiResult + Send + Sync {
+    return move |request: &HttpRequest| {
+        let principal = request.principal().ok_or_else(ApiError::unauthorized)?;
+
+        let ttl_seconds = match request.media_type().as_deref() {
+            Some(FORM_URLENCODED_CONTENT_TYPE) | Some(MULTIPART_CONTENT_TYPE) => match request.form()?.field("ttl_seconds") {
+                None | Some("") => None,
+                Some(ttl_seconds) => Some(ttl_seconds.parse().map_err(|_| ApiError::bad_request("ttl_seconds must be a number."))?)
+            },
+            _ => request.json::<TokenRequest>()?.ttl_seconds
+        };
+        let time_to_live = ttl_seconds.map(Duration::from_secs).unwrap_or(max_time_to_live).min(max_time_to_live);
+        let token = authenticator.issue(&principal.name, time_to_live)?;
+
+        let token_response = TokenResponse { token, principal: principal.name.clone(), expires_in: time_to_live.as_secs() };
+        return Ok(HttpResponse::json("200 OK", &token_response)?);
+    };
+}
+
+/// Middleware that requires requests to authenticate with any of the given authenticators, and
+/// exposes the principal via `HttpRequest::principal`. Requests without valid credentials receive
+/// a 401 challenging them to authenticate; authenticated principals that are not allowed receive
+/// a 403. Preflight OPTIONS requests, which browsers send without credentials, pass through.
+pub struct AuthLayer {
+    authenticators: Vec<Arc<dyn Authenticator>>,
+    // The principals allowed through. None allows any authenticated principal.
+    allowed_principals: Option<Vec<String>>,
+}
+
+impl AuthLayer {
+    pub fn new(authenticators: Vec<Arc<dyn Authenticator>>, allowed_principals: Option<Vec<String>>) -> AuthLayer {
+        return AuthLayer { authenticators, allowed_principals };
+    }
+
+    /// An `AuthLayer` for the token route, which accepts only the given authenticators whose
+    /// credentials were not issued by the server, so that tokens cannot be renewed with tokens.
+    pub fn for_token_route(authenticators: Vec<Arc<dyn Authenticator>>, allowed_principals: Option<Vec<String>>) -> Result<AuthLayer> {
+        let authenticators: Vec<Arc<dyn Authenticator>> = authenticators.into_iter()
+            .filter(|authenticator| !authenticator.accepts_issued_credentials())
+            .collect();
+        if authenticators.is_empty() {
+            return Err(ServerError::new("Tokens can only be issued to clients with passwords or static tokens, and neither is configured.".into()));
+        }
+        return Ok(AuthLayer::new(authenticators, allowed_principals));
+    }
+
+    fn unauthorized_response(&self) -> Result<HttpResponse> {
+        let mut response = HttpResponse::from_file("401 UNAUTHORIZED", ERROR_PAGE_401)?;
+
+        let mut challenges: Vec<String> = self.authenticators.iter()
+            .map(|authenticator| authenticator.challenge())
+            .filter(|challenge| !challenge.is_empty())
+            .collect();
+        challenges.dedup();
+        for challenge in challenges.iter() {
+            response.add_header("WWW-Authenticate", challenge);
+        }
+
+        return Ok(response);
+    }
+}
+
+impl Middleware for AuthLayer {
+    fn handle(&self, request: &mut HttpRequest, connection: &ConnectionInfo, next: Next) -> Result<HttpResponse> {
+        if request.method == "OPTIONS" {
+            return next.run(request, connection);
+        }
+
+        let principal = self.authenticators.iter()
+            .map(|authenticator| authenticator.authenticate(request))
+            .find_map(|authentication| match authentication {
+                Authentication::Authenticated(principal) => Some(principal),
+                _ => None
+            });
+
+        let principal = match principal {
+            None => return self.unauthorized_response(),
+            Some(principal) => principal
+        };
+
+        if let Some(allowed_principals) = &self.allowed_principals {
+            if !allowed_principals.contains(&principal.name) {
+                logger::global().debug(&format!("Forbade {} from {}.", principal.name, request.request_uri));
+                return HttpResponse::from_file("403 FORBIDDEN", ERROR_PAGE_403);
+            }
+        }
+
+        request.principal = Some(principal);
+        return next.run(request, connection);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufReader, BufWriter};
+    use std::str::from_utf8;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde_json::Value;
+
+    use crate::api::ApiError;
+    use crate::auth::{token_route, hash_password, AuthLayer, Authentication, Authenticator, BasicAuthenticator, Principal, SignedTokenAuthenticator, TokenAuthenticator};
+    use crate::handler::{ConnectionInfo, HttpHandler, HttpRequest, HttpResponse};
+    use crate::middleware::MiddlewareChain;
+    use crate::servererror::Result;
+
+    fn request_with(headers: &[(&str, &str)]) -> HttpRequest {
+        let headers: HashMap<String, String> = headers.iter().map(|(name, value)| (name.to_lowercase(), value.to_string())).collect();
+        return HttpRequest::new("GET", "/", "HTTP/1.1", headers);
+    }
+
+    fn basic_authorization(username: &str, password: &str) -> String {
+        return format!("Basic {}", STANDARD.encode(format!("{}:{}", username, password)));
+    }
+
+    fn basic_authenticator() -> BasicAuthenticator {
+        let credentials = format!("# Comment\n\nalice:{}\n", hash_password("secret", 1000).unwrap());
+        return BasicAuthenticator::parse(&credentials, "test").unwrap();
+    }
+
+    #[test]
+    fn basic_authenticator_checks_hashed_passwords() {
+        let authenticator = basic_authenticator();
+
+        assert_eq!(authenticator.authenticate(&request_with(&[("Authorization", &basic_authorization("alice", "secret"))])),
+                   Authentication::Authenticated(Principal::new("alice")));
+        assert_eq!(authenticator.authenticate(&request_with(&[("Authorization", &basic_authorization("alice", "wrong"))])),
+                   Authentication::Invalid);
+        assert_eq!(authenticator.authenticate(&request_with(&[("Authorization", &basic_authorization("bob", "secret"))])),
+                   Authentication::Invalid);
+        // Unknown usernames are hashed as slowly as known ones.
+        assert_eq!(authenticator.dummy_hash.iterations.get(), 1000);
+        assert_eq!(authenticator.authenticate(&request_with(&[("Authorization", "Basic !!!")])), Authentication::Invalid);
+        assert_eq!(authenticator.authenticate(&request_with(&[])), Authentication::Missing);
+    }
+
+    #[test]
+    fn basic_authenticator_rejects_malformed_credentials_files() {
+        assert!(BasicAuthenticator::parse("alice", "test").is_err());
+        assert!(BasicAuthenticator::parse("alice:plaintext", "test").is_err());
+        assert!(BasicAuthenticator::parse("alice:$pbkdf2-sha256$0$AAAA$AAAA", "test").is_err());
+    }
+
+    #[test]
+    fn token_authenticator_accepts_bearer_tokens_and_api_keys() {
+        let mut authenticator = TokenAuthenticator::new("test", "X-API-Key");
+        authenticator.add_token("token-1", "service");
+
+        let expected = Authentication::Authenticated(Principal::new("service"));
+        assert_eq!(authenticator.authenticate(&request_with(&[("Authorization", "Bearer token-1")])), expected);
+        assert_eq!(authenticator.authenticate(&request_with(&[("X-API-Key", "token-1")])), expected);
+        assert_eq!(authenticator.authenticate(&request_with(&[("X-API-Key", "token-2")])), Authentication::Invalid);
+        assert_eq!(authenticator.authenticate(&request_with(&[("Authorization", "Basic token-1")])), Authentication::Missing);
+    }
+
+    #[test]
+    fn signed_tokens_are_verified_and_expire() {
+        let authenticator = SignedTokenAuthenticator::new("test", b"key");
+        let token = authenticator.issue_until("alice.smith", 1000);
+
+        assert_eq!(authenticator.verify(&token, UNIX_EPOCH + Duration::from_secs(999)),
+                   Authentication::Authenticated(Principal::new("alice.smith")));
+        assert_eq!(authenticator.verify(&token, UNIX_EPOCH + Duration::from_secs(1000)), Authentication::Invalid);
+
+        // Tokens signed with another key, or tampered with, are rejected.
+        let other_token = SignedTokenAuthenticator::new("test", b"other key").issue_until("alice.smith", 1000);
+        let tampered_token = token.replacen(".1000.", ".2000.", 1);
+        for invalid_token in [other_token.as_str(), tampered_token.as_str(), "garbage"].iter() {
+            assert_eq!(authenticator.verify(invalid_token, UNIX_EPOCH), Authentication::Invalid);
+        }
+    }
+
+    /// Responds with the principal's name.
+    fn whoami(request: &HttpRequest) -> Result<HttpResponse> {
+        let name = request.principal().map(|principal| principal.name.clone()).unwrap_or_else(|| "-".into());
+        return Ok(HttpResponse::new("200 OK", "text/plain", name.into()));
+    }
+
+    fn handle(layer: AuthLayer, request: &str) -> String {
+        let mut chain = MiddlewareChain::new();
+        chain.add(layer);
+        let mut reader = BufReader::new(request.as_bytes());
+        let mut response = Vec::<u8>::new();
+
+        let mut http_request = HttpHandler::read_http_request(&mut reader, &ConnectionInfo::default()).unwrap();
+        chain.run(&mut http_request, &ConnectionInfo::default(), &whoami).unwrap().write(BufWriter::new(&mut response)).unwrap();
+
+        return from_utf8(&response).unwrap().into();
+    }
+
+    fn authenticators() -> Vec<Arc<dyn Authenticator>> {
+        let mut token_authenticator = TokenAuthenticator::new("test", "X-API-Key");
+        token_authenticator.add_token("token-1", "service");
+        return vec![
+            Arc::new(basic_authenticator()),
+            Arc::new(token_authenticator),
+            Arc::new(SignedTokenAuthenticator::new("test", b"key")),
+        ];
+    }
+
+    fn auth_layer(allowed_principals: Option<Vec<String>>) -> AuthLayer {
+        return AuthLayer::new(authenticators(), allowed_principals);
+    }
+
+    #[test]
+    fn auth_layer_exposes_principal() {
+        let request = format!("GET / HTTP/1.1\r\nAuthorization: {}\r\n\r\n", basic_authorization("alice", "secret"));
+
+        assert!(handle(auth_layer(None), &request).ends_with("\r\n\r\nalice"));
+        assert!(handle(auth_layer(None), "GET / HTTP/1.1\r\nX-API-Key: token-1\r\n\r\n").ends_with("\r\n\r\nservice"));
+    }
+
+    #[test]
+    fn auth_layer_challenges_unauthenticated_requests() {
+        for request in ["GET / HTTP/1.1\r\n\r\n", "GET / HTTP/1.1\r\nAuthorization: Bearer wrong\r\n\r\n"].iter() {
+            let response = handle(auth_layer(None), request);
+
+            assert!(response.starts_with("HTTP/1.1 401 UNAUTHORIZED\r\n"));
+            assert!(response.contains("\r\nWWW-Authenticate: Basic realm=\"test\"\r\nWWW-Authenticate: Bearer realm=\"test\"\r\n"));
+        }
+    }
+
+    #[test]
+    fn auth_layer_forbids_principals_not_allowed() {
+        let layer = auth_layer(Some(vec!["alice".into()]));
+
+        let response = handle(layer, "GET / HTTP/1.1\r\nX-API-Key: token-1\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 403 FORBIDDEN\r\n"));
+    }
+
+    #[test]
+    fn token_route_issues_tokens_to_principal() {
+        let route = token_route(SignedTokenAuthenticator::new("test", b"key"), Duration::from_secs(3600));
+        let mut headers = HashMap::new();
+        headers.insert("content-type".into(), "application/json".into());
+        let mut request = HttpRequest::new("POST", "/api/tokens", "HTTP/1.1", headers);
+        request.body = b"{\"ttl_seconds\":7200}".to_vec();
+
+        assert_eq!(route(&request).unwrap_err(), ApiError::unauthorized());
+
+        request.principal = Some(Principal::new("alice"));
+        let response: Value = serde_json::from_slice(&route(&request).unwrap().body).unwrap();
+
+        assert_eq!(response["principal"], "alice");
+        assert_eq!(response["expires_in"], 3600);
+        let token = response["token"].as_str().unwrap();
+        let bearer_request = request_with(&[("Authorization", &format!("Bearer {}", token))]);
+        assert_eq!(SignedTokenAuthenticator::new("test", b"key").authenticate(&bearer_request),
+                   Authentication::Authenticated(Principal::new("alice")));
+
+        request.headers.insert("content-type".into(), "application/x-www-form-urlencoded".into());
+        request.body = b"ttl_seconds=60".to_vec();
+        let response: Value = serde_json::from_slice(&route(&request).unwrap().body).unwrap();
+        assert_eq!(response["expires_in"], 60);
+    }
+
+    #[test]
+    fn token_route_layers_refuse_issued_tokens() {
+        let token = SignedTokenAuthenticator::new("test", b"key").issue_until("alice", u64::MAX);
+        let signed_request = format!("POST /api/tokens HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n", token);
+        // Accepted anywhere else.
+        assert!(handle(auth_layer(None), &signed_request).ends_with("\r\n\r\nalice"));
+
+        let layer = || AuthLayer::for_token_route(authenticators(), None).unwrap();
+        let response = handle(layer(), &signed_request);
+        assert!(response.starts_with("HTTP/1.1 401 UNAUTHORIZED\r\n"));
+        let request = format!("POST /api/tokens HTTP/1.1\r\nAuthorization: {}\r\n\r\n", basic_authorization("alice", "secret"));
+        assert!(handle(layer(), &request).ends_with("\r\n\r\nalice"));
+        assert!(handle(layer(), "POST /api/tokens HTTP/1.1\r\nX-API-Key: token-1\r\n\r\n").ends_with("\r\n\r\nservice"));
+
+        assert!(AuthLayer::for_token_route(vec![Arc::new(SignedTokenAuthenticator::new("test", b"key"))], None).is_err());
+    }
+
+    #[test]
+    fn auth_layer_lets_preflight_requests_through() {
+        assert!(handle(auth_layer(None), "OPTIONS / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
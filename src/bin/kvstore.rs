@@ -0,0 +1,41 @@
+// Explicit returns are the house style.
+#![allow(clippy::needless_return)]
+
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use blockchain::cli::{env_or, wait_for_shutdown_request};
+use blockchain::kvstore::KvStore;
+use blockchain::logger;
+use blockchain::server::{ServerInternal, ServerOptions};
+use blockchain::servererror::Result;
+
+// The environment variable overriding the port the store listens on, and its default.
+const PORT_VAR: &str = "KV_PORT";
+const DEFAULT_PORT: &str = "10008";
+// The environment variable overriding the path of the store's log, and its default.
+const LOG_PATH_VAR: &str = "KV_LOG_PATH";
+const DEFAULT_LOG_PATH: &str = "kvstore.log";
+// The environment variable overriding how many seconds a connection may stay idle. The default
+// outlasts the web server's pool, which closes surplus connections after five minutes.
+const IDLE_TIMEOUT_VAR: &str = "KV_IDLE_TIMEOUT";
+const DEFAULT_IDLE_TIMEOUT: u64 = 10 * 60;
+
+/// Starts a key-value store that serves the database protocol until the user exits the program.
+/// Its entries are recovered from its log on startup.
+pub fn main() -> Result<()> {
+    let port = env::var(PORT_VAR).unwrap_or_else(|_| DEFAULT_PORT.into());
+    let log_path = PathBuf::from(env::var(LOG_PATH_VAR).unwrap_or_else(|_| DEFAULT_LOG_PATH.into()));
+    let idle_timeout = Duration::from_secs(env_or(IDLE_TIMEOUT_VAR, DEFAULT_IDLE_TIMEOUT)?);
+
+    let store = KvStore::open(&log_path)?;
+    let options = ServerOptions { limits: KvStore::connection_limits(idle_timeout), ..ServerOptions::default() };
+    let mut server_handle = ServerInternal::start_with_options(&port, store, options)?;
+
+    logger::global().info(&format!("Key-value store listening on port {}, logging to {}.", port, log_path.display()));
+    wait_for_shutdown_request()?;
+
+    logger::global().info("Shutting down.");
+    return server_handle.stop_listening();
+}
@@ -0,0 +1,82 @@
+use std::env;
+use std::io::{stdin, BufRead};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+use crate::servererror::{Result, ServerError};
+
+// How often we check whether a shutdown has been requested.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Parses the given environment variable, or returns the default if it is not set.
+pub fn env_or<T: FromStr>(var: &str, default: T) -> Result<T> {
+    return Ok(env_opt(var)?.unwrap_or(default));
+}
+
+/// Parses the given environment variable, if it is set.
+pub fn env_opt<T: FromStr>(var: &str) -> Result<Option<T>> {
+    return match env::var(var) {
+        Err(_) => Ok(None),
+        Ok(value) => value.parse().map(Some)
+            .map_err(|_| ServerError::new(format!("Invalid value for {}: {}", var, value)))
+    };
+}
+
+/// Loop until the reader reads the word 'exit' (plus optional whitespace). Returns whether it did,
+/// rather than reaching the end of its input.
+pub fn loop_until_exit_requested<R: BufRead>(mut reader: R) -> Result<bool> {
+    let mut maybe_exit = String::new();
+
+    loop {
+        println!("Type 'exit' to exit.");
+        maybe_exit.clear();
+
+        if reader.read_line(&mut maybe_exit)? == 0 {
+            return Ok(false);
+        }
+        if maybe_exit.trim() == "exit" {
+            return Ok(true);
+        }
+    }
+}
+
+/// Blocks until SIGTERM or SIGINT is received, or 'exit' is typed on stdin. If stdin is closed, as
+/// it is when run by an orchestrator, only a signal ends the wait.
+pub fn wait_for_shutdown_request() -> Result<()> {
+    let requested = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT].iter() {
+        signal_hook::flag::register(*signal, requested.clone())?;
+    }
+
+    let requested_from_stdin = requested.clone();
+    spawn(move || {
+        if let Ok(true) = loop_until_exit_requested(stdin().lock()) {
+            requested_from_stdin.store(true, Ordering::Relaxed);
+        }
+    });
+
+    while !requested.load(Ordering::Relaxed) {
+        sleep(SHUTDOWN_POLL_INTERVAL);
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::cli::loop_until_exit_requested;
+
+    #[test]
+    fn exit_loops_stop_at_exit_or_the_end_of_input() {
+        assert!(loop_until_exit_requested(Cursor::new("stay\n  exit \nafter\n")).unwrap());
+        // Closed input does not spin, and does not count as a request to exit.
+        assert!(!loop_until_exit_requested(Cursor::new("stay\n")).unwrap());
+        assert!(!loop_until_exit_requested(Cursor::new("")).unwrap());
+    }
+}
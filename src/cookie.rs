@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use crate::handler::{HttpRequest, HttpResponse};
+use crate::servererror::{Result, ServerError};
+
+/// Whether browsers send a cookie on requests from other sites.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    // Browsers only accept this for cookies that are also `Secure`.
+    None,
+}
+
+impl SameSite {
+    /// Parses a SameSite value, ignoring case.
+    pub fn parse(name: &str) -> Result<SameSite> {
+        return match name.to_lowercase().as_str() {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            _ => Err(ServerError::new(format!("Unknown SameSite value: {}", name)))
+        };
+    }
+
+    fn name(&self) -> &'static str {
+        return match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        };
+    }
+}
+
+/// A cookie to set via a `Set-Cookie` header. The defaults suit a session cookie: it is sent for
+/// every path, hidden from scripts and withheld from cross-site subrequests.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetCookie {
+    pub name: String,
+    pub value: String,
+    // How long until the cookie expires. None makes it last until the browser closes.
+    pub max_age: Option<Duration>,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    // Whether the cookie is only sent over HTTPS.
+    pub secure: bool,
+    // Whether the cookie is hidden from scripts.
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    pub fn new(name: &str, value: &str) -> SetCookie {
+        return SetCookie {
+            name: name.into(),
+            value: value.into(),
+            max_age: None,
+            path: Some("/".into()),
+            domain: None,
+            secure: false,
+            http_only: true,
+            same_site: Some(SameSite::Lax),
+        };
+    }
+
+    /// Returns a copy of the cookie that removes it from the browser.
+    pub fn expired(&self) -> SetCookie {
+        return SetCookie { value: String::new(), max_age: Some(Duration::from_secs(0)), ..self.clone() };
+    }
+
+    /// Formats the cookie as the value of a `Set-Cookie` header.
+    pub fn to_header_value(&self) -> String {
+        let mut header_value = format!("{}={}", self.name, self.value);
+        if let Some(max_age) = self.max_age {
+            header_value.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+        }
+        if let Some(path) = &self.path {
+            header_value.push_str(&format!("; Path={}", path));
+        }
+        if let Some(domain) = &self.domain {
+            header_value.push_str(&format!("; Domain={}", domain));
+        }
+        if self.secure {
+            header_value.push_str("; Secure");
+        }
+        if self.http_only {
+            header_value.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = self.same_site {
+            header_value.push_str(&format!("; SameSite={}", same_site.name()));
+        }
+        return header_value;
+    }
+}
+
+/// Parses the value of a `Cookie` header, e.g. `theme=dark; session="abc"`, skipping malformed
+/// pairs.
+pub fn parse_cookies(header_value: &str) -> Vec<(&str, &str)> {
+    return header_value.split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .filter(|(name, _)| !name.is_empty())
+        .map(|(name, value)| (name, value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value)))
+        .collect();
+}
+
+impl HttpRequest {
+    /// Returns the value of the named cookie, if the client sent it.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        return parse_cookies(self.header("Cookie")?).into_iter()
+            .find(|(cookie_name, _)| *cookie_name == name)
+            .map(|(_, value)| value);
+    }
+}
+
+impl HttpResponse {
+    /// Adds a `Set-Cookie` header, alongside any others.
+    pub fn add_cookie(&mut self, cookie: &SetCookie) {
+        self.add_header("Set-Cookie", &cookie.to_header_value());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use crate::cookie::{parse_cookies, SameSite, SetCookie};
+    use crate::handler::{HttpRequest, HttpResponse};
+
+    #[test]
+    fn cookie_headers_are_parsed() {
+        assert_eq!(parse_cookies("theme=dark; session=\"a=b\";;junk; =empty;lang=en "),
+                   vec![("theme", "dark"), ("session", "a=b"), ("lang", "en")]);
+
+        let mut headers = HashMap::new();
+        headers.insert("cookie".into(), "theme=dark; session=abc".into());
+        let request = HttpRequest::new("GET", "/", "HTTP/1.1", headers);
+
+        assert_eq!(request.cookie("session"), Some("abc"));
+        assert_eq!(request.cookie("missing"), None);
+        assert_eq!(HttpRequest::new("GET", "/", "HTTP/1.1", HashMap::new()).cookie("session"), None);
+    }
+
+    #[test]
+    fn set_cookie_headers_include_every_attribute() {
+        let cookie = SetCookie {
+            max_age: Some(Duration::from_secs(3600)),
+            domain: Some("example.com".into()),
+            secure: true,
+            same_site: Some(SameSite::parse("strict").unwrap()),
+            ..SetCookie::new("session", "abc")
+        };
+
+        assert_eq!(cookie.to_header_value(), "session=abc; Max-Age=3600; Path=/; Domain=example.com; Secure; HttpOnly; SameSite=Strict");
+        assert_eq!(cookie.expired().to_header_value(), "session=; Max-Age=0; Path=/; Domain=example.com; Secure; HttpOnly; SameSite=Strict");
+
+        let minimal_cookie = SetCookie { path: None, http_only: false, same_site: None, ..SetCookie::new("theme", "dark") };
+        assert_eq!(minimal_cookie.to_header_value(), "theme=dark");
+        assert!(SameSite::parse("sometimes").is_err());
+    }
+
+    #[test]
+    fn responses_can_set_several_cookies() {
+        let mut response = HttpResponse::empty("204 NO CONTENT");
+
+        response.add_cookie(&SetCookie::new("a", "1"));
+        response.add_cookie(&SetCookie::new("b", "2"));
+
+        let set_cookies: Vec<&str> = response.headers.iter().filter(|(name, _)| name == "Set-Cookie").map(|(_, value)| value.as_str()).collect();
+        assert_eq!(set_cookies, vec!["a=1; Path=/; HttpOnly; SameSite=Lax", "b=2; Path=/; HttpOnly; SameSite=Lax"]);
+    }
+}
@@ -0,0 +1,340 @@
+use std::time::Duration;
+
+use crate::handler::{ConnectionInfo, HttpRequest, HttpResponse};
+use crate::middleware::{Middleware, Next};
+use crate::servererror::{Result, ServerError};
+
+/// An origin that may make cross-origin requests.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OriginPattern {
+    // Any origin, written `*`.
+    Any,
+    // A single origin, e.g. `https://example.com`.
+    Exact(String),
+    // Origins matching a pattern with a single `*` standing for one or more characters, e.g.
+    // `https://*.example.com`.
+    Wildcard { prefix: String, suffix: String },
+}
+
+impl OriginPattern {
+    pub fn parse(pattern: &str) -> Result<OriginPattern> {
+        let pattern = pattern.trim().to_lowercase();
+
+        if pattern == "*" {
+            return Ok(OriginPattern::Any);
+        }
+        return match pattern.split_once('*') {
+            None => Ok(OriginPattern::Exact(pattern)),
+            Some((_, suffix)) if suffix.contains('*') => Err(ServerError::new(format!("Origin pattern has more than one wildcard: {}", pattern))),
+            Some((prefix, suffix)) => Ok(OriginPattern::Wildcard { prefix: prefix.into(), suffix: suffix.into() })
+        };
+    }
+
+    /// Whether the origin matches. Origins are compared case-insensitively.
+    pub fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_lowercase();
+
+        return match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(exact) => origin == *exact,
+            OriginPattern::Wildcard { prefix, suffix } =>
+                origin.len() > prefix.len() + suffix.len() && origin.starts_with(prefix.as_str()) && origin.ends_with(suffix.as_str())
+        };
+    }
+}
+
+/// Which cross-origin requests browsers should allow.
+#[derive(Clone)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<OriginPattern>,
+    pub allowed_methods: Vec<String>,
+    // The request headers that may be sent, beyond those browsers always allow.
+    pub allowed_headers: Vec<String>,
+    // Whether requests may carry cookies and credentials.
+    pub allow_credentials: bool,
+    // How long browsers may cache preflight responses.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> CorsConfig {
+        return CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".into(), "HEAD".into(), "POST".into()],
+            allowed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: None,
+        };
+    }
+}
+
+impl CorsConfig {
+    /// Checks the config is safe to serve. Credentials cannot be allowed for any origin, since
+    /// every origin would be reflected back with them, letting any site make credentialed
+    /// requests.
+    pub fn validate(&self) -> Result<()> {
+        if self.allow_credentials && self.allowed_origins.contains(&OriginPattern::Any) {
+            return Err(ServerError::new("CORS credentials cannot be allowed for any origin (`*`).".into()));
+        }
+        return Ok(());
+    }
+}
+
+/// Middleware that adds CORS headers to responses for allowed origins, and answers preflight
+/// requests. Preflights are only answered for requests that the routes themselves answer
+/// successfully, so unknown routes still 404.
+pub struct Cors {
+    config: CorsConfig,
+}
+
+impl Cors {
+    pub fn new(config: CorsConfig) -> Cors {
+        return Cors { config };
+    }
+
+    fn is_allowed_origin(&self, origin: &str) -> bool {
+        return self.config.allowed_origins.iter().any(|pattern| pattern.matches(origin));
+    }
+
+    /// Whether the response's allowed origin depends on the request's origin, in which case
+    /// caches must key on it.
+    fn varies_by_origin(&self) -> bool {
+        return self.config.allow_credentials || !self.config.allowed_origins.contains(&OriginPattern::Any);
+    }
+
+    /// Whether the method and headers that the preflight asks about are allowed.
+    fn is_allowed_preflight(&self, request: &HttpRequest, requested_method: &str) -> bool {
+        let method_allowed = self.config.allowed_methods.iter().any(|method| method.eq_ignore_ascii_case(requested_method));
+
+        let requested_headers = request.header("Access-Control-Request-Headers").unwrap_or("");
+        let headers_allowed = requested_headers.split(',')
+            .map(str::trim)
+            .filter(|header| !header.is_empty())
+            .all(|header| self.config.allowed_headers.iter().any(|allowed| allowed.eq_ignore_ascii_case(header)));
+
+        return method_allowed && headers_allowed;
+    }
+
+    /// Adds the headers that let the browser share the response with the origin.
+    fn add_origin_headers(&self, response: &mut HttpResponse, origin: &str) {
+        let allowed_origin = match self.varies_by_origin() {
+            true => origin,
+            false => "*"
+        };
+        response.set_header("Access-Control-Allow-Origin", allowed_origin);
+
+        if self.config.allow_credentials {
+            response.set_header("Access-Control-Allow-Credentials", "true");
+        }
+    }
+
+    /// Turns a successful response to a preflight into a CORS preflight response.
+    fn add_preflight_headers(&self, response: &mut HttpResponse, origin: &str) {
+        response.status_code = "204 NO CONTENT".into();
+        response.body.clear();
+        self.add_origin_headers(response, origin);
+        response.set_header("Access-Control-Allow-Methods", &self.config.allowed_methods.join(", "));
+
+        if !self.config.allowed_headers.is_empty() {
+            response.set_header("Access-Control-Allow-Headers", &self.config.allowed_headers.join(", "));
+        }
+        if let Some(max_age) = self.config.max_age {
+            response.set_header("Access-Control-Max-Age", &max_age.as_secs().to_string());
+        }
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &mut HttpRequest, connection: &ConnectionInfo, next: Next) -> Result<HttpResponse> {
+        let origin = request.header("Origin").map(|origin| origin.to_string());
+        let requested_method = request.header("Access-Control-Request-Method").map(|method| method.to_string());
+        let is_preflight = request.method == "OPTIONS" && origin.is_some() && requested_method.is_some();
+
+        let mut response = next.run(request, connection)?;
+
+        if self.varies_by_origin() {
+            add_vary(&mut response, "Origin");
+        }
+        if is_preflight {
+            add_vary(&mut response, "Access-Control-Request-Method");
+            add_vary(&mut response, "Access-Control-Request-Headers");
+        }
+
+        let origin = match origin {
+            Some(origin) if self.is_allowed_origin(&origin) => origin,
+            // Without CORS headers, the browser will not share the response.
+            _ => return Ok(response)
+        };
+
+        match requested_method {
+            Some(requested_method) if is_preflight => {
+                if response.is_success() && self.is_allowed_preflight(request, &requested_method) {
+                    self.add_preflight_headers(&mut response, &origin);
+                }
+            }
+            _ => self.add_origin_headers(&mut response, &origin)
+        }
+
+        return Ok(response);
+    }
+}
+
+/// Adds a field to the response's Vary header, if it is not already there.
+fn add_vary(response: &mut HttpResponse, field: &str) {
+    let vary = match response.header("Vary") {
+        None => field.to_string(),
+        Some(vary) if vary.split(',').any(|existing| existing.trim().eq_ignore_ascii_case(field)) => return,
+        Some(vary) => format!("{}, {}", vary, field)
+    };
+    response.set_header("Vary", &vary);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, BufWriter};
+    use std::str::from_utf8;
+    use std::time::Duration;
+
+    use crate::cors::{Cors, CorsConfig, OriginPattern};
+    use crate::handler::{ConnectionInfo, HttpHandler, HttpRequest, HttpResponse};
+    use crate::middleware::MiddlewareChain;
+    use crate::servererror::Result;
+
+    /// Answers OPTIONS like `HttpHandler` does for a known route, and 404s for `/missing`.
+    fn endpoint(request: &HttpRequest) -> Result<HttpResponse> {
+        if request.request_uri == "/missing" {
+            return Ok(HttpResponse::new("404 NOT FOUND", "text/plain", "Not found".into()));
+        }
+        if request.method == "OPTIONS" {
+            let mut response = HttpResponse::empty("204 NO CONTENT");
+            response.set_header("Allow", "GET, HEAD, OPTIONS");
+            return Ok(response);
+        }
+        return Ok(HttpResponse::new("200 OK", "text/plain", "OK".into()));
+    }
+
+    fn config() -> CorsConfig {
+        return CorsConfig {
+            allowed_origins: vec![
+                OriginPattern::parse("https://app.example.com").unwrap(),
+                OriginPattern::parse("https://*.example.org").unwrap()
+            ],
+            allowed_methods: vec!["GET".into(), "PUT".into()],
+            allowed_headers: vec!["Content-Type".into(), "X-API-Key".into()],
+            allow_credentials: true,
+            max_age: Some(Duration::from_secs(600)),
+        };
+    }
+
+    fn handle(config: CorsConfig, request: &str) -> String {
+        let mut chain = MiddlewareChain::new();
+        chain.add(Cors::new(config));
+        let mut reader = BufReader::new(request.as_bytes());
+        let mut response = Vec::<u8>::new();
+
+        let mut http_request = HttpHandler::read_http_request(&mut reader, &ConnectionInfo::default()).unwrap();
+        chain.run(&mut http_request, &ConnectionInfo::default(), &endpoint).unwrap().write(BufWriter::new(&mut response)).unwrap();
+
+        return from_utf8(&response).unwrap().into();
+    }
+
+    #[test]
+    fn origin_patterns_match_origins() {
+        let wildcard = OriginPattern::parse("https://*.example.org").unwrap();
+
+        assert!(wildcard.matches("https://app.example.org"));
+        assert!(wildcard.matches("HTTPS://A.B.EXAMPLE.ORG"));
+        assert!(!wildcard.matches("https://.example.org"));
+        assert!(!wildcard.matches("https://example.org"));
+        assert!(!wildcard.matches("https://app.example.org.evil.com"));
+        assert!(OriginPattern::parse("https://app.example.com").unwrap().matches("https://app.example.com"));
+        assert!(OriginPattern::parse("*").unwrap().matches("https://anything.com"));
+        assert!(OriginPattern::parse("https://*.*.org").is_err());
+    }
+
+    #[test]
+    fn configs_refuse_credentials_for_any_origin() {
+        assert!(config().validate().is_ok());
+
+        let any_origin = CorsConfig { allowed_origins: vec![OriginPattern::parse("*").unwrap()], ..config() };
+        assert!(any_origin.validate().is_err());
+        assert!(CorsConfig { allow_credentials: false, ..any_origin }.validate().is_ok());
+    }
+
+    #[test]
+    fn cors_adds_headers_for_allowed_origins() {
+        let response = handle(config(), "GET / HTTP/1.1\r\nOrigin: https://app.example.com\r\n\r\n");
+
+        assert_eq!(response, "HTTP/1.1 200 OK\r\n\
+            Content-Length: 2\r\n\
+            Content-Type: text/plain\r\n\
+            Vary: Origin\r\n\
+            Access-Control-Allow-Origin: https://app.example.com\r\n\
+            Access-Control-Allow-Credentials: true\r\n\
+            Connection: Closed\r\n\r\n\
+            OK");
+    }
+
+    #[test]
+    fn cors_omits_headers_for_other_origins() {
+        let response = handle(config(), "GET / HTTP/1.1\r\nOrigin: https://evil.com\r\n\r\n");
+
+        assert!(response.contains("\r\nVary: Origin\r\n"));
+        assert!(!response.contains("Access-Control-Allow-Origin"));
+    }
+
+    #[test]
+    fn cors_allows_any_origin_without_credentials() {
+        let config = CorsConfig { allowed_origins: vec![OriginPattern::Any], ..CorsConfig::default() };
+
+        let response = handle(config, "GET / HTTP/1.1\r\nOrigin: https://anything.com\r\n\r\n");
+
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: *\r\n"));
+        assert!(!response.contains("Vary"));
+    }
+
+    #[test]
+    fn cors_answers_preflight_requests() {
+        let response = handle(config(), "OPTIONS / HTTP/1.1\r\n\
+            Origin: https://api.example.org\r\n\
+            Access-Control-Request-Method: PUT\r\n\
+            Access-Control-Request-Headers: content-type, x-api-key\r\n\r\n");
+
+        assert_eq!(response, "HTTP/1.1 204 NO CONTENT\r\n\
+            Allow: GET, HEAD, OPTIONS\r\n\
+            Vary: Origin, Access-Control-Request-Method, Access-Control-Request-Headers\r\n\
+            Access-Control-Allow-Origin: https://api.example.org\r\n\
+            Access-Control-Allow-Credentials: true\r\n\
+            Access-Control-Allow-Methods: GET, PUT\r\n\
+            Access-Control-Allow-Headers: Content-Type, X-API-Key\r\n\
+            Access-Control-Max-Age: 600\r\n\
+            Connection: Closed\r\n\r\n");
+    }
+
+    #[test]
+    fn cors_rejects_disallowed_preflight_requests() {
+        let disallowed_requests = [
+            // Method not allowed.
+            "OPTIONS / HTTP/1.1\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: DELETE\r\n\r\n",
+            // Header not allowed.
+            "OPTIONS / HTTP/1.1\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: GET\r\n\
+                Access-Control-Request-Headers: X-Other\r\n\r\n",
+            // Origin not allowed.
+            "OPTIONS / HTTP/1.1\r\nOrigin: https://evil.com\r\nAccess-Control-Request-Method: GET\r\n\r\n",
+        ];
+
+        for request in disallowed_requests.iter() {
+            assert!(!handle(config(), request).contains("Access-Control-Allow"));
+        }
+    }
+
+    #[test]
+    fn cors_leaves_preflights_to_unknown_routes_unanswered() {
+        let response = handle(config(), "OPTIONS /missing HTTP/1.1\r\n\
+            Origin: https://app.example.com\r\n\
+            Access-Control-Request-Method: GET\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+        assert!(!response.contains("Access-Control-Allow-Methods"));
+    }
+}
@@ -0,0 +1,596 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::api::{ApiError, ApiResult};
+use crate::handler::{HttpRequest, HttpResponse};
+use crate::servererror::ServerError;
+
+// The largest frame either side will read, in bytes, so that a corrupt length cannot exhaust memory.
+pub const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// The protocol error for a message that ends before its length says it should.
+pub const TRUNCATED_MESSAGE: &str = "Message is truncated.";
+
+// The most entries the key-value route lists.
+const KV_SCAN_LIMIT: u32 = 1000;
+
+// The commands a request may carry.
+const COMMAND_PING: u8 = 0x01;
+const COMMAND_GET: u8 = 0x02;
+const COMMAND_PUT: u8 = 0x03;
+const COMMAND_DELETE: u8 = 0x04;
+const COMMAND_SCAN: u8 = 0x05;
+
+// The kinds of response.
+const RESPONSE_DONE: u8 = 0x00;
+const RESPONSE_VALUE: u8 = 0x01;
+const RESPONSE_NOT_FOUND: u8 = 0x02;
+const RESPONSE_DELETED: u8 = 0x03;
+const RESPONSE_ENTRIES: u8 = 0x04;
+const RESPONSE_ERROR: u8 = 0x7F;
+
+/// The errors a database client can fail with.
+#[derive(Debug, PartialEq)]
+pub enum DbError {
+    // The connection to the database could not be made or was lost.
+    Connection(String),
+    // The database took too long to answer.
+    Timeout,
+    // A message could not be understood.
+    Protocol(String),
+    // The database understood the request but failed to carry it out.
+    Server(String),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            DbError::Connection(message) => write!(f, "Database connection failed: {}", message),
+            DbError::Timeout => f.write_str("Database request timed out."),
+            DbError::Protocol(message) => write!(f, "Database protocol error: {}", message),
+            DbError::Server(message) => write!(f, "Database error: {}", message),
+        };
+    }
+}
+
+impl From<io::Error> for DbError {
+    fn from(err: io::Error) -> Self {
+        return match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => DbError::Timeout,
+            _ => DbError::Connection(err.to_string())
+        };
+    }
+}
+
+impl From<DbError> for ServerError {
+    fn from(err: DbError) -> Self {
+        return ServerError::new(err.to_string());
+    }
+}
+
+pub type DbResult<T> = std::result::Result<T, DbError>;
+
+/// A request to the database.
+///
+/// On the wire, each message is a frame: a four-byte big-endian length, then that many bytes.
+/// A request's frame holds a one-byte command followed by its arguments, each of which is a
+/// four-byte big-endian length then the bytes, except for SCAN's limit, which is a bare four-byte
+/// big-endian integer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DbRequest {
+    Ping,
+    Get { key: Vec<u8> },
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+    // Returns up to `limit` entries whose keys start with the prefix, in key order.
+    Scan { prefix: Vec<u8>, limit: u32 },
+}
+
+impl DbRequest {
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            DbRequest::Ping => body.push(COMMAND_PING),
+            DbRequest::Get { key } => {
+                body.push(COMMAND_GET);
+                put_bytes(&mut body, key);
+            }
+            DbRequest::Put { key, value } => {
+                body.push(COMMAND_PUT);
+                put_bytes(&mut body, key);
+                put_bytes(&mut body, value);
+            }
+            DbRequest::Delete { key } => {
+                body.push(COMMAND_DELETE);
+                put_bytes(&mut body, key);
+            }
+            DbRequest::Scan { prefix, limit } => {
+                body.push(COMMAND_SCAN);
+                put_bytes(&mut body, prefix);
+                body.extend_from_slice(&limit.to_be_bytes());
+            }
+        }
+        return body;
+    }
+
+    fn decode(body: &[u8]) -> DbResult<DbRequest> {
+        let mut decoder = Decoder { remaining: body };
+        let request = match decoder.byte()? {
+            COMMAND_PING => DbRequest::Ping,
+            COMMAND_GET => DbRequest::Get { key: decoder.bytes()? },
+            COMMAND_PUT => DbRequest::Put { key: decoder.bytes()?, value: decoder.bytes()? },
+            COMMAND_DELETE => DbRequest::Delete { key: decoder.bytes()? },
+            COMMAND_SCAN => DbRequest::Scan { prefix: decoder.bytes()?, limit: decoder.u32()? },
+            command => return Err(DbError::Protocol(format!("Unknown command {}.", command)))
+        };
+        decoder.finish()?;
+        return Ok(request);
+    }
+
+    /// Writes the request as a frame.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> DbResult<()> {
+        return write_frame(writer, &self.encode());
+    }
+
+    /// Reads a request frame. Returns None if the connection closed cleanly beforehand.
+    pub fn read_from<R: Read>(reader: &mut R) -> DbResult<Option<DbRequest>> {
+        return read_frame(reader)?.map(|body| DbRequest::decode(&body)).transpose();
+    }
+}
+
+/// A response from the database. On the wire, a response's frame holds a one-byte kind followed
+/// by its fields, encoded as for requests.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DbResponse {
+    // Answers PING and PUT.
+    Done,
+    Value(Vec<u8>),
+    NotFound,
+    // Whether DELETE removed a key. On the wire, a single byte.
+    Deleted(bool),
+    // On the wire, a four-byte big-endian count followed by each key and value.
+    Entries(Vec<(Vec<u8>, Vec<u8>)>),
+    Error(String),
+}
+
+impl DbResponse {
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            DbResponse::Done => body.push(RESPONSE_DONE),
+            DbResponse::Value(value) => {
+                body.push(RESPONSE_VALUE);
+                put_bytes(&mut body, value);
+            }
+            DbResponse::NotFound => body.push(RESPONSE_NOT_FOUND),
+            DbResponse::Deleted(deleted) => body.extend_from_slice(&[RESPONSE_DELETED, *deleted as u8]),
+            DbResponse::Entries(entries) => {
+                body.push(RESPONSE_ENTRIES);
+                body.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for (key, value) in entries.iter() {
+                    put_bytes(&mut body, key);
+                    put_bytes(&mut body, value);
+                }
+            }
+            DbResponse::Error(message) => {
+                body.push(RESPONSE_ERROR);
+                put_bytes(&mut body, message.as_bytes());
+            }
+        }
+        return body;
+    }
+
+    fn decode(body: &[u8]) -> DbResult<DbResponse> {
+        let mut decoder = Decoder { remaining: body };
+        let response = match decoder.byte()? {
+            RESPONSE_DONE => DbResponse::Done,
+            RESPONSE_VALUE => DbResponse::Value(decoder.bytes()?),
+            RESPONSE_NOT_FOUND => DbResponse::NotFound,
+            RESPONSE_DELETED => DbResponse::Deleted(decoder.byte()? != 0),
+            RESPONSE_ENTRIES => {
+                let count = decoder.u32()?;
+                // Each entry takes at least eight bytes, which bounds the allocation.
+                let mut entries = Vec::with_capacity((count as usize).min(decoder.remaining.len() / 8));
+                for _ in 0..count {
+                    entries.push((decoder.bytes()?, decoder.bytes()?));
+                }
+                DbResponse::Entries(entries)
+            }
+            RESPONSE_ERROR => DbResponse::Error(String::from_utf8_lossy(&decoder.bytes()?).into()),
+            kind => return Err(DbError::Protocol(format!("Unknown response kind {}.", kind)))
+        };
+        decoder.finish()?;
+        return Ok(response);
+    }
+
+    /// Writes the response as a frame.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> DbResult<()> {
+        return write_frame(writer, &self.encode());
+    }
+
+    /// Reads a response frame. Fails if the connection closed beforehand.
+    pub fn read_from<R: Read>(reader: &mut R) -> DbResult<DbResponse> {
+        let body = read_frame(reader)?.ok_or_else(|| DbError::Connection("Closed by the database.".into()))?;
+        return DbResponse::decode(&body);
+    }
+}
+
+/// Appends a length-prefixed byte string.
+fn put_bytes(body: &mut Vec<u8>, bytes: &[u8]) {
+    body.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    body.extend_from_slice(bytes);
+}
+
+/// Reads the fields of a message body in turn.
+struct Decoder<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, length: usize) -> DbResult<&'a [u8]> {
+        if self.remaining.len() < length {
+            return Err(DbError::Protocol(TRUNCATED_MESSAGE.into()));
+        }
+        let (taken, remaining) = self.remaining.split_at(length);
+        self.remaining = remaining;
+        return Ok(taken);
+    }
+
+    fn byte(&mut self) -> DbResult<u8> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u32(&mut self) -> DbResult<u32> {
+        let bytes = self.take(4)?;
+        return Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    }
+
+    fn bytes(&mut self) -> DbResult<Vec<u8>> {
+        let length = self.u32()? as usize;
+        return Ok(self.take(length)?.to_vec());
+    }
+
+    /// Fails if anything is left over.
+    fn finish(&self) -> DbResult<()> {
+        return match self.remaining.is_empty() {
+            true => Ok(()),
+            false => Err(DbError::Protocol("Message has trailing bytes.".into()))
+        };
+    }
+}
+
+fn write_frame<W: Write>(writer: &mut W, body: &[u8]) -> DbResult<()> {
+    if body.len() > MAX_FRAME_LENGTH {
+        return Err(DbError::Protocol(format!("Message exceeds {} bytes.", MAX_FRAME_LENGTH)));
+    }
+    writer.write_all(&(body.len() as u32).to_be_bytes())?;
+    writer.write_all(body)?;
+    writer.flush()?;
+    return Ok(());
+}
+
+/// Reads a frame's body. Returns None if the stream ends before the frame starts.
+fn read_frame<R: Read>(reader: &mut R) -> DbResult<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    let mut length_read = 0;
+    while length_read < length.len() {
+        match reader.read(&mut length[length_read..]) {
+            Ok(0) if length_read == 0 => return Ok(None),
+            Ok(0) => return Err(DbError::Protocol(TRUNCATED_MESSAGE.into())),
+            Ok(bytes_read) => length_read += bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into())
+        }
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(DbError::Protocol(format!("Message exceeds {} bytes.", MAX_FRAME_LENGTH)));
+    }
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => DbError::Protocol(TRUNCATED_MESSAGE.into()),
+        _ => e.into()
+    })?;
+    return Ok(Some(body));
+}
+
+/// A client of the database, shared by every connection thread.
+pub trait Database: Send + Sync {
+    // Sends the request and waits for the response.
+    fn execute(&self, request: &DbRequest) -> DbResult<DbResponse>;
+
+    /// Whether the database is believed to be reachable, judging by recent requests rather than
+    /// by contacting it.
+    fn is_available(&self) -> bool {
+        return true;
+    }
+
+    /// Checks that the database is reachable and answering.
+    fn ping(&self) -> DbResult<()> {
+        return match self.execute(&DbRequest::Ping)? {
+            DbResponse::Done => Ok(()),
+            DbResponse::Error(message) => Err(DbError::Server(message)),
+            response => Err(DbError::Protocol(format!("Unexpected response to PING: {:?}", response)))
+        };
+    }
+}
+
+/// An open connection to the database server, which sends requests one at a time.
+pub struct DbConnection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl DbConnection {
+    /// Connects to the database server, waiting at most the timeout to connect and then for each
+    /// response.
+    pub fn open(address: &str, timeout: Duration) -> DbResult<DbConnection> {
+        let mut last_error = DbError::Connection(format!("{} did not resolve to an address.", address));
+        for socket_address in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_address, timeout) {
+                Err(e) => last_error = e.into(),
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    stream.set_nodelay(true)?;
+                    return Ok(DbConnection { reader: BufReader::new(stream.try_clone()?), writer: BufWriter::new(stream) });
+                }
+            }
+        }
+        return Err(last_error);
+    }
+
+    /// Sends the request and waits for the response. After a failure, the connection may be
+    /// part-way through a message, so must not be reused.
+    pub fn execute(&mut self, request: &DbRequest) -> DbResult<DbResponse> {
+        request.write_to(&mut self.writer)?;
+        return DbResponse::read_from(&mut self.reader);
+    }
+}
+
+/// Key-value storage held in memory, which carries out requests.
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        return MemoryStore::default();
+    }
+
+    /// Carries out the request, returning the response to send.
+    pub fn apply(&mut self, request: &DbRequest) -> DbResponse {
+        return match request {
+            DbRequest::Ping => DbResponse::Done,
+            DbRequest::Get { key } => match self.entries.get(key) {
+                None => DbResponse::NotFound,
+                Some(value) => DbResponse::Value(value.clone())
+            },
+            DbRequest::Put { key, value } => {
+                self.entries.insert(key.clone(), value.clone());
+                DbResponse::Done
+            }
+            DbRequest::Delete { key } => DbResponse::Deleted(self.entries.remove(key).is_some()),
+            DbRequest::Scan { prefix, limit } => DbResponse::Entries(self.entries.range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .take(*limit as usize)
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect())
+        };
+    }
+}
+
+/// A database held in memory in this process, for tests and for running without a database
+/// server. Requests and responses still pass through the wire protocol, so that it behaves as a
+/// client of a real server would.
+#[derive(Default)]
+pub struct LocalDatabase {
+    store: Mutex<MemoryStore>,
+}
+
+impl LocalDatabase {
+    pub fn new() -> LocalDatabase {
+        return LocalDatabase { store: Mutex::new(MemoryStore::new()) };
+    }
+}
+
+impl Database for LocalDatabase {
+    fn execute(&self, request: &DbRequest) -> DbResult<DbResponse> {
+        let mut request_frame = Vec::new();
+        request.write_to(&mut request_frame)?;
+        let request = DbRequest::read_from(&mut request_frame.as_slice())?
+            .ok_or_else(|| DbError::Protocol("Request is empty.".into()))?;
+
+        let response = self.store.lock().map_err(|e| DbError::Server(e.to_string()))?.apply(&request);
+
+        let mut response_frame = Vec::new();
+        response.write_to(&mut response_frame)?;
+        return DbResponse::read_from(&mut response_frame.as_slice());
+    }
+}
+
+/// Failures to reach the database are temporary, so the client may retry. Other failures are
+/// internal errors.
+impl From<DbError> for ApiError {
+    fn from(err: DbError) -> Self {
+        return match err {
+            DbError::Connection(_) | DbError::Timeout => ApiError::service_unavailable(&err.to_string()),
+            _ => ServerError::from(err).into()
+        };
+    }
+}
+
+/// The body of a request to the key-value route.
+#[derive(Deserialize)]
+struct KvRequest {
+    key: String,
+    // Only needed to store a value.
+    value: Option<String>,
+}
+
+/// Returns an API route over the database's entries, which are treated as text: GET lists them,
+/// PUT stores `{"key":"...","value":"..."}` and DELETE removes `{"key":"..."}`.
+pub fn kv_route(database: Arc<dyn Database>) -> impl Fn(&HttpRequest) -> ApiResult + Send + Sync {
+    return move |request: &HttpRequest| {
+        if request.method == "GET" {
+            return match database.execute(&DbRequest::Scan { prefix: Vec::new(), limit: KV_SCAN_LIMIT })? {
+                DbResponse::Entries(entries) => {
+                    let entries: Map<String, Value> = entries.into_iter()
+                        .map(|(key, value)| (String::from_utf8_lossy(&key).into(), String::from_utf8_lossy(&value).into()))
+                        .collect();
+                    Ok(HttpResponse::json("200 OK", &json!({ "entries": entries }))?)
+                }
+                response => Err(unexpected_response(response).into())
+            };
+        }
+
+        let kv_request: KvRequest = request.json()?;
+        let key = kv_request.key.into_bytes();
+        return match request.method.as_str() {
+            "PUT" => {
+                let value = kv_request.value.ok_or_else(|| ApiError::bad_request("A value is required."))?;
+                match database.execute(&DbRequest::Put { key, value: value.into_bytes() })? {
+                    DbResponse::Done => Ok(HttpResponse::empty("204 NO CONTENT")),
+                    response => Err(unexpected_response(response).into())
+                }
+            }
+            _ => match database.execute(&DbRequest::Delete { key })? {
+                DbResponse::Deleted(existed) => Ok(HttpResponse::json("200 OK", &json!({ "deleted": existed }))?),
+                response => Err(unexpected_response(response).into())
+            }
+        };
+    };
+}
+
+/// The error for a response that does not answer the request, or that reports a server error.
+fn unexpected_response(response: DbResponse) -> DbError {
+    return match response {
+        DbResponse::Error(message) => DbError::Server(message),
+        response => DbError::Protocol(format!("Unexpected response: {:?}", response))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::str::from_utf8;
+    use std::sync::Arc;
+
+    use crate::api::ApiError;
+    use crate::database::{kv_route, Database, DbError, DbRequest, DbResponse, LocalDatabase, MemoryStore};
+    use crate::handler::HttpRequest;
+
+    #[test]
+    fn messages_survive_the_wire_protocol() {
+        let requests = [
+            DbRequest::Ping,
+            DbRequest::Get { key: b"block:1".to_vec() },
+            DbRequest::Put { key: b"block:1".to_vec(), value: vec![0, 255, 10] },
+            DbRequest::Delete { key: Vec::new() },
+            DbRequest::Scan { prefix: b"block:".to_vec(), limit: 70_000 },
+        ];
+        for request in requests.iter() {
+            let mut frame = Vec::new();
+            request.write_to(&mut frame).unwrap();
+            assert_eq!(DbRequest::read_from(&mut frame.as_slice()).unwrap().as_ref(), Some(request));
+        }
+
+        let responses = [
+            DbResponse::Done,
+            DbResponse::Value(b"value".to_vec()),
+            DbResponse::NotFound,
+            DbResponse::Deleted(true),
+            DbResponse::Entries(vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), Vec::new())]),
+            DbResponse::Error("Disk full.".into()),
+        ];
+        for response in responses.iter() {
+            let mut frame = Vec::new();
+            response.write_to(&mut frame).unwrap();
+            assert_eq!(&DbResponse::read_from(&mut frame.as_slice()).unwrap(), response);
+        }
+
+        assert_eq!(DbRequest::read_from(&mut [].as_ref()).unwrap(), None);
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let malformed_frames: [&[u8]; 5] = [
+            &[0, 0, 0, 1, 0x09],
+            &[0, 0, 0, 3, 0x02, 0, 0],
+            &[0, 0, 0, 2, 0x01, 0],
+            &[0, 0, 0, 5, 0x01],
+            &[0xFF, 0xFF, 0xFF, 0xFF],
+        ];
+
+        for frame in malformed_frames.iter() {
+            assert!(matches!(DbRequest::read_from(&mut &frame[..]), Err(DbError::Protocol(_))));
+        }
+        assert!(matches!(DbResponse::read_from(&mut [].as_ref()), Err(DbError::Connection(_))));
+    }
+
+    #[test]
+    fn stores_get_put_delete_and_scan() {
+        let mut store = MemoryStore::new();
+        for key in ["b:2", "a:1", "b:1", "b:3", "c:1"].iter() {
+            assert_eq!(store.apply(&DbRequest::Put { key: key.as_bytes().to_vec(), value: key.as_bytes().to_vec() }), DbResponse::Done);
+        }
+
+        assert_eq!(store.apply(&DbRequest::Get { key: b"a:1".to_vec() }), DbResponse::Value(b"a:1".to_vec()));
+        assert_eq!(store.apply(&DbRequest::Delete { key: b"a:1".to_vec() }), DbResponse::Deleted(true));
+        assert_eq!(store.apply(&DbRequest::Delete { key: b"a:1".to_vec() }), DbResponse::Deleted(false));
+        assert_eq!(store.apply(&DbRequest::Get { key: b"a:1".to_vec() }), DbResponse::NotFound);
+
+        let scanned = store.apply(&DbRequest::Scan { prefix: b"b:".to_vec(), limit: 2 });
+        assert_eq!(scanned, DbResponse::Entries(vec![(b"b:1".to_vec(), b"b:1".to_vec()), (b"b:2".to_vec(), b"b:2".to_vec())]));
+    }
+
+    #[test]
+    fn local_databases_answer_through_the_protocol() {
+        let database = LocalDatabase::new();
+
+        database.execute(&DbRequest::Put { key: b"k".to_vec(), value: b"v".to_vec() }).unwrap();
+
+        assert_eq!(database.ping(), Ok(()));
+        assert_eq!(database.execute(&DbRequest::Get { key: b"k".to_vec() }), Ok(DbResponse::Value(b"v".to_vec())));
+    }
+
+    fn kv_request(method: &str, body: &str) -> HttpRequest {
+        let mut headers = HashMap::new();
+        headers.insert("content-type".into(), "application/json".into());
+        let mut request = HttpRequest::new(method, "/api/kv", "HTTP/1.1", headers);
+        request.body = body.into();
+        return request;
+    }
+
+    #[test]
+    fn kv_route_stores_lists_and_deletes_entries() {
+        let route = kv_route(Arc::new(LocalDatabase::new()));
+
+        assert_eq!(route(&kv_request("PUT", "{\"key\":\"b\",\"value\":\"2\"}")).unwrap().status_code, "204 NO CONTENT");
+        assert_eq!(route(&kv_request("PUT", "{\"key\":\"a\",\"value\":\"1\"}")).unwrap().status_code, "204 NO CONTENT");
+        assert_eq!(route(&kv_request("PUT", "{\"key\":\"c\"}")).unwrap_err(), ApiError::bad_request("A value is required."));
+
+        let response = route(&kv_request("DELETE", "{\"key\":\"b\"}")).unwrap();
+        assert_eq!(from_utf8(&response.body).unwrap(), "{\"deleted\":true}");
+
+        let response = route(&kv_request("GET", "")).unwrap();
+        assert_eq!(from_utf8(&response.body).unwrap(), "{\"entries\":{\"a\":\"1\"}}");
+    }
+
+    #[test]
+    fn unreachable_databases_are_reported_as_unavailable() {
+        let error = ApiError::from(DbError::Timeout);
+
+        assert_eq!(error.status_code, "503 SERVICE UNAVAILABLE");
+        assert_eq!(ApiError::from(DbError::Protocol("Bad frame.".into())).status_code, "500 INTERNAL SERVER ERROR");
+    }
+}
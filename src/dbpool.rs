@@ -0,0 +1,451 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use crate::database::{Database, DbConnection, DbError, DbRequest, DbResponse, DbResult};
+use crate::logger;
+use crate::metrics;
+
+// How often the pool tops itself up to its minimum size and closes surplus idle connections.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The sizes and timeouts of a connection pool.
+#[derive(Clone, Debug)]
+pub struct DbPoolConfig {
+    // The connections kept open, even while idle.
+    pub min_size: usize,
+    // The most connections open at once.
+    pub max_size: usize,
+    // How long to wait for a connection when all are in use.
+    pub checkout_timeout: Duration,
+    // How long to wait to connect, and for each response.
+    pub request_timeout: Duration,
+    // Connections idle for longer than this are pinged before they are handed out.
+    pub validation_interval: Duration,
+    // Connections beyond the minimum are closed once idle for this long.
+    pub idle_timeout: Duration,
+    // How long to wait before reconnecting after the first failure. The wait doubles with each
+    // further failure, up to the maximum.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for DbPoolConfig {
+    fn default() -> DbPoolConfig {
+        return DbPoolConfig {
+            min_size: 1,
+            max_size: 10,
+            checkout_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(5),
+            validation_interval: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(5 * 60),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+        };
+    }
+}
+
+/// A connection waiting to be checked out.
+struct IdleConnection {
+    connection: DbConnection,
+    // When the connection was last returned to the pool.
+    returned: Instant,
+}
+
+/// The state of the pool, guarded by a single lock.
+struct PoolState {
+    // The most recently returned connections are at the end, and are handed out first.
+    idle: Vec<IdleConnection>,
+    // The connections open, including those checked out and those being opened.
+    open: usize,
+    // The wait after the latest failure to connect, if the last attempt failed.
+    backoff: Option<Duration>,
+    // No connections are attempted before this, while backing off.
+    retry_at: Option<Instant>,
+}
+
+/// A pool of connections to the database server, shared by every connection thread. Threads check
+/// out a connection for each request, waiting if all are in use. Connections that fail are closed
+/// and replaced, backing off exponentially while the database is unreachable.
+pub struct DbPool {
+    address: String,
+    config: DbPoolConfig,
+    state: Mutex<PoolState>,
+    // Notified when a connection is returned, or a slot for one frees up.
+    available: Condvar,
+}
+
+impl DbPool {
+    /// Creates the pool, and starts a background thread that keeps the minimum number of
+    /// connections open. The thread stops once the pool is dropped.
+    pub fn start(address: &str, config: DbPoolConfig) -> Arc<DbPool> {
+        let pool = Arc::new(DbPool::new(address, config));
+
+        let weak_pool: Weak<DbPool> = Arc::downgrade(&pool);
+        spawn(move || {
+            while let Some(pool) = weak_pool.upgrade() {
+                pool.maintain();
+                drop(pool);
+                sleep(MAINTENANCE_INTERVAL);
+            }
+        });
+        return pool;
+    }
+
+    fn new(address: &str, config: DbPoolConfig) -> DbPool {
+        let state = PoolState { idle: Vec::new(), open: 0, backoff: None, retry_at: None };
+        return DbPool { address: address.into(), config, state: Mutex::new(state), available: Condvar::new() };
+    }
+
+    fn lock(&self) -> DbResult<MutexGuard<'_, PoolState>> {
+        return self.state.lock().map_err(|_| DbError::Connection("The connection pool is poisoned.".into()));
+    }
+
+    /// Checks out a connection, reusing an idle one if possible, otherwise opening one if the pool
+    /// has room, otherwise waiting for one to be returned. Fails at once while backing off from a
+    /// failure to connect, and with a timeout if none is returned in time.
+    fn checkout(&self) -> DbResult<PooledConnection<'_>> {
+        let deadline = Instant::now() + self.config.checkout_timeout;
+        let mut state = self.lock()?;
+
+        loop {
+            if let Some(idle) = state.idle.pop() {
+                record_state(&state);
+                drop(state);
+                if let Some(connection) = self.validate(idle) {
+                    return Ok(PooledConnection { pool: self, connection: Some(connection) });
+                }
+                state = self.lock()?;
+                continue;
+            }
+
+            if state.open < self.config.max_size {
+                if let Some(retry_at) = state.retry_at.filter(|retry_at| *retry_at > Instant::now()) {
+                    let wait = retry_at.saturating_duration_since(Instant::now());
+                    return Err(DbError::Connection(format!("Unreachable, retrying in {} ms.", wait.as_millis())));
+                }
+                state.open += 1;
+                drop(state);
+                let connection = self.connect()?;
+                return Ok(PooledConnection { pool: self, connection: Some(connection) });
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                metrics::global().db_pool_checkout_timeouts_total.inc();
+                return Err(DbError::Timeout);
+            }
+            state = self.available.wait_timeout(state, deadline - now)
+                .map_err(|_| DbError::Connection("The connection pool is poisoned.".into()))?.0;
+        }
+    }
+
+    /// Returns the connection if it is still usable, pinging it first if it has been idle a while.
+    /// Otherwise, closes it.
+    fn validate(&self, idle: IdleConnection) -> Option<DbConnection> {
+        let mut connection = idle.connection;
+        if idle.returned.elapsed() < self.config.validation_interval || connection.execute(&DbRequest::Ping) == Ok(DbResponse::Done) {
+            return Some(connection);
+        }
+
+        metrics::global().db_pool_validation_failures_total.inc();
+        self.release_slot();
+        return None;
+    }
+
+    /// Opens a connection in a slot already counted as open, releasing the slot and backing off
+    /// if that fails.
+    fn connect(&self) -> DbResult<DbConnection> {
+        let result = DbConnection::open(&self.address, self.config.request_timeout);
+
+        let mut state = self.lock()?;
+        match &result {
+            Ok(_) => {
+                if state.backoff.is_some() {
+                    logger::global().info(&format!("Reconnected to the database at {}.", self.address));
+                }
+                state.backoff = None;
+                state.retry_at = None;
+            }
+            Err(e) => {
+                if state.backoff.is_none() {
+                    logger::global().warn(&format!("The database at {} is unreachable, retrying in the background: {}", self.address, e));
+                }
+                metrics::global().db_pool_connect_failures_total.inc();
+                let backoff = match state.backoff {
+                    None => self.config.initial_backoff,
+                    Some(backoff) => (backoff * 2).min(self.config.max_backoff)
+                };
+                state.backoff = Some(backoff);
+                state.retry_at = Some(Instant::now() + backoff);
+                state.open -= 1;
+                self.available.notify_one();
+            }
+        }
+        record_state(&state);
+        return result;
+    }
+
+    /// Forgets a connection that has been closed, freeing its slot.
+    fn release_slot(&self) {
+        if let Ok(mut state) = self.lock() {
+            state.open -= 1;
+            record_state(&state);
+        }
+        self.available.notify_one();
+    }
+
+    /// Returns a healthy connection to the pool.
+    fn check_in(&self, connection: DbConnection) {
+        if let Ok(mut state) = self.lock() {
+            state.idle.push(IdleConnection { connection, returned: Instant::now() });
+            record_state(&state);
+        }
+        self.available.notify_one();
+    }
+
+    /// Closes surplus connections that have been idle too long, and opens connections until the
+    /// pool has its minimum once each backoff has passed. While the database is unreachable, at
+    /// least one connection is attempted, so that the pool notices once it is back.
+    fn maintain(&self) {
+        if let Ok(mut state) = self.lock() {
+            let min_size = self.config.min_size;
+            let idle_timeout = self.config.idle_timeout;
+            // The longest-idle connections are at the front.
+            while state.open > min_size && state.idle.first().map(|idle| idle.returned.elapsed() >= idle_timeout).unwrap_or(false) {
+                state.idle.remove(0);
+                state.open -= 1;
+            }
+            record_state(&state);
+        }
+
+        loop {
+            match self.lock() {
+                Ok(mut state) if state.open < self.wanted_size(&state) && state.retry_at.map(|retry_at| retry_at <= Instant::now()).unwrap_or(true) => {
+                    state.open += 1;
+                }
+                _ => return
+            }
+            match self.connect() {
+                Ok(connection) => self.check_in(connection),
+                Err(_) => return
+            }
+        }
+    }
+
+    /// The connections maintenance keeps open.
+    fn wanted_size(&self, state: &PoolState) -> usize {
+        return match state.backoff {
+            None => self.config.min_size,
+            Some(_) => self.config.min_size.max(1)
+        };
+    }
+}
+
+impl Database for DbPool {
+    fn execute(&self, request: &DbRequest) -> DbResult<DbResponse> {
+        return self.checkout()?.execute(request);
+    }
+
+    /// The database is unavailable from a failure to connect until a connection next succeeds.
+    fn is_available(&self) -> bool {
+        return self.lock().map(|state| state.backoff.is_none()).unwrap_or(false);
+    }
+}
+
+/// Publishes the pool's size.
+fn record_state(state: &PoolState) {
+    metrics::global().db_pool_connections_open.set(state.open as i64);
+    metrics::global().db_pool_connections_idle.set(state.idle.len() as i64);
+}
+
+/// A connection checked out of the pool, which is returned once dropped.
+struct PooledConnection<'a> {
+    pool: &'a DbPool,
+    // Taken when the connection fails, so that it is closed rather than returned.
+    connection: Option<DbConnection>,
+}
+
+impl PooledConnection<'_> {
+    fn execute(&mut self, request: &DbRequest) -> DbResult<DbResponse> {
+        let connection = self.connection.as_mut().ok_or_else(|| DbError::Connection("Already closed.".into()))?;
+        let result = connection.execute(request);
+        if result.is_err() {
+            self.connection = None;
+            self.pool.release_slot();
+        }
+        return result;
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.check_in(connection);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, BufWriter};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{sleep, spawn};
+    use std::time::{Duration, Instant};
+
+    use crate::database::{Database, DbError, DbRequest, DbResponse, MemoryStore};
+    use crate::dbpool::{DbPool, DbPoolConfig};
+
+    /// Starts a database server on its own thread, which serves each connection on a thread of its
+    /// own until the client hangs up, or it has answered the given number of requests. Returns its
+    /// address and the number of connections it has accepted.
+    fn start_database(requests_per_connection: usize) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let store = Arc::new(Mutex::new(MemoryStore::new()));
+
+        let accepted_by_server = accepted.clone();
+        spawn(move || {
+            for stream in listener.incoming() {
+                accepted_by_server.fetch_add(1, Ordering::SeqCst);
+                let stream = stream.unwrap();
+                let store = store.clone();
+                spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut writer = BufWriter::new(stream);
+                    for _ in 0..requests_per_connection {
+                        match DbRequest::read_from(&mut reader) {
+                            Ok(Some(request)) => store.lock().unwrap().apply(&request).write_to(&mut writer).unwrap(),
+                            _ => return
+                        }
+                    }
+                });
+            }
+        });
+        return (address, accepted);
+    }
+
+    fn config(min_size: usize, max_size: usize) -> DbPoolConfig {
+        return DbPoolConfig { min_size, max_size, checkout_timeout: Duration::from_millis(200), ..DbPoolConfig::default() };
+    }
+
+    #[test]
+    fn pools_reuse_connections() {
+        let (address, accepted) = start_database(usize::MAX);
+        let pool = DbPool::new(&address, config(0, 4));
+
+        for index in 0..10u8 {
+            pool.execute(&DbRequest::Put { key: vec![index], value: vec![index] }).unwrap();
+        }
+
+        assert_eq!(pool.execute(&DbRequest::Get { key: vec![9] }), Ok(DbResponse::Value(vec![9])));
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn pools_share_connections_across_threads_up_to_their_maximum() {
+        let (address, accepted) = start_database(usize::MAX);
+        let pool = Arc::new(DbPool::new(&address, DbPoolConfig { checkout_timeout: Duration::from_secs(5), ..config(0, 3) }));
+
+        let threads: Vec<_> = (0..8).map(|_| {
+            let pool = pool.clone();
+            spawn(move || {
+                for _ in 0..20 {
+                    pool.ping().unwrap();
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(accepted.load(Ordering::SeqCst) <= 3);
+        let state = pool.lock().unwrap();
+        assert_eq!(state.open, state.idle.len());
+    }
+
+    #[test]
+    fn checkouts_time_out_once_every_connection_is_in_use() {
+        let (address, _) = start_database(usize::MAX);
+        let pool = DbPool::new(&address, config(0, 1));
+
+        let checked_out = pool.checkout().unwrap();
+        let start = Instant::now();
+        assert!(matches!(pool.checkout(), Err(DbError::Timeout)));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        drop(checked_out);
+        assert!(pool.checkout().is_ok());
+    }
+
+    #[test]
+    fn idle_connections_are_validated_and_replaced() {
+        // The server hangs up after answering a single request on each connection.
+        let (address, accepted) = start_database(1);
+        let pool = DbPool::new(&address, DbPoolConfig { validation_interval: Duration::from_secs(0), ..config(0, 2) });
+
+        pool.ping().unwrap();
+        pool.ping().unwrap();
+
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.lock().unwrap().open, 1);
+    }
+
+    #[test]
+    fn reconnection_backs_off_exponentially() {
+        let backoff_config = DbPoolConfig { initial_backoff: Duration::from_millis(50), max_backoff: Duration::from_millis(80), ..config(0, 2) };
+        let pool = DbPool::new("127.0.0.1:1", backoff_config);
+
+        assert!(matches!(pool.ping(), Err(DbError::Connection(message)) if !message.starts_with("Unreachable")));
+        assert!(matches!(pool.ping(), Err(DbError::Connection(message)) if message.starts_with("Unreachable")));
+        assert_eq!(pool.lock().unwrap().backoff, Some(Duration::from_millis(50)));
+
+        sleep(Duration::from_millis(60));
+        assert!(pool.ping().is_err());
+        assert_eq!(pool.lock().unwrap().backoff, Some(Duration::from_millis(80)));
+        assert_eq!(pool.lock().unwrap().open, 0);
+    }
+
+    #[test]
+    fn pools_are_unavailable_until_a_connection_succeeds() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let pool = DbPool::new(&address, DbPoolConfig { initial_backoff: Duration::from_millis(0), ..config(0, 2) });
+        assert!(pool.is_available());
+
+        assert!(pool.ping().is_err());
+        assert!(!pool.is_available());
+
+        // Maintenance reconnects in the background, even though the pool keeps no minimum.
+        let _listener = TcpListener::bind(&address).unwrap();
+        pool.maintain();
+        assert!(pool.is_available());
+        assert_eq!(pool.lock().unwrap().idle.len(), 1);
+    }
+
+    #[test]
+    fn maintenance_keeps_the_minimum_open_and_closes_surplus_idle_connections() {
+        let (address, accepted) = start_database(usize::MAX);
+        let pool = DbPool::new(&address, DbPoolConfig { idle_timeout: Duration::from_secs(0), ..config(2, 4) });
+
+        pool.maintain();
+        let state = pool.lock().unwrap();
+        assert_eq!((state.open, state.idle.len()), (2, 2));
+        drop(state);
+
+        let mut connections: Vec<_> = (0..3).map(|_| pool.checkout().unwrap()).collect();
+        for connection in &mut connections {
+            connection.execute(&DbRequest::Ping).unwrap();
+        }
+        drop(connections);
+        assert_eq!(pool.lock().unwrap().open, 3);
+
+        pool.maintain();
+        assert_eq!(pool.lock().unwrap().open, 2);
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::{ApiError, ApiResult};
+use crate::handler::{HttpRequest, HttpResponse};
+use crate::logger;
+use crate::metrics;
+use crate::servererror::{Result, ServerError};
+
+pub const EVENT_STREAM_CONTENT_TYPE: &str = "text/event-stream";
+// The methods accepted by event stream routes.
+pub const EVENT_STREAM_ALLOWED_METHODS: &str = "GET, OPTIONS";
+// How often an open stream checks for events to send and whether the client has gone.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// How many events may be queued for a subscriber before it is dropped as too slow.
+const SUBSCRIBER_CAPACITY: usize = 256;
+
+/// Limits applied to each event stream.
+#[derive(Clone, Debug)]
+pub struct EventStreamLimits {
+    // How long clients should wait before reconnecting once the stream ends.
+    pub retry: Duration,
+    // How long a stream may go without sending anything before a comment is sent, so that
+    // proxies keep the connection open and disconnected clients are noticed.
+    pub keep_alive_interval: Duration,
+}
+
+impl Default for EventStreamLimits {
+    fn default() -> EventStreamLimits {
+        return EventStreamLimits {
+            retry: Duration::from_secs(3),
+            keep_alive_interval: Duration::from_secs(15),
+        };
+    }
+}
+
+/// An event sent to subscribers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    // Sent back by clients as Last-Event-ID when they reconnect.
+    pub id: Option<String>,
+    // The event type. Clients treat events without one as "message".
+    pub event: Option<String>,
+    pub data: String,
+    // Overrides how long clients wait before reconnecting.
+    pub retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Event {
+        return Event { id: None, event: None, data: data.into(), retry: None };
+    }
+
+    /// Formats the event as it is sent on the stream. Line breaks in the ID and type are dropped,
+    /// and the data is split into one field per line.
+    pub fn encode(&self) -> String {
+        let single_line = |value: &str| value.replace(['\r', '\n'], "");
+
+        let mut encoded = String::new();
+        if let Some(id) = &self.id {
+            encoded.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(event) = &self.event {
+            encoded.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(retry) = self.retry {
+            encoded.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            encoded.push_str(&format!("data: {}\n", line));
+        }
+        encoded.push('\n');
+        return encoded;
+    }
+}
+
+/// The events for a single client: those it missed while disconnected, then those to come.
+pub struct Subscription {
+    missed: Vec<Arc<Event>>,
+    receiver: Receiver<Arc<Event>>,
+    // Dropped with the subscription, so that its source can tell it has gone.
+    alive: Arc<()>,
+}
+
+impl Subscription {
+    pub fn new(missed: Vec<Arc<Event>>, receiver: Receiver<Arc<Event>>) -> Subscription {
+        return Subscription { missed, receiver, alive: Arc::new(()) };
+    }
+}
+
+/// Provides the events for an event stream route. Each stream is served on its own thread, which
+/// sends the subscription's events as they arrive.
+pub trait EventSource: Send + Sync {
+    // Subscribes a client, which last saw the event with the given ID if it is reconnecting.
+    fn subscribe(&self, request: &HttpRequest, last_event_id: Option<&str>) -> Result<Subscription>;
+}
+
+/// The event stream routes, keyed by request URI, and the limits applied to their streams.
+#[derive(Clone, Default)]
+pub struct EventStreamRoutes {
+    routes: HashMap<String, Arc<dyn EventSource>>,
+    limits: EventStreamLimits,
+}
+
+impl EventStreamRoutes {
+    pub fn new(limits: EventStreamLimits) -> EventStreamRoutes {
+        return EventStreamRoutes { routes: HashMap::new(), limits };
+    }
+
+    pub fn add<S: EventSource + 'static>(&mut self, path: &str, source: Arc<S>) {
+        self.routes.insert(path.into(), source);
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Arc<dyn EventSource>> {
+        return self.routes.get(path);
+    }
+
+    pub fn limits(&self) -> &EventStreamLimits {
+        return &self.limits;
+    }
+}
+
+/// Returns the response opening an event stream, or a 405 for methods other than GET.
+pub fn stream_response(request: &HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        let mut response = ApiError::new("405 METHOD NOT ALLOWED", &format!("{} is not allowed.", request.method)).to_response();
+        response.set_header("Allow", EVENT_STREAM_ALLOWED_METHODS);
+        return response;
+    }
+    return HttpResponse::event_stream();
+}
+
+/// A request whose response opened an event stream, which is to be served from an event source.
+pub struct EventStreamUpgrade {
+    pub(crate) source: Arc<dyn EventSource>,
+    pub(crate) request: HttpRequest,
+    pub(crate) limits: EventStreamLimits,
+    // Set once the server begins shutting down, to end the stream.
+    pub(crate) shutting_down: Arc<AtomicBool>,
+}
+
+impl EventStreamUpgrade {
+    /// Sends events until the client disconnects or the server shuts down. The socket is the one
+    /// underlying the reader, and has its read timeout set so that the stream can notice the
+    /// client disconnecting while waiting for events.
+    pub fn run<R: Read, W: Write>(self, socket: &TcpStream, mut reader: R, mut writer: W) -> Result<()> {
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let subscription = match self.source.subscribe(&self.request, self.request.header("Last-Event-ID")) {
+            Ok(subscription) => subscription,
+            Err(e) => {
+                // The client reconnects once the retry interval has passed.
+                logger::global().warn(&format!("Refused event stream subscription: {}", e));
+                return Ok(());
+            }
+        };
+
+        metrics::global().event_stream_subscribers_active.inc();
+        let result = self.send_events(subscription, &mut reader, &mut writer);
+        metrics::global().event_stream_subscribers_active.dec();
+
+        return match result {
+            Err(e) if is_disconnection(&e) => Ok(()),
+            result => Ok(result?)
+        };
+    }
+
+    /// Alternates between sending events and checking whether the client has gone.
+    fn send_events<R: Read, W: Write>(&self, subscription: Subscription, reader: &mut R, writer: &mut W) -> io::Result<()> {
+        write!(writer, "retry: {}\n\n", self.limits.retry.as_millis())?;
+        for event in subscription.missed.iter() {
+            writer.write_all(event.encode().as_bytes())?;
+        }
+        writer.flush()?;
+        let mut last_sent = Instant::now();
+        let mut buffer = [0u8; 512];
+
+        while !self.shutting_down.load(Ordering::SeqCst) {
+            let mut sent_events = false;
+            loop {
+                match subscription.receiver.try_recv() {
+                    Ok(event) => {
+                        writer.write_all(event.encode().as_bytes())?;
+                        sent_events = true;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    // The source dropped the subscriber for falling behind. Once it reconnects,
+                    // it resumes from where it left off.
+                    Err(TryRecvError::Disconnected) => return writer.flush()
+                }
+            }
+
+            if !sent_events && last_sent.elapsed() >= self.limits.keep_alive_interval {
+                writer.write_all(b": keep-alive\n\n")?;
+                sent_events = true;
+            }
+            if sent_events {
+                writer.flush()?;
+                last_sent = Instant::now();
+            }
+
+            // Clients send nothing after the request, so reading only ends when they disconnect.
+            match reader.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e)
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Whether the error means that the client has gone.
+fn is_disconnection(error: &io::Error) -> bool {
+    return matches!(error.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted);
+}
+
+/// A subscriber to a hub.
+struct Subscriber {
+    sender: SyncSender<Arc<Event>>,
+    // Gone once the subscription has been dropped.
+    alive: Weak<()>,
+}
+
+/// The state of a hub, guarded by a single lock so that subscribers see events in order.
+struct HubState {
+    next_id: u64,
+    // The most recent events, with their IDs, to replay to reconnecting clients.
+    history: VecDeque<(u64, Arc<Event>)>,
+    subscribers: Vec<Subscriber>,
+}
+
+/// Broadcasts published events to every subscriber. Events are numbered, and the most recent are
+/// kept so that reconnecting clients receive those they missed.
+pub struct EventHub {
+    state: Mutex<HubState>,
+    // How many recent events are kept.
+    history_size: usize,
+    // Clients subscribing beyond this are refused.
+    max_subscribers: usize,
+}
+
+impl EventHub {
+    pub fn new(history_size: usize, max_subscribers: usize) -> EventHub {
+        let state = HubState { next_id: 1, history: VecDeque::new(), subscribers: Vec::new() };
+        return EventHub { state: Mutex::new(state), history_size, max_subscribers };
+    }
+
+    /// Sends an event of the given type to every subscriber, and returns its ID. Subscribers that
+    /// have gone or fallen too far behind are dropped.
+    pub fn publish(&self, event_type: Option<&str>, data: &str) -> Result<u64> {
+        let mut state = self.state.lock()?;
+        let id = state.next_id;
+        state.next_id += 1;
+
+        let event = Arc::new(Event { id: Some(id.to_string()), event: event_type.map(|event_type| event_type.into()), ..Event::new(data) });
+        state.history.push_back((id, event.clone()));
+        while state.history.len() > self.history_size {
+            state.history.pop_front();
+        }
+        state.subscribers.retain(|subscriber| subscriber.sender.try_send(event.clone()).is_ok());
+
+        metrics::global().events_published_total.inc();
+        return Ok(id);
+    }
+}
+
+impl EventSource for EventHub {
+    /// Subscribes the client, replaying the events after its last event ID that are still kept.
+    fn subscribe(&self, _request: &HttpRequest, last_event_id: Option<&str>) -> Result<Subscription> {
+        let mut state = self.state.lock()?;
+        state.subscribers.retain(|subscriber| subscriber.alive.strong_count() > 0);
+        if state.subscribers.len() >= self.max_subscribers {
+            return Err(ServerError::new("Too many event stream subscribers.".into()));
+        }
+
+        let missed = match last_event_id.and_then(|last_event_id| last_event_id.trim().parse::<u64>().ok()) {
+            None => Vec::new(),
+            Some(last_event_id) => state.history.iter()
+                .filter(|(id, _)| *id > last_event_id)
+                .map(|(_, event)| event.clone())
+                .collect()
+        };
+
+        let (sender, receiver) = sync_channel(SUBSCRIBER_CAPACITY);
+        let subscription = Subscription::new(missed, receiver);
+        state.subscribers.push(Subscriber { sender, alive: Arc::downgrade(&subscription.alive) });
+        return Ok(subscription);
+    }
+}
+
+#[derive(Deserialize)]
+struct PublishRequest {
+    event: Option<String>,
+    data: String,
+}
+
+#[derive(Serialize)]
+struct PublishResponse {
+    id: u64,
+}
+
+/// Returns an API route that publishes the JSON-encoded event, e.g.
+/// `{"event":"block","data":"..."}`, to the hub. The route should be protected by an `AuthLayer`.
+pub fn publish_route(hub: Arc<EventHub>) -> impl Fn(&HttpRequest) -> ApiResult + Send + Sync {
+    return move |request: &HttpRequest| {
+        let publish_request = request.json::<PublishRequest>()?;
+        let id = hub.publish(publish_request.event.as_deref(), &publish_request.data)?;
+        return Ok(HttpResponse::json("202 ACCEPTED", &PublishResponse { id })?);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Cursor, Read, Write};
+    use std::net::TcpStream;
+    use std::str::from_utf8;
+    use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::eventstream::{publish_route, Event, EventHub, EventSource, EventStreamLimits, EventStreamRoutes, EventStreamUpgrade};
+    use crate::database::LocalDatabase;
+    use crate::handler::{HttpHandler, HttpRequest};
+    use crate::server::ServerInternal;
+
+    // Used to allocate different ports for the listeners across tests.
+    static PORT: AtomicU16 = AtomicU16::new(10700);
+
+    fn get_port() -> String {
+        return PORT.fetch_add(1, Ordering::Relaxed).to_string();
+    }
+
+    fn new_request() -> HttpRequest {
+        return HttpRequest::new("GET", "/events", "HTTP/1.1", HashMap::new());
+    }
+
+    #[test]
+    fn events_are_encoded_field_by_field() {
+        let event = Event {
+            id: Some("7\n".into()),
+            event: Some("block".into()),
+            retry: Some(Duration::from_secs(2)),
+            ..Event::new("first\r\nsecond\nthird")
+        };
+
+        assert_eq!(event.encode(), "id: 7\nevent: block\nretry: 2000\ndata: first\ndata: second\ndata: third\n\n");
+        assert_eq!(Event::new("").encode(), "data: \n\n");
+    }
+
+    #[test]
+    fn hubs_replay_missed_events_to_reconnecting_clients() {
+        let hub = EventHub::new(2, 10);
+        let subscription = hub.subscribe(&new_request(), None).unwrap();
+        for data in ["a", "b", "c"].iter() {
+            hub.publish(Some("block"), data).unwrap();
+        }
+
+        let received: Vec<String> = subscription.receiver.try_iter().map(|event| event.data.clone()).collect();
+        assert_eq!(received, vec!["a", "b", "c"]);
+        assert!(subscription.missed.is_empty());
+
+        // Only the last two events are kept.
+        let resumed = hub.subscribe(&new_request(), Some("1")).unwrap();
+        let missed: Vec<Option<String>> = resumed.missed.iter().map(|event| event.id.clone()).collect();
+        assert_eq!(missed, vec![Some("2".into()), Some("3".into())]);
+        assert!(hub.subscribe(&new_request(), Some("3")).unwrap().missed.is_empty());
+    }
+
+    #[test]
+    fn hubs_drop_subscribers_that_have_gone_or_fallen_behind() {
+        let hub = EventHub::new(10, 2);
+        let first = hub.subscribe(&new_request(), None).unwrap();
+        let second = hub.subscribe(&new_request(), None).unwrap();
+        assert!(hub.subscribe(&new_request(), None).is_err());
+
+        drop(second);
+        let third = hub.subscribe(&new_request(), None).unwrap();
+
+        for index in 0..300 {
+            hub.publish(None, &index.to_string()).unwrap();
+            third.receiver.try_recv().unwrap();
+        }
+        assert_eq!(first.receiver.try_iter().count(), 256);
+        assert_eq!(hub.state.lock().unwrap().subscribers.len(), 1);
+    }
+
+    #[test]
+    fn streams_send_missed_events_then_end_when_the_client_disconnects() {
+        let hub = Arc::new(EventHub::new(10, 10));
+        hub.publish(Some("block"), "one").unwrap();
+        let mut headers = HashMap::new();
+        headers.insert("last-event-id".into(), "0".into());
+        let upgrade = EventStreamUpgrade {
+            source: hub.clone(),
+            request: HttpRequest::new("GET", "/events", "HTTP/1.1", headers),
+            limits: EventStreamLimits::default(),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+        };
+
+        let mut output = Vec::new();
+        let subscription = upgrade.source.subscribe(&upgrade.request, Some("0")).unwrap();
+        upgrade.send_events(subscription, &mut Cursor::new(Vec::new()), &mut output).unwrap();
+
+        assert_eq!(from_utf8(&output).unwrap(), "retry: 3000\n\nid: 1\nevent: block\ndata: one\n\n");
+    }
+
+    #[test]
+    fn publish_route_publishes_json_events() {
+        let hub = Arc::new(EventHub::new(10, 10));
+        let subscription = hub.subscribe(&new_request(), None).unwrap();
+        let route = publish_route(hub);
+        let mut headers = HashMap::new();
+        headers.insert("content-type".into(), "application/json".into());
+        let mut request = HttpRequest::new("POST", "/api/events", "HTTP/1.1", headers);
+        request.body = b"{\"event\":\"block\",\"data\":\"42\"}".to_vec();
+
+        let response = route(&request).unwrap();
+
+        assert_eq!(response.status_code, "202 ACCEPTED");
+        assert_eq!(from_utf8(&response.body).unwrap(), "{\"id\":1}");
+        assert_eq!(subscription.receiver.try_recv().unwrap().encode(), "id: 1\nevent: block\ndata: 42\n\n");
+    }
+
+    #[test]
+    fn server_streams_published_events() {
+        let port = &get_port();
+        let hub = Arc::new(EventHub::new(10, 10));
+        let mut event_stream_routes = EventStreamRoutes::new(EventStreamLimits::default());
+        event_stream_routes.add("/events", hub.clone());
+        let handler = HttpHandler::new(Arc::new(LocalDatabase::new()), HashMap::new()).with_event_stream_routes(event_stream_routes);
+        let mut server_handle = ServerInternal::start(port, handler).unwrap();
+
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
+        stream.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: text/event-stream\r\n"));
+        assert!(!head.contains("Content-Length"));
+
+        let mut retry = String::new();
+        reader.read_line(&mut retry).unwrap();
+        reader.read_line(&mut retry).unwrap();
+        assert_eq!(retry, "retry: 3000\n\n");
+
+        hub.publish(None, "new block").unwrap();
+        let mut event = [0u8; 23];
+        reader.read_exact(&mut event).unwrap();
+        assert_eq!(from_utf8(&event).unwrap(), "id: 1\ndata: new block\n\n");
+
+        drop(reader);
+        server_handle.stop_listening().unwrap();
+    }
+}
@@ -0,0 +1,618 @@
+use std::env::temp_dir;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::from_utf8;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::api::{ApiError, ApiResult};
+use crate::handler::{HttpRequest, HttpResponse};
+use crate::servererror::{Result, ServerError};
+
+pub const FORM_URLENCODED_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+pub const MULTIPART_CONTENT_TYPE: &str = "multipart/form-data";
+// The longest boundary RFC 2046 allows.
+const MAX_BOUNDARY_LENGTH: usize = 70;
+// The longest line, and the most lines, allowed in the headers of each part.
+const MAX_PART_HEADER_LINE_LENGTH: usize = 8 * 1024;
+const MAX_PART_HEADERS: usize = 16;
+// How much is read from the body at a time.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+// Used to give temporary and uploaded files unique names.
+static NEXT_FILE_ID: AtomicU64 = AtomicU64::new(0);
+
+type FormResult<T> = std::result::Result<T, ApiError>;
+
+/// Limits on the forms a route accepts. Breaking one gives a 413.
+#[derive(Clone, Debug)]
+pub struct FormLimits {
+    // The most fields and files in a form.
+    pub max_parts: usize,
+    // The longest field value, in bytes.
+    pub max_field_length: usize,
+    // The largest file, in bytes.
+    pub max_file_size: usize,
+    // Files larger than this are written to a temporary file rather than kept in memory.
+    pub memory_threshold: usize,
+}
+
+impl Default for FormLimits {
+    fn default() -> FormLimits {
+        return FormLimits {
+            max_parts: 100,
+            max_field_length: 64 * 1024,
+            max_file_size: 10 * 1024 * 1024,
+            memory_threshold: 64 * 1024,
+        };
+    }
+}
+
+/// The fields and files of a submitted form.
+#[derive(Debug, Default)]
+pub struct Form {
+    // In the order they were submitted. Names may repeat.
+    fields: Vec<(String, String)>,
+    files: Vec<UploadedFile>,
+}
+
+impl Form {
+    /// Returns the first value of the named field, if present.
+    pub fn field(&self, name: &str) -> Option<&str> {
+        return self.fields.iter().find(|(field_name, _)| field_name == name).map(|(_, value)| value.as_str());
+    }
+
+    pub fn files(&self) -> &[UploadedFile] {
+        return &self.files;
+    }
+}
+
+/// A file uploaded in a multipart form.
+#[derive(Debug)]
+pub struct UploadedFile {
+    // The name of the form field.
+    pub name: String,
+    // The file name given by the client, without any directories. May be empty.
+    pub filename: String,
+    pub content_type: String,
+    pub size: usize,
+    contents: FileContents,
+}
+
+#[derive(Debug)]
+enum FileContents {
+    Memory(Vec<u8>),
+    TempFile(TempFile),
+}
+
+impl UploadedFile {
+    /// Copies the file's contents to the destination.
+    pub fn persist(&self, destination: &Path) -> Result<()> {
+        match &self.contents {
+            FileContents::Memory(bytes) => fs::write(destination, bytes)?,
+            FileContents::TempFile(temp_file) => { fs::copy(&temp_file.path, destination)?; }
+        }
+        return Ok(());
+    }
+}
+
+/// A temporary file, which is deleted when dropped.
+#[derive(Debug)]
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn create() -> Result<(TempFile, File)> {
+        let path = temp_dir().join(format!("blockchain-upload-{}-{}", process::id(), next_file_id()));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        return Ok((TempFile { path }, file));
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn next_file_id() -> u64 {
+    return NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+impl HttpRequest {
+    /// Parses the body as a URL-encoded or multipart form, within the default limits.
+    pub fn form(&self) -> FormResult<Form> {
+        return self.form_with_limits(&FormLimits::default());
+    }
+
+    /// Parses the body as a URL-encoded or multipart form. Fails with a 415 if the request is not
+    /// marked as a form, a 400 if the body is malformed, or a 413 if it breaks the limits.
+    pub fn form_with_limits(&self, limits: &FormLimits) -> FormResult<Form> {
+        return match self.media_type().as_deref() {
+            Some(FORM_URLENCODED_CONTENT_TYPE) => parse_urlencoded(self.body(), limits),
+            Some(MULTIPART_CONTENT_TYPE) => {
+                let boundary = parse_boundary(self.header("Content-Type").unwrap_or(""))?;
+                parse_multipart(self.body(), &boundary, limits)
+            }
+            _ => Err(ApiError::unsupported_media_type(&format!("{} or {}", FORM_URLENCODED_CONTENT_TYPE, MULTIPART_CONTENT_TYPE)))
+        };
+    }
+}
+
+/// Parses an `application/x-www-form-urlencoded` body, e.g. `name=Bob&amount=5`.
+pub fn parse_urlencoded(body: &[u8], limits: &FormLimits) -> FormResult<Form> {
+    let body = from_utf8(body).map_err(|_| ApiError::bad_request("Form is not valid UTF-8."))?;
+    let mut form = Form::default();
+
+    for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+        if form.fields.len() >= limits.max_parts {
+            return Err(ApiError::payload_too_large(&format!("Form has more than {} fields.", limits.max_parts)));
+        }
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode(value)?;
+        if value.len() > limits.max_field_length {
+            return Err(ApiError::payload_too_large(&format!("Field {} exceeds {} bytes.", name, limits.max_field_length)));
+        }
+        form.fields.push((percent_decode(name)?, value));
+    }
+
+    return Ok(form);
+}
+
+/// Decodes `+` as a space and `%XX` as the byte XX.
+fn percent_decode(encoded: &str) -> FormResult<String> {
+    let malformed = || ApiError::bad_request(&format!("Malformed form encoding: {}", encoded));
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = encoded.get(i + 1..i + 3).filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+                    .ok_or_else(malformed)?;
+                decoded.push(u8::from_str_radix(hex, 16).map_err(|_| malformed())?);
+                i += 2;
+            }
+            byte => decoded.push(byte)
+        }
+        i += 1;
+    }
+
+    return String::from_utf8(decoded).map_err(|_| malformed());
+}
+
+/// Extracts the boundary parameter from a multipart Content-Type.
+fn parse_boundary(content_type: &str) -> FormResult<String> {
+    let boundary = content_type.split(';').skip(1)
+        .filter_map(|parameter| parameter.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| unquote(value.trim()));
+
+    return match boundary {
+        Some(boundary) if !boundary.is_empty() && boundary.len() <= MAX_BOUNDARY_LENGTH => Ok(boundary),
+        _ => Err(ApiError::bad_request("Multipart form has no valid boundary."))
+    };
+}
+
+/// Removes the quotes, and any backslash escapes, from a quoted string. Other strings are
+/// returned as they are.
+fn unquote(value: &str) -> String {
+    let inner = match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        None => return value.into(),
+        Some(inner) => inner
+    };
+
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            _ => unquoted.push(c)
+        }
+    }
+    return unquoted;
+}
+
+/// Reads a multipart body a chunk at a time, so that parts need not be held in memory.
+struct MultipartReader<R: Read> {
+    reader: R,
+    // Bytes read from the reader but not yet consumed.
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> MultipartReader<R> {
+    /// Reads another chunk into the buffer. Returns false if the body is exhausted.
+    fn fill(&mut self) -> FormResult<bool> {
+        let mut chunk = [0u8; CHUNK_SIZE];
+        let bytes_read = self.reader.read(&mut chunk)?;
+        self.buffer.extend_from_slice(&chunk[..bytes_read]);
+        return Ok(bytes_read > 0);
+    }
+
+    /// Reads a line, without its CRLF. The last line of the body need not end in a CRLF.
+    fn read_line(&mut self) -> FormResult<Vec<u8>> {
+        loop {
+            if let Some(position) = find(&self.buffer, b"\r\n") {
+                let line = self.buffer.drain(..position + 2).take(position).collect::<Vec<_>>();
+                return check_line_length(line);
+            }
+            if self.buffer.len() > MAX_PART_HEADER_LINE_LENGTH {
+                return check_line_length(self.buffer.clone());
+            }
+            if !self.fill()? {
+                return match self.buffer.is_empty() {
+                    true => Err(ApiError::bad_request("Multipart form ended unexpectedly.")),
+                    false => Ok(self.buffer.split_off(0))
+                };
+            }
+        }
+    }
+
+    /// Passes everything up to the delimiter to the sink, a chunk at a time, and consumes the
+    /// delimiter.
+    fn read_until<F: FnMut(&[u8]) -> FormResult<()>>(&mut self, delimiter: &[u8], mut sink: F) -> FormResult<()> {
+        loop {
+            if let Some(position) = find(&self.buffer, delimiter) {
+                sink(&self.buffer[..position])?;
+                self.buffer.drain(..position + delimiter.len());
+                return Ok(());
+            }
+
+            // Anything but the last few bytes cannot be the start of the delimiter.
+            let safe_length = self.buffer.len().saturating_sub(delimiter.len() - 1);
+            sink(&self.buffer[..safe_length])?;
+            self.buffer.drain(..safe_length);
+
+            if !self.fill()? {
+                return Err(ApiError::bad_request("Multipart form ended unexpectedly."));
+            }
+        }
+    }
+}
+
+fn check_line_length(line: Vec<u8>) -> FormResult<Vec<u8>> {
+    if line.len() > MAX_PART_HEADER_LINE_LENGTH {
+        return Err(ApiError::bad_request("Multipart form has an over-long line."));
+    }
+    return Ok(line);
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    return haystack.windows(needle.len()).position(|window| window == needle);
+}
+
+/// Parses a `multipart/form-data` body. Fields are kept in memory, and files larger than the
+/// memory threshold are streamed to temporary files.
+pub fn parse_multipart<R: Read>(body: R, boundary: &str, limits: &FormLimits) -> FormResult<Form> {
+    let mut reader = MultipartReader { reader: body, buffer: Vec::new() };
+    let delimiter = format!("--{}", boundary);
+    let mut form = Form::default();
+
+    // Skips the preamble, up to the first delimiter.
+    loop {
+        let line = reader.read_line()?;
+        let line = trim_padding(&line);
+        if line == delimiter.as_bytes() {
+            break;
+        }
+        if line == format!("{}--", delimiter).as_bytes() {
+            return Ok(form);
+        }
+    }
+
+    let body_delimiter = format!("\r\n{}", delimiter);
+    loop {
+        if form.fields.len() + form.files.len() >= limits.max_parts {
+            return Err(ApiError::payload_too_large(&format!("Form has more than {} parts.", limits.max_parts)));
+        }
+
+        let headers = read_part_headers(&mut reader)?;
+        let disposition = headers.iter().find(|(name, _)| name == "content-disposition").map(|(_, value)| value.as_str())
+            .ok_or_else(|| ApiError::bad_request("Multipart part has no Content-Disposition."))?;
+        let (name, filename) = parse_content_disposition(disposition)?;
+
+        let mut part = PartWriter::new(&name, filename.is_some(), limits);
+        reader.read_until(body_delimiter.as_bytes(), |chunk| part.write(chunk))?;
+        let PartWriter { size, memory, temp_file, .. } = part;
+
+        match filename {
+            None => {
+                let value = String::from_utf8(memory).map_err(|_| ApiError::bad_request("Form field is not valid UTF-8."))?;
+                form.fields.push((name, value));
+            }
+            Some(filename) => {
+                let content_type = headers.iter().find(|(name, _)| name == "content-type")
+                    .map(|(_, value)| value.clone())
+                    .unwrap_or_else(|| "application/octet-stream".into());
+                let contents = match temp_file {
+                    None => FileContents::Memory(memory),
+                    Some((temp_file, _)) => FileContents::TempFile(temp_file)
+                };
+                form.files.push(UploadedFile { name, filename, content_type, size, contents });
+            }
+        }
+
+        // The delimiter is followed by `--` after the last part, or by a CRLF before the next.
+        match trim_padding(&reader.read_line()?) {
+            b"--" => return Ok(form),
+            b"" => continue,
+            _ => return Err(ApiError::bad_request("Multipart form has a malformed boundary."))
+        }
+    }
+}
+
+/// Strips the spaces and tabs that may follow a delimiter.
+fn trim_padding(line: &[u8]) -> &[u8] {
+    let end = line.iter().rposition(|byte| *byte != b' ' && *byte != b'\t').map(|i| i + 1).unwrap_or(0);
+    return &line[..end];
+}
+
+/// Reads a part's headers, up to the blank line before its contents. Names are lower-cased.
+fn read_part_headers<R: Read>(reader: &mut MultipartReader<R>) -> FormResult<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+
+    loop {
+        let line = reader.read_line()?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if headers.len() >= MAX_PART_HEADERS {
+            return Err(ApiError::bad_request("Multipart part has too many headers."));
+        }
+
+        let line = from_utf8(&line).map_err(|_| ApiError::bad_request("Multipart part header is not valid UTF-8."))?;
+        let (name, value) = line.split_once(':')
+            .ok_or_else(|| ApiError::bad_request(&format!("Malformed multipart part header: {}", line)))?;
+        headers.push((name.trim().to_lowercase(), value.trim().into()));
+    }
+}
+
+/// Parses `form-data; name="field"; filename="file.txt"` into the field name and file name.
+fn parse_content_disposition(disposition: &str) -> FormResult<(String, Option<String>)> {
+    let mut parameters = disposition.split(';');
+    if !parameters.next().unwrap_or("").trim().eq_ignore_ascii_case("form-data") {
+        return Err(ApiError::bad_request("Multipart part is not form-data."));
+    }
+
+    let mut name = None;
+    let mut filename = None;
+    for parameter in parameters {
+        match parameter.split_once('=').map(|(key, value)| (key.trim().to_lowercase(), unquote(value.trim()))) {
+            Some((key, value)) if key == "name" => name = Some(value),
+            // Some clients send the full path, which is not ours to know.
+            Some((key, value)) if key == "filename" => filename = Some(value.rsplit(['/', '\\']).next().unwrap_or("").into()),
+            _ => {}
+        }
+    }
+
+    let name = name.ok_or_else(|| ApiError::bad_request("Multipart part has no name."))?;
+    return Ok((name, filename));
+}
+
+/// Collects a part's contents, moving files to a temporary file once they outgrow memory.
+struct PartWriter<'a> {
+    name: &'a str,
+    is_file: bool,
+    limits: &'a FormLimits,
+    size: usize,
+    memory: Vec<u8>,
+    temp_file: Option<(TempFile, File)>,
+}
+
+impl<'a> PartWriter<'a> {
+    fn new(name: &'a str, is_file: bool, limits: &'a FormLimits) -> PartWriter<'a> {
+        return PartWriter { name, is_file, limits, size: 0, memory: Vec::new(), temp_file: None };
+    }
+
+    fn write(&mut self, chunk: &[u8]) -> FormResult<()> {
+        self.size += chunk.len();
+        let max_size = match self.is_file {
+            true => self.limits.max_file_size,
+            false => self.limits.max_field_length
+        };
+        if self.size > max_size {
+            return Err(ApiError::payload_too_large(&format!("Part {} exceeds {} bytes.", self.name, max_size)));
+        }
+
+        if self.is_file && self.temp_file.is_none() && self.size > self.limits.memory_threshold {
+            let (temp_file, mut file) = TempFile::create()?;
+            file.write_all(&self.memory)?;
+            self.memory = Vec::new();
+            self.temp_file = Some((temp_file, file));
+        }
+
+        match &mut self.temp_file {
+            None => self.memory.extend_from_slice(chunk),
+            Some((_, file)) => file.write_all(chunk)?
+        }
+        return Ok(());
+    }
+}
+
+/// Describes a file stored by the upload route.
+#[derive(Serialize)]
+struct StoredUpload {
+    field: String,
+    filename: String,
+    stored_as: String,
+    content_type: String,
+    size: usize,
+}
+
+/// Returns an API route that stores the files in a multipart form in the given directory, under
+/// unique names, and describes them in its response.
+pub fn upload_route(upload_dir: PathBuf, limits: FormLimits) -> impl Fn(&HttpRequest) -> ApiResult + Send + Sync {
+    return move |request: &HttpRequest| {
+        let form = request.form_with_limits(&limits)?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_err(ServerError::from)?.as_secs();
+
+        let mut stored_uploads = Vec::new();
+        for file in form.files().iter().filter(|file| !file.filename.is_empty()) {
+            let stored_as = format!("{}-{}-{}", timestamp, next_file_id(), sanitise_filename(&file.filename));
+            file.persist(&upload_dir.join(&stored_as))?;
+            stored_uploads.push(StoredUpload {
+                field: file.name.clone(),
+                filename: file.filename.clone(),
+                stored_as,
+                content_type: file.content_type.clone(),
+                size: file.size,
+            });
+        }
+
+        return Ok(HttpResponse::json("201 CREATED", &stored_uploads)?);
+    };
+}
+
+/// Replaces characters that are unsafe in file names, and leading dots.
+fn sanitise_filename(filename: &str) -> String {
+    let sanitised = filename.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
+        .collect::<String>();
+    return sanitised.trim_start_matches('.').into();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env::temp_dir;
+    use std::fs;
+    use std::io::BufReader;
+
+    use crate::form::{parse_multipart, parse_urlencoded, upload_route, FileContents, FormLimits};
+    use crate::handler::{ConnectionInfo, HttpHandler, HttpRequest};
+
+    const BOUNDARY: &str = "----boundary1234";
+
+    fn multipart_body(parts: &[(&str, &str)]) -> String {
+        let mut body = String::from("preamble\r\n");
+        for (headers, contents) in parts.iter() {
+            body.push_str(&format!("--{}\r\n{}\r\n\r\n{}\r\n", BOUNDARY, headers, contents));
+        }
+        body.push_str(&format!("--{}--\r\nepilogue", BOUNDARY));
+        return body;
+    }
+
+    fn multipart_request(body: &str) -> HttpRequest {
+        let mut headers = HashMap::new();
+        headers.insert("content-type".into(), format!("multipart/form-data; boundary=\"{}\"", BOUNDARY));
+        let mut request = HttpRequest::new("POST", "/uploads", "HTTP/1.1", headers);
+        request.body = body.into();
+        return request;
+    }
+
+    #[test]
+    fn urlencoded_forms_are_decoded() {
+        let form = parse_urlencoded(b"name=Bob+Smith&note=50%25+off%21&empty=&flag&name=Alice", &FormLimits::default()).unwrap();
+
+        assert_eq!(form.field("name"), Some("Bob Smith"));
+        assert_eq!(form.field("note"), Some("50% off!"));
+        assert_eq!(form.field("empty"), Some(""));
+        assert_eq!(form.field("flag"), Some(""));
+        assert_eq!(form.field("missing"), None);
+        assert_eq!(form.fields.len(), 5);
+    }
+
+    #[test]
+    fn malformed_and_oversized_urlencoded_forms_are_rejected() {
+        let limits = FormLimits { max_parts: 2, max_field_length: 4, ..FormLimits::default() };
+
+        for body in ["a=%", "a=%zz", "a=%+f", "a=%ff", "a=%C3"].iter() {
+            assert_eq!(parse_urlencoded(body.as_bytes(), &limits).unwrap_err().status_code, "400 BAD REQUEST");
+        }
+        for body in ["a=1&b=2&c=3", "a=12345"].iter() {
+            assert_eq!(parse_urlencoded(body.as_bytes(), &limits).unwrap_err().status_code, "413 PAYLOAD TOO LARGE");
+        }
+    }
+
+    #[test]
+    fn multipart_forms_are_parsed_from_requests() {
+        let body = multipart_body(&[
+            ("Content-Disposition: form-data; name=\"comment\"", "Hello,\r\nworld"),
+            ("Content-Disposition: form-data; name=\"upload\"; filename=\"C:\\\\docs\\\\a \\\"b\\\".txt\"\r\nContent-Type: text/plain", "--not a boundary"),
+        ]);
+        let request = format!("POST /uploads HTTP/1.1\r\n\
+            Content-Type: multipart/form-data; boundary={}\r\n\
+            Content-Length: {}\r\n\r\n{}", BOUNDARY, body.len(), body);
+
+        let http_request = HttpHandler::read_http_request(&mut BufReader::new(request.as_bytes()), &ConnectionInfo::default()).unwrap();
+        let form = http_request.form().unwrap();
+
+        assert_eq!(form.field("comment"), Some("Hello,\r\nworld"));
+        let file = &form.files()[0];
+        assert_eq!((file.name.as_str(), file.filename.as_str(), file.content_type.as_str()), ("upload", "a \"b\".txt", "text/plain"));
+        assert_eq!(file.size, 16);
+        assert!(matches!(&file.contents, FileContents::Memory(bytes) if bytes == b"--not a boundary"));
+    }
+
+    #[test]
+    fn large_files_are_streamed_to_temp_files() {
+        let contents = "0123456789".repeat(5000);
+        let body = multipart_body(&[("Content-Disposition: form-data; name=\"upload\"; filename=\"big.bin\"", &contents)]);
+        let limits = FormLimits { memory_threshold: 1024, ..FormLimits::default() };
+
+        let form = parse_multipart(body.as_bytes(), BOUNDARY, &limits).unwrap();
+
+        let path = match &form.files()[0].contents {
+            FileContents::TempFile(temp_file) => temp_file.path.clone(),
+            FileContents::Memory(_) => panic!("File was kept in memory.")
+        };
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        drop(form);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn malformed_and_oversized_multipart_forms_are_rejected() {
+        let limits = FormLimits { max_parts: 2, max_field_length: 8, max_file_size: 16, ..FormLimits::default() };
+        let field = ("Content-Disposition: form-data; name=\"a\"", "value");
+
+        let malformed_bodies = [
+            String::new(),
+            format!("--{}\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nunterminated", BOUNDARY),
+            multipart_body(&[("Content-Type: text/plain", "no disposition")]),
+            multipart_body(&[("Content-Disposition: attachment; name=\"a\"", "not form-data")]),
+            multipart_body(&[("Content-Disposition: form-data", "no name")]),
+            multipart_body(&[field]).replace("--\r\nepilogue", "junk"),
+        ];
+        for body in malformed_bodies.iter() {
+            assert_eq!(parse_multipart(body.as_bytes(), BOUNDARY, &limits).unwrap_err().status_code, "400 BAD REQUEST", "{}", body);
+        }
+
+        let oversized_bodies = [
+            multipart_body(&[field, field, field]),
+            multipart_body(&[("Content-Disposition: form-data; name=\"a\"", "123456789")]),
+            multipart_body(&[("Content-Disposition: form-data; name=\"a\"; filename=\"a\"", "12345678901234567")]),
+        ];
+        for body in oversized_bodies.iter() {
+            assert_eq!(parse_multipart(body.as_bytes(), BOUNDARY, &limits).unwrap_err().status_code, "413 PAYLOAD TOO LARGE");
+        }
+    }
+
+    #[test]
+    fn upload_route_stores_files_under_safe_names() {
+        let upload_dir = temp_dir().join(format!("blockchain-test-{}-uploads", std::process::id()));
+        fs::create_dir_all(&upload_dir).unwrap();
+        let route = upload_route(upload_dir.clone(), FormLimits::default());
+        let body = multipart_body(&[
+            ("Content-Disposition: form-data; name=\"upload\"; filename=\"../../etc/passwd\"", "contents"),
+            ("Content-Disposition: form-data; name=\"empty\"; filename=\"\"", ""),
+        ]);
+
+        let response = route(&multipart_request(&body)).unwrap();
+
+        assert_eq!(response.status_code, "201 CREATED");
+        let uploads: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(uploads.as_array().unwrap().len(), 1);
+        let stored_as = uploads[0]["stored_as"].as_str().unwrap();
+        assert!(stored_as.ends_with("-passwd"));
+        assert_eq!(fs::read_to_string(upload_dir.join(stored_as)).unwrap(), "contents");
+        fs::remove_dir_all(&upload_dir).unwrap();
+    }
+}
@@ -1,68 +1,318 @@
 use std::collections::HashMap;
 use std::fs;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::{BufRead, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
 use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
-use crate::servererror::{Result, ServerError};
+use serde_json::json;
 
-const ERROR_PAGE_404: &str = "./src/html/404.html";
+use crate::api::{ApiError, ApiRoutes};
+use crate::database::Database;
+use crate::eventstream::{self, EventStreamRoutes, EventStreamUpgrade, EVENT_STREAM_ALLOWED_METHODS, EVENT_STREAM_CONTENT_TYPE};
+use crate::auth::Principal;
+use crate::health::{self, Check, Readiness, LIVENESS_PATH, READINESS_PATH};
+use crate::logger::{self, AccessLogEntry};
+use crate::metrics::{self, UNMATCHED_ROUTE};
+use crate::middleware::MiddlewareChain;
+use crate::limits::{DEFAULT_MAX_BODY_LENGTH, DEFAULT_MAX_HEADER_COUNT, DEFAULT_MAX_HEADER_LINE_LENGTH, DEFAULT_MAX_REQUEST_LINE_LENGTH};
+use crate::servererror::{ErrorKind, Result, ServerError};
+use crate::session::Session;
+use crate::websocket::{self, WebSocketRoutes, WebSocketUpgrade, WEBSOCKET_ALLOWED_METHODS};
+
+const ERROR_PAGE_400: &str = "./src/html/400.html";
+const ERROR_PAGE_408: &str = "./src/html/408.html";
+const ERROR_PAGE_413: &str = "./src/html/413.html";
+const ERROR_PAGE_414: &str = "./src/html/414.html";
+const ERROR_PAGE_431: &str = "./src/html/431.html";
 const ERROR_PAGE_500: &str = "./src/html/500.html";
+// The methods accepted by the HTTP handler's routes.
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
 
 /// A handler for streams.
 pub trait Handler {
-    // Handles incoming connections.
-    fn handle<R: Read, W: Write>(&self, reader: R, writer: W) -> Result<()>;
+    // Handles incoming connections. Returns an upgrade if the connection is to be kept open once
+    // the response has been written.
+    fn handle<R: BufRead, W: Write>(&self, reader: R, writer: W, connection: &ConnectionInfo) -> Result<Option<Upgrade>>;
+
+    // Called when the server begins a graceful shutdown, while it is still accepting connections.
+    fn begin_shutdown(&self) {}
+}
+
+/// A connection that outlives its response.
+pub enum Upgrade {
+    WebSocket(WebSocketUpgrade),
+    EventStream(EventStreamUpgrade),
+}
+
+impl Upgrade {
+    /// Serves the connection until it closes. The socket is the one underlying the reader.
+    pub fn run<R: Read, W: Write>(self, socket: &TcpStream, reader: R, writer: W) -> Result<()> {
+        return match self {
+            Upgrade::WebSocket(upgrade) => upgrade.run(socket, reader, writer),
+            Upgrade::EventStream(upgrade) => upgrade.run(socket, reader, writer)
+        };
+    }
+}
+
+/// Details of the connection a handler is serving.
+#[derive(Clone)]
+pub struct ConnectionInfo {
+    // The address of the client, if known.
+    pub peer_address: Option<SocketAddr>,
+    // The longest request line the handler should accept, in bytes.
+    pub max_request_line_length: usize,
+    // The longest header line the handler should accept, in bytes including its CRLF.
+    pub max_header_line_length: usize,
+    // The most header lines the handler should accept.
+    pub max_header_count: usize,
+    // The largest request body the handler should accept, in bytes.
+    pub max_body_length: usize
+}
+
+impl Default for ConnectionInfo {
+    fn default() -> ConnectionInfo {
+        return ConnectionInfo {
+            peer_address: None,
+            max_request_line_length: DEFAULT_MAX_REQUEST_LINE_LENGTH,
+            max_header_line_length: DEFAULT_MAX_HEADER_LINE_LENGTH,
+            max_header_count: DEFAULT_MAX_HEADER_COUNT,
+            max_body_length: DEFAULT_MAX_BODY_LENGTH
+        };
+    }
+}
+
+impl ConnectionInfo {
+    /// Formats the client's IP address for logging, or "-" if it is unknown.
+    pub fn peer_ip_string(&self) -> String {
+        return match self.peer_address {
+            None => "-".into(),
+            Some(peer_address) => peer_address.ip().to_string()
+        };
+    }
 }
 
 /// A handler for HTTP requests.
 pub struct HttpHandler {
-    // Used to connect to the database.
-    db_connection: TcpStream,
+    // The database the server's data is kept in.
+    database: Arc<dyn Database>,
     // Used to store the server's routes.
-    routes: HashMap<String, String>
+    routes: HashMap<String, String>,
+    // Set once a graceful shutdown begins, to fail readiness checks and end upgraded connections.
+    shutting_down: Arc<AtomicBool>,
+    // The middleware wrapped around the routes.
+    middleware: MiddlewareChain,
+    // The routes served by code rather than by pages.
+    api_routes: ApiRoutes,
+    // The routes upgraded to WebSocket connections.
+    websocket_routes: WebSocketRoutes,
+    // The routes that stream events.
+    event_stream_routes: EventStreamRoutes
 }
 
 impl Handler for HttpHandler {
-    /// Reads the HTTP request, handles it and writes an HTTP response.
-    fn handle<R: Read, W: Write>(&self, reader: R, writer: W) -> Result<()> {
-        let http_request = HttpHandler::read_http_request(reader);
+    /// Reads the HTTP request, handles it and writes an HTTP response. Accepted WebSocket
+    /// handshakes and opened event streams are returned as upgrades.
+    fn handle<R: BufRead, W: Write>(&self, mut reader: R, writer: W, connection: &ConnectionInfo) -> Result<Option<Upgrade>> {
+        let start_time = Instant::now();
+        let http_request = HttpHandler::read_http_request(&mut reader, connection);
 
         return match http_request {
-            Err(_e) => HttpHandler::write_http_500_response(writer),
-            Ok(http_request) => {
-                let maybe_file_path = self.routes.get(&http_request.request_uri);
+            Err(e) => {
+                metrics::global().http_parse_errors_total.inc();
+                logger::global().warn(&format!("Rejected HTTP request from {}: {}", connection.peer_ip_string(), e));
+                HttpHandler::write_http_error_response(writer, &e)?;
+                Ok(None)
+            }
+            Ok(mut http_request) => {
+                let route = match self.is_known_route(&http_request.request_uri) {
+                    true => http_request.request_uri.clone(),
+                    false => UNMATCHED_ROUTE.into()
+                };
 
-                match maybe_file_path {
-                    None => HttpHandler::write_http_404_response(writer),
-                    Some(file_path) => HttpHandler::write_http_ok_response(writer, file_path)
-                }
+                let response = self.middleware.run(&mut http_request, connection, &|request| self.respond(request))?;
+                let written_response = response.write(writer)?;
+
+                let latency = start_time.elapsed();
+                metrics::global().record_request(&route, &http_request.method, written_response.status, latency);
+                logger::global().access(&AccessLogEntry::new(connection, &http_request, &written_response, latency));
+
+                Ok(self.upgrade(http_request, &written_response))
             }
         };
     }
+
+    /// Fails subsequent readiness checks, so that traffic is routed elsewhere.
+    fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
 }
 
 impl HttpHandler {
-    pub fn new(db_connection_string: &str, routes: HashMap<String, String>) -> Result<HttpHandler> {
-        let db_connection = TcpStream::connect(db_connection_string)?;
+    pub fn new(database: Arc<dyn Database>, routes: HashMap<String, String>) -> HttpHandler {
+        return HttpHandler {
+            database,
+            routes,
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            middleware: MiddlewareChain::new(),
+            api_routes: ApiRoutes::new(),
+            websocket_routes: WebSocketRoutes::default(),
+            event_stream_routes: EventStreamRoutes::default()
+        };
+    }
+
+    /// Wraps the handler's routes in the given middleware.
+    pub fn with_middleware(mut self, middleware: MiddlewareChain) -> HttpHandler {
+        self.middleware = middleware;
+        return self;
+    }
+
+    /// Serves the given API routes alongside the pages.
+    pub fn with_api_routes(mut self, api_routes: ApiRoutes) -> HttpHandler {
+        self.api_routes = api_routes;
+        return self;
+    }
+
+    /// Upgrades requests to the given routes to WebSocket connections.
+    pub fn with_websocket_routes(mut self, websocket_routes: WebSocketRoutes) -> HttpHandler {
+        self.websocket_routes = websocket_routes;
+        return self;
+    }
+
+    /// Streams events to clients of the given routes.
+    pub fn with_event_stream_routes(mut self, event_stream_routes: EventStreamRoutes) -> HttpHandler {
+        self.event_stream_routes = event_stream_routes;
+        return self;
+    }
+
+    /// Whether the URI is a health endpoint, page, API route, WebSocket route or event stream.
+    fn is_known_route(&self, uri: &str) -> bool {
+        return uri == LIVENESS_PATH || uri == READINESS_PATH || self.routes.contains_key(uri) || self.api_routes.get(uri).is_some()
+            || self.websocket_routes.get(uri).is_some() || self.event_stream_routes.get(uri).is_some();
+    }
+
+    /// Returns the upgrade for a request whose response accepted a WebSocket handshake or opened
+    /// an event stream.
+    fn upgrade(&self, http_request: HttpRequest, written_response: &WrittenResponse) -> Option<Upgrade> {
+        let uri = http_request.request_uri.as_str();
+        if let (101, Some(handler)) = (written_response.status, self.websocket_routes.get(uri)) {
+            return Some(Upgrade::WebSocket(WebSocketUpgrade {
+                handler: handler.clone(),
+                request: http_request,
+                limits: self.websocket_routes.limits().clone(),
+                shutting_down: self.shutting_down.clone()
+            }));
+        }
+        if let (200, Some(source)) = (written_response.status, self.event_stream_routes.get(uri)) {
+            return Some(Upgrade::EventStream(EventStreamUpgrade {
+                source: source.clone(),
+                request: http_request,
+                limits: self.event_stream_routes.limits().clone(),
+                shutting_down: self.shutting_down.clone()
+            }));
+        }
+        return None;
+    }
+
+    /// Produces the response to a request: the health endpoints, the WebSocket handshake, event
+    /// stream, API route or page for the requested route, or a 404. OPTIONS requests to known routes receive
+    /// the methods they accept, and API routes that need the database receive a 503 while it is
+    /// unavailable.
+    fn respond(&self, http_request: &HttpRequest) -> Result<HttpResponse> {
+        let uri = http_request.request_uri.as_str();
+        if http_request.method == "OPTIONS" && self.is_known_route(uri) {
+            let allowed_methods = match self.api_routes.get(uri) {
+                Some(api_route) => api_route.allowed_methods(),
+                None if self.websocket_routes.get(uri).is_some() => WEBSOCKET_ALLOWED_METHODS.into(),
+                None if self.event_stream_routes.get(uri).is_some() => EVENT_STREAM_ALLOWED_METHODS.into(),
+                None => ALLOWED_METHODS.into()
+            };
+            let mut response = HttpResponse::empty("204 NO CONTENT");
+            response.set_header("Allow", &allowed_methods);
+            return Ok(response);
+        }
+
+        if let Some(api_route) = self.api_routes.get(uri) {
+            if api_route.requires_database && !self.database.is_available() {
+                return Ok(ApiError::service_unavailable("The database is unavailable.").to_response());
+            }
+            return Ok(api_route.respond(http_request));
+        }
+        if self.websocket_routes.get(uri).is_some() {
+            return Ok(websocket::handshake_response(http_request));
+        }
+        if self.event_stream_routes.get(uri).is_some() {
+            return Ok(eventstream::stream_response(http_request));
+        }
+
+        return match (uri, self.routes.get(uri)) {
+            (LIVENESS_PATH, _) => Ok(HttpResponse::new("200 OK", "application/json", health::liveness_json().into())),
+            (READINESS_PATH, _) => {
+                let readiness = self.readiness();
+                Ok(HttpResponse::new(readiness.status_code(), "application/json", readiness.to_json().into()))
+            }
+            (_, None) => HttpHandler::not_found(uri),
+            (_, Some(file_path)) => HttpResponse::from_file("200 OK", file_path)
+        };
+    }
+
+    /// Checks whether the server is ready to serve traffic: the database connection is alive,
+    /// every route's page can be found, and the server is not shutting down.
+    fn readiness(&self) -> Readiness {
+        let config_check = match self.routes.values().find(|file_path| !Path::new(file_path).is_file()) {
+            None => Check::passed("config"),
+            Some(file_path) => Check::failed("config", &format!("Page {} not found.", file_path))
+        };
+
+        let shutdown_check = match self.shutting_down.load(Ordering::SeqCst) {
+            false => Check::passed("shutdown"),
+            true => Check::failed("shutdown", "Server is shutting down.")
+        };
 
-        return Ok(HttpHandler {
-            db_connection,
-            routes
-        });
+        return Readiness::new(vec![self.check_database(), config_check, shutdown_check]);
     }
 
-    /// Extracts the method, URI and version from an incoming HTTP request.
-    // TODO: Read headers, check post-header line, get message body.
-    fn read_http_request<R: Read>(reader: R) -> Result<HttpRequest> {
+    /// Checks that the database answers a ping.
+    fn check_database(&self) -> Check {
+        return match self.database.ping() {
+            Ok(()) => Check::passed("database"),
+            Err(e) => Check::failed("database", &e.to_string())
+        };
+    }
+
+    /// Extracts the method, URI, version, headers and body from an incoming HTTP request. Fails if
+    /// the request line, headers or body are larger than the connection allows.
+    pub(crate) fn read_http_request<R: BufRead>(reader: &mut R, connection: &ConnectionInfo) -> Result<HttpRequest> {
+        let (method, request_uri, http_version) = HttpHandler::read_start_line(reader, connection.max_request_line_length)?;
+        let headers = HttpHandler::read_headers(reader, connection.max_header_line_length, connection.max_header_count)?;
+        let body = HttpHandler::read_body(reader, &headers, connection.max_body_length)?;
+
+        let mut http_request = HttpRequest::new(&method, &request_uri, &http_version, headers);
+        http_request.body = body;
+        return Ok(http_request);
+    }
+
+    /// Extracts the method, URI and version from the start-line of an HTTP request.
+    fn read_start_line<R: BufRead>(reader: &mut R, max_request_line_length: usize) -> Result<(String, String, String)> {
         let mut incoming_bytes = reader.bytes();
         let mut current_token = Vec::<u8>::new();
         let mut tokens = Vec::<String>::new();
+        let mut line_length = 0;
 
         loop {
+            // We stop reading as soon as the line is too long, rather than buffering it.
+            if line_length > max_request_line_length {
+                return Err(ServerError::with_kind(ErrorKind::RequestLineTooLong,
+                                                  format!("Request line exceeds {} bytes.", max_request_line_length)));
+            }
+            line_length += 1;
+
             let current_byte = incoming_bytes.next()
                 // We've reached the end of the bytes without encountering a CRLF.
-                .ok_or(ServerError { message: "HTTP request ended without CRLF.".into() })?
+                .ok_or_else(|| ServerError::new("HTTP request ended without CRLF.".into()))?
                 // We've failed to read the byte.
                 ?;
 
@@ -82,7 +332,7 @@ impl HttpHandler {
                     // We check that the next byte is a line-feed.
                     let maybe_line_feed = incoming_bytes.next()
                         // There is no next byte.
-                        .ok_or(ServerError { message: "HTTP request start-line not terminated by CRLF.".into() })?
+                        .ok_or_else(|| ServerError::new("HTTP request start-line not terminated by CRLF.".into()))?
                         // We've failed to read the byte.
                         ?;
 
@@ -90,82 +340,376 @@ impl HttpHandler {
                         // The start-line is correctly terminated by a CRLF.
                         b'\n' => {
                             if tokens.len() != 3 {
-                                return Err(ServerError { message: "Request line does not have three tokens.".into() })
+                                return Err(ServerError::new("Request line does not have three tokens.".into()))
                             }
 
-                            Ok(HttpRequest {
-                                method: tokens[0].to_string(),
-                                request_uri: tokens[1].to_string(),
-                                http_version: tokens[2].to_string(),
-                            })
+                            Ok((tokens[0].to_string(), tokens[1].to_string(), tokens[2].to_string()))
                         }
-                        _ => Err(ServerError { message: "HTTP request start-line not terminated by LF.".into() })
+                        _ => Err(ServerError::new("HTTP request start-line not terminated by LF.".into()))
                     };
                 }
 
+                // Control characters have no place in a request line, and could forge log lines.
+                control_byte if control_byte < 0x20 || control_byte == 0x7F => {
+                    return Err(ServerError::with_kind(ErrorKind::BadRequest, "Request line contains a control character.".into()));
+                }
+
                 // We're mid-token.
                 any_other_byte => current_token.push(any_other_byte),
             }
         }
     }
 
-    /// Writes a valid HTTP response.
-    fn write_http_ok_response<W: Write>(writer: W, file_path: &str) -> Result<()> {
-        return HttpHandler::write_http_response(writer, "200 OK", file_path);
+    /// Reads header lines up to and including the empty line that ends them. Header names are
+    /// lower-cased, and repeated headers are combined into a comma-separated list. For leniency,
+    /// the request may also end where a header line would start. Fails once a line or the number of
+    /// lines exceeds the maximum, without buffering the excess.
+    fn read_headers<R: BufRead>(reader: &mut R, max_line_length: usize, max_count: usize) -> Result<HashMap<String, String>> {
+        let mut headers = HashMap::<String, String>::new();
+        let mut line = Vec::<u8>::new();
+        let mut count = 0;
+
+        loop {
+            line.clear();
+            let bytes_read = reader.by_ref().take(max_line_length as u64).read_until(b'\n', &mut line)?;
+
+            // The request ended after the last header.
+            if bytes_read == 0 {
+                return Ok(headers);
+            }
+            if bytes_read == max_line_length && !line.ends_with(b"\n") {
+                return Err(ServerError::with_kind(ErrorKind::HeadersTooLarge,
+                                                  format!("Header line exceeds {} bytes.", max_line_length)));
+            }
+
+            if !line.ends_with(b"\r\n") {
+                return Err(ServerError::new("HTTP header line not terminated by CRLF.".into()));
+            }
+            let line_string = from_utf8(&line[..line.len() - 2])?;
+
+            // We've reached the empty line that ends the headers.
+            if line_string.is_empty() {
+                return Ok(headers);
+            }
+            count += 1;
+            if count > max_count {
+                return Err(ServerError::with_kind(ErrorKind::HeadersTooLarge,
+                                                  format!("Request has more than {} headers.", max_count)));
+            }
+
+            let (name, value) = line_string.split_once(':')
+                .ok_or_else(|| ServerError::new("HTTP header line has no colon.".into()))?;
+            if name.is_empty() || name.ends_with(|c: char| c.is_whitespace()) {
+                return Err(ServerError::new("HTTP header name is invalid.".into()));
+            }
+
+            headers.entry(name.to_lowercase())
+                .and_modify(|existing| { existing.push_str(", "); existing.push_str(value.trim()); })
+                .or_insert_with(|| value.trim().into());
+        }
+    }
+
+    /// Reads the body, whose length is given by the Content-Length header. Requests without one
+    /// have no body.
+    fn read_body<R: BufRead>(reader: &mut R, headers: &HashMap<String, String>, max_body_length: usize) -> Result<Vec<u8>> {
+        if headers.contains_key("transfer-encoding") {
+            return Err(ServerError::new("Request bodies with a Transfer-Encoding are not supported.".into()));
+        }
+
+        let content_length: usize = match headers.get("content-length") {
+            None => return Ok(Vec::new()),
+            Some(content_length) => content_length.parse()?
+        };
+        if content_length > max_body_length {
+            return Err(ServerError::with_kind(ErrorKind::PayloadTooLarge,
+                                              format!("Request body of {} bytes exceeds {} bytes.", content_length, max_body_length)));
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        return Ok(body);
+    }
+
+    /// Writes the HTTP response appropriate to an error reading the request: a 408 if the client
+    /// was too slow, a 414 if the request line was too long, a 431 if the headers were too large, a
+    /// 413 if the body was too large, and a 500 otherwise.
+    pub(crate) fn write_http_error_response<W: Write>(writer: W, error: &ServerError) -> Result<WrittenResponse> {
+        return match error.kind {
+            ErrorKind::BadRequest => HttpHandler::write_http_response(writer, "400 BAD REQUEST", ERROR_PAGE_400),
+            ErrorKind::TimedOut => HttpHandler::write_http_response(writer, "408 REQUEST TIMEOUT", ERROR_PAGE_408),
+            ErrorKind::RequestLineTooLong => HttpHandler::write_http_response(writer, "414 URI TOO LONG", ERROR_PAGE_414),
+            ErrorKind::HeadersTooLarge => HttpHandler::write_http_response(writer, "431 REQUEST HEADER FIELDS TOO LARGE", ERROR_PAGE_431),
+            ErrorKind::PayloadTooLarge => HttpHandler::write_http_response(writer, "413 PAYLOAD TOO LARGE", ERROR_PAGE_413),
+            ErrorKind::Other => HttpHandler::write_http_500_response(writer)
+        };
     }
 
     /// Writes a 500 HTTP response.
-    fn write_http_500_response<W: Write>(writer: W) -> Result<()> {
+    pub(crate) fn write_http_500_response<W: Write>(writer: W) -> Result<WrittenResponse> {
         return HttpHandler::write_http_response(writer, "500 INTERNAL SERVER ERROR", ERROR_PAGE_500);
     }
 
-    /// Writes a 404 HTTP response.
-    fn write_http_404_response<W: Write>(writer: W) -> Result<()> {
-        return HttpHandler::write_http_response(writer, "404 NOT FOUND", ERROR_PAGE_404);
+    /// Writes a 404 HTTP response for the requested URI.
+    pub(crate) fn write_http_404_response<W: Write>(writer: W, request_uri: &str) -> Result<WrittenResponse> {
+        return HttpHandler::not_found(request_uri)?.write(writer);
+    }
+
+    /// Renders the 404 page, which names the requested URI.
+    fn not_found(request_uri: &str) -> Result<HttpResponse> {
+        return HttpResponse::from_template("404 NOT FOUND", "404.html", &json!({ "path": request_uri }));
     }
 
     /// Writes an HTTP response for a given status code and page.
-    fn write_http_response<W: Write>(mut writer: W, status_code: &str, file_path: &str) -> Result<()> {
+    fn write_http_response<W: Write>(writer: W, status_code: &str, file_path: &str) -> Result<WrittenResponse> {
+        return HttpResponse::from_file(status_code, file_path)?.write(writer);
+    }
+
+    /// Writes an HTTP response for a given status code, content type and body.
+    pub(crate) fn write_http_body_response<W: Write>(writer: W, status_code: &str, content_type: &str, body: &str) -> Result<WrittenResponse> {
+        return HttpResponse::new(status_code, content_type, body.into()).write(writer);
+    }
+}
+
+pub struct HttpRequest {
+    pub(crate) method: String,
+    pub(crate) request_uri: String,
+    pub(crate) http_version: String,
+    // Keyed by lower-case header name.
+    pub(crate) headers: HashMap<String, String>,
+    // Empty if the request has no body.
+    pub(crate) body: Vec<u8>,
+    // Set by authentication middleware once the client has authenticated.
+    pub(crate) principal: Option<Principal>,
+    // Set by session middleware.
+    pub(crate) session: Option<Session>,
+}
+
+impl HttpRequest {
+    pub fn new(method: &str, request_uri: &str, http_version: &str, headers: HashMap<String, String>) -> HttpRequest {
+        return HttpRequest {
+            method: method.into(),
+            request_uri: request_uri.into(),
+            http_version: http_version.into(),
+            headers,
+            body: Vec::new(),
+            principal: None,
+            session: None,
+        };
+    }
+
+    pub fn body(&self) -> &[u8] {
+        return &self.body;
+    }
+
+    /// Returns the authenticated principal, if the route requires authentication.
+    pub fn principal(&self) -> Option<&Principal> {
+        return self.principal.as_ref();
+    }
+
+    /// Returns the value of the given header, if present. Header names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        return self.headers.get(&name.to_lowercase()).map(|value| value.as_str());
+    }
+
+    /// Returns the lower-case media type of the body, without parameters such as the charset.
+    pub fn media_type(&self) -> Option<String> {
+        return self.header("Content-Type")
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|media_type| media_type.trim().to_lowercase());
+    }
+}
+
+/// An HTTP response that has yet to be written, so that middleware can inspect and modify it.
+#[derive(Debug)]
+pub struct HttpResponse {
+    // The status code as it appears in the status line, e.g. "200 OK".
+    pub(crate) status_code: String,
+    // In the order they are written. Content-Length is added when writing.
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+    // Whether the body is streamed after the response is written, until the connection closes,
+    // so has no Content-Length.
+    pub(crate) streamed: bool,
+}
+
+impl HttpResponse {
+    pub fn new(status_code: &str, content_type: &str, body: Vec<u8>) -> HttpResponse {
+        return HttpResponse {
+            status_code: status_code.into(),
+            headers: vec![("Content-Type".into(), content_type.into())],
+            body,
+            streamed: false
+        };
+    }
+
+    /// Creates a response with no body or headers.
+    pub fn empty(status_code: &str) -> HttpResponse {
+        return HttpResponse { status_code: status_code.into(), headers: Vec::new(), body: Vec::new(), streamed: false };
+    }
+
+    /// Creates a response opening an event stream, whose events are written afterwards.
+    pub fn event_stream() -> HttpResponse {
+        let mut response = HttpResponse::new("200 OK", EVENT_STREAM_CONTENT_TYPE, Vec::new());
+        response.set_header("Cache-Control", "no-cache");
+        response.streamed = true;
+        return response;
+    }
+
+    /// Creates an HTML response whose body is the page at the given path.
+    pub fn from_file(status_code: &str, file_path: &str) -> Result<HttpResponse> {
         let html = fs::read_to_string(file_path)?;
+        return Ok(HttpResponse::new(status_code, "text/html", html.into()));
+    }
 
-        let headers = format!("HTTP/1.1 {}\r\n\
-            Content-Length: {}\r\n\
-            Content-Type: text/html\r\n\
-            Connection: Closed\r\n\r\n", status_code, html.len().to_string());
+    /// Returns the value of the given header, if present. Header names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        return self.headers.iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str());
+    }
+
+    /// Adds the given header, keeping any existing values.
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.into(), value.into()));
+    }
+
+    /// Sets the given header, replacing any existing values.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(header_name, _)| !header_name.eq_ignore_ascii_case(name));
+        self.headers.push((name.into(), value.into()));
+    }
+
+    /// Whether the status code is 2xx.
+    pub fn is_success(&self) -> bool {
+        return (200..300).contains(&parse_status(&self.status_code));
+    }
+
+    /// Writes the response. The connection is closed afterwards, unless the response sets its own
+    /// Connection header to switch protocols.
+    pub fn write<W: Write>(self, mut writer: W) -> Result<WrittenResponse> {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status_code);
+        // Informational responses and 204s must not have a Content-Length.
+        let status = parse_status(&self.status_code);
+        if status >= 200 && status != 204 && !self.streamed {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if self.header("Connection").is_none() {
+            head.push_str("Connection: Closed\r\n");
+        }
+        head.push_str("\r\n");
 
-        writer.write((headers + &html).as_bytes())?;
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(&self.body)?;
 
-        return Ok(());
+        return Ok(WrittenResponse::new(&self.status_code, self.body.len()));
     }
 }
 
-pub struct HttpRequest {
-    method: String,
-    request_uri: String,
-    http_version: String,
+/// A summary of a response that has been written, for logging.
+pub struct WrittenResponse {
+    // The numeric status code, e.g. 200.
+    pub(crate) status: u16,
+    // The size of the response body, excluding headers.
+    pub(crate) body_bytes: usize,
+}
+
+impl WrittenResponse {
+    /// Takes the status code as it appears in the status line, e.g. "200 OK".
+    fn new(status_code: &str, body_bytes: usize) -> WrittenResponse {
+        return WrittenResponse { status: parse_status(status_code), body_bytes };
+    }
+}
+
+/// Extracts the numeric status from a status code as it appears in the status line, e.g. 200
+/// from "200 OK". Returns 0 if there is none.
+fn parse_status(status_code: &str) -> u16 {
+    return status_code.split(' ').next()
+        .and_then(|status| status.parse().ok())
+        .unwrap_or(0);
+}
+
+/// A handler that redirects every HTTP request to the same path over HTTPS.
+pub struct RedirectHandler {
+    // The host (and optional port) that requests are redirected to.
+    https_authority: String
+}
+
+impl Handler for RedirectHandler {
+    /// Reads the HTTP request and writes a permanent redirect to its HTTPS equivalent. GET and
+    /// HEAD requests receive a 301; other methods receive a 308, so that clients preserve the
+    /// method and body.
+    fn handle<R: BufRead, W: Write>(&self, mut reader: R, writer: W, connection: &ConnectionInfo) -> Result<Option<Upgrade>> {
+        let start_time = Instant::now();
+        let http_request = HttpHandler::read_http_request(&mut reader, connection);
+
+        return match http_request {
+            Err(e) => {
+                logger::global().warn(&format!("Rejected HTTP request from {}: {}", connection.peer_ip_string(), e));
+                HttpHandler::write_http_error_response(writer, &e)?;
+                Ok(None)
+            }
+            Ok(http_request) => {
+                let status_code = match http_request.method.as_str() {
+                    "GET" | "HEAD" => "301 MOVED PERMANENTLY",
+                    _ => "308 PERMANENT REDIRECT"
+                };
+                let location = format!("https://{}{}", self.https_authority, http_request.request_uri);
+                let written_response = RedirectHandler::write_http_redirect_response(writer, status_code, &location)?;
+
+                logger::global().access(&AccessLogEntry::new(connection, &http_request, &written_response, start_time.elapsed()));
+                Ok(None)
+            }
+        };
+    }
+}
+
+impl RedirectHandler {
+    pub fn new(https_authority: &str) -> RedirectHandler {
+        return RedirectHandler { https_authority: https_authority.into() };
+    }
+
+    /// Writes an HTTP redirect response with no body.
+    fn write_http_redirect_response<W: Write>(mut writer: W, status_code: &str, location: &str) -> Result<WrittenResponse> {
+        let headers = format!("HTTP/1.1 {}\r\n\
+            Location: {}\r\n\
+            Content-Length: 0\r\n\
+            Connection: Closed\r\n\r\n", status_code, location);
+
+        writer.write_all(headers.as_bytes())?;
+
+        return Ok(WrittenResponse::new(status_code, 0));
+    }
 }
 
 /// A dummy handler for testing.
+#[cfg(test)]
 pub struct DummyHandler;
 
+#[cfg(test)]
 impl Handler for DummyHandler {
-    /// Reads the first byte. Enters an infinite loop if the first byte is '#' (this is useful for
-    /// testing the parallelism of the server). Otherwise, writes "DUMMY" back out.
-    fn handle<R: Read, W: Write>(&self, reader: R, mut writer: W) -> Result<()> {
-        let byte = reader.bytes().next()
+    /// Reads the first byte. If the first byte is '#', keeps reading until the client disconnects
+    /// or the connection times out (this is useful for testing the parallelism of the server).
+    /// Otherwise, writes "DUMMY" back out.
+    fn handle<R: BufRead, W: Write>(&self, mut reader: R, mut writer: W, _connection: &ConnectionInfo) -> Result<Option<Upgrade>> {
+        let byte = (&mut reader).bytes().next()
             // There were no bytes to read.
-            .ok_or(ServerError { message: "Nothing to read from stream.".into() })?
+            .ok_or_else(|| ServerError::new("Nothing to read from stream.".into()))?
             // We've failed to read the byte.
             ?;
 
         match byte {
-            b'#' => loop { },
+            b'#' => {
+                std::io::copy(&mut reader, &mut std::io::sink())?;
+            }
             _ => {
-                writer.write(b"DUMMY\n")?;
+                writer.write_all(b"DUMMY\n")?;
             }
         }
 
-        return Ok(());
+        return Ok(None);
     }
 }
 
@@ -174,32 +718,54 @@ mod tests {
     use std::fs;
     use std::io::{BufReader, BufWriter};
     use std::str::from_utf8;
+    use std::sync::Arc;
 
-    use crate::handler::{Handler, HttpHandler};
+    use serde_json::json;
+
+    use crate::api::ApiRoutes;
+    use crate::database::{Database, LocalDatabase};
+    use crate::dbpool::{DbPool, DbPoolConfig};
+    use crate::handler::{ConnectionInfo, Handler, HttpHandler, HttpResponse, RedirectHandler};
+    use crate::limits::{DEFAULT_MAX_HEADER_COUNT, DEFAULT_MAX_HEADER_LINE_LENGTH, DEFAULT_MAX_REQUEST_LINE_LENGTH};
+    use crate::metrics;
+    use crate::middleware::{DefaultHeaders, MiddlewareChain};
+    use crate::template;
     use std::collections::HashMap;
 
-    const ERROR_PAGE_404: &str = "./src/html/404.html";
     const ERROR_PAGE_500: &str = "./src/html/500.html";
 
-    fn handle(request: &str) -> String {
+    fn new_handler() -> HttpHandler {
         let mut routes = HashMap::new();
         routes.insert("/".into(), "./src/html/hello_world.html".into());
         routes.insert("/2".into(), "./src/html/hello_world_2.html".into());
 
-        let handler = HttpHandler::new(
-            "www.google.com:80",
-            routes
-        ).unwrap();
+        return HttpHandler::new(Arc::new(LocalDatabase::new()), routes);
+    }
+
+    fn handle(request: &str) -> String {
+        return handle_with(&new_handler(), request);
+    }
 
+    fn handle_with(handler: &HttpHandler, request: &str) -> String {
         let mut response = Vec::<u8>::new();
         let reader = BufReader::new(request.as_bytes());
         let writer = BufWriter::new(&mut response);
 
-        handler.handle(reader, writer).unwrap();
+        handler.handle(reader, writer, &ConnectionInfo::default()).unwrap();
 
         return from_utf8(&response).unwrap().into();
     }
 
+    #[test]
+    fn handler_records_unknown_methods_as_other() {
+        handle("FROBNICATE / HTTP/1.1\r\n\r\n");
+
+        let rendered = metrics::global().render();
+        assert!(rendered.contains("http_requests_total{route=\"/\",method=\"other\","));
+        assert!(rendered.contains("http_request_duration_seconds_count{route=\"/\",method=\"other\"}"));
+        assert!(!rendered.contains("FROBNICATE"));
+    }
+
     #[test]
     fn handler_accepts_valid_http_requests_and_returns_expected_response() {
         let valid_requests_and_file_paths = [
@@ -214,7 +780,7 @@ mod tests {
             let expected_headers = format!("HTTP/1.1 200 OK\r\n\
                 Content-Length: {}\r\n\
                 Content-Type: text/html\r\n\
-                Connection: Closed\r\n\r\n", expected_body.len().to_string());
+                Connection: Closed\r\n\r\n", expected_body.len());
             let expected_response = expected_headers + &expected_body;
 
             assert_eq!(response, expected_response);
@@ -230,8 +796,6 @@ mod tests {
             "GET / HTTP/1.1 EXTRA\r\n", // Too many items.
             "GET / HTTP/1.1", // Missing CRLF.
             "GET / HTTP/1.1 EXTRA\r", // Missing LF.
-            "GET / HTTP/1.1\n", // Missing CR.
-            "GET / HTTP/1.1 EXTRA\n\r", // CR and LF in wrong order.
             // TODO: Test of invalid UTF-8.
         ];
 
@@ -239,7 +803,7 @@ mod tests {
         let expected_headers = format!("HTTP/1.1 500 INTERNAL SERVER ERROR\r\n\
                 Content-Length: {}\r\n\
                 Content-Type: text/html\r\n\
-                Connection: Closed\r\n\r\n", expected_body.len().to_string());
+                Connection: Closed\r\n\r\n", expected_body.len());
         let expected_response = expected_headers + &expected_body;
 
         for request in invalid_requests.iter() {
@@ -254,13 +818,216 @@ mod tests {
         let valid_request = "GET /unknown_route HTTP/1.1\r\n";
         let response = handle(valid_request);
 
-        let expected_body = fs::read_to_string(ERROR_PAGE_404).unwrap();
+        let expected_body = template::global().render("404.html", &json!({ "path": "/unknown_route" })).unwrap();
         let expected_headers = format!("HTTP/1.1 404 NOT FOUND\r\n\
                 Content-Length: {}\r\n\
                 Content-Type: text/html\r\n\
-                Connection: Closed\r\n\r\n", expected_body.len().to_string());
+                Connection: Closed\r\n\r\n", expected_body.len());
         let expected_response = expected_headers + &expected_body;
 
         assert_eq!(response, expected_response);
+        assert!(expected_body.contains("Nothing was found at /unknown_route."));
+        assert!(handle("GET /<script> HTTP/1.1\r\n").contains("Nothing was found at /&lt;script&gt;."));
+    }
+
+    #[test]
+    fn handler_answers_options_requests_for_known_routes() {
+        assert_eq!(handle("OPTIONS / HTTP/1.1\r\n"), "HTTP/1.1 204 NO CONTENT\r\n\
+            Allow: GET, HEAD, OPTIONS\r\n\
+            Connection: Closed\r\n\r\n");
+        assert!(handle("OPTIONS /unknown_route HTTP/1.1\r\n").starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+    }
+
+    #[test]
+    fn handler_wraps_routes_in_middleware() {
+        let mut middleware = MiddlewareChain::new();
+        middleware.add_for_route("/unknown_route", DefaultHeaders::new(&[("X-Frame-Options", "DENY")]));
+        let handler = new_handler().with_middleware(middleware);
+
+        let response = handle_with(&handler, "GET /unknown_route HTTP/1.1\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+        assert!(response.contains("\r\nX-Frame-Options: DENY\r\n"));
+        assert!(!handle_with(&handler, "GET / HTTP/1.1\r\n").contains("X-Frame-Options"));
+    }
+
+    fn handle_redirect(request: &str) -> String {
+        let handler = RedirectHandler::new("example.com:10443");
+
+        let mut response = Vec::<u8>::new();
+        let reader = BufReader::new(request.as_bytes());
+        let writer = BufWriter::new(&mut response);
+
+        handler.handle(reader, writer, &ConnectionInfo::default()).unwrap();
+
+        return from_utf8(&response).unwrap().into();
+    }
+
+    #[test]
+    fn redirect_handler_redirects_to_https() {
+        let requests_and_expected_statuses = [
+            ("GET /some/path HTTP/1.1\r\n", "301 MOVED PERMANENTLY"),
+            ("HEAD /some/path HTTP/1.1\r\n", "301 MOVED PERMANENTLY"),
+            ("POST /some/path HTTP/1.1\r\n", "308 PERMANENT REDIRECT"),
+        ];
+
+        for (request, status_code) in requests_and_expected_statuses.iter() {
+            let response = handle_redirect(request);
+
+            let expected_response = format!("HTTP/1.1 {}\r\n\
+                Location: https://example.com:10443/some/path\r\n\
+                Content-Length: 0\r\n\
+                Connection: Closed\r\n\r\n", status_code);
+
+            assert_eq!(response, expected_response);
+        }
+    }
+
+    #[test]
+    fn redirect_handler_rejects_invalid_http_requests() {
+        let response = handle_redirect("GET /\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 500 INTERNAL SERVER ERROR\r\n"));
+    }
+
+    #[test]
+    fn redirect_handler_rejects_overlong_request_lines() {
+        let request = format!("GET /{} HTTP/1.1\r\n", "a".repeat(DEFAULT_MAX_REQUEST_LINE_LENGTH));
+
+        let response = handle_redirect(&request);
+
+        assert!(response.starts_with("HTTP/1.1 414 URI TOO LONG\r\n"));
+    }
+
+    #[test]
+    fn handler_rejects_control_characters_in_request_lines() {
+        let invalid_requests = [
+            "GET /a\nb HTTP/1.1\r\n\r\n",
+            "GET /a\tb HTTP/1.1\r\n\r\n",
+            "GET /a\x7Fb HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\n", // Missing CR.
+            "GET / HTTP/1.1 EXTRA\n\r", // CR and LF in wrong order.
+        ];
+
+        for request in invalid_requests.iter() {
+            let response = handle(request);
+
+            assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST\r\n"), "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn handler_rejects_overlong_header_lines() {
+        let request = format!("GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(DEFAULT_MAX_HEADER_LINE_LENGTH));
+
+        let response = handle(&request);
+
+        assert!(response.starts_with("HTTP/1.1 431 REQUEST HEADER FIELDS TOO LARGE\r\n"));
+    }
+
+    #[test]
+    fn handler_rejects_too_many_headers() {
+        let headers = |count: usize| (0..count).map(|_| "X-Repeated: a\r\n").collect::<String>();
+
+        let response = handle(&format!("GET /healthz HTTP/1.1\r\n{}\r\n", headers(DEFAULT_MAX_HEADER_COUNT)));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // Repeated headers are combined, but still count against the limit.
+        let response = handle(&format!("GET /healthz HTTP/1.1\r\n{}\r\n", headers(DEFAULT_MAX_HEADER_COUNT + 1)));
+        assert!(response.starts_with("HTTP/1.1 431 REQUEST HEADER FIELDS TOO LARGE\r\n"));
+    }
+
+    #[test]
+    fn handler_serves_liveness_endpoint() {
+        let response = handle("GET /healthz HTTP/1.1\r\n");
+
+        assert_eq!(response, "HTTP/1.1 200 OK\r\n\
+            Content-Length: 15\r\n\
+            Content-Type: application/json\r\n\
+            Connection: Closed\r\n\r\n\
+            {\"status\":\"ok\"}");
+    }
+
+    #[test]
+    fn handler_serves_readiness_endpoint() {
+        let response = handle("GET /readyz HTTP/1.1\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("{\"status\":\"ok\",\"checks\":{\"database\":{\"status\":\"ok\"},\
+            \"config\":{\"status\":\"ok\"},\"shutdown\":{\"status\":\"ok\"}}}"));
+    }
+
+    #[test]
+    fn handler_fails_readiness_once_shutting_down() {
+        let handler = new_handler();
+        handler.begin_shutdown();
+
+        let response = handle_with(&handler, "GET /readyz HTTP/1.1\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 503 SERVICE UNAVAILABLE\r\n"));
+        assert!(response.contains("\"shutdown\":{\"status\":\"fail\",\"reason\":\"Server is shutting down.\"}"));
+    }
+
+    #[test]
+    fn handler_fails_readiness_while_the_database_is_unreachable() {
+        let database = DbPool::start("127.0.0.1:1", DbPoolConfig::default());
+        let handler = HttpHandler::new(database, HashMap::new());
+
+        let response = handle_with(&handler, "GET /readyz HTTP/1.1\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 503 SERVICE UNAVAILABLE\r\n"));
+        assert!(response.contains("\"database\":{\"status\":\"fail\",\"reason\":\"Database connection failed: "));
+    }
+
+    #[test]
+    fn handler_rejects_database_routes_while_the_database_is_unreachable() {
+        let database = DbPool::start("127.0.0.1:1", DbPoolConfig::default());
+        assert!(database.ping().is_err());
+        let mut api_routes = ApiRoutes::new();
+        api_routes.add_database_route("/records", &["GET"], |_| Ok(HttpResponse::empty("204 NO CONTENT")));
+        api_routes.add("/status", &["GET"], |_| Ok(HttpResponse::empty("204 NO CONTENT")));
+        let handler = HttpHandler::new(database, HashMap::new()).with_api_routes(api_routes);
+
+        let response = handle_with(&handler, "GET /records HTTP/1.1\r\n");
+        assert!(response.starts_with("HTTP/1.1 503 SERVICE UNAVAILABLE\r\n"));
+        assert!(response.ends_with("{\"error\":\"The database is unavailable.\"}"));
+
+        let response = handle_with(&handler, "GET /status HTTP/1.1\r\n");
+        assert!(response.starts_with("HTTP/1.1 204 NO CONTENT\r\n"));
+    }
+
+    #[test]
+    fn handler_reads_http_headers() {
+        let request = "GET / HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            X-Repeated: one\r\n\
+            x-repeated:  two \r\n\
+            Empty:\r\n\r\n\
+            Body";
+        let mut reader = BufReader::new(request.as_bytes());
+
+        let http_request = HttpHandler::read_http_request(&mut reader, &ConnectionInfo::default()).unwrap();
+
+        assert_eq!(http_request.header("HOST"), Some("localhost"));
+        assert_eq!(http_request.header("X-Repeated"), Some("one, two"));
+        assert_eq!(http_request.header("Empty"), Some(""));
+        assert_eq!(http_request.header("Missing"), None);
+    }
+
+    #[test]
+    fn handler_rejects_invalid_http_headers() {
+        let invalid_requests = [
+            "GET / HTTP/1.1\r\nNoColon\r\n\r\n", // Missing colon.
+            "GET / HTTP/1.1\r\n: value\r\n\r\n", // Missing name.
+            "GET / HTTP/1.1\r\nName : value\r\n\r\n", // Whitespace before colon.
+            "GET / HTTP/1.1\r\nName: value\n\r\n", // Missing CR.
+            "GET / HTTP/1.1\r\nName: value", // Missing CRLF.
+        ];
+
+        for request in invalid_requests.iter() {
+            let mut reader = BufReader::new(request.as_bytes());
+
+            assert!(HttpHandler::read_http_request(&mut reader, &ConnectionInfo::default()).is_err());
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,94 @@
+use crate::logger::json_string;
+
+// The paths of the built-in liveness and readiness endpoints.
+pub const LIVENESS_PATH: &str = "/healthz";
+pub const READINESS_PATH: &str = "/readyz";
+
+/// The outcome of a single readiness check.
+pub struct Check {
+    name: &'static str,
+    // Why the check failed, if it did.
+    failure: Option<String>,
+}
+
+impl Check {
+    pub fn passed(name: &'static str) -> Check {
+        return Check { name, failure: None };
+    }
+
+    pub fn failed(name: &'static str, reason: &str) -> Check {
+        return Check { name, failure: Some(reason.into()) };
+    }
+
+    pub fn is_passing(&self) -> bool {
+        return self.failure.is_none();
+    }
+}
+
+/// The outcome of all the readiness checks.
+pub struct Readiness {
+    checks: Vec<Check>
+}
+
+impl Readiness {
+    pub fn new(checks: Vec<Check>) -> Readiness {
+        return Readiness { checks };
+    }
+
+    /// Whether every check passed.
+    pub fn is_ready(&self) -> bool {
+        return self.checks.iter().all(Check::is_passing);
+    }
+
+    /// The status line to respond with: 200 if ready, 503 otherwise.
+    pub fn status_code(&self) -> &'static str {
+        return match self.is_ready() {
+            true => "200 OK",
+            false => "503 SERVICE UNAVAILABLE"
+        };
+    }
+
+    /// Renders the overall status and the outcome of each check as JSON, e.g.
+    /// `{"status":"unavailable","checks":{"database":{"status":"fail","reason":"..."}}}`.
+    pub fn to_json(&self) -> String {
+        let checks: Vec<String> = self.checks.iter().map(|check| match &check.failure {
+            None => format!("{}:{{\"status\":\"ok\"}}", json_string(check.name)),
+            Some(reason) => format!("{}:{{\"status\":\"fail\",\"reason\":{}}}", json_string(check.name), json_string(reason))
+        }).collect();
+
+        let status = match self.is_ready() {
+            true => "ok",
+            false => "unavailable"
+        };
+        return format!("{{\"status\":\"{}\",\"checks\":{{{}}}}}", status, checks.join(","));
+    }
+}
+
+/// The body returned by the liveness endpoint. If the server can respond at all, it is live.
+pub fn liveness_json() -> String {
+    return "{\"status\":\"ok\"}".into();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::{Check, Readiness};
+
+    #[test]
+    fn readiness_passes_if_all_checks_pass() {
+        let readiness = Readiness::new(vec![Check::passed("database"), Check::passed("config")]);
+
+        assert!(readiness.is_ready());
+        assert_eq!(readiness.status_code(), "200 OK");
+        assert_eq!(readiness.to_json(), "{\"status\":\"ok\",\"checks\":{\"database\":{\"status\":\"ok\"},\"config\":{\"status\":\"ok\"}}}");
+    }
+
+    #[test]
+    fn readiness_fails_if_any_check_fails() {
+        let readiness = Readiness::new(vec![Check::passed("database"), Check::failed("shutdown", "Shutting \"down\".")]);
+
+        assert!(!readiness.is_ready());
+        assert_eq!(readiness.status_code(), "503 SERVICE UNAVAILABLE");
+        assert_eq!(readiness.to_json(), "{\"status\":\"unavailable\",\"checks\":{\"database\":{\"status\":\"ok\"},\
+            \"shutdown\":{\"status\":\"fail\",\"reason\":\"Shutting \\\"down\\\".\"}}}");
+    }
+}
@@ -0,0 +1,267 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::database::{DbError, DbRequest, DbResponse, DbResult, MemoryStore, TRUNCATED_MESSAGE};
+use crate::handler::{ConnectionInfo, Handler, Upgrade};
+use crate::limits::ConnectionLimits;
+use crate::logger;
+use crate::servererror::{Result, ServerError};
+
+// Connections are held open by the web server's pool indefinitely, so only idleness ends them.
+const MAX_CONNECTION_AGE: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// The store's entries, and the log their changes are appended to.
+struct KvState {
+    entries: MemoryStore,
+    log: File,
+    // The length of the log up to the end of its last complete record.
+    log_length: u64,
+}
+
+/// A key-value store serving the database protocol. Entries are held in memory, and each change
+/// is appended to a log and synced to disk before it is acknowledged. On startup, the log is
+/// replayed to recover the entries.
+pub struct KvStore {
+    state: Mutex<KvState>,
+}
+
+impl KvStore {
+    /// Opens the store whose log is at the path, creating the log if need be. A record left
+    /// incomplete by a crash is discarded, and the log is truncated to the last complete record.
+    /// Any other damage stops the store opening, rather than discarding the records after it.
+    pub fn open(path: &Path) -> Result<KvStore> {
+        let log = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut entries = MemoryStore::new();
+        let mut records = 0;
+
+        let mut reader = BufReader::new(&log);
+        let mut log_length = 0;
+        loop {
+            match DbRequest::read_from(&mut reader) {
+                Ok(Some(request)) => {
+                    entries.apply(&request);
+                    records += 1;
+                    log_length = reader.stream_position()?;
+                }
+                Ok(None) => break,
+                // Only the last record can have been cut short by a crash, so it must run to the end of the log.
+                Err(DbError::Protocol(message)) if message == TRUNCATED_MESSAGE && reader.stream_position()? == log.metadata()?.len() => {
+                    let file_length = log.metadata()?.len();
+                    logger::global().warn(&format!("Discarding the last {} bytes of {}, which hold an incomplete record: {}",
+                                                   file_length - log_length, path.display(), message));
+                    log.set_len(log_length)?;
+                    log.sync_data()?;
+                    break;
+                }
+                Err(DbError::Protocol(message)) => {
+                    return Err(ServerError::new(format!("{} is corrupt at byte {}: {}", path.display(), log_length, message)));
+                }
+                Err(e) => return Err(ServerError::with_cause(format!("Failed to read {}", path.display()), e.into()))
+            }
+        }
+
+        logger::global().info(&format!("Recovered {} records from {}.", records, path.display()));
+        return Ok(KvStore { state: Mutex::new(KvState { entries, log, log_length }) });
+    }
+
+    /// The limits suited to connections from a pool, which stay open and send requests whenever
+    /// they need to, but are closed once idle for the given time.
+    pub fn connection_limits(idle_timeout: Duration) -> ConnectionLimits {
+        return ConnectionLimits {
+            header_read_timeout: MAX_CONNECTION_AGE,
+            body_read_timeout: MAX_CONNECTION_AGE,
+            idle_timeout,
+            min_bytes_per_second: 0,
+            ..ConnectionLimits::default()
+        };
+    }
+
+    fn lock(&self) -> DbResult<MutexGuard<'_, KvState>> {
+        return self.state.lock().map_err(|_| DbError::Server("The store is poisoned.".into()));
+    }
+
+    /// Carries out the request. Changes are logged first, and are not made if logging fails.
+    pub fn apply(&self, request: &DbRequest) -> DbResult<DbResponse> {
+        let mut state = self.lock()?;
+
+        if let DbRequest::Put { .. } | DbRequest::Delete { .. } = request {
+            let mut record = Vec::new();
+            request.write_to(&mut record)?;
+            if let Err(e) = state.log.write_all(&record).and_then(|_| state.log.sync_data()) {
+                // We cut off any partial record, so that later records can still be recovered.
+                let log_length = state.log_length;
+                let _ = state.log.set_len(log_length);
+                return Err(DbError::Server(format!("Failed to log the change: {}", e)));
+            }
+            state.log_length += record.len() as u64;
+        }
+
+        return Ok(state.entries.apply(request));
+    }
+}
+
+impl Handler for KvStore {
+    /// Answers requests until the client hangs up, goes idle or breaks the protocol.
+    fn handle<R: BufRead, W: Write>(&self, mut reader: R, mut writer: W, connection: &ConnectionInfo) -> Result<Option<Upgrade>> {
+        loop {
+            let request = match DbRequest::read_from(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) | Err(DbError::Timeout) => return Ok(None),
+                // The rest of the stream cannot be framed, so we report the error and hang up.
+                Err(DbError::Protocol(message)) => {
+                    logger::global().warn(&format!("Rejected request from {}: {}", connection.peer_ip_string(), message));
+                    DbResponse::Error(message).write_to(&mut writer)?;
+                    return Ok(None);
+                }
+                Err(e) => return Err(e.into())
+            };
+
+            let response = self.apply(&request).unwrap_or_else(|e| {
+                let message = e.to_string();
+                logger::global().error(&ServerError::with_cause("Request failed".into(), e.into()));
+                DbResponse::Error(message)
+            });
+            response.write_to(&mut writer)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{self, OpenOptions};
+    use std::io::{BufReader, Write};
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicU16, Ordering};
+    use std::time::Duration;
+
+    use crate::database::{DbRequest, DbResponse};
+    use crate::handler::{ConnectionInfo, Handler};
+    use crate::kvstore::KvStore;
+    use crate::server::{ServerInternal, ServerOptions};
+
+    // Used to allocate different ports for the listeners across tests.
+    static PORT: AtomicU16 = AtomicU16::new(10800);
+
+    fn get_port() -> String {
+        return PORT.fetch_add(1, Ordering::Relaxed).to_string();
+    }
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = temp_dir().join(format!("blockchain-test-{}-{}.log", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        return path;
+    }
+
+    fn put(key: &str, value: &str) -> DbRequest {
+        return DbRequest::Put { key: key.into(), value: value.into() };
+    }
+
+    fn get(key: &str) -> DbRequest {
+        return DbRequest::Get { key: key.into() };
+    }
+
+    #[test]
+    fn stores_recover_their_entries_from_the_log() {
+        let path = log_path("recover");
+        let store = KvStore::open(&path).unwrap();
+        store.apply(&put("a", "1")).unwrap();
+        store.apply(&put("b", "2")).unwrap();
+        store.apply(&put("a", "3")).unwrap();
+        assert_eq!(store.apply(&DbRequest::Delete { key: "b".into() }).unwrap(), DbResponse::Deleted(true));
+        drop(store);
+
+        let store = KvStore::open(&path).unwrap();
+
+        assert_eq!(store.apply(&get("a")).unwrap(), DbResponse::Value("3".into()));
+        assert_eq!(store.apply(&get("b")).unwrap(), DbResponse::NotFound);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stores_discard_records_cut_short_by_a_crash() {
+        let path = log_path("torn");
+        let store = KvStore::open(&path).unwrap();
+        store.apply(&put("a", "1")).unwrap();
+        drop(store);
+        let complete_length = fs::metadata(&path).unwrap().len();
+        let mut record = Vec::new();
+        put("b", "2").write_to(&mut record).unwrap();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&record[..record.len() - 1]).unwrap();
+
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete_length);
+        store.apply(&put("c", "3")).unwrap();
+        drop(store);
+
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(store.apply(&get("a")).unwrap(), DbResponse::Value("1".into()));
+        assert_eq!(store.apply(&get("b")).unwrap(), DbResponse::NotFound);
+        assert_eq!(store.apply(&get("c")).unwrap(), DbResponse::Value("3".into()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stores_refuse_to_open_logs_damaged_before_their_end() {
+        let path = log_path("corrupt");
+        let store = KvStore::open(&path).unwrap();
+        store.apply(&put("a", "1")).unwrap();
+        let first_length = fs::metadata(&path).unwrap().len();
+        store.apply(&put("b", "2")).unwrap();
+        store.apply(&put("c", "3")).unwrap();
+        drop(store);
+        let mut log = fs::read(&path).unwrap();
+        // Overwrites the command of the middle record, just after its length.
+        log[first_length as usize + 4] = 0xFF;
+        fs::write(&path, &log).unwrap();
+
+        let error = KvStore::open(&path).err().unwrap();
+
+        assert!(error.to_string().contains(&format!("is corrupt at byte {}", first_length)), "{}", error);
+        assert_eq!(fs::read(&path).unwrap(), log);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stores_answer_each_request_until_the_client_hangs_up() {
+        let path = log_path("handle");
+        let store = KvStore::open(&path).unwrap();
+        let mut input = Vec::new();
+        for request in [put("a", "1"), get("a"), DbRequest::Scan { prefix: Vec::new(), limit: 10 }].iter() {
+            request.write_to(&mut input).unwrap();
+        }
+        let mut output = Vec::new();
+
+        store.handle(BufReader::new(input.as_slice()), &mut output, &ConnectionInfo::default()).unwrap();
+
+        let mut responses = output.as_slice();
+        assert_eq!(DbResponse::read_from(&mut responses).unwrap(), DbResponse::Done);
+        assert_eq!(DbResponse::read_from(&mut responses).unwrap(), DbResponse::Value("1".into()));
+        assert_eq!(DbResponse::read_from(&mut responses).unwrap(), DbResponse::Entries(vec![("a".into(), "1".into())]));
+        assert!(responses.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stores_serve_the_database_protocol_over_tcp() {
+        let path = log_path("server");
+        let options = ServerOptions { limits: KvStore::connection_limits(Duration::from_secs(5)), ..ServerOptions::default() };
+        let port = get_port();
+        let mut server_handle = ServerInternal::start_with_options(&port, KvStore::open(&path).unwrap(), options).unwrap();
+
+        let stream = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut writer = &stream;
+        put("key", "value").write_to(&mut writer).unwrap();
+        assert_eq!(DbResponse::read_from(&mut reader).unwrap(), DbResponse::Done);
+        get("key").write_to(&mut writer).unwrap();
+        assert_eq!(DbResponse::read_from(&mut reader).unwrap(), DbResponse::Value("value".into()));
+
+        server_handle.stop_listening().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
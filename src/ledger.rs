@@ -0,0 +1,704 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::merkle::{merkle_proof, merkle_root, MerkleProof};
+use crate::sha256::{to_hex, Sha256, DIGEST_LENGTH};
+use crate::transaction::{Transaction, TransactionError};
+use crate::utxo::{UtxoError, UtxoSet};
+
+// The hash of a block.
+pub type Hash = [u8; DIGEST_LENGTH];
+
+// The genesis block's fields, which are fixed so that every chain starts from the same block.
+const GENESIS_TIMESTAMP: u64 = 0;
+// The most leading zero bits a hash can have.
+const MAX_DIFFICULTY: u32 = (DIGEST_LENGTH * 8) as u32;
+
+/// The rules a chain's blocks follow.
+#[derive(Clone, Debug)]
+pub struct ChainConfig {
+    // The leading zero bits required of the first block's hash.
+    pub initial_difficulty: u32,
+    // The time blocks should take to mine, in seconds.
+    pub target_block_time: u64,
+    // How many blocks pass between changes in difficulty.
+    pub retarget_interval: u64,
+    // The most the difficulty changes by at once, in bits. Each bit doubles or halves the work.
+    pub max_adjustment: u32,
+    // The coins each block's coinbase may create, on top of the block's fees.
+    pub block_reward: u64,
+    // How far ahead of the local clock a block's timestamp may be, in seconds, so that miners
+    // cannot date blocks ahead to lower the difficulty.
+    pub max_future_drift: u64,
+}
+
+impl Default for ChainConfig {
+    fn default() -> ChainConfig {
+        return ChainConfig {
+            initial_difficulty: 16,
+            target_block_time: 60,
+            retarget_interval: 10,
+            max_adjustment: 2,
+            block_reward: 50,
+            max_future_drift: 2 * 60 * 60
+        };
+    }
+}
+
+impl ChainConfig {
+    /// The difficulty required of the block following the given blocks. It changes every
+    /// retarget interval, by a bit for each doubling or halving of the time the last interval's
+    /// blocks took against the target time.
+    pub fn next_difficulty<H: AsRef<BlockHeader>>(&self, chain: &[H]) -> u32 {
+        let next_index = chain.len() as u64;
+        let previous = match chain.last().map(|previous| previous.as_ref()) {
+            Some(previous) if previous.index > 0 => previous,
+            // The genesis block is exempt from proof of work, so does not set the difficulty.
+            _ => return self.initial_difficulty
+        };
+        if self.retarget_interval < 2 || next_index % self.retarget_interval != 0 || next_index <= self.retarget_interval {
+            return previous.difficulty;
+        }
+
+        // We measure from the first block of the interval rather than the genesis block, whose
+        // timestamp is fixed.
+        let first = chain[(next_index - self.retarget_interval) as usize].as_ref();
+        let actual = previous.timestamp.saturating_sub(first.timestamp).max(1);
+        let expected = (self.retarget_interval - 1) * self.target_block_time;
+
+        let mut adjustment = 0;
+        if actual < expected {
+            while adjustment < self.max_adjustment && actual << (adjustment + 1) <= expected {
+                adjustment += 1;
+            }
+            return (previous.difficulty + adjustment).min(MAX_DIFFICULTY);
+        }
+        while adjustment < self.max_adjustment && expected << (adjustment + 1) <= actual {
+            adjustment += 1;
+        }
+        return previous.difficulty.saturating_sub(adjustment);
+    }
+
+    /// Checks that the header can follow the given headers, at the difficulty they require, and is
+    /// not dated too far ahead of the local clock.
+    pub fn validate_next_header<H: AsRef<BlockHeader>>(&self, chain: &[H], header: &BlockHeader) -> ChainResult<()> {
+        // A clock set before the epoch only makes us stricter.
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+        return self.validate_next_header_at(chain, header, now);
+    }
+
+    fn validate_next_header_at<H: AsRef<BlockHeader>>(&self, chain: &[H], header: &BlockHeader, now: u64) -> ChainResult<()> {
+        let expected = self.next_difficulty(chain);
+        if header.difficulty != expected {
+            return Err(ChainError::InvalidDifficulty { index: header.index, expected, found: header.difficulty });
+        }
+        let previous = chain.last().ok_or(ChainError::InvalidGenesis)?;
+        header.validate_after(previous.as_ref())?;
+        if header.timestamp > now.saturating_add(self.max_future_drift) {
+            return Err(ChainError::FutureTimestamp { index: header.index });
+        }
+        return Ok(());
+    }
+
+    /// Checks a chain of headers without their blocks' transactions, e.g. for a client that only
+    /// needs to check Merkle proofs against them: each header matches its hash, meets the
+    /// difficulty required of it, and follows on from the one before.
+    pub fn verify_headers(&self, headers: &[BlockHeader]) -> ChainResult<()> {
+        if headers.first() != Some(&Block::genesis().header) {
+            return Err(ChainError::InvalidGenesis);
+        }
+        for length in 1..headers.len() {
+            self.validate_next_header(&headers[..length], &headers[length])?;
+        }
+        return Ok(());
+    }
+}
+
+/// Counts the zero bits at the start of the hash.
+pub fn leading_zero_bits(hash: &Hash) -> u32 {
+    let mut bits = 0;
+    for byte in hash.iter() {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    return bits;
+}
+
+/// The ways a block can fail to extend a chain.
+#[derive(Debug, PartialEq)]
+pub enum ChainError {
+    // The first block is not the genesis block.
+    InvalidGenesis,
+    // The block's index does not follow the previous block's.
+    InvalidIndex { expected: u64, found: u64 },
+    // The block does not name the previous block's hash.
+    InvalidPreviousHash { index: u64 },
+    // The block's recorded hash does not match its contents.
+    InvalidHash { index: u64 },
+    // The block is older than the previous block.
+    InvalidTimestamp { index: u64 },
+    // The block is dated further ahead of the local clock than the chain allows.
+    FutureTimestamp { index: u64 },
+    // The block claims a different difficulty to the one the chain requires.
+    InvalidDifficulty { index: u64, expected: u32, found: u32 },
+    // The block's hash does not have as many leading zero bits as its difficulty requires.
+    InsufficientWork { index: u64 },
+    // The header's Merkle root does not match the block's transactions.
+    InvalidMerkleRoot { index: u64 },
+    // One of the block's transactions is invalid.
+    InvalidTransaction { index: u64, transaction: usize, error: TransactionError },
+    // The block spends outputs that are missing or not its own, or creates too many coins.
+    InvalidSpend { index: u64, error: UtxoError },
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            ChainError::InvalidGenesis => f.write_str("The chain does not start with the genesis block."),
+            ChainError::InvalidIndex { expected, found } => write!(f, "Expected block {}, found block {}.", expected, found),
+            ChainError::InvalidPreviousHash { index } => write!(f, "Block {} does not follow the previous block's hash.", index),
+            ChainError::InvalidHash { index } => write!(f, "Block {} does not match its hash.", index),
+            ChainError::InvalidTimestamp { index } => write!(f, "Block {} is older than the previous block.", index),
+            ChainError::FutureTimestamp { index } => write!(f, "Block {} is dated too far in the future.", index),
+            ChainError::InvalidDifficulty { index, expected, found } => write!(f, "Block {} has difficulty {}, but {} is required.", index, found, expected),
+            ChainError::InsufficientWork { index } => write!(f, "Block {} does not meet its difficulty.", index),
+            ChainError::InvalidMerkleRoot { index } => write!(f, "Block {} does not match its transactions' Merkle root.", index),
+            ChainError::InvalidTransaction { index, transaction, error } => write!(f, "Transaction {} in block {} is invalid: {}", transaction, index, error),
+            ChainError::InvalidSpend { index, error } => write!(f, "Block {} cannot be applied: {}", index, error),
+        };
+    }
+}
+
+pub type ChainResult<T> = std::result::Result<T, ChainError>;
+
+/// The part of a block that is hashed. It commits to the block's transactions through their
+/// Merkle root, so that a chain of headers can be validated, and transactions proven to be in it,
+/// without the transactions themselves.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockHeader {
+    // The block's position in the chain, starting from zero for the genesis block.
+    pub index: u64,
+    // When the block was created, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub previous_hash: Hash,
+    // The leading zero bits required of the block's hash.
+    pub difficulty: u32,
+    // Varied to change the block's hash without changing its contents.
+    pub nonce: u64,
+    // The root of the Merkle tree over the block's transaction IDs.
+    pub merkle_root: Hash,
+    pub hash: Hash,
+}
+
+impl BlockHeader {
+    /// Hashes the header's fields other than its hash. Integers are hashed as big-endian bytes.
+    pub fn calculate_hash(&self) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.index.to_be_bytes());
+        hasher.update(&self.timestamp.to_be_bytes());
+        hasher.update(&self.previous_hash);
+        hasher.update(&self.difficulty.to_be_bytes());
+        hasher.update(&self.nonce.to_be_bytes());
+        hasher.update(&self.merkle_root);
+        return hasher.finish();
+    }
+
+    /// Whether the header's hash has as many leading zero bits as its difficulty requires.
+    pub fn meets_difficulty(&self) -> bool {
+        return leading_zero_bits(&self.hash) >= self.difficulty;
+    }
+
+    /// The header's hash, as hexadecimal.
+    pub fn hash_hex(&self) -> String {
+        return to_hex(&self.hash);
+    }
+
+    /// Checks that the header matches its hash, meets its difficulty, and follows on from the
+    /// previous header.
+    pub fn validate_after(&self, previous: &BlockHeader) -> ChainResult<()> {
+        if self.index != previous.index + 1 {
+            return Err(ChainError::InvalidIndex { expected: previous.index + 1, found: self.index });
+        }
+        if self.previous_hash != previous.hash {
+            return Err(ChainError::InvalidPreviousHash { index: self.index });
+        }
+        if self.timestamp < previous.timestamp {
+            return Err(ChainError::InvalidTimestamp { index: self.index });
+        }
+        if self.hash != self.calculate_hash() {
+            return Err(ChainError::InvalidHash { index: self.index });
+        }
+        if !self.meets_difficulty() {
+            return Err(ChainError::InsufficientWork { index: self.index });
+        }
+        return Ok(());
+    }
+}
+
+impl AsRef<BlockHeader> for BlockHeader {
+    fn as_ref(&self) -> &BlockHeader {
+        return self;
+    }
+}
+
+/// A block in the chain: its header and transactions. The header's hash covers the transactions
+/// through their Merkle root, so changing any of them breaks the link from the next block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub header: BlockHeader,
+    // The first may be a coinbase, paying the miner.
+    pub transactions: Vec<Transaction>,
+}
+
+impl Block {
+    /// Creates a block, computing its Merkle root and hash.
+    pub fn new(index: u64, timestamp: u64, previous_hash: Hash, difficulty: u32, nonce: u64, transactions: Vec<Transaction>) -> Block {
+        let merkle_root = Block::transactions_root(&transactions);
+        let mut header = BlockHeader { index, timestamp, previous_hash, difficulty, nonce, merkle_root, hash: [0; DIGEST_LENGTH] };
+        header.hash = header.calculate_hash();
+        return Block { header, transactions };
+    }
+
+    /// The first block of every chain.
+    pub fn genesis() -> Block {
+        return Block::new(0, GENESIS_TIMESTAMP, [0; DIGEST_LENGTH], 0, 0, Vec::new());
+    }
+
+    /// The root of the Merkle tree over the transactions' IDs.
+    fn transactions_root(transactions: &[Transaction]) -> Hash {
+        let txids: Vec<Hash> = transactions.iter().map(|transaction| transaction.txid()).collect();
+        return merkle_root(&txids);
+    }
+
+    /// Proves that the transaction at the position is in the block, against the header's Merkle
+    /// root.
+    pub fn merkle_proof(&self, transaction: usize) -> Option<MerkleProof> {
+        let txids: Vec<Hash> = self.transactions.iter().map(|transaction| transaction.txid()).collect();
+        return merkle_proof(&txids, transaction);
+    }
+
+    /// Checks that the header commits to the block's transactions, and that they are
+    /// well-formed and correctly signed.
+    pub fn validate_transactions(&self) -> ChainResult<()> {
+        let index = self.header.index;
+        if self.header.merkle_root != Block::transactions_root(&self.transactions) {
+            return Err(ChainError::InvalidMerkleRoot { index });
+        }
+        for (position, transaction) in self.transactions.iter().enumerate() {
+            let result = match transaction.is_coinbase() && position > 0 {
+                true => Err(TransactionError::MisplacedCoinbase),
+                false => transaction.validate()
+            };
+            result.map_err(|error| ChainError::InvalidTransaction { index, transaction: position, error })?;
+        }
+        return Ok(());
+    }
+}
+
+impl AsRef<BlockHeader> for Block {
+    fn as_ref(&self) -> &BlockHeader {
+        return &self.header;
+    }
+}
+
+/// A chain of blocks, each linked to the one before by its hash, starting from the genesis block,
+/// along with the outputs its transactions leave unspent.
+#[derive(Clone, Debug)]
+pub struct Blockchain {
+    config: ChainConfig,
+    blocks: Vec<Block>,
+    utxos: UtxoSet,
+}
+
+impl Blockchain {
+    /// Creates a chain holding only the genesis block.
+    pub fn new(config: ChainConfig) -> Blockchain {
+        let genesis = Block::genesis();
+        let mut utxos = UtxoSet::new(config.block_reward);
+        // The genesis block has no transactions to fail.
+        utxos.apply_block(&genesis).unwrap();
+        return Blockchain { config, blocks: vec![genesis], utxos };
+    }
+
+    /// Rebuilds a chain from its blocks, failing if they do not form a valid chain.
+    pub fn from_blocks(config: ChainConfig, blocks: Vec<Block>) -> ChainResult<Blockchain> {
+        let utxos = Blockchain::replay(&config, &blocks)?;
+        return Ok(Blockchain { config, blocks, utxos });
+    }
+
+    pub fn config(&self) -> &ChainConfig {
+        return &self.config;
+    }
+
+    pub fn blocks(&self) -> &[Block] {
+        return &self.blocks;
+    }
+
+    /// The outputs left unspent as of the latest block.
+    pub fn utxos(&self) -> &UtxoSet {
+        return &self.utxos;
+    }
+
+    /// The most recent block.
+    pub fn latest(&self) -> &Block {
+        // A chain always holds at least the genesis block.
+        return self.blocks.last().unwrap();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.blocks.len();
+    }
+
+    /// A chain is never empty, as it always holds the genesis block.
+    pub fn is_empty(&self) -> bool {
+        return false;
+    }
+
+    /// The difficulty required of the next block.
+    pub fn next_difficulty(&self) -> u32 {
+        return self.config.next_difficulty(&self.blocks);
+    }
+
+    /// Creates the block that would follow the latest one, without appending it. It must be mined
+    /// before it meets its difficulty.
+    pub fn next_block(&self, timestamp: u64, transactions: Vec<Transaction>) -> Block {
+        let latest = self.latest();
+        return Block::new(latest.header.index + 1, timestamp, latest.header.hash, self.next_difficulty(), 0, transactions);
+    }
+
+    /// Appends the block if it validly follows the latest one, at the required difficulty, and
+    /// only spends outputs left unspent so far.
+    pub fn append(&mut self, block: Block) -> ChainResult<()> {
+        Blockchain::validate_next(&self.config, &self.blocks, &block)?;
+        self.utxos.apply_block(&block).map_err(|error| ChainError::InvalidSpend { index: block.header.index, error })?;
+        self.blocks.push(block);
+        return Ok(());
+    }
+
+    /// Removes the latest block, restoring the outputs it spent, e.g. to switch to a competing
+    /// branch. Returns None rather than remove the genesis block.
+    pub fn pop(&mut self) -> Option<Block> {
+        if self.blocks.len() == 1 {
+            return None;
+        }
+        let block = self.blocks.pop()?;
+        // The latest block is always the latest applied to the set.
+        self.utxos.revert_block(&block).unwrap();
+        return Some(block);
+    }
+
+    /// Checks the whole chain: it starts with the genesis block, and each block matches its hash,
+    /// meets the difficulty required of it, follows on from the one before, and spends only
+    /// outputs left unspent before it.
+    pub fn verify(&self) -> ChainResult<()> {
+        Blockchain::replay(&self.config, &self.blocks)?;
+        return Ok(());
+    }
+
+    /// Validates the blocks as a chain from the genesis block, building the set of outputs they
+    /// leave unspent.
+    fn replay(config: &ChainConfig, blocks: &[Block]) -> ChainResult<UtxoSet> {
+        if blocks.first() != Some(&Block::genesis()) {
+            return Err(ChainError::InvalidGenesis);
+        }
+        let mut utxos = UtxoSet::new(config.block_reward);
+        utxos.apply_block(&blocks[0]).unwrap();
+        for length in 1..blocks.len() {
+            let block = &blocks[length];
+            Blockchain::validate_next(config, &blocks[..length], block)?;
+            utxos.apply_block(block).map_err(|error| ChainError::InvalidSpend { index: block.header.index, error })?;
+        }
+        return Ok(utxos);
+    }
+
+    /// Checks that the block can follow the given blocks.
+    fn validate_next(config: &ChainConfig, blocks: &[Block], block: &Block) -> ChainResult<()> {
+        config.validate_next_header(blocks, &block.header)?;
+        return block.validate_transactions();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ledger::{leading_zero_bits, Block, BlockHeader, Blockchain, ChainConfig, ChainError};
+    use crate::merkle::merkle_root;
+    use crate::sha256::sha256;
+    use crate::transaction::{OutPoint, Transaction, TransactionError, TxOutput};
+    use crate::utxo::{UtxoError, UtxoSet};
+    use crate::wallet::{Address, Wallet};
+
+    /// Rules under which any hash will do, so that blocks need no mining.
+    fn no_work() -> ChainConfig {
+        return ChainConfig { initial_difficulty: 0, ..ChainConfig::default() };
+    }
+
+    /// A coinbase paying a fixed address, distinguished by the nonce.
+    fn coinbase(nonce: u64) -> Vec<Transaction> {
+        return vec![Transaction::coinbase(Address::from_public_key(b"miner"), 50, nonce)];
+    }
+
+    fn chain_of(length: u64) -> Blockchain {
+        let mut chain = Blockchain::new(no_work());
+        for index in 1..length {
+            let block = chain.next_block(index * 10, coinbase(index));
+            chain.append(block).unwrap();
+        }
+        return chain;
+    }
+
+    /// Finds the first nonce that meets the block's difficulty.
+    fn solve(mut block: Block) -> Block {
+        while !block.header.meets_difficulty() {
+            block.header.nonce += 1;
+            block.header.hash = block.header.calculate_hash();
+        }
+        return block;
+    }
+
+    #[test]
+    fn blocks_hash_their_header_fields() {
+        let block = Block::new(1, 2, [3; 32], 4, 5, coinbase(1));
+
+        let mut fields = Vec::new();
+        fields.extend_from_slice(&1u64.to_be_bytes());
+        fields.extend_from_slice(&2u64.to_be_bytes());
+        fields.extend_from_slice(&[3; 32]);
+        fields.extend_from_slice(&4u32.to_be_bytes());
+        fields.extend_from_slice(&5u64.to_be_bytes());
+        fields.extend_from_slice(&merkle_root(&[coinbase(1)[0].txid()]));
+        assert_eq!(block.header.hash, sha256(&fields));
+        assert_ne!(Block::new(1, 2, [3; 32], 4, 6, coinbase(1)).header.hash, block.header.hash);
+        assert_ne!(Block::new(1, 2, [3; 32], 4, 5, coinbase(2)).header.hash, block.header.hash);
+    }
+
+    #[test]
+    fn leading_zero_bits_are_counted_across_bytes() {
+        let mut hash = [0xff; 32];
+        assert_eq!(leading_zero_bits(&hash), 0);
+        hash[0] = 0;
+        hash[1] = 0x1f;
+        assert_eq!(leading_zero_bits(&hash), 11);
+        assert_eq!(leading_zero_bits(&[0; 32]), 256);
+    }
+
+    #[test]
+    fn chains_start_with_the_genesis_block() {
+        let chain = Blockchain::new(ChainConfig::default());
+
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain.latest(), &Block::genesis());
+        assert_eq!(chain.latest().header.previous_hash, [0; 32]);
+        assert_eq!(chain.next_difficulty(), 16);
+        assert!(chain.verify().is_ok());
+    }
+
+    #[test]
+    fn chains_append_linked_blocks() {
+        let chain = chain_of(4);
+
+        assert_eq!(chain.len(), 4);
+        assert_eq!(chain.latest().header.index, 3);
+        assert_eq!(chain.latest().header.previous_hash, chain.blocks()[2].header.hash);
+        assert!(chain.verify().is_ok());
+        assert!(Blockchain::from_blocks(no_work(), chain.blocks().to_vec()).is_ok());
+    }
+
+    #[test]
+    fn chains_reject_blocks_dated_too_far_ahead() {
+        let mut chain = chain_of(2);
+        let config = ChainConfig { max_future_drift: 100, ..no_work() };
+        let now = 1000;
+
+        let ahead = chain.next_block(now + 100, coinbase(2));
+        assert_eq!(config.validate_next_header_at(chain.blocks(), &ahead.header, now), Ok(()));
+        let too_far_ahead = chain.next_block(now + 101, coinbase(2));
+        assert_eq!(config.validate_next_header_at(chain.blocks(), &too_far_ahead.header, now), Err(ChainError::FutureTimestamp { index: 2 }));
+
+        // Appended blocks are checked against the local clock.
+        let far_future = chain.next_block(u64::MAX, coinbase(2));
+        assert_eq!(chain.append(far_future), Err(ChainError::FutureTimestamp { index: 2 }));
+    }
+
+    #[test]
+    fn chains_reject_blocks_that_do_not_follow_the_latest() {
+        let mut chain = chain_of(2);
+        let next = chain.next_block(20, coinbase(2));
+
+        let skipped = Block::new(3, 20, next.header.previous_hash, 0, 0, coinbase(2));
+        assert_eq!(chain.append(skipped), Err(ChainError::InvalidIndex { expected: 2, found: 3 }));
+
+        let unlinked = Block::new(2, 20, [9; 32], 0, 0, coinbase(2));
+        assert_eq!(chain.append(unlinked), Err(ChainError::InvalidPreviousHash { index: 2 }));
+
+        let older = Block::new(2, 5, next.header.previous_hash, 0, 0, coinbase(2));
+        assert_eq!(chain.append(older), Err(ChainError::InvalidTimestamp { index: 2 }));
+
+        let mut tampered = next.clone();
+        tampered.header.timestamp += 1;
+        assert_eq!(chain.append(tampered), Err(ChainError::InvalidHash { index: 2 }));
+
+        // The header commits to the transactions through their Merkle root.
+        let mut tampered = next.clone();
+        tampered.transactions[0].outputs[0].amount = 5000;
+        assert_eq!(chain.append(tampered), Err(ChainError::InvalidMerkleRoot { index: 2 }));
+
+        assert_eq!(chain.append(next), Ok(()));
+    }
+
+    #[test]
+    fn chains_reject_blocks_without_the_required_work() {
+        let mut chain = Blockchain::new(ChainConfig { initial_difficulty: 8, ..ChainConfig::default() });
+        let template = chain.next_block(10, coinbase(1));
+
+        let mut unmined = template.clone();
+        while unmined.header.meets_difficulty() {
+            unmined.header.nonce += 1;
+            unmined.header.hash = unmined.header.calculate_hash();
+        }
+        assert_eq!(chain.append(unmined), Err(ChainError::InsufficientWork { index: 1 }));
+
+        let easier = solve(Block::new(1, 10, template.header.previous_hash, 4, 0, coinbase(1)));
+        assert_eq!(chain.append(easier), Err(ChainError::InvalidDifficulty { index: 1, expected: 8, found: 4 }));
+
+        assert_eq!(chain.append(solve(template)), Ok(()));
+    }
+
+    #[test]
+    fn difficulty_is_retargeted_by_recent_block_times() {
+        let config = ChainConfig { initial_difficulty: 4, target_block_time: 10, retarget_interval: 4, max_adjustment: 2, ..ChainConfig::default() };
+        let retargeted = |block_time: u64| {
+            let mut chain = Blockchain::new(config.clone());
+            // The first retarget measures blocks 4 to 7, as the genesis block's time is fixed.
+            for index in 1..8 {
+                let block = solve(chain.next_block(index * block_time, Vec::new()));
+                chain.append(block).unwrap();
+            }
+            return chain.next_difficulty();
+        };
+
+        // The difficulty holds while blocks take between the target time and twice it.
+        assert_eq!(retargeted(10), 4);
+        assert_eq!(retargeted(19), 4);
+        // Each halving of the block time adds a bit, and each doubling removes one, up to the limit.
+        assert_eq!(retargeted(5), 5);
+        assert_eq!(retargeted(1), 6);
+        assert_eq!(retargeted(20), 3);
+        assert_eq!(retargeted(1000), 2);
+    }
+
+    #[test]
+    fn verification_finds_tampering_anywhere_in_the_chain() {
+        let chain = chain_of(5);
+
+        let mut blocks = chain.blocks().to_vec();
+        blocks[2].header.timestamp += 1;
+        assert_eq!(Blockchain::from_blocks(no_work(), blocks.clone()).unwrap_err(), ChainError::InvalidHash { index: 2 });
+
+        // Rehashing the forged block breaks the link from the next one instead.
+        blocks[2].header.hash = blocks[2].header.calculate_hash();
+        assert_eq!(Blockchain::from_blocks(no_work(), blocks).unwrap_err(), ChainError::InvalidPreviousHash { index: 3 });
+
+        let mut blocks = chain.blocks().to_vec();
+        blocks[0] = Block::new(0, 1, [0; 32], 0, 0, Vec::new());
+        assert_eq!(Blockchain::from_blocks(no_work(), blocks).unwrap_err(), ChainError::InvalidGenesis);
+
+        // The same blocks do not make a valid chain under rules requiring more work.
+        let demanding = ChainConfig { initial_difficulty: 30, ..ChainConfig::default() };
+        assert_eq!(Blockchain::from_blocks(demanding, chain.blocks().to_vec()).unwrap_err(),
+                   ChainError::InvalidDifficulty { index: 1, expected: 30, found: 0 });
+    }
+
+    #[test]
+    fn chains_reject_blocks_with_invalid_transactions() {
+        let mut chain = Blockchain::new(no_work());
+        let wallet = Wallet::generate().unwrap();
+        chain.append(chain.next_block(10, vec![Transaction::coinbase(wallet.address(), 50, 1)])).unwrap();
+        let spent = vec![OutPoint { txid: chain.latest().transactions[0].txid(), index: 0 }];
+        let payment = wallet.create_transaction(spent, vec![TxOutput { amount: 40, address: wallet.address() }], 10, 0);
+
+        let mut forged = payment.clone();
+        forged.outputs[0].amount = 50;
+        let mut transactions = coinbase(2);
+        transactions.push(forged);
+        assert_eq!(chain.append(chain.next_block(20, transactions)),
+                   Err(ChainError::InvalidTransaction { index: 2, transaction: 1, error: TransactionError::InvalidSignature { input: 0 } }));
+
+        let mut transactions = vec![payment.clone()];
+        transactions.extend(coinbase(2));
+        assert_eq!(chain.append(chain.next_block(20, transactions)),
+                   Err(ChainError::InvalidTransaction { index: 2, transaction: 1, error: TransactionError::MisplacedCoinbase }));
+
+        let mut transactions = coinbase(2);
+        transactions.push(payment);
+        assert_eq!(chain.append(chain.next_block(20, transactions)), Ok(()));
+    }
+
+    #[test]
+    fn chains_track_unspent_outputs_and_reject_double_spends() {
+        let mut chain = Blockchain::new(no_work());
+        let (alice, bob) = (Wallet::generate().unwrap(), Wallet::generate().unwrap());
+        let reward = Transaction::coinbase(alice.address(), 50, 1);
+        chain.append(chain.next_block(10, vec![reward.clone()])).unwrap();
+
+        let spent = vec![OutPoint { txid: reward.txid(), index: 0 }];
+        let payment = alice.create_transaction(spent.clone(), vec![TxOutput { amount: 50, address: bob.address() }], 0, 0);
+        chain.append(chain.next_block(20, vec![payment])).unwrap();
+        assert_eq!(chain.utxos().balance(&alice.address()), 0);
+        assert_eq!(chain.utxos().balance(&bob.address()), 50);
+
+        let respend = alice.create_transaction(spent.clone(), vec![TxOutput { amount: 50, address: alice.address() }], 0, 0);
+        assert_eq!(chain.append(chain.next_block(30, vec![respend.clone()])),
+                   Err(ChainError::InvalidSpend { index: 3, error: UtxoError::MissingOutput { output: spent[0] } }));
+        assert_eq!(chain.len(), 3);
+
+        // Removing the payment makes the coins spendable again, by either transaction.
+        assert_eq!(chain.pop().unwrap().header.index, 2);
+        assert_eq!(chain.utxos().balance(&alice.address()), 50);
+        chain.append(chain.next_block(30, vec![respend])).unwrap();
+        assert_eq!(chain.utxos().snapshot(), UtxoSet::rebuild(50, chain.blocks()).unwrap().snapshot());
+        assert!(chain.verify().is_ok());
+
+        chain.pop();
+        chain.pop();
+        assert_eq!(chain.pop(), None);
+        assert!(chain.utxos().is_empty());
+    }
+
+    #[test]
+    fn headers_are_verified_and_prove_transactions_without_the_blocks() {
+        let mut chain = Blockchain::new(ChainConfig { initial_difficulty: 4, ..ChainConfig::default() });
+        let wallet = Wallet::generate().unwrap();
+        let outputs = (0..3).map(|_| TxOutput { amount: 10, address: wallet.address() }).collect();
+        let reward = Transaction { inputs: Vec::new(), outputs, fee: 0, nonce: 1 };
+        chain.append(solve(chain.next_block(10, vec![reward.clone()]))).unwrap();
+        chain.append(solve(chain.next_block(20, coinbase(2)))).unwrap();
+        let mut transactions = coinbase(3);
+        for index in 0..3 {
+            let spent = vec![OutPoint { txid: reward.txid(), index }];
+            transactions.push(wallet.create_transaction(spent, vec![TxOutput { amount: 10, address: wallet.address() }], 0, 0));
+        }
+        chain.append(solve(chain.next_block(30, transactions))).unwrap();
+
+        let headers: Vec<BlockHeader> = chain.blocks().iter().map(|block| block.header.clone()).collect();
+        assert_eq!(chain.config().verify_headers(&headers), Ok(()));
+
+        // A client holding only the headers can check a transaction is in a block.
+        let block = &chain.blocks()[3];
+        let proof = block.merkle_proof(2).unwrap();
+        assert!(proof.verify(&block.transactions[2].txid(), &headers[3].merkle_root));
+        assert!(!proof.verify(&block.transactions[1].txid(), &headers[3].merkle_root));
+        assert_eq!(block.merkle_proof(4), None);
+
+        let mut forged = headers.clone();
+        forged[2].merkle_root = [9; 32];
+        assert_eq!(chain.config().verify_headers(&forged), Err(ChainError::InvalidHash { index: 2 }));
+        // Even with the work redone, the next header no longer follows it.
+        forged[2].hash = forged[2].calculate_hash();
+        forged[2] = solve(Block { header: forged[2].clone(), transactions: Vec::new() }).header;
+        assert_eq!(chain.config().verify_headers(&forged), Err(ChainError::InvalidPreviousHash { index: 3 }));
+        assert_eq!(no_work().verify_headers(&forged), Err(ChainError::InvalidDifficulty { index: 1, expected: 0, found: 4 }));
+        assert_eq!(chain.config().verify_headers(&headers[1..]), Err(ChainError::InvalidGenesis));
+    }
+}
@@ -0,0 +1,34 @@
+// Explicit returns are the house style.
+#![allow(clippy::needless_return)]
+
+pub mod api;
+pub mod auth;
+pub mod cli;
+pub mod cookie;
+pub mod cors;
+pub mod database;
+pub mod dbpool;
+pub mod eventstream;
+pub mod form;
+pub mod handler;
+pub mod health;
+pub mod kvstore;
+pub mod ledger;
+pub mod limits;
+pub mod logger;
+pub mod mempool;
+pub mod merkle;
+pub mod metrics;
+pub mod middleware;
+pub mod mining;
+pub mod ratelimit;
+pub mod server;
+pub mod servererror;
+pub mod session;
+pub mod sha256;
+pub mod template;
+pub mod tls;
+pub mod transaction;
+pub mod utxo;
+pub mod wallet;
+pub mod websocket;
@@ -0,0 +1,266 @@
+use std::cmp::min;
+use std::io::{Error, ErrorKind, Read};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+// The longest request line accepted by default.
+pub const DEFAULT_MAX_REQUEST_LINE_LENGTH: usize = 8 * 1024;
+// The longest header line accepted by default, including its CRLF.
+pub const DEFAULT_MAX_HEADER_LINE_LENGTH: usize = 8 * 1024;
+// The most header lines accepted by default.
+pub const DEFAULT_MAX_HEADER_COUNT: usize = 100;
+// The largest request body accepted by default.
+pub const DEFAULT_MAX_BODY_LENGTH: usize = 1024 * 1024;
+
+/// Limits applied to each connection, so that slow or idle clients cannot tie up a handler thread
+/// indefinitely.
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    // The time allowed to receive the request line and headers.
+    pub header_read_timeout: Duration,
+    // The time allowed to receive the body, once the headers have been received.
+    pub body_read_timeout: Duration,
+    // The time allowed for any single write to the client.
+    pub write_timeout: Duration,
+    // The longest the client may go without sending anything.
+    pub idle_timeout: Duration,
+    // The slowest average rate at which the client may send the headers or the body, once the
+    // grace period has passed. Zero disables the check.
+    pub min_bytes_per_second: u64,
+    // How long into the headers or the body the minimum rate starts being enforced.
+    pub min_rate_grace_period: Duration,
+    // The longest request line accepted, in bytes.
+    pub max_request_line_length: usize,
+    // The longest header line accepted, in bytes including its CRLF.
+    pub max_header_line_length: usize,
+    // The most header lines accepted. Together with the line length, this bounds the headers'
+    // total size, including repeated headers combined into one.
+    pub max_header_count: usize,
+    // The largest request body accepted, in bytes.
+    pub max_body_length: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> ConnectionLimits {
+        return ConnectionLimits {
+            header_read_timeout: Duration::from_secs(10),
+            body_read_timeout: Duration::from_secs(60),
+            write_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(5),
+            min_bytes_per_second: 100,
+            min_rate_grace_period: Duration::from_secs(5),
+            max_request_line_length: DEFAULT_MAX_REQUEST_LINE_LENGTH,
+            max_header_line_length: DEFAULT_MAX_HEADER_LINE_LENGTH,
+            max_header_count: DEFAULT_MAX_HEADER_COUNT,
+            max_body_length: DEFAULT_MAX_BODY_LENGTH,
+        };
+    }
+}
+
+/// Which part of the request is being read.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ReadPhase {
+    Headers,
+    Body,
+}
+
+/// A reader that enforces the connection's read timeouts and minimum transfer rate. It watches
+/// for the empty line ending the headers, and switches from the header timeout to the body
+/// timeout once it has been seen. Reads that break a limit fail with `ErrorKind::TimedOut`.
+pub struct TimeoutReader<R> {
+    inner: R,
+    // A handle on the underlying socket, used to set its read timeout. For TLS connections,
+    // `inner` reads from the same socket.
+    socket: TcpStream,
+    limits: ConnectionLimits,
+    phase: ReadPhase,
+    phase_start: Instant,
+    // The bytes received in the current phase.
+    phase_bytes: u64,
+    // The last few bytes received, to spot the end of the headers across reads.
+    recent_bytes: [u8; 4],
+}
+
+impl<R: Read> TimeoutReader<R> {
+    pub fn new(inner: R, socket: TcpStream, limits: ConnectionLimits) -> TimeoutReader<R> {
+        return TimeoutReader {
+            inner,
+            socket,
+            limits,
+            phase: ReadPhase::Headers,
+            phase_start: Instant::now(),
+            phase_bytes: 0,
+            recent_bytes: [0; 4],
+        };
+    }
+
+    fn phase_timeout(&self) -> Duration {
+        return match self.phase {
+            ReadPhase::Headers => self.limits.header_read_timeout,
+            ReadPhase::Body => self.limits.body_read_timeout,
+        };
+    }
+
+    /// Tracks the bytes received, switching to the body phase at the end of the headers.
+    fn observe(&mut self, bytes: &[u8]) {
+        for (index, byte) in bytes.iter().enumerate() {
+            if self.phase == ReadPhase::Body {
+                self.phase_bytes += (bytes.len() - index) as u64;
+                return;
+            }
+
+            self.phase_bytes += 1;
+            self.recent_bytes.rotate_left(1);
+            self.recent_bytes[3] = *byte;
+
+            if &self.recent_bytes == b"\r\n\r\n" {
+                self.phase = ReadPhase::Body;
+                self.phase_start = Instant::now();
+                self.phase_bytes = 0;
+            }
+        }
+    }
+
+    /// Fails if, past the grace period, the client is sending more slowly than the minimum rate.
+    fn check_transfer_rate(&self) -> std::io::Result<()> {
+        let elapsed = self.phase_start.elapsed();
+        if self.limits.min_bytes_per_second == 0 || elapsed < self.limits.min_rate_grace_period {
+            return Ok(());
+        }
+
+        let bytes_per_second = self.phase_bytes as f64 / elapsed.as_secs_f64();
+        if bytes_per_second < self.limits.min_bytes_per_second as f64 {
+            return Err(timed_out(&format!("Client sent {:.0} bytes per second, below the minimum of {}.",
+                                          bytes_per_second, self.limits.min_bytes_per_second)));
+        }
+
+        return Ok(());
+    }
+}
+
+impl<R: Read> Read for TimeoutReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let deadline = self.phase_start + self.phase_timeout();
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(timed_out(&format!("Client took longer than {:?} to send the {:?}.", self.phase_timeout(), self.phase)));
+        }
+
+        // We wake up at whichever comes first: the idle timeout or the end of the phase.
+        self.socket.set_read_timeout(Some(min(self.limits.idle_timeout, deadline - now)))?;
+
+        let bytes_read = match self.inner.read(buf) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return Err(timed_out("Client stopped sending data."));
+            }
+            result => result?
+        };
+
+        self.observe(&buf[..bytes_read]);
+        self.check_transfer_rate()?;
+        return Ok(bytes_read);
+    }
+}
+
+fn timed_out(message: &str) -> Error {
+    return Error::new(ErrorKind::TimedOut, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::limits::{ConnectionLimits, TimeoutReader};
+
+    /// Returns a connected client and server socket pair.
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        return (client, server);
+    }
+
+    fn limits() -> ConnectionLimits {
+        return ConnectionLimits {
+            header_read_timeout: Duration::from_millis(300),
+            body_read_timeout: Duration::from_millis(600),
+            idle_timeout: Duration::from_millis(150),
+            min_bytes_per_second: 0,
+            ..ConnectionLimits::default()
+        };
+    }
+
+    fn reader(server: &TcpStream, limits: ConnectionLimits) -> TimeoutReader<TcpStream> {
+        return TimeoutReader::new(server.try_clone().unwrap(), server.try_clone().unwrap(), limits);
+    }
+
+    #[test]
+    fn reader_times_out_idle_clients() {
+        let (_client, server) = socket_pair();
+        let mut reader = reader(&server, limits());
+
+        let result = reader.read(&mut [0; 16]);
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn reader_times_out_slow_headers() {
+        let (mut client, server) = socket_pair();
+        let mut reader = reader(&server, limits());
+        let mut buffer = [0; 16];
+
+        // Each byte arrives within the idle timeout, but the headers never finish.
+        let mut result = Ok(0);
+        for _ in 0..10 {
+            client.write_all(b"G").unwrap();
+            result = reader.read(&mut buffer);
+            if result.is_err() {
+                break;
+            }
+            sleep(Duration::from_millis(100));
+        }
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn reader_switches_to_body_timeout_after_headers() {
+        let (mut client, server) = socket_pair();
+        let mut reader = reader(&server, limits());
+        let mut buffer = [0; 64];
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(reader.read(&mut buffer).unwrap() > 0);
+
+        // We're past the header timeout, but within the body timeout.
+        for _ in 0..4 {
+            sleep(Duration::from_millis(100));
+            client.write_all(b"B").unwrap();
+            assert!(reader.read(&mut buffer).unwrap() > 0);
+        }
+    }
+
+    #[test]
+    fn reader_enforces_minimum_transfer_rate() {
+        let (mut client, server) = socket_pair();
+        let limits = ConnectionLimits {
+            min_bytes_per_second: 1000,
+            min_rate_grace_period: Duration::from_millis(100),
+            ..limits()
+        };
+        let mut reader = reader(&server, limits);
+        let mut buffer = [0; 16];
+
+        client.write_all(b"G").unwrap();
+        assert!(reader.read(&mut buffer).unwrap() > 0);
+        sleep(Duration::from_millis(120));
+        client.write_all(b"E").unwrap();
+        let result = reader.read(&mut buffer);
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+}
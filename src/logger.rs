@@ -0,0 +1,522 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{stderr, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::handler::{ConnectionInfo, HttpRequest, WrittenResponse};
+use crate::servererror::{Result, ServerError};
+
+// Used by `global` until `init` is called.
+static GLOBAL_LOGGER: OnceLock<Logger> = OnceLock::new();
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Installs the process-wide logger. Can only be called once, before the first call to `global`.
+pub fn init(logger: Logger) -> Result<()> {
+    return GLOBAL_LOGGER.set(logger)
+        .map_err(|_| ServerError::new("The global logger is already initialised.".into()));
+}
+
+/// Returns the process-wide logger. Defaults to logging at `Info` to stderr in Common Log Format.
+pub fn global() -> &'static Logger {
+    return GLOBAL_LOGGER.get_or_init(|| Logger::new(LogLevel::Info, LogFormat::Common, LogOutput::Stderr).unwrap());
+}
+
+/// The severity of a log line. Each level includes the levels above it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    /// Parses a level name, ignoring case.
+    pub fn parse(name: &str) -> Result<LogLevel> {
+        return match name.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(ServerError::new(format!("Unknown log level: {}", name)))
+        };
+    }
+
+    fn name(&self) -> &'static str {
+        return match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        };
+    }
+}
+
+/// The format log lines are written in. Access logs follow the Apache Common or Combined Log
+/// Formats in the text formats, which do not include latency; JSON lines include every field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Common,
+    Combined,
+    Json,
+}
+
+impl LogFormat {
+    /// Parses a format name, ignoring case.
+    pub fn parse(name: &str) -> Result<LogFormat> {
+        return match name.to_lowercase().as_str() {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(ServerError::new(format!("Unknown log format: {}", name)))
+        };
+    }
+}
+
+/// Where log lines are written.
+pub enum LogOutput {
+    Stderr,
+    // A file that is rotated once it reaches `max_bytes`, keeping up to `max_files` old files
+    // alongside it as `<path>.1` (newest) to `<path>.<max_files>` (oldest).
+    RotatingFile { path: PathBuf, max_bytes: u64, max_files: usize },
+}
+
+/// A single request, as recorded in the access log.
+pub struct AccessLogEntry<'a> {
+    pub(crate) connection: &'a ConnectionInfo,
+    pub(crate) request: &'a HttpRequest,
+    pub(crate) response: &'a WrittenResponse,
+    pub(crate) latency: Duration,
+    pub(crate) time: SystemTime,
+}
+
+impl<'a> AccessLogEntry<'a> {
+    pub fn new(connection: &'a ConnectionInfo, request: &'a HttpRequest, response: &'a WrittenResponse, latency: Duration) -> AccessLogEntry<'a> {
+        return AccessLogEntry { connection, request, response, latency, time: SystemTime::now() };
+    }
+}
+
+/// Writes access and error logs.
+pub struct Logger {
+    // Lines below this level are discarded.
+    level: LogLevel,
+    format: LogFormat,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Logger {
+    pub fn new(level: LogLevel, format: LogFormat, output: LogOutput) -> Result<Logger> {
+        let writer: Box<dyn Write + Send> = match output {
+            LogOutput::Stderr => Box::new(stderr()),
+            LogOutput::RotatingFile { path, max_bytes, max_files } => Box::new(RotatingFile::open(path, max_bytes, max_files)?)
+        };
+
+        return Ok(Logger::with_writer(level, format, writer));
+    }
+
+    /// Creates a logger that writes to an arbitrary writer.
+    pub fn with_writer(level: LogLevel, format: LogFormat, writer: Box<dyn Write + Send>) -> Logger {
+        return Logger { level, format, output: Mutex::new(writer) };
+    }
+
+    /// Records a handled request, at the `Info` level.
+    pub fn access(&self, entry: &AccessLogEntry) {
+        if self.level < LogLevel::Info {
+            return;
+        }
+
+        let line = match self.format {
+            LogFormat::Common => format_common(entry),
+            LogFormat::Combined => format_combined(entry),
+            LogFormat::Json => format_access_json(entry),
+        };
+        self.write_line(&line);
+    }
+
+    /// Records an error, including the chain of errors that caused it.
+    pub fn error(&self, error: &ServerError) {
+        self.log(LogLevel::Error, &error.chain());
+    }
+
+    pub fn warn(&self, message: &str) {
+        self.log(LogLevel::Warn, &[message]);
+    }
+
+    pub fn info(&self, message: &str) {
+        self.log(LogLevel::Info, &[message]);
+    }
+
+    pub fn debug(&self, message: &str) {
+        self.log(LogLevel::Debug, &[message]);
+    }
+
+    /// Writes a line with the given message and causes, if the level is enabled.
+    fn log(&self, level: LogLevel, messages: &[&str]) {
+        if self.level < level {
+            return;
+        }
+
+        let time = SystemTime::now();
+        let line = match self.format {
+            LogFormat::Common | LogFormat::Combined => {
+                format!("[{}] [{}] {}", format_clf_time(time), level.name(), messages.join(": "))
+            }
+            LogFormat::Json => {
+                let causes: Vec<String> = messages[1..].iter().map(|cause| json_string(cause)).collect();
+                format!("{{\"time\":{},\"level\":{},\"message\":{},\"causes\":[{}]}}",
+                        json_string(&format_iso_time(time)), json_string(level.name()), json_string(messages[0]), causes.join(","))
+            }
+        };
+        self.write_line(&line);
+    }
+
+    /// Writes a line to the output. Failures are ignored, as there is nowhere left to report them.
+    fn write_line(&self, line: &str) {
+        if let Ok(mut output) = self.output.lock() {
+            let _ = output.write_all(format!("{}\n", line).as_bytes());
+            let _ = output.flush();
+        }
+    }
+}
+
+/// Formats an entry in the Common Log Format, e.g.
+/// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 2326`.
+fn format_common(entry: &AccessLogEntry) -> String {
+    // A body size of zero is written as "-".
+    let body_bytes = match entry.response.body_bytes {
+        0 => "-".into(),
+        body_bytes => body_bytes.to_string()
+    };
+
+    // The authenticated user, if any, is written in place of the "authuser" field.
+    let user = entry.request.principal().map(|principal| principal.name.replace(char::is_whitespace, "_"));
+
+    return format!("{} - {} [{}] \"{} {} {}\" {} {}",
+                   entry.connection.peer_ip_string(), user.as_deref().unwrap_or("-"), format_clf_time(entry.time),
+                   clf_escape(&entry.request.method), clf_escape(&entry.request.request_uri), clf_escape(&entry.request.http_version),
+                   entry.response.status, body_bytes);
+}
+
+/// Formats an entry in the Combined Log Format, which extends the Common Log Format with the
+/// referer and user agent.
+fn format_combined(entry: &AccessLogEntry) -> String {
+    let referer = entry.request.header("Referer").unwrap_or("-");
+    let user_agent = entry.request.header("User-Agent").unwrap_or("-");
+
+    return format!("{} \"{}\" \"{}\"", format_common(entry), clf_escape(referer), clf_escape(user_agent));
+}
+
+/// Formats an entry as a single-line JSON object.
+fn format_access_json(entry: &AccessLogEntry) -> String {
+    let optional_string = |value: Option<&str>| value.map(json_string).unwrap_or_else(|| "null".into());
+
+    return format!("{{\"time\":{},\"remote_address\":{},\"user\":{},\"method\":{},\"path\":{},\"http_version\":{},\
+                    \"status\":{},\"bytes\":{},\"latency_ms\":{:.3},\"referer\":{},\"user_agent\":{}}}",
+                   json_string(&format_iso_time(entry.time)), json_string(&entry.connection.peer_ip_string()),
+                   optional_string(entry.request.principal().map(|principal| principal.name.as_str())),
+                   json_string(&entry.request.method), json_string(&entry.request.request_uri),
+                   json_string(&entry.request.http_version), entry.response.status, entry.response.body_bytes,
+                   entry.latency.as_secs_f64() * 1000.0, optional_string(entry.request.header("Referer")),
+                   optional_string(entry.request.header("User-Agent")));
+}
+
+/// Escapes double-quotes, backslashes and control characters in a quoted log field, so that a
+/// client cannot end the field or the line early.
+fn clf_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+/// Formats a string as a JSON string literal, including the surrounding quotes.
+pub(crate) fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+    return escaped;
+}
+
+/// Formats a time as in the Common Log Format, e.g. `10/Oct/2000:13:55:36 +0000`. Times are
+/// always in UTC.
+fn format_clf_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc_components(time);
+    return format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000", day, MONTHS[month as usize - 1], year, hour, minute, second);
+}
+
+/// Formats a time in ISO 8601, e.g. `2000-10-10T13:55:36Z`.
+fn format_iso_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc_components(time);
+    return format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second);
+}
+
+/// Splits a time into its UTC year, month, day, hour, minute and second.
+pub(crate) fn utc_components(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0) as i64;
+    let days = seconds.div_euclid(86400);
+    let seconds_of_day = seconds.rem_euclid(86400);
+
+    // Converts days since the epoch to a civil date. See
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return (year, month, day, (seconds_of_day / 3600) as u32, (seconds_of_day % 3600 / 60) as u32, (seconds_of_day % 60) as u32);
+}
+
+/// A log file that is rotated once it reaches a maximum size.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    // The size of the current file.
+    current_bytes: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let current_bytes = file.metadata()?.len();
+        return Ok(RotatingFile { path, max_bytes, max_files, file, current_bytes });
+    }
+
+    /// Shifts each old file along by one, discarding the oldest, and starts a new file.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+
+        self.current_bytes = 0;
+        return Ok(());
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.current_bytes > 0 && self.current_bytes + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        let bytes_written = self.file.write(buf)?;
+        self.current_bytes += bytes_written as u64;
+        return Ok(bytes_written);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.file.flush();
+    }
+}
+
+/// Returns the path of the rotated file with the given index, e.g. `access.log.1`.
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    return PathBuf::from(rotated);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env::temp_dir;
+    use std::fs;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::auth::Principal;
+    use crate::handler::{ConnectionInfo, HttpRequest, WrittenResponse};
+    use crate::logger::{AccessLogEntry, LogFormat, LogLevel, Logger, RotatingFile, rotated_path, utc_components};
+    use crate::servererror::ServerError;
+
+    /// A writer whose contents can be inspected after being handed to a logger.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            return self.0.lock().unwrap().write(buf);
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            return String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+        }
+    }
+
+    fn log_access(format: LogFormat, level: LogLevel, principal: Option<&str>) -> String {
+        return log_access_to(format, level, principal, "/index");
+    }
+
+    fn log_access_to(format: LogFormat, level: LogLevel, principal: Option<&str>, request_uri: &str) -> String {
+        let buffer = SharedBuffer::default();
+        let logger = Logger::with_writer(level, format, Box::new(buffer.clone()));
+
+        let connection = ConnectionInfo { peer_address: Some("127.0.0.1:54321".parse().unwrap()), ..ConnectionInfo::default() };
+        let mut headers = HashMap::new();
+        headers.insert("user-agent".into(), "curl/7.0 \"test\"".into());
+        let mut request = HttpRequest::new("GET", request_uri, "HTTP/1.1", headers);
+        request.principal = principal.map(Principal::new);
+        let response = WrittenResponse { status: 200, body_bytes: 2326 };
+        let mut entry = AccessLogEntry::new(&connection, &request, &response, Duration::from_micros(1500));
+        entry.time = UNIX_EPOCH + Duration::from_secs(971186136);
+
+        logger.access(&entry);
+        return buffer.contents();
+    }
+
+    #[test]
+    fn access_logs_use_common_log_format() {
+        let line = log_access(LogFormat::Common, LogLevel::Info, None);
+
+        assert_eq!(line, "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /index HTTP/1.1\" 200 2326\n");
+    }
+
+    #[test]
+    fn access_logs_include_authenticated_user() {
+        let line = log_access(LogFormat::Common, LogLevel::Info, Some("alice"));
+
+        assert_eq!(line, "127.0.0.1 - alice [10/Oct/2000:13:55:36 +0000] \"GET /index HTTP/1.1\" 200 2326\n");
+    }
+
+    #[test]
+    fn access_logs_use_combined_log_format() {
+        let line = log_access(LogFormat::Combined, LogLevel::Info, None);
+
+        assert_eq!(line, "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /index HTTP/1.1\" 200 2326 \
+            \"-\" \"curl/7.0 \\\"test\\\"\"\n");
+    }
+
+    #[test]
+    fn access_logs_use_json_format() {
+        let line = log_access(LogFormat::Json, LogLevel::Info, None);
+
+        assert_eq!(line, "{\"time\":\"2000-10-10T13:55:36Z\",\"remote_address\":\"127.0.0.1\",\"user\":null,\"method\":\"GET\",\
+            \"path\":\"/index\",\"http_version\":\"HTTP/1.1\",\"status\":200,\"bytes\":2326,\"latency_ms\":1.500,\
+            \"referer\":null,\"user_agent\":\"curl/7.0 \\\"test\\\"\"}\n");
+    }
+
+    #[test]
+    fn access_logs_escape_request_lines() {
+        let request_uri = "/a HTTP/1.1\" 200 1\n127.0.0.1 - admin [forged] \"GET /\u{7}";
+
+        for format in [LogFormat::Common, LogFormat::Combined, LogFormat::Json].iter() {
+            let line = log_access_to(*format, LogLevel::Info, None, request_uri);
+
+            assert_eq!(line.matches('\n').count(), 1, "{}", line);
+            assert!(line.ends_with('\n'));
+        }
+        let line = log_access_to(LogFormat::Common, LogLevel::Info, None, request_uri);
+        assert_eq!(line, "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /a HTTP/1.1\\\" 200 1\\n127.0.0.1 - admin [forged] \
+            \\\"GET /\\x07 HTTP/1.1\" 200 2326\n");
+    }
+
+    #[test]
+    fn access_logs_are_suppressed_below_info() {
+        assert_eq!(log_access(LogFormat::Common, LogLevel::Warn, None), "");
+    }
+
+    #[test]
+    fn error_logs_include_the_error_chain() {
+        let buffer = SharedBuffer::default();
+        let text_logger = Logger::with_writer(LogLevel::Error, LogFormat::Common, Box::new(buffer.clone()));
+        let json_buffer = SharedBuffer::default();
+        let json_logger = Logger::with_writer(LogLevel::Error, LogFormat::Json, Box::new(json_buffer.clone()));
+
+        let error = ServerError::with_cause("Failed to handle connection".into(), ServerError::new("Broken pipe".into()));
+        text_logger.error(&error);
+        json_logger.error(&error);
+
+        assert!(buffer.contents().ends_with("] [error] Failed to handle connection: Broken pipe\n"));
+        assert!(json_buffer.contents().ends_with(",\"level\":\"error\",\"message\":\"Failed to handle connection\",\"causes\":[\"Broken pipe\"]}\n"));
+    }
+
+    #[test]
+    fn logs_below_the_configured_level_are_discarded() {
+        let buffer = SharedBuffer::default();
+        let logger = Logger::with_writer(LogLevel::Warn, LogFormat::Common, Box::new(buffer.clone()));
+
+        logger.debug("debug");
+        logger.info("info");
+        logger.warn("warn");
+        logger.error(&ServerError::new("error".into()));
+
+        let contents = buffer.contents();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("[warn] warn"));
+        assert!(lines[1].ends_with("[error] error"));
+    }
+
+    #[test]
+    fn utc_components_handle_leap_years() {
+        assert_eq!(utc_components(UNIX_EPOCH), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(utc_components(UNIX_EPOCH + Duration::from_secs(951782400)), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(utc_components(UNIX_EPOCH + Duration::from_secs(1709251199)), (2024, 2, 29, 23, 59, 59));
+    }
+
+    #[test]
+    fn rotating_file_rotates_at_max_size() {
+        let path = temp_dir().join(format!("blockchain-test-{}-rotation.log", std::process::id()));
+        for index in 0..4 {
+            let _ = fs::remove_file(if index == 0 { path.clone() } else { rotated_path(&path, index) });
+        }
+
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["line one\n", "line two\n", "line three\n", "line four\n"].iter() {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line four\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 1)).unwrap(), "line three\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 2)).unwrap(), "line two\n");
+        assert!(!rotated_path(&path, 3).exists());
+    }
+}
@@ -1,49 +1,566 @@
+// Explicit returns are the house style.
+#![allow(clippy::needless_return)]
+
 use std::collections::HashMap;
+use std::env;
 use std::io::{BufRead, stdin};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
 
-use crate::server::Server;
-use crate::servererror::Result;
-
-mod handler;
-mod server;
-mod servererror;
+use blockchain::api::ApiRoutes;
+use blockchain::auth::{AuthLayer, Authenticator, BasicAuthenticator, SignedTokenAuthenticator, TokenAuthenticator, DEFAULT_PASSWORD_HASH_ITERATIONS, hash_password, token_route};
+use blockchain::cli::{env_opt, env_or, wait_for_shutdown_request};
+use blockchain::cookie::{SameSite, SetCookie};
+use blockchain::cors::{Cors, CorsConfig, OriginPattern};
+use blockchain::database::{kv_route, Database, LocalDatabase};
+use blockchain::dbpool::{DbPool, DbPoolConfig};
+use blockchain::eventstream::{publish_route, EventHub, EventStreamLimits, EventStreamRoutes};
+use blockchain::form::{FormLimits, upload_route};
+use blockchain::health::{LIVENESS_PATH, READINESS_PATH};
+use blockchain::limits::ConnectionLimits;
+use blockchain::middleware::{DefaultHeaders, MiddlewareChain};
+use blockchain::ratelimit::{ClientLimits, RateLimit, RateLimitLayer};
+use blockchain::server::{Server, ServerOptions};
+use blockchain::logger::{self, LogFormat, LogLevel, LogOutput, Logger};
+use blockchain::servererror::{Result, ServerError};
+use blockchain::session::{SessionAuthenticator, SessionLayer, session_route};
+use blockchain::template::{self, Templates, TEMPLATE_DIR};
+use blockchain::tls::{CertificatePaths, TlsConfig};
+use blockchain::wallet::Wallet;
+use blockchain::websocket::{WebSocketHub, WebSocketLimits, WebSocketRoutes};
 
-// The port the server listens on.
+// The port the server listens on, unless another is given with `-p`.
 const PORT: &str = "10005";
-// The string the server uses to connect to its database.
-// TODO: Update to meaningful DB connection string.
-const DB_CONNECTION_STRING: &str = "www.google.com:80";
+// The port the admin listener, which serves metrics, listens on.
+const ADMIN_PORT: &str = "10007";
+// The environment variable giving the path metrics are served on, and its default.
+const METRICS_PATH_VAR: &str = "METRICS_PATH";
+const DEFAULT_METRICS_PATH: &str = "/metrics";
+// The port that redirects plain HTTP to HTTPS, when TLS is enabled.
+const REDIRECT_PORT: &str = "10006";
+// The environment variable giving the address of the database server. If it is unset, data is
+// kept in memory and lost when the server stops.
+const DB_ADDRESS_VAR: &str = "DB_ADDRESS";
+// The environment variables overriding the fewest and most connections kept to the database.
+const DB_POOL_MIN_SIZE_VAR: &str = "DB_POOL_MIN_SIZE";
+const DB_POOL_MAX_SIZE_VAR: &str = "DB_POOL_MAX_SIZE";
+// The environment variables that enable TLS, if both are set.
+const TLS_CERT_CHAIN_PATH_VAR: &str = "TLS_CERT_CHAIN_PATH";
+const TLS_PRIVATE_KEY_PATH_VAR: &str = "TLS_PRIVATE_KEY_PATH";
+// The environment variable listing additional certificates to select via SNI, in the form
+// `server_name=cert_chain_path,private_key_path;...`.
+const TLS_SNI_CERTIFICATES_VAR: &str = "TLS_SNI_CERTIFICATES";
+// The environment variable giving the HTTPS authority that plain HTTP requests are redirected to.
+const TLS_REDIRECT_AUTHORITY_VAR: &str = "TLS_REDIRECT_AUTHORITY";
+// The environment variables that configure logging. See the README for their meanings.
+const LOG_LEVEL_VAR: &str = "LOG_LEVEL";
+const LOG_FORMAT_VAR: &str = "LOG_FORMAT";
+const LOG_FILE_VAR: &str = "LOG_FILE";
+const LOG_FILE_MAX_BYTES_VAR: &str = "LOG_FILE_MAX_BYTES";
+const LOG_FILE_MAX_COUNT_VAR: &str = "LOG_FILE_MAX_COUNT";
+// The defaults used for log file rotation.
+const DEFAULT_LOG_FILE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_LOG_FILE_MAX_COUNT: usize = 5;
+// The environment variables that override the default connection limits. Timeouts are in seconds.
+const HEADER_READ_TIMEOUT_VAR: &str = "HEADER_READ_TIMEOUT";
+const BODY_READ_TIMEOUT_VAR: &str = "BODY_READ_TIMEOUT";
+const WRITE_TIMEOUT_VAR: &str = "WRITE_TIMEOUT";
+const IDLE_TIMEOUT_VAR: &str = "IDLE_TIMEOUT";
+const MIN_BYTES_PER_SECOND_VAR: &str = "MIN_BYTES_PER_SECOND";
+const MAX_REQUEST_LINE_LENGTH_VAR: &str = "MAX_REQUEST_LINE_LENGTH";
+const MAX_HEADER_LINE_LENGTH_VAR: &str = "MAX_HEADER_LINE_LENGTH";
+const MAX_HEADER_COUNT_VAR: &str = "MAX_HEADER_COUNT";
+const MAX_BODY_LENGTH_VAR: &str = "MAX_BODY_LENGTH";
+// The environment variables that enable per-client rate limiting. Clients may open connections at
+// `RATE_LIMIT_PER_SECOND`, with bursts of up to `RATE_LIMIT_BURST`. If `RATE_LIMIT_API_KEY_HEADER`
+// is set, requests carrying a key from `AUTH_TOKENS_FILE` in that header are also limited per
+// principal.
+const RATE_LIMIT_PER_SECOND_VAR: &str = "RATE_LIMIT_PER_SECOND";
+const RATE_LIMIT_BURST_VAR: &str = "RATE_LIMIT_BURST";
+const RATE_LIMIT_API_KEY_HEADER_VAR: &str = "RATE_LIMIT_API_KEY_HEADER";
+// The environment variable capping each client's concurrent connections.
+const MAX_CONNECTIONS_PER_IP_VAR: &str = "MAX_CONNECTIONS_PER_IP";
+// The environment variable listing client IPs exempt from limits, separated by commas.
+const RATE_LIMIT_ALLOWLIST_VAR: &str = "RATE_LIMIT_ALLOWLIST";
+// The burst allowed if only a rate is given.
+const DEFAULT_RATE_LIMIT_BURST: u32 = 20;
+// The environment variables that enable CORS, if `CORS_ALLOWED_ORIGINS` is set. Origins, methods
+// and headers are separated by commas. Origins may be exact, `*`, or contain a single `*`
+// wildcard, e.g. `https://*.example.com`.
+const CORS_ALLOWED_ORIGINS_VAR: &str = "CORS_ALLOWED_ORIGINS";
+const CORS_ALLOWED_METHODS_VAR: &str = "CORS_ALLOWED_METHODS";
+const CORS_ALLOWED_HEADERS_VAR: &str = "CORS_ALLOWED_HEADERS";
+const CORS_ALLOW_CREDENTIALS_VAR: &str = "CORS_ALLOW_CREDENTIALS";
+const CORS_MAX_AGE_VAR: &str = "CORS_MAX_AGE";
+// The environment variable listing the routes that require authentication, separated by commas.
+const AUTH_ROUTES_VAR: &str = "AUTH_ROUTES";
+// The environment variables configuring how clients authenticate. Any combination may be set.
+const AUTH_CREDENTIALS_FILE_VAR: &str = "AUTH_CREDENTIALS_FILE";
+const AUTH_TOKENS_FILE_VAR: &str = "AUTH_TOKENS_FILE";
+const AUTH_SIGNING_KEY_VAR: &str = "AUTH_SIGNING_KEY";
+// The environment variable listing the only principals allowed through, separated by commas.
+const AUTH_ALLOWED_PRINCIPALS_VAR: &str = "AUTH_ALLOWED_PRINCIPALS";
+// The realm sent in authentication challenges.
+const AUTH_REALM: &str = "blockchain";
+// The path of the API route that issues signed tokens, if AUTH_SIGNING_KEY is set, and the
+// longest a token it issues can last.
+const TOKENS_PATH: &str = "/api/tokens";
+const MAX_TOKEN_TIME_TO_LIVE: Duration = Duration::from_secs(24 * 60 * 60);
+// The environment variable that enables the upload route, naming the directory uploads are
+// stored in, and the one overriding the largest file it accepts, in bytes.
+const UPLOAD_DIR_VAR: &str = "UPLOAD_DIR";
+const MAX_UPLOAD_SIZE_VAR: &str = "MAX_UPLOAD_SIZE";
+// The path of the upload route.
+const UPLOADS_PATH: &str = "/api/uploads";
+// The environment variable that enables sessions, giving the seconds a session lasts while idle,
+// and those that override the session cookie's SameSite attribute and set its Domain.
+const SESSION_IDLE_TIMEOUT_VAR: &str = "SESSION_IDLE_TIMEOUT";
+const SESSION_COOKIE_SAME_SITE_VAR: &str = "SESSION_COOKIE_SAME_SITE";
+const SESSION_COOKIE_DOMAIN_VAR: &str = "SESSION_COOKIE_DOMAIN";
+const SESSION_COOKIE_NAME: &str = "session";
+// The most sessions kept in memory at once.
+const MAX_SESSIONS: usize = 100_000;
+// The path of the API route that logs in to and out of sessions, if sessions are enabled.
+const SESSION_PATH: &str = "/api/session";
+// The environment variable that enables the WebSocket relay, giving its path, and those that
+// override the largest message it accepts, in bytes, and the seconds between pings.
+const WEBSOCKET_RELAY_PATH_VAR: &str = "WEBSOCKET_RELAY_PATH";
+const WEBSOCKET_MAX_MESSAGE_SIZE_VAR: &str = "WEBSOCKET_MAX_MESSAGE_SIZE";
+const WEBSOCKET_PING_INTERVAL_VAR: &str = "WEBSOCKET_PING_INTERVAL";
+// The most clients connected to the WebSocket relay at once.
+const MAX_WEBSOCKET_RELAY_CLIENTS: usize = 10_000;
+// The environment variable that enables the event stream, giving its path, and the one overriding
+// the seconds clients wait before reconnecting.
+const EVENT_STREAM_PATH_VAR: &str = "EVENT_STREAM_PATH";
+const EVENT_STREAM_RETRY_VAR: &str = "EVENT_STREAM_RETRY";
+// How many recent events are kept for clients that reconnect, and the most clients subscribed at
+// once.
+const EVENT_HISTORY_SIZE: usize = 1000;
+const MAX_EVENT_STREAM_SUBSCRIBERS: usize = 10_000;
+// The path of the API route that publishes events to the event stream, if it is enabled.
+const EVENTS_PATH: &str = "/api/events";
+// The environment variable that enables the route listing, storing and deleting the database's
+// entries, if `true`, and the route's path.
+const KV_ROUTE_ENABLED_VAR: &str = "KV_ROUTE_ENABLED";
+const KV_PATH: &str = "/api/kv";
+// The header clients may send static tokens in, as an alternative to bearer tokens.
+const API_KEY_HEADER: &str = "X-API-Key";
+// How long the server keeps serving, while failing readiness checks, before it stops listening.
+const SHUTDOWN_DRAIN_PERIOD: Duration = Duration::from_secs(5);
+// How long the server waits for in-flight connections to finish when shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+// How often the TLS certificates are re-read from disk.
+const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
 
-/// Starts a TCP server that listens for incoming packets until the user exits the program.
+/// Starts a TCP server that listens for incoming packets until the user exits the program. Serves
+/// HTTPS if a certificate chain and private key are provided via the environment.
 pub fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let port = match server_port(&args) {
+        Some(port) => port,
+        None => return run_command(&args)
+    };
+
+    logger::init(prepare_logger()?)?;
+    // Compiles the templates up front, so that broken templates stop the server starting.
+    template::init(Templates::load(TEMPLATE_DIR)?)?;
     let routes = prepare_routes();
-    let mut server_handle = Server::start(PORT, DB_CONNECTION_STRING, routes)?;
 
-    loop_until_exit_requested(stdin().lock())?;
-    server_handle.stop_listening()?;
+    // Listeners other than the main server.
+    let mut auxiliary_server_handles = Vec::new();
+
+    let tls_config = prepare_tls_config()?;
+    if let Some(tls_config) = &tls_config {
+        spawn_tls_reloader(tls_config.clone());
+
+        if let Ok(https_authority) = env::var(TLS_REDIRECT_AUTHORITY_VAR) {
+            auxiliary_server_handles.push(Server::start_redirect(REDIRECT_PORT, &https_authority)?);
+        }
+    }
+
+    let client_limits = prepare_client_limits()?;
+    let middleware = prepare_middleware(&client_limits, tls_config.is_some())?;
+    let (event_stream_routes, event_hub) = prepare_event_stream_routes()?;
+    let database = prepare_database()?;
+    let options = ServerOptions {
+        tls_config,
+        limits: prepare_connection_limits()?,
+        middleware,
+        api_routes: prepare_api_routes(database.clone(), event_hub)?,
+        websocket_routes: prepare_websocket_routes()?,
+        event_stream_routes,
+        client_limits
+    };
+    let mut main_server_handle = Server::start_with_options(&port, database, routes, options)?;
+
+    let metrics_path = env::var(METRICS_PATH_VAR).unwrap_or_else(|_| DEFAULT_METRICS_PATH.into());
+    auxiliary_server_handles.push(Server::start_admin(ADMIN_PORT, &metrics_path)?);
+
+    logger::global().info(&format!("Listening on port {}, with metrics on port {}.", port, ADMIN_PORT));
+    wait_for_shutdown_request()?;
+
+    // We shut the main server down gracefully, then stop the others.
+    logger::global().info("Shutting down.");
+    main_server_handle.shutdown(SHUTDOWN_DRAIN_PERIOD, SHUTDOWN_TIMEOUT)?;
+    for server_handle in auxiliary_server_handles.iter_mut() {
+        server_handle.stop_listening()?;
+    }
 
     return Ok(());
 }
 
-/// Returns the routes that the server will serve.
-fn prepare_routes() -> HashMap<String, String> {
-    let mut routes = HashMap::new();
-    routes.insert("/".into(), "./src/html/hello_world.html".into());
-    return routes;
+/// Returns the database described by the environment.
+fn prepare_database() -> Result<Arc<dyn Database>> {
+    return match env::var(DB_ADDRESS_VAR) {
+        Ok(db_address) => {
+            let defaults = DbPoolConfig::default();
+            let config = DbPoolConfig {
+                min_size: env_or(DB_POOL_MIN_SIZE_VAR, defaults.min_size)?,
+                max_size: env_or(DB_POOL_MAX_SIZE_VAR, defaults.max_size)?,
+                ..defaults
+            };
+            Ok(DbPool::start(&db_address, config))
+        }
+        Err(_) => {
+            logger::global().warn("No DB_ADDRESS is set, so data is kept in memory and lost when the server stops.");
+            Ok(Arc::new(LocalDatabase::new()))
+        }
+    };
+}
+
+/// Returns the logger described by the environment. Defaults to logging at `info` to stderr in
+/// Common Log Format.
+fn prepare_logger() -> Result<Logger> {
+    let level = match env::var(LOG_LEVEL_VAR) {
+        Ok(level) => LogLevel::parse(&level)?,
+        Err(_) => LogLevel::Info
+    };
+    let format = match env::var(LOG_FORMAT_VAR) {
+        Ok(format) => LogFormat::parse(&format)?,
+        Err(_) => LogFormat::Common
+    };
+    let output = match env::var(LOG_FILE_VAR) {
+        Err(_) => LogOutput::Stderr,
+        Ok(path) => {
+            let max_bytes = env_or(LOG_FILE_MAX_BYTES_VAR, DEFAULT_LOG_FILE_MAX_BYTES)?;
+            let max_files = env_or(LOG_FILE_MAX_COUNT_VAR, DEFAULT_LOG_FILE_MAX_COUNT)?;
+            LogOutput::RotatingFile { path: PathBuf::from(path), max_bytes, max_files }
+        }
+    };
+
+    return Logger::new(level, format, output);
+}
+
+/// Returns the connection limits described by the environment, falling back to the defaults.
+fn prepare_connection_limits() -> Result<ConnectionLimits> {
+    let defaults = ConnectionLimits::default();
+
+    return Ok(ConnectionLimits {
+        header_read_timeout: Duration::from_secs(env_or(HEADER_READ_TIMEOUT_VAR, defaults.header_read_timeout.as_secs())?),
+        body_read_timeout: Duration::from_secs(env_or(BODY_READ_TIMEOUT_VAR, defaults.body_read_timeout.as_secs())?),
+        write_timeout: Duration::from_secs(env_or(WRITE_TIMEOUT_VAR, defaults.write_timeout.as_secs())?),
+        idle_timeout: Duration::from_secs(env_or(IDLE_TIMEOUT_VAR, defaults.idle_timeout.as_secs())?),
+        min_bytes_per_second: env_or(MIN_BYTES_PER_SECOND_VAR, defaults.min_bytes_per_second)?,
+        max_request_line_length: env_or(MAX_REQUEST_LINE_LENGTH_VAR, defaults.max_request_line_length)?,
+        max_header_line_length: env_or(MAX_HEADER_LINE_LENGTH_VAR, defaults.max_header_line_length)?,
+        max_header_count: env_or(MAX_HEADER_COUNT_VAR, defaults.max_header_count)?,
+        max_body_length: env_or(MAX_BODY_LENGTH_VAR, defaults.max_body_length)?,
+        ..defaults
+    });
+}
+
+/// Returns the per-client limits described by the environment. By default, clients are unlimited.
+fn prepare_client_limits() -> Result<ClientLimits> {
+    let mut allowlist = Vec::new();
+    for ip in split_list(&env::var(RATE_LIMIT_ALLOWLIST_VAR).unwrap_or_default()) {
+        allowlist.push(ip.parse().map_err(|_| ServerError::new(format!("Invalid IP in {}: {}", RATE_LIMIT_ALLOWLIST_VAR, ip)))?);
+    }
+
+    return Ok(ClientLimits {
+        connection_rate: prepare_rate_limit()?,
+        max_connections_per_ip: env_opt(MAX_CONNECTIONS_PER_IP_VAR)?,
+        allowlist,
+        ..ClientLimits::default()
+    });
+}
+
+/// Returns the rate limit described by the environment, if any.
+fn prepare_rate_limit() -> Result<Option<RateLimit>> {
+    let per_second: f64 = match env_opt(RATE_LIMIT_PER_SECOND_VAR)? {
+        None => return Ok(None),
+        Some(per_second) => per_second
+    };
+    if per_second <= 0.0 {
+        return Err(ServerError::new(format!("{} must be positive.", RATE_LIMIT_PER_SECOND_VAR)));
+    }
+    return Ok(Some(RateLimit { per_second, burst: env_or(RATE_LIMIT_BURST_VAR, DEFAULT_RATE_LIMIT_BURST)? }));
+}
+
+/// Returns the CORS config described by the environment, if any.
+fn prepare_cors_config() -> Result<Option<CorsConfig>> {
+    let allowed_origins = match env::var(CORS_ALLOWED_ORIGINS_VAR) {
+        Err(_) => return Ok(None),
+        Ok(allowed_origins) => split_list(&allowed_origins).iter()
+            .map(|origin| OriginPattern::parse(origin))
+            .collect::<Result<Vec<OriginPattern>>>()?
+    };
+    let defaults = CorsConfig::default();
+
+    let cors_config = CorsConfig {
+        allowed_origins,
+        allowed_methods: env::var(CORS_ALLOWED_METHODS_VAR).map(|methods| split_list(&methods)).unwrap_or(defaults.allowed_methods),
+        allowed_headers: env::var(CORS_ALLOWED_HEADERS_VAR).map(|headers| split_list(&headers)).unwrap_or(defaults.allowed_headers),
+        allow_credentials: env_or(CORS_ALLOW_CREDENTIALS_VAR, defaults.allow_credentials)?,
+        max_age: env_opt(CORS_MAX_AGE_VAR)?.map(Duration::from_secs),
+    };
+    cors_config.validate()?;
+    return Ok(Some(cors_config));
+}
+
+/// Splits a comma-separated list, dropping empty entries.
+fn split_list(list: &str) -> Vec<String> {
+    return list.split(',').map(str::trim).filter(|entry| !entry.is_empty()).map(|entry| entry.into()).collect();
+}
+
+/// Returns the API routes the server serves: the key-value route over the database, if enabled,
+/// and the route publishing to the event hub, if there is one, among others.
+fn prepare_api_routes(database: Arc<dyn Database>, event_hub: Option<Arc<EventHub>>) -> Result<ApiRoutes> {
+    let mut api_routes = ApiRoutes::new();
+    if env_or(KV_ROUTE_ENABLED_VAR, false)? {
+        api_routes.add_database_route(KV_PATH, &["GET", "PUT", "DELETE"], kv_route(database));
+    }
+
+    if let Some(event_hub) = event_hub {
+        api_routes.add(EVENTS_PATH, &["POST"], publish_route(event_hub));
+    }
+
+    if let Ok(signing_key) = env::var(AUTH_SIGNING_KEY_VAR) {
+        let authenticator = SignedTokenAuthenticator::new(AUTH_REALM, signing_key.as_bytes());
+        api_routes.add(TOKENS_PATH, &["POST"], token_route(authenticator, MAX_TOKEN_TIME_TO_LIVE));
+    }
+
+    if env::var(SESSION_IDLE_TIMEOUT_VAR).is_ok() {
+        api_routes.add(SESSION_PATH, &["GET", "POST", "DELETE"], session_route());
+    }
+
+    if let Ok(upload_dir) = env::var(UPLOAD_DIR_VAR) {
+        let limits = FormLimits { max_file_size: env_or(MAX_UPLOAD_SIZE_VAR, FormLimits::default().max_file_size)?, ..FormLimits::default() };
+        api_routes.add(UPLOADS_PATH, &["POST"], upload_route(PathBuf::from(upload_dir), limits));
+    }
+
+    return Ok(api_routes);
+}
+
+/// Returns the event stream routes the server serves, and the hub behind the event stream, if it
+/// is enabled.
+fn prepare_event_stream_routes() -> Result<(EventStreamRoutes, Option<Arc<EventHub>>)> {
+    let defaults = EventStreamLimits::default();
+    let limits = EventStreamLimits {
+        retry: Duration::from_secs(env_or(EVENT_STREAM_RETRY_VAR, defaults.retry.as_secs())?),
+        ..defaults
+    };
+    let mut event_stream_routes = EventStreamRoutes::new(limits);
+
+    let event_hub = match env::var(EVENT_STREAM_PATH_VAR) {
+        Err(_) => None,
+        Ok(event_stream_path) => {
+            let event_hub = Arc::new(EventHub::new(EVENT_HISTORY_SIZE, MAX_EVENT_STREAM_SUBSCRIBERS));
+            event_stream_routes.add(&event_stream_path, event_hub.clone());
+            Some(event_hub)
+        }
+    };
+
+    return Ok((event_stream_routes, event_hub));
 }
 
-/// Loop until the reader reads the word 'exit' (plus optional whitespace).
-fn loop_until_exit_requested<R: BufRead>(mut reader: R) -> Result<()> {
-    let mut maybe_exit = String::new();
+/// Returns the WebSocket routes the server serves.
+fn prepare_websocket_routes() -> Result<WebSocketRoutes> {
+    let defaults = WebSocketLimits::default();
+    let limits = WebSocketLimits {
+        max_message_size: env_or(WEBSOCKET_MAX_MESSAGE_SIZE_VAR, defaults.max_message_size)?,
+        ping_interval: Duration::from_secs(env_or(WEBSOCKET_PING_INTERVAL_VAR, defaults.ping_interval.as_secs())?),
+        ..defaults
+    };
+    let mut websocket_routes = WebSocketRoutes::new(limits);
+
+    if let Ok(relay_path) = env::var(WEBSOCKET_RELAY_PATH_VAR) {
+        websocket_routes.add(&relay_path, Arc::new(WebSocketHub::new(MAX_WEBSOCKET_RELAY_CLIENTS)));
+    }
+
+    return Ok(websocket_routes);
+}
+
+/// Returns the authenticators described by the environment. At least one must be configured.
+fn prepare_authenticators() -> Result<Vec<Arc<dyn Authenticator>>> {
+    let mut authenticators: Vec<Arc<dyn Authenticator>> = Vec::new();
+
+    if let Ok(credentials_file) = env::var(AUTH_CREDENTIALS_FILE_VAR) {
+        authenticators.push(Arc::new(BasicAuthenticator::from_file(&credentials_file, AUTH_REALM)?));
+    }
+    if let Ok(tokens_file) = env::var(AUTH_TOKENS_FILE_VAR) {
+        authenticators.push(Arc::new(TokenAuthenticator::from_file(&tokens_file, AUTH_REALM, API_KEY_HEADER)?));
+    }
+    if let Ok(signing_key) = env::var(AUTH_SIGNING_KEY_VAR) {
+        authenticators.push(Arc::new(SignedTokenAuthenticator::new(AUTH_REALM, signing_key.as_bytes())));
+    }
+
+    if authenticators.is_empty() {
+        return Err(ServerError::new("Routes require authentication, but no way of authenticating is configured.".into()));
+    }
+
+    // Sessions are logged in to using one of the other authenticators.
+    if env::var(SESSION_IDLE_TIMEOUT_VAR).is_ok() {
+        authenticators.push(Arc::new(SessionAuthenticator));
+    }
+    return Ok(authenticators);
+}
+
+/// The port to serve on, if the arguments start the server: none, `server`, or either followed by
+/// `-p <port>`.
+fn server_port(args: &[String]) -> Option<String> {
+    return match args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>().as_slice() {
+        [] | ["server"] => Some(PORT.into()),
+        ["-p", port] | ["server", "-p", port] => Some(port.to_string()),
+        _ => None
+    };
+}
+
+/// Runs a command-line utility instead of the server:
+/// * `hash-password`: hashes a password read from stdin, for a credentials file
+/// * `issue-token <principal> <seconds>`: issues a signed token using `AUTH_SIGNING_KEY`
+fn run_command(args: &[String]) -> Result<()> {
+    return match args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>().as_slice() {
+        ["hash-password"] => {
+            let mut password = String::new();
+            stdin().lock().read_line(&mut password)?;
+            println!("{}", hash_password(password.trim_end_matches(&['\r', '\n'][..]), DEFAULT_PASSWORD_HASH_ITERATIONS)?);
+            Ok(())
+        }
+        ["issue-token", principal, seconds] => {
+            let signing_key = env::var(AUTH_SIGNING_KEY_VAR)
+                .map_err(|_| ServerError::new(format!("{} must be set to issue tokens.", AUTH_SIGNING_KEY_VAR)))?;
+            let authenticator = SignedTokenAuthenticator::new(AUTH_REALM, signing_key.as_bytes());
+            println!("{}", authenticator.issue(principal, Duration::from_secs(seconds.parse()?))?);
+            Ok(())
+        }
+        ["new-wallet", path] => {
+            let wallet = Wallet::generate()?;
+            wallet.save(Path::new(path))?;
+            println!("{}", wallet.address());
+            Ok(())
+        }
+        _ => Err(ServerError::new("Usage: blockchain [server [-p <port>] | hash-password | issue-token <principal> <seconds> | new-wallet <path>]".into()))
+    };
+}
+
+/// Returns the middleware wrapped around the server's routes.
+fn prepare_middleware(client_limits: &ClientLimits, is_tls: bool) -> Result<MiddlewareChain> {
+    let mut middleware = MiddlewareChain::new();
+    middleware.add(DefaultHeaders::new(&[("X-Content-Type-Options", "nosniff"), ("X-Frame-Options", "DENY")]));
+
+    // CORS wraps the rate limiting, so that browsers can read the 429s.
+    if let Some(cors_config) = prepare_cors_config()? {
+        middleware.add(Cors::new(cors_config));
+    }
+
+    if let (Ok(api_key_header), Some(rate_limit)) = (env::var(RATE_LIMIT_API_KEY_HEADER_VAR), client_limits.connection_rate) {
+        // Only keys that authenticate are trusted to identify a client.
+        let tokens_file = env::var(AUTH_TOKENS_FILE_VAR)
+            .map_err(|_| ServerError::new(format!("{} requires {} to be set.", RATE_LIMIT_API_KEY_HEADER_VAR, AUTH_TOKENS_FILE_VAR)))?;
+        let key_authenticator = TokenAuthenticator::from_file(&tokens_file, AUTH_REALM, &api_key_header)?;
+        middleware.add(RateLimitLayer::new(rate_limit, Some(Arc::new(key_authenticator)), client_limits));
+    }
 
-    loop {
-        println!("Type 'exit' to exit.");
-        maybe_exit.clear();
+    // The event publishing, key-value and session routes always require authentication, as does
+    // the token route, below.
+    let mut auth_routes = split_list(&env::var(AUTH_ROUTES_VAR).unwrap_or_default());
+    if env::var(EVENT_STREAM_PATH_VAR).is_ok() {
+        auth_routes.push(EVENTS_PATH.into());
+    }
+    if env_or(KV_ROUTE_ENABLED_VAR, false)? {
+        auth_routes.push(KV_PATH.into());
+    }
 
-        reader.read_line(&mut maybe_exit)?;
+    // Sessions wrap authentication, so that sessions can authenticate requests.
+    if let Some(idle_timeout) = env_opt::<u64>(SESSION_IDLE_TIMEOUT_VAR)? {
+        let same_site = SameSite::parse(&env::var(SESSION_COOKIE_SAME_SITE_VAR).unwrap_or_else(|_| "lax".into()))?;
+        let cookie = SetCookie {
+            domain: env::var(SESSION_COOKIE_DOMAIN_VAR).ok(),
+            secure: is_tls,
+            same_site: Some(same_site),
+            ..SetCookie::new(SESSION_COOKIE_NAME, "")
+        };
+        middleware.add(SessionLayer::new(cookie, Duration::from_secs(idle_timeout), MAX_SESSIONS));
+        auth_routes.push(SESSION_PATH.into());
+    }
 
-        if maybe_exit.trim() == "exit" {
-            return Ok(());
+    let issues_tokens = env::var(AUTH_SIGNING_KEY_VAR).is_ok();
+    if !auth_routes.is_empty() || issues_tokens {
+        let authenticators = prepare_authenticators()?;
+        let allowed_principals = env::var(AUTH_ALLOWED_PRINCIPALS_VAR).ok().map(|principals| split_list(&principals));
+        for route in auth_routes {
+            middleware.add_for_route(&route, AuthLayer::new(authenticators.clone(), allowed_principals.clone()));
+        }
+        // Tokens are only issued for passwords and static tokens, so that they cannot be renewed
+        // forever with the tokens and sessions they lead to.
+        if issues_tokens {
+            middleware.add_for_route(TOKENS_PATH, AuthLayer::for_token_route(authenticators, allowed_principals)?);
         }
     }
-}
\ No newline at end of file
+
+    // Probes should always see the server's current state.
+    for health_path in [LIVENESS_PATH, READINESS_PATH].iter() {
+        middleware.add_for_route(health_path, DefaultHeaders::new(&[("Cache-Control", "no-store")]));
+    }
+
+    return Ok(middleware);
+}
+
+/// Returns the TLS config described by the environment, if any.
+fn prepare_tls_config() -> Result<Option<TlsConfig>> {
+    return match (env::var(TLS_CERT_CHAIN_PATH_VAR), env::var(TLS_PRIVATE_KEY_PATH_VAR)) {
+        (Ok(cert_chain_path), Ok(private_key_path)) => {
+            let certificate = CertificatePaths::new(&cert_chain_path, &private_key_path);
+            let tls_config = TlsConfig::new(certificate)?;
+
+            let sni_certificates = env::var(TLS_SNI_CERTIFICATES_VAR).unwrap_or_default();
+            for entry in sni_certificates.split(';').filter(|entry| !entry.trim().is_empty()) {
+                let (server_name, certificate) = parse_sni_certificate(entry)?;
+                tls_config.add_certificate(server_name, certificate)?;
+            }
+
+            Ok(Some(tls_config))
+        }
+        _ => Ok(None)
+    };
+}
+
+/// Parses an entry of the form `server_name=cert_chain_path,private_key_path`.
+fn parse_sni_certificate(entry: &str) -> Result<(&str, CertificatePaths)> {
+    let malformed = || ServerError::new(format!("Malformed SNI certificate entry: {}", entry));
+
+    let (server_name, paths) = entry.split_once('=').ok_or_else(malformed)?;
+    let (cert_chain_path, private_key_path) = paths.split_once(',').ok_or_else(malformed)?;
+
+    return Ok((server_name.trim(), CertificatePaths::new(cert_chain_path.trim(), private_key_path.trim())));
+}
+
+/// Periodically re-reads the TLS certificates from disk, so that renewed certificates are picked
+/// up without a restart. A failed reload keeps the previous certificates.
+fn spawn_tls_reloader(tls_config: TlsConfig) {
+    spawn(move || loop {
+        sleep(TLS_RELOAD_INTERVAL);
+        if let Err(e) = tls_config.reload() {
+            logger::global().error(&ServerError::with_cause("Failed to reload TLS certificates".into(), e));
+        }
+    });
+}
+
+/// Returns the routes that the server will serve.
+fn prepare_routes() -> HashMap<String, String> {
+    let mut routes = HashMap::new();
+    routes.insert("/".into(), "./src/html/hello_world.html".into());
+    return routes;
+}
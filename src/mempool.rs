@@ -0,0 +1,440 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::ledger::{Block, Hash};
+use crate::sha256::to_hex;
+use crate::transaction::{OutPoint, Transaction, TransactionError};
+use crate::utxo::{UtxoError, UtxoSet};
+
+/// Limits on the transactions waiting to be mined.
+#[derive(Clone, Debug)]
+pub struct MempoolConfig {
+    // The most the waiting transactions may take up, in encoded bytes. The lowest fee rates are
+    // evicted first.
+    pub max_size: usize,
+    // How long a transaction may wait before it is evicted.
+    pub max_age: Duration,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> MempoolConfig {
+        return MempoolConfig { max_size: 1_000_000, max_age: Duration::from_secs(24 * 60 * 60) };
+    }
+}
+
+/// The reasons a transaction is refused.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MempoolError {
+    // Coinbases are only valid in blocks.
+    Coinbase,
+    // The transaction is malformed or badly signed.
+    InvalidTransaction { error: TransactionError },
+    // The transaction cannot spend from the chain's unspent outputs.
+    InvalidSpend { error: UtxoError },
+    // The transaction is already waiting.
+    Duplicate { txid: Hash },
+    // A waiting transaction already spends the output.
+    Conflict { output: OutPoint },
+    // The pool is full of transactions paying higher fee rates.
+    Full,
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            MempoolError::Coinbase => f.write_str("Coinbases cannot be submitted."),
+            MempoolError::InvalidTransaction { error } => write!(f, "The transaction is invalid: {}", error),
+            MempoolError::InvalidSpend { error } => write!(f, "The transaction cannot be spent: {}", error),
+            MempoolError::Duplicate { txid } => write!(f, "Transaction {} is already waiting.", to_hex(txid)),
+            MempoolError::Conflict { output } => write!(f, "Output {} is already spent by a waiting transaction.", output),
+            MempoolError::Full => f.write_str("The fee rate is too low for the transaction to be accepted."),
+        };
+    }
+}
+
+pub type MempoolResult<T> = std::result::Result<T, MempoolError>;
+
+/// A transaction waiting to be mined.
+#[derive(Clone, Debug)]
+struct Entry {
+    transaction: Transaction,
+    // The encoded size, in bytes.
+    size: usize,
+    added: Instant,
+}
+
+impl Entry {
+    /// Orders entries by fee per byte, comparing the products rather than dividing so that
+    /// nothing is lost to rounding.
+    fn cmp_fee_rate(&self, other: &Entry) -> Ordering {
+        let own = self.transaction.fee as u128 * other.size as u128;
+        let others = other.transaction.fee as u128 * self.size as u128;
+        return own.cmp(&others);
+    }
+}
+
+/// The transactions waiting to be mined. Each spends outputs of the chain as it stands, and no two
+/// spend the same output, so that any selection of them can go in a block together.
+#[derive(Debug)]
+pub struct Mempool {
+    config: MempoolConfig,
+    entries: HashMap<Hash, Entry>,
+    // The waiting transaction spending each output.
+    spends: HashMap<OutPoint, Hash>,
+    // The total size of the waiting transactions, in bytes.
+    size: usize,
+}
+
+impl Mempool {
+    pub fn new(config: MempoolConfig) -> Mempool {
+        return Mempool { config, entries: HashMap::new(), spends: HashMap::new(), size: 0 };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    /// The total size of the waiting transactions, in bytes.
+    pub fn size(&self) -> usize {
+        return self.size;
+    }
+
+    pub fn contains(&self, txid: &Hash) -> bool {
+        return self.entries.contains_key(txid);
+    }
+
+    /// Validates the transaction against the unspent outputs and admits it, returning its ID.
+    /// Transactions may only spend outputs already in the chain. If the pool grows too large, the
+    /// lowest fee rates are evicted, which may be the transaction itself. A transaction larger
+    /// than the whole pool is refused outright.
+    pub fn add(&mut self, transaction: Transaction, utxos: &UtxoSet) -> MempoolResult<Hash> {
+        return self.add_at(transaction, utxos, Instant::now());
+    }
+
+    /// The waiting transactions by descending fee rate, as many as fit within the given size, for
+    /// building a block. Larger transactions are skipped if smaller ones still fit.
+    pub fn select(&self, max_size: usize) -> Vec<Transaction> {
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        entries.sort_by(|a, b| b.cmp_fee_rate(a).then_with(|| a.added.cmp(&b.added)));
+
+        let mut size = 0;
+        let mut selected = Vec::new();
+        for entry in entries {
+            if size + entry.size <= max_size {
+                size += entry.size;
+                selected.push(entry.transaction.clone());
+            }
+        }
+        return selected;
+    }
+
+    /// Evicts transactions that have waited longer than the maximum age.
+    pub fn expire(&mut self) {
+        self.expire_at(Instant::now());
+    }
+
+    /// Removes the transactions the block confirms, and any others spending the same outputs, as
+    /// they can no longer be mined.
+    pub fn block_connected(&mut self, block: &Block) {
+        for transaction in block.transactions.iter() {
+            self.remove(&transaction.txid());
+            for input in transaction.inputs.iter() {
+                if let Some(txid) = self.spends.get(&input.previous_output).copied() {
+                    self.remove(&txid);
+                }
+            }
+        }
+    }
+
+    /// Re-admits the transactions of a block removed from the chain, e.g. in a reorganisation, so
+    /// that they can be mined again. The unspent outputs must be as they are without the block.
+    /// Waiting transactions that spent the block's outputs are dropped first. Then the block's
+    /// transactions that no longer apply are dropped, as are those spending outputs created in the
+    /// same block, whose outputs are no longer in the chain.
+    pub fn block_disconnected(&mut self, block: &Block, utxos: &UtxoSet) {
+        self.block_disconnected_at(block, utxos, Instant::now());
+    }
+
+    fn add_at(&mut self, transaction: Transaction, utxos: &UtxoSet, now: Instant) -> MempoolResult<Hash> {
+        if transaction.is_coinbase() {
+            return Err(MempoolError::Coinbase);
+        }
+        let txid = transaction.txid();
+        if self.entries.contains_key(&txid) {
+            return Err(MempoolError::Duplicate { txid });
+        }
+        if let Some(input) = transaction.inputs.iter().find(|input| self.spends.contains_key(&input.previous_output)) {
+            return Err(MempoolError::Conflict { output: input.previous_output });
+        }
+        transaction.validate().map_err(|error| MempoolError::InvalidTransaction { error })?;
+        utxos.validate_transaction(&transaction).map_err(|error| MempoolError::InvalidSpend { error })?;
+        // Admitting it would evict every other transaction before evicting it too.
+        let size = transaction.size();
+        if size > self.config.max_size {
+            return Err(MempoolError::Full);
+        }
+
+        self.expire_at(now);
+        for input in transaction.inputs.iter() {
+            self.spends.insert(input.previous_output, txid);
+        }
+        self.size += size;
+        self.entries.insert(txid, Entry { transaction, size, added: now });
+
+        self.evict_to_fit();
+        if !self.entries.contains_key(&txid) {
+            return Err(MempoolError::Full);
+        }
+        return Ok(txid);
+    }
+
+    fn expire_at(&mut self, now: Instant) {
+        let max_age = self.config.max_age;
+        let expired: Vec<Hash> = self.entries.iter()
+            .filter(|(_, entry)| now.saturating_duration_since(entry.added) > max_age)
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in expired.iter() {
+            self.remove(txid);
+        }
+    }
+
+    fn block_disconnected_at(&mut self, block: &Block, utxos: &UtxoSet, now: Instant) {
+        let invalid: Vec<Hash> = self.entries.iter()
+            .filter(|(_, entry)| utxos.validate_transaction(&entry.transaction).is_err())
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in invalid.iter() {
+            self.remove(txid);
+        }
+
+        for transaction in block.transactions.iter().filter(|transaction| !transaction.is_coinbase()) {
+            let _ = self.add_at(transaction.clone(), utxos, now);
+        }
+    }
+
+    /// Evicts the lowest fee rates, and among those the newest, until the pool fits its size.
+    fn evict_to_fit(&mut self) {
+        while self.size > self.config.max_size {
+            let lowest = self.entries.iter()
+                .min_by(|(_, a), (_, b)| a.cmp_fee_rate(b).then_with(|| b.added.cmp(&a.added)))
+                .map(|(txid, _)| *txid);
+            match lowest {
+                Some(txid) => self.remove(&txid),
+                None => return
+            }
+        }
+    }
+
+    fn remove(&mut self, txid: &Hash) {
+        if let Some(entry) = self.entries.remove(txid) {
+            for input in entry.transaction.inputs.iter() {
+                self.spends.remove(&input.previous_output);
+            }
+            self.size -= entry.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::ledger::{Blockchain, ChainConfig};
+    use crate::mempool::{Mempool, MempoolConfig, MempoolError};
+    use crate::transaction::{OutPoint, Transaction, TransactionError, TxOutput};
+    use crate::utxo::UtxoError;
+    use crate::wallet::Wallet;
+
+    /// A chain, without proof of work, in which the wallet has mined the given number of blocks.
+    fn funded(wallet: &Wallet, blocks: u64) -> (Blockchain, Vec<OutPoint>) {
+        let mut chain = Blockchain::new(ChainConfig { initial_difficulty: 0, ..ChainConfig::default() });
+        let mut outputs = Vec::new();
+        for index in 1..=blocks {
+            let coinbase = Transaction::coinbase(wallet.address(), 50, index);
+            outputs.push(OutPoint { txid: coinbase.txid(), index: 0 });
+            chain.append(chain.next_block(index * 10, vec![coinbase])).unwrap();
+        }
+        return (chain, outputs);
+    }
+
+    fn pay(wallet: &Wallet, spent: OutPoint, fee: u64) -> Transaction {
+        return wallet.create_transaction(vec![spent], vec![TxOutput { amount: 50 - fee, address: wallet.address() }], fee, 0);
+    }
+
+    #[test]
+    fn valid_transactions_are_admitted_once() {
+        let wallet = Wallet::generate().unwrap();
+        let (chain, outputs) = funded(&wallet, 1);
+        let mut pool = Mempool::new(MempoolConfig::default());
+        let transaction = pay(&wallet, outputs[0], 5);
+
+        assert_eq!(pool.add(transaction.clone(), chain.utxos()), Ok(transaction.txid()));
+        assert!(pool.contains(&transaction.txid()));
+        assert_eq!(pool.size(), transaction.size());
+
+        assert_eq!(pool.add(transaction.clone(), chain.utxos()), Err(MempoolError::Duplicate { txid: transaction.txid() }));
+        assert_eq!(pool.add(pay(&wallet, outputs[0], 6), chain.utxos()), Err(MempoolError::Conflict { output: outputs[0] }));
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn invalid_transactions_are_refused() {
+        let wallet = Wallet::generate().unwrap();
+        let (chain, outputs) = funded(&wallet, 1);
+        let mut pool = Mempool::new(MempoolConfig::default());
+
+        let coinbase = Transaction::coinbase(wallet.address(), 50, 9);
+        assert_eq!(pool.add(coinbase, chain.utxos()), Err(MempoolError::Coinbase));
+
+        let mut forged = pay(&wallet, outputs[0], 5);
+        forged.fee = 0;
+        assert_eq!(pool.add(forged, chain.utxos()),
+                   Err(MempoolError::InvalidTransaction { error: TransactionError::InvalidSignature { input: 0 } }));
+
+        let missing = OutPoint { txid: [7; 32], index: 0 };
+        assert_eq!(pool.add(pay(&wallet, missing, 5), chain.utxos()),
+                   Err(MempoolError::InvalidSpend { error: UtxoError::MissingOutput { output: missing } }));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn blocks_are_filled_by_descending_fee_rate() {
+        let wallet = Wallet::generate().unwrap();
+        let (chain, outputs) = funded(&wallet, 3);
+        let mut pool = Mempool::new(MempoolConfig::default());
+        let (low, high, middle) = (pay(&wallet, outputs[0], 1), pay(&wallet, outputs[1], 9), pay(&wallet, outputs[2], 5));
+        for transaction in [low.clone(), high.clone(), middle.clone()].iter() {
+            pool.add(transaction.clone(), chain.utxos()).unwrap();
+        }
+
+        assert_eq!(pool.select(usize::MAX), vec![high.clone(), middle.clone(), low]);
+        assert_eq!(pool.select(high.size() * 2), vec![high, middle]);
+        assert_eq!(pool.select(10), Vec::new());
+    }
+
+    #[test]
+    fn the_lowest_fee_rates_are_evicted_when_full() {
+        let wallet = Wallet::generate().unwrap();
+        let (chain, outputs) = funded(&wallet, 4);
+        let size = pay(&wallet, outputs[0], 1).size();
+        let mut pool = Mempool::new(MempoolConfig { max_size: size * 2, ..MempoolConfig::default() });
+
+        pool.add(pay(&wallet, outputs[0], 3), chain.utxos()).unwrap();
+        pool.add(pay(&wallet, outputs[1], 5), chain.utxos()).unwrap();
+        assert_eq!(pool.add(pay(&wallet, outputs[2], 2), chain.utxos()), Err(MempoolError::Full));
+
+        let better = pay(&wallet, outputs[3], 4);
+        assert_eq!(pool.add(better.clone(), chain.utxos()), Ok(better.txid()));
+        assert_eq!(pool.select(usize::MAX).iter().map(|transaction| transaction.fee).collect::<Vec<_>>(), vec![5, 4]);
+        // The evicted transaction's output can be spent again.
+        pool.add(pay(&wallet, outputs[0], 6), chain.utxos()).unwrap();
+        assert_eq!(pool.size(), size * 2);
+    }
+
+    #[test]
+    fn transactions_larger_than_the_pool_are_refused_without_evictions() {
+        let wallet = Wallet::generate().unwrap();
+        let (chain, outputs) = funded(&wallet, 3);
+        // Spends two outputs, so is larger than the pool, which fits one spending a single output.
+        let large = wallet.create_transaction(vec![outputs[1], outputs[2]], vec![TxOutput { amount: 50, address: wallet.address() }], 50, 0);
+        let mut pool = Mempool::new(MempoolConfig { max_size: large.size() - 1, ..MempoolConfig::default() });
+        let waiting = pay(&wallet, outputs[0], 1);
+        pool.add(waiting.clone(), chain.utxos()).unwrap();
+
+        // However high its fee, it is refused.
+        assert_eq!(pool.add(large, chain.utxos()), Err(MempoolError::Full));
+
+        assert!(pool.contains(&waiting.txid()));
+        assert_eq!(pool.size(), waiting.size());
+    }
+
+    #[test]
+    fn old_transactions_expire() {
+        let wallet = Wallet::generate().unwrap();
+        let (chain, outputs) = funded(&wallet, 2);
+        let mut pool = Mempool::new(MempoolConfig { max_age: Duration::from_secs(60), ..MempoolConfig::default() });
+        let start = Instant::now();
+
+        let old = pool.add_at(pay(&wallet, outputs[0], 1), chain.utxos(), start).unwrap();
+        let new = pool.add_at(pay(&wallet, outputs[1], 1), chain.utxos(), start + Duration::from_secs(30)).unwrap();
+        pool.expire_at(start + Duration::from_secs(61));
+
+        assert!(!pool.contains(&old));
+        assert!(pool.contains(&new));
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn blocks_remove_confirmed_and_conflicting_transactions() {
+        let wallet = Wallet::generate().unwrap();
+        let (mut chain, outputs) = funded(&wallet, 2);
+        let mut pool = Mempool::new(MempoolConfig::default());
+        let (confirmed, waiting) = (pay(&wallet, outputs[0], 1), pay(&wallet, outputs[1], 1));
+        pool.add(confirmed.clone(), chain.utxos()).unwrap();
+        pool.add(waiting.clone(), chain.utxos()).unwrap();
+
+        // The block confirms one transaction, and a different spend of the other's output.
+        let conflicting = pay(&wallet, outputs[1], 2);
+        let block = chain.next_block(30, vec![Transaction::coinbase(wallet.address(), 53, 3), confirmed, conflicting]);
+        chain.append(block.clone()).unwrap();
+        pool.block_connected(&block);
+
+        assert!(pool.is_empty());
+        assert_eq!(pool.size(), 0);
+    }
+
+    #[test]
+    fn transactions_from_disconnected_blocks_are_readmitted() {
+        let wallet = Wallet::generate().unwrap();
+        let (mut chain, outputs) = funded(&wallet, 2);
+        let mut pool = Mempool::new(MempoolConfig::default());
+        let payment = pay(&wallet, outputs[0], 1);
+        // Spends an output created in the same block, so cannot wait without it.
+        let child = wallet.create_transaction(vec![OutPoint { txid: payment.txid(), index: 0 }], vec![TxOutput { amount: 49, address: wallet.address() }], 0, 0);
+        let block = chain.next_block(30, vec![Transaction::coinbase(wallet.address(), 51, 3), payment.clone(), child.clone()]);
+        chain.append(block.clone()).unwrap();
+        pool.block_connected(&block);
+
+        // A competing branch replaces the block.
+        let removed = chain.pop().unwrap();
+        pool.block_disconnected(&removed, chain.utxos());
+
+        assert!(pool.contains(&payment.txid()));
+        assert!(!pool.contains(&child.txid()));
+        assert_eq!(pool.len(), 1);
+
+        let competing = chain.next_block(31, pool.select(usize::MAX));
+        chain.append(competing.clone()).unwrap();
+        pool.block_connected(&competing);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn transactions_spending_disconnected_blocks_are_dropped() {
+        let wallet = Wallet::generate().unwrap();
+        let (mut chain, outputs) = funded(&wallet, 2);
+        let mut pool = Mempool::new(MempoolConfig::default());
+        let unaffected = pay(&wallet, outputs[0], 1);
+        pool.add(unaffected.clone(), chain.utxos()).unwrap();
+        // Spends the coinbase of the latest block.
+        let dependent = pay(&wallet, outputs[1], 1);
+        pool.add(dependent.clone(), chain.utxos()).unwrap();
+
+        let removed = chain.pop().unwrap();
+        pool.block_disconnected(&removed, chain.utxos());
+
+        assert!(!pool.contains(&dependent.txid()));
+        assert!(pool.contains(&unaffected.txid()));
+        assert_eq!(pool.size(), unaffected.size());
+        // Its output can be spent by another transaction once the block's coinbase is back.
+        chain.append(removed).unwrap();
+        pool.add(pay(&wallet, outputs[1], 2), chain.utxos()).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+use crate::ledger::Hash;
+use crate::sha256::{Sha256, DIGEST_LENGTH};
+
+// Prefixed to leaves and interior nodes before hashing, so that a node can never be passed off
+// as a leaf or the other way round.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// The root of an empty tree.
+pub const EMPTY_ROOT: Hash = [0; DIGEST_LENGTH];
+
+fn hash_leaf(leaf: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(&[LEAF_PREFIX]);
+    hasher.update(leaf);
+    return hasher.finish();
+}
+
+fn hash_node(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(&[NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    return hasher.finish();
+}
+
+/// Hashes each level of the tree into the next, pairing nodes from the left. A node left without
+/// a pair is carried up unchanged, rather than paired with itself, so that no two lists of leaves
+/// share a root.
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    return level.chunks(2).map(|pair| match pair {
+        [left, right] => hash_node(left, right),
+        _ => pair[0]
+    }).collect();
+}
+
+/// The root of the Merkle tree over the leaves, e.g. a block's transaction IDs.
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return EMPTY_ROOT;
+    }
+    let mut level: Vec<Hash> = leaves.iter().map(hash_leaf).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    return level[0];
+}
+
+/// Builds the proof that the leaf at the index is in the tree, or None if there is no such leaf.
+pub fn merkle_proof(leaves: &[Hash], index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut steps = Vec::new();
+    let mut level: Vec<Hash> = leaves.iter().map(hash_leaf).collect();
+    let mut position = index;
+    while level.len() > 1 {
+        if position % 2 == 1 {
+            steps.push(ProofStep { side: Side::Left, sibling: level[position - 1] });
+        } else if position + 1 < level.len() {
+            steps.push(ProofStep { side: Side::Right, sibling: level[position + 1] });
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+    return Some(MerkleProof { steps });
+}
+
+/// Which side of the path a sibling is on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// A sibling of a node on the path from a leaf to the root.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProofStep {
+    pub side: Side,
+    pub sibling: Hash,
+}
+
+/// Proves a leaf is in a tree with a given root, using only the hashes of the siblings on the path
+/// between them, e.g. so that a client holding only block headers can check a transaction was
+/// mined.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleProof {
+    // From the leaf's sibling up to the root's children.
+    pub steps: Vec<ProofStep>,
+}
+
+impl MerkleProof {
+    /// The root the proof leads to from the leaf.
+    pub fn root(&self, leaf: &Hash) -> Hash {
+        return self.steps.iter().fold(hash_leaf(leaf), |node, step| match step.side {
+            Side::Left => hash_node(&step.sibling, &node),
+            Side::Right => hash_node(&node, &step.sibling)
+        });
+    }
+
+    pub fn verify(&self, leaf: &Hash, root: &Hash) -> bool {
+        return self.root(leaf) == *root;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ledger::Hash;
+    use crate::merkle::{hash_leaf, hash_node, merkle_proof, merkle_root, EMPTY_ROOT};
+
+    fn leaves(count: u8) -> Vec<Hash> {
+        return (0..count).map(|leaf| [leaf; 32]).collect();
+    }
+
+    #[test]
+    fn roots_hash_pairs_up_the_tree() {
+        let [a, b, c] = [[0; 32], [1; 32], [2; 32]];
+
+        assert_eq!(merkle_root(&[]), EMPTY_ROOT);
+        assert_eq!(merkle_root(&[a]), hash_leaf(&a));
+        assert_eq!(merkle_root(&[a, b]), hash_node(&hash_leaf(&a), &hash_leaf(&b)));
+        // The odd leaf out is carried up to be paired on the next level.
+        assert_eq!(merkle_root(&[a, b, c]), hash_node(&hash_node(&hash_leaf(&a), &hash_leaf(&b)), &hash_leaf(&c)));
+    }
+
+    #[test]
+    fn roots_change_with_the_leaves_and_their_order() {
+        let leaves = leaves(5);
+        let root = merkle_root(&leaves);
+
+        let mut reordered = leaves.clone();
+        reordered.swap(1, 2);
+        assert_ne!(merkle_root(&reordered), root);
+        let mut changed = leaves.clone();
+        changed[4][0] ^= 1;
+        assert_ne!(merkle_root(&changed), root);
+        // Repeating the last leaf does not give the same root, as it would if it were paired with
+        // itself.
+        let mut repeated = leaves.clone();
+        repeated.push(leaves[4]);
+        assert_ne!(merkle_root(&repeated), root);
+        // Nor does passing off an interior node as a leaf.
+        assert_ne!(merkle_root(&[hash_node(&hash_leaf(&leaves[0]), &hash_leaf(&leaves[1]))]), merkle_root(&leaves[..2]));
+    }
+
+    #[test]
+    fn proofs_verify_every_leaf_of_every_size_of_tree() {
+        for count in 1..=17 {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, index).unwrap();
+                assert!(proof.verify(leaf, &root), "leaf {} of {}", index, count);
+                assert!(proof.steps.len() <= 5);
+            }
+            assert_eq!(merkle_proof(&leaves, count as usize), None);
+        }
+    }
+
+    #[test]
+    fn proofs_fail_for_other_leaves_and_roots() {
+        let leaves = leaves(6);
+        let root = merkle_root(&leaves);
+        let proof = merkle_proof(&leaves, 2).unwrap();
+
+        assert!(!proof.verify(&leaves[3], &root));
+        assert!(!proof.verify(&leaves[2], &merkle_root(&leaves[..5])));
+
+        let mut tampered = proof.clone();
+        tampered.steps[1].sibling[0] ^= 1;
+        assert!(!tampered.verify(&leaves[2], &root));
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{BufRead, Read, Write};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::handler::{ConnectionInfo, Handler, HttpHandler, Upgrade};
+use crate::servererror::Result;
+
+// Used by `global`.
+static GLOBAL_METRICS: OnceLock<Metrics> = OnceLock::new();
+
+// The upper bounds of the request latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+// The label used for requests that did not match any route, to bound the number of time series.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+// The methods recorded by name. Clients can send any token as a method, so the rest share the
+// `OTHER_METHOD` label, to bound the number of time series.
+const KNOWN_METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS"];
+const OTHER_METHOD: &str = "other";
+// The content type of the Prometheus text exposition format.
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Returns the process-wide metrics registry.
+pub fn global() -> &'static Metrics {
+    return GLOBAL_METRICS.get_or_init(Metrics::new);
+}
+
+/// A monotonically-increasing count.
+#[derive(Default)]
+pub struct Counter {
+    value: AtomicU64
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, amount: u64) {
+        self.value.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        return self.value.load(Ordering::Relaxed);
+    }
+}
+
+/// A value that can go up and down.
+#[derive(Default)]
+pub struct Gauge {
+    value: AtomicI64
+}
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        return self.value.load(Ordering::Relaxed);
+    }
+}
+
+/// Counts observations into cumulative buckets, as Prometheus expects.
+pub struct Histogram {
+    // The upper bound of each bucket. An implicit `+Inf` bucket follows.
+    bounds: Vec<f64>,
+    // The number of observations falling in each bucket (not cumulative), plus the `+Inf` bucket.
+    bucket_counts: Vec<AtomicU64>,
+    // The sum of all observations, as the bits of an f64.
+    sum_bits: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Histogram {
+        return Histogram {
+            bounds: bounds.to_vec(),
+            bucket_counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_bits: AtomicU64::new(0f64.to_bits()),
+        };
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        self.bucket_counts[bucket].fetch_add(1, Ordering::Relaxed);
+
+        // There is no atomic f64, so we add to the sum with a compare-and-swap loop.
+        let _ = self.sum_bits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + value).to_bits())
+        });
+    }
+
+    fn count(&self) -> u64 {
+        return self.bucket_counts.iter().map(|count| count.load(Ordering::Relaxed)).sum();
+    }
+
+    fn sum(&self) -> f64 {
+        return f64::from_bits(self.sum_bits.load(Ordering::Relaxed));
+    }
+}
+
+/// A set of metrics of the same kind, distinguished by their label values.
+pub struct Family<M> {
+    label_names: Vec<&'static str>,
+    // Keyed by label values, in the same order as the label names.
+    metrics: Mutex<HashMap<Vec<String>, M>>,
+    // Creates the metric for a new set of label values.
+    create: fn() -> M,
+}
+
+impl<M> Family<M> {
+    pub fn new(label_names: &[&'static str], create: fn() -> M) -> Family<M> {
+        return Family { label_names: label_names.to_vec(), metrics: Mutex::new(HashMap::new()), create };
+    }
+
+    /// Runs the given function on the metric with the given label values, creating it if needed.
+    pub fn with<F: FnOnce(&M)>(&self, label_values: &[&str], f: F) {
+        if let Ok(mut metrics) = self.metrics.lock() {
+            let key: Vec<String> = label_values.iter().map(|value| value.to_string()).collect();
+            let create = self.create;
+            f(metrics.entry(key).or_insert_with(create));
+        }
+    }
+
+    /// Returns the metrics in a stable order, with their formatted labels.
+    fn sorted_entries<T, F: Fn(&M) -> T>(&self, read: F) -> Vec<(String, T)> {
+        let mut entries = match self.metrics.lock() {
+            Err(_) => Vec::new(),
+            Ok(metrics) => metrics.iter()
+                .map(|(label_values, metric)| (format_labels(&self.label_names, label_values), read(metric)))
+                .collect()
+        };
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        return entries;
+    }
+}
+
+/// The metrics recorded by the server.
+pub struct Metrics {
+    pub http_requests_total: Family<Counter>,
+    pub http_request_duration_seconds: Family<Histogram>,
+    pub http_parse_errors_total: Counter,
+    pub rate_limited_total: Family<Counter>,
+    pub connections_active: Gauge,
+    pub connections_total: Counter,
+    pub handler_threads_active: Gauge,
+    pub bytes_received_total: Counter,
+    pub bytes_sent_total: Counter,
+    pub websocket_connections_active: Gauge,
+    pub websocket_messages_total: Family<Counter>,
+    pub event_stream_subscribers_active: Gauge,
+    pub events_published_total: Counter,
+    pub db_pool_connections_open: Gauge,
+    pub db_pool_connections_idle: Gauge,
+    pub db_pool_checkout_timeouts_total: Counter,
+    pub db_pool_connect_failures_total: Counter,
+    pub db_pool_validation_failures_total: Counter,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        return Metrics {
+            http_requests_total: Family::new(&["route", "method", "status"], Counter::default),
+            http_request_duration_seconds: Family::new(&["route", "method"], || Histogram::new(&LATENCY_BUCKETS)),
+            http_parse_errors_total: Counter::default(),
+            rate_limited_total: Family::new(&["reason"], Counter::default),
+            connections_active: Gauge::default(),
+            connections_total: Counter::default(),
+            handler_threads_active: Gauge::default(),
+            bytes_received_total: Counter::default(),
+            bytes_sent_total: Counter::default(),
+            websocket_connections_active: Gauge::default(),
+            websocket_messages_total: Family::new(&["direction"], Counter::default),
+            event_stream_subscribers_active: Gauge::default(),
+            events_published_total: Counter::default(),
+            db_pool_connections_open: Gauge::default(),
+            db_pool_connections_idle: Gauge::default(),
+            db_pool_checkout_timeouts_total: Counter::default(),
+            db_pool_connect_failures_total: Counter::default(),
+            db_pool_validation_failures_total: Counter::default(),
+        };
+    }
+
+    /// Records a handled HTTP request.
+    pub fn record_request(&self, route: &str, method: &str, status: u16, latency: Duration) {
+        let method = if KNOWN_METHODS.contains(&method) { method } else { OTHER_METHOD };
+        self.http_requests_total.with(&[route, method, &status.to_string()], |counter| counter.inc());
+        self.http_request_duration_seconds.with(&[route, method], |histogram| histogram.observe(latency.as_secs_f64()));
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        write_header(&mut output, "http_requests_total", "counter", "HTTP requests handled, by route, method and status.");
+        for (labels, value) in self.http_requests_total.sorted_entries(Counter::get) {
+            let _ = writeln!(output, "http_requests_total{} {}", labels, value);
+        }
+
+        write_header(&mut output, "http_request_duration_seconds", "histogram", "Time taken to handle HTTP requests.");
+        let histograms = self.http_request_duration_seconds.sorted_entries(|histogram| {
+            let counts: Vec<u64> = histogram.bucket_counts.iter().map(|count| count.load(Ordering::Relaxed)).collect();
+            (histogram.bounds.clone(), counts, histogram.count(), histogram.sum())
+        });
+        for (labels, (bounds, counts, count, sum)) in histograms {
+            write_histogram(&mut output, "http_request_duration_seconds", &labels, &bounds, &counts, count, sum);
+        }
+
+        write_simple(&mut output, "http_parse_errors_total", "counter", "HTTP requests that could not be parsed.", self.http_parse_errors_total.get() as i64);
+
+        write_header(&mut output, "rate_limited_total", "counter", "Connections and requests rejected by rate limiting, by reason.");
+        for (labels, value) in self.rate_limited_total.sorted_entries(Counter::get) {
+            let _ = writeln!(output, "rate_limited_total{} {}", labels, value);
+        }
+
+        write_simple(&mut output, "connections_active", "gauge", "Connections currently being handled.", self.connections_active.get());
+        write_simple(&mut output, "connections_total", "counter", "Connections accepted.", self.connections_total.get() as i64);
+        write_simple(&mut output, "handler_threads_active", "gauge", "Threads currently handling a connection.", self.handler_threads_active.get());
+        write_simple(&mut output, "bytes_received_total", "counter", "Bytes read from clients.", self.bytes_received_total.get() as i64);
+        write_simple(&mut output, "bytes_sent_total", "counter", "Bytes written to clients.", self.bytes_sent_total.get() as i64);
+        write_simple(&mut output, "websocket_connections_active", "gauge", "WebSocket connections currently open.", self.websocket_connections_active.get());
+
+        write_header(&mut output, "websocket_messages_total", "counter", "WebSocket messages, by direction.");
+        for (labels, value) in self.websocket_messages_total.sorted_entries(Counter::get) {
+            let _ = writeln!(output, "websocket_messages_total{} {}", labels, value);
+        }
+        write_simple(&mut output, "event_stream_subscribers_active", "gauge", "Event streams currently open.", self.event_stream_subscribers_active.get());
+        write_simple(&mut output, "events_published_total", "counter", "Events published to event stream hubs.", self.events_published_total.get() as i64);
+        write_simple(&mut output, "db_pool_connections_open", "gauge", "Database connections open, including those in use.", self.db_pool_connections_open.get());
+        write_simple(&mut output, "db_pool_connections_idle", "gauge", "Database connections waiting to be used.", self.db_pool_connections_idle.get());
+        write_simple(&mut output, "db_pool_checkout_timeouts_total", "counter", "Requests that gave up waiting for a database connection.", self.db_pool_checkout_timeouts_total.get() as i64);
+        write_simple(&mut output, "db_pool_connect_failures_total", "counter", "Failed attempts to connect to the database.", self.db_pool_connect_failures_total.get() as i64);
+        write_simple(&mut output, "db_pool_validation_failures_total", "counter", "Idle database connections found broken when checked out.", self.db_pool_validation_failures_total.get() as i64);
+
+        if let Some(process_threads) = process_thread_count() {
+            write_simple(&mut output, "process_threads", "gauge", "Threads in the server process.", process_threads);
+        }
+
+        return output;
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        return Metrics::new();
+    }
+}
+
+fn write_header(output: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+}
+
+fn write_simple(output: &mut String, name: &str, metric_type: &str, help: &str, value: i64) {
+    write_header(output, name, metric_type, help);
+    let _ = writeln!(output, "{} {}", name, value);
+}
+
+/// Writes the cumulative buckets, sum and count of a histogram.
+fn write_histogram(output: &mut String, name: &str, labels: &str, bounds: &[f64], counts: &[u64], count: u64, sum: f64) {
+    // We insert the `le` label alongside any existing labels.
+    let bucket_labels = |le: &str| match labels {
+        "" => format!("{{le=\"{}\"}}", le),
+        labels => format!("{},le=\"{}\"}}", &labels[..labels.len() - 1], le)
+    };
+
+    let mut cumulative = 0;
+    for (bound, bucket_count) in bounds.iter().zip(counts.iter()) {
+        cumulative += bucket_count;
+        let _ = writeln!(output, "{}_bucket{} {}", name, bucket_labels(&bound.to_string()), cumulative);
+    }
+    let _ = writeln!(output, "{}_bucket{} {}", name, bucket_labels("+Inf"), count);
+    let _ = writeln!(output, "{}_sum{} {}", name, labels, sum);
+    let _ = writeln!(output, "{}_count{} {}", name, labels, count);
+}
+
+/// Formats label names and values as `{name="value",...}`, escaping the values.
+fn format_labels(label_names: &[&str], label_values: &[String]) -> String {
+    if label_names.is_empty() {
+        return String::new();
+    }
+
+    let pairs: Vec<String> = label_names.iter().zip(label_values.iter())
+        .map(|(name, value)| format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect();
+    return format!("{{{}}}", pairs.join(","));
+}
+
+/// Returns the number of threads in this process, where the platform exposes it.
+fn process_thread_count() -> Option<i64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("Threads:"))?;
+    return line["Threads:".len()..].trim().parse().ok();
+}
+
+/// A reader that counts the bytes read from a client.
+pub struct CountingReader<R> {
+    inner: R
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> CountingReader<R> {
+        return CountingReader { inner };
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        global().bytes_received_total.inc_by(bytes_read as u64);
+        return Ok(bytes_read);
+    }
+}
+
+/// A writer that counts the bytes written to a client.
+pub struct CountingWriter<W> {
+    inner: W
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> CountingWriter<W> {
+        return CountingWriter { inner };
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let bytes_written = self.inner.write(buf)?;
+        global().bytes_sent_total.inc_by(bytes_written as u64);
+        return Ok(bytes_written);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+/// A handler that exposes the server's metrics in the Prometheus text format, for use on an
+/// admin listener.
+pub struct MetricsHandler {
+    // The path metrics are served on, e.g. `/metrics`.
+    metrics_path: String
+}
+
+impl MetricsHandler {
+    pub fn new(metrics_path: &str) -> MetricsHandler {
+        return MetricsHandler { metrics_path: metrics_path.into() };
+    }
+}
+
+impl Handler for MetricsHandler {
+    /// Serves the metrics on the metrics path, and 404s elsewhere.
+    fn handle<R: BufRead, W: Write>(&self, mut reader: R, writer: W, connection: &ConnectionInfo) -> Result<Option<Upgrade>> {
+        let http_request = HttpHandler::read_http_request(&mut reader, connection);
+
+        match http_request {
+            Err(e) => { HttpHandler::write_http_error_response(writer, &e)?; }
+            Ok(http_request) if http_request.request_uri == self.metrics_path => {
+                HttpHandler::write_http_body_response(writer, "200 OK", PROMETHEUS_CONTENT_TYPE, &global().render())?;
+            }
+            Ok(http_request) => { HttpHandler::write_http_404_response(writer, &http_request.request_uri)?; }
+        }
+
+        return Ok(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, BufWriter};
+    use std::str::from_utf8;
+    use std::time::Duration;
+
+    use crate::handler::{ConnectionInfo, Handler};
+    use crate::metrics::{Metrics, MetricsHandler};
+
+    #[test]
+    fn metrics_render_in_prometheus_format() {
+        let metrics = Metrics::new();
+        metrics.record_request("/", "GET", 200, Duration::from_millis(250));
+        metrics.record_request("/", "GET", 200, Duration::from_millis(500));
+        metrics.record_request("unmatched", "GET", 404, Duration::from_millis(1));
+        metrics.http_parse_errors_total.inc();
+        metrics.connections_active.inc();
+        metrics.bytes_sent_total.inc_by(512);
+
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("# TYPE http_requests_total counter\n\
+            http_requests_total{route=\"/\",method=\"GET\",status=\"200\"} 2\n\
+            http_requests_total{route=\"unmatched\",method=\"GET\",status=\"404\"} 1\n"));
+        assert!(rendered.contains("# TYPE http_request_duration_seconds histogram\n"));
+        assert!(rendered.contains("http_request_duration_seconds_bucket{route=\"/\",method=\"GET\",le=\"0.1\"} 0\n\
+            http_request_duration_seconds_bucket{route=\"/\",method=\"GET\",le=\"0.25\"} 1\n\
+            http_request_duration_seconds_bucket{route=\"/\",method=\"GET\",le=\"0.5\"} 2\n"));
+        assert!(rendered.contains("http_request_duration_seconds_bucket{route=\"/\",method=\"GET\",le=\"+Inf\"} 2\n\
+            http_request_duration_seconds_sum{route=\"/\",method=\"GET\"} 0.75\n\
+            http_request_duration_seconds_count{route=\"/\",method=\"GET\"} 2\n"));
+        assert!(rendered.contains("\nhttp_parse_errors_total 1\n"));
+        assert!(rendered.contains("\nconnections_active 1\n"));
+        assert!(rendered.contains("\nbytes_sent_total 512\n"));
+    }
+
+    #[test]
+    fn metrics_escape_label_values() {
+        let metrics = Metrics::new();
+        metrics.record_request("/\"quoted\"\\", "GET", 200, Duration::from_millis(1));
+
+        assert!(metrics.render().contains("route=\"/\\\"quoted\\\"\\\\\""));
+    }
+
+    fn handle(request: &str) -> String {
+        let handler = MetricsHandler::new("/metrics");
+
+        let mut response = Vec::<u8>::new();
+        let reader = BufReader::new(request.as_bytes());
+        let writer = BufWriter::new(&mut response);
+
+        handler.handle(reader, writer, &ConnectionInfo::default()).unwrap();
+
+        return from_utf8(&response).unwrap().into();
+    }
+
+    #[test]
+    fn metrics_handler_serves_metrics_on_metrics_path() {
+        let response = handle("GET /metrics HTTP/1.1\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("# TYPE connections_active gauge\n"));
+    }
+
+    #[test]
+    fn metrics_handler_rejects_other_paths() {
+        let response = handle("GET / HTTP/1.1\r\n\r\n");
+
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::handler::{ConnectionInfo, HttpRequest, HttpResponse};
+use crate::servererror::Result;
+
+/// A layer wrapped around an HTTP handler's routes. A middleware can inspect or modify the request
+/// before passing it on with `next.run`, inspect or modify the response that comes back, or
+/// short-circuit by returning its own response without calling `next` at all.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut HttpRequest, connection: &ConnectionInfo, next: Next) -> Result<HttpResponse>;
+}
+
+/// The remainder of a middleware chain, ending in the route itself.
+pub struct Next<'a> {
+    layers: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Fn(&HttpRequest) -> Result<HttpResponse>,
+}
+
+impl<'a> Next<'a> {
+    /// Passes the request to the next layer, or to the route if no layers remain.
+    pub fn run(self, request: &mut HttpRequest, connection: &ConnectionInfo) -> Result<HttpResponse> {
+        return match self.layers.split_first() {
+            None => (self.endpoint)(request),
+            Some((layer, rest)) => layer.handle(request, connection, Next { layers: rest, endpoint: self.endpoint })
+        };
+    }
+}
+
+/// The middleware applied to every route, and to specific routes. Global layers run first, in the
+/// order they were added, followed by the layers for the requested route, in the order they were
+/// added. Responses pass back through the layers in the reverse order.
+#[derive(Clone, Default)]
+pub struct MiddlewareChain {
+    global: Vec<Arc<dyn Middleware>>,
+    // Keyed by request URI.
+    routes: HashMap<String, Vec<Arc<dyn Middleware>>>,
+}
+
+impl MiddlewareChain {
+    pub fn new() -> MiddlewareChain {
+        return MiddlewareChain::default();
+    }
+
+    /// Adds a layer that wraps every route.
+    pub fn add<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.global.push(Arc::new(middleware));
+    }
+
+    /// Adds a layer that wraps only the given route.
+    pub fn add_for_route<M: Middleware + 'static>(&mut self, route: &str, middleware: M) {
+        self.routes.entry(route.into()).or_default().push(Arc::new(middleware));
+    }
+
+    /// Runs the request through the layers that apply to it, and then through the endpoint.
+    pub fn run(&self, request: &mut HttpRequest, connection: &ConnectionInfo,
+               endpoint: &dyn Fn(&HttpRequest) -> Result<HttpResponse>) -> Result<HttpResponse> {
+        let mut layers = self.global.clone();
+        if let Some(route_layers) = self.routes.get(&request.request_uri) {
+            layers.extend(route_layers.iter().cloned());
+        }
+
+        return Next { layers: &layers, endpoint }.run(request, connection);
+    }
+}
+
+/// Adds the given headers to every response that does not already set them.
+pub struct DefaultHeaders {
+    headers: Vec<(String, String)>,
+}
+
+impl DefaultHeaders {
+    pub fn new(headers: &[(&str, &str)]) -> DefaultHeaders {
+        let headers = headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        return DefaultHeaders { headers };
+    }
+}
+
+impl Middleware for DefaultHeaders {
+    fn handle(&self, request: &mut HttpRequest, connection: &ConnectionInfo, next: Next) -> Result<HttpResponse> {
+        let mut response = next.run(request, connection)?;
+
+        for (name, value) in self.headers.iter() {
+            if response.header(name).is_none() {
+                response.set_header(name, value);
+            }
+        }
+
+        return Ok(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, BufWriter};
+    use std::str::from_utf8;
+    use std::sync::{Arc, Mutex};
+
+    use crate::handler::{ConnectionInfo, HttpHandler, HttpRequest, HttpResponse};
+    use crate::middleware::{DefaultHeaders, Middleware, MiddlewareChain, Next};
+    use crate::servererror::Result;
+
+    /// Records when the request reaches it and when the response passes back through it.
+    struct Recorder {
+        name: &'static str,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Recorder {
+        fn handle(&self, request: &mut HttpRequest, connection: &ConnectionInfo, next: Next) -> Result<HttpResponse> {
+            self.events.lock().unwrap().push(format!("{} before", self.name));
+            let response = next.run(request, connection);
+            self.events.lock().unwrap().push(format!("{} after", self.name));
+            return response;
+        }
+    }
+
+    /// Responds with a 403 without calling the rest of the chain.
+    struct Forbid;
+
+    impl Middleware for Forbid {
+        fn handle(&self, _request: &mut HttpRequest, _connection: &ConnectionInfo, _next: Next) -> Result<HttpResponse> {
+            return Ok(HttpResponse::new("403 FORBIDDEN", "text/plain", "Forbidden".into()));
+        }
+    }
+
+    /// Adds a header to the request before passing it on.
+    struct TagRequest;
+
+    impl Middleware for TagRequest {
+        fn handle(&self, request: &mut HttpRequest, connection: &ConnectionInfo, next: Next) -> Result<HttpResponse> {
+            request.headers.insert("x-tag".into(), "tagged".into());
+            return next.run(request, connection);
+        }
+    }
+
+    /// Responds with the request's URI and X-Tag header.
+    fn echo(request: &HttpRequest) -> Result<HttpResponse> {
+        let body = format!("{} {}", request.request_uri, request.header("X-Tag").unwrap_or("-"));
+        return Ok(HttpResponse::new("200 OK", "text/plain", body.into()));
+    }
+
+    /// Reads the request, runs it through the chain to the echo endpoint, and writes the response.
+    fn handle(chain: &MiddlewareChain, request: &str) -> String {
+        let mut reader = BufReader::new(request.as_bytes());
+        let mut response = Vec::<u8>::new();
+
+        let mut http_request = HttpHandler::read_http_request(&mut reader, &ConnectionInfo::default()).unwrap();
+        let http_response = chain.run(&mut http_request, &ConnectionInfo::default(), &echo).unwrap();
+        http_response.write(BufWriter::new(&mut response)).unwrap();
+
+        return from_utf8(&response).unwrap().into();
+    }
+
+    #[test]
+    fn empty_chain_calls_endpoint() {
+        let response = handle(&MiddlewareChain::new(), "GET /path HTTP/1.1\r\n\r\n");
+
+        assert_eq!(response, "HTTP/1.1 200 OK\r\n\
+            Content-Length: 7\r\n\
+            Content-Type: text/plain\r\n\
+            Connection: Closed\r\n\r\n\
+            /path -");
+    }
+
+    #[test]
+    fn layers_run_in_order() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = MiddlewareChain::new();
+        chain.add_for_route("/path", Recorder { name: "route", events: events.clone() });
+        chain.add(Recorder { name: "first", events: events.clone() });
+        chain.add(Recorder { name: "second", events: events.clone() });
+
+        handle(&chain, "GET /path HTTP/1.1\r\n\r\n");
+
+        assert_eq!(*events.lock().unwrap(), vec![
+            "first before", "second before", "route before", "route after", "second after", "first after"
+        ]);
+    }
+
+    #[test]
+    fn route_layers_only_apply_to_their_route() {
+        let mut chain = MiddlewareChain::new();
+        chain.add_for_route("/private", Forbid);
+
+        assert!(handle(&chain, "GET /private HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 403 FORBIDDEN\r\n"));
+        assert!(handle(&chain, "GET /public HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn layers_can_short_circuit() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = MiddlewareChain::new();
+        chain.add(Forbid);
+        chain.add(Recorder { name: "inner", events: events.clone() });
+
+        let response = handle(&chain, "GET /path HTTP/1.1\r\n\r\n");
+
+        assert!(response.ends_with("Forbidden"));
+        assert!(events.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn layers_can_modify_requests_and_responses() {
+        let mut chain = MiddlewareChain::new();
+        chain.add(DefaultHeaders::new(&[("X-Frame-Options", "DENY"), ("Content-Type", "text/html")]));
+        chain.add(TagRequest);
+
+        let response = handle(&chain, "GET /path HTTP/1.1\r\n\r\n");
+
+        assert_eq!(response, "HTTP/1.1 200 OK\r\n\
+            Content-Length: 12\r\n\
+            Content-Type: text/plain\r\n\
+            X-Frame-Options: DENY\r\n\
+            Connection: Closed\r\n\r\n\
+            /path tagged");
+    }
+}
@@ -0,0 +1,173 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::ledger::{Block, BlockHeader};
+
+// How many nonces each thread tries between checks for whether to stop.
+const STOP_CHECK_INTERVAL: u64 = 1024;
+
+/// Mines blocks by searching for a nonce that gives the block's hash as many leading zero bits
+/// as its difficulty requires. The nonces are split between threads, each trying every nth one.
+#[derive(Clone, Debug)]
+pub struct Miner {
+    threads: usize,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Miner {
+        return Miner { threads: threads.max(1) };
+    }
+
+    /// Mines the block on the miner's threads, blocking until one finds a nonce. Returns the block
+    /// with that nonce, or None if the search is cancelled first.
+    pub fn mine(&self, template: &Block, cancelled: &AtomicBool) -> Option<Block> {
+        // Set once any thread finds a nonce, to stop the others.
+        let found = AtomicBool::new(false);
+        let step = self.threads as u64;
+
+        return thread::scope(|scope| {
+            let searches: Vec<_> = (0..step).map(|first_nonce| {
+                let found = &found;
+                scope.spawn(move || Miner::search(&template.header, first_nonce, step, cancelled, found))
+            }).collect();
+
+            // Threads that lose the race return None, as do all of them if the search is cancelled.
+            let header = searches.into_iter().filter_map(|search| search.join().unwrap()).next()?;
+            return Some(Block { header, transactions: template.transactions.clone() });
+        });
+    }
+
+    /// Mines the block on background threads, returning a job that can be cancelled, e.g. when a
+    /// competing block arrives.
+    pub fn start(&self, template: Block) -> MiningJob {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let miner = self.clone();
+        let cancelled_by_job = cancelled.clone();
+        let thread = thread::spawn(move || miner.mine(&template, &cancelled_by_job));
+        return MiningJob { cancelled, thread: Some(thread) };
+    }
+
+    /// Tries nonces from the first, stepping by the given amount, until one meets the header's
+    /// difficulty, the nonces run out, or the search is to stop. Only the header is hashed, so
+    /// the transactions play no part.
+    fn search(template: &BlockHeader, first_nonce: u64, step: u64, cancelled: &AtomicBool, found: &AtomicBool) -> Option<BlockHeader> {
+        let mut header = template.clone();
+        let mut nonce = first_nonce;
+        let mut tries: u64 = 0;
+
+        loop {
+            if tries % STOP_CHECK_INTERVAL == 0 && (cancelled.load(Ordering::Relaxed) || found.load(Ordering::Relaxed)) {
+                return None;
+            }
+
+            header.nonce = nonce;
+            header.hash = header.calculate_hash();
+            if header.meets_difficulty() {
+                found.store(true, Ordering::Relaxed);
+                return Some(header);
+            }
+
+            nonce = nonce.checked_add(step)?;
+            tries += 1;
+        }
+    }
+}
+
+/// A block being mined in the background. Dropping the job cancels it.
+pub struct MiningJob {
+    cancelled: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Option<Block>>>,
+}
+
+impl MiningJob {
+    /// Stops the search. The job then finishes without a block, unless it has already found one.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        return self.thread.as_ref().map(|thread| thread.is_finished()).unwrap_or(true);
+    }
+
+    /// Waits for the job to finish, returning the mined block, or None if it was cancelled first.
+    pub fn wait(mut self) -> Option<Block> {
+        return self.thread.take().and_then(|thread| thread.join().ok()).flatten();
+    }
+}
+
+impl Drop for MiningJob {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    use crate::ledger::{leading_zero_bits, Block, Blockchain, ChainConfig};
+    use crate::mining::Miner;
+    use crate::transaction::Transaction;
+    use crate::wallet::Address;
+
+    fn chain(initial_difficulty: u32) -> Blockchain {
+        return Blockchain::new(ChainConfig { initial_difficulty, ..ChainConfig::default() });
+    }
+
+    #[test]
+    fn miners_find_the_first_nonce_meeting_the_difficulty() {
+        let template = chain(8).next_block(10, vec![Transaction::coinbase(Address::from_public_key(b"miner"), 50, 1)]);
+
+        let block = Miner::new(1).mine(&template, &AtomicBool::new(false)).unwrap();
+
+        assert!(leading_zero_bits(&block.header.hash) >= 8);
+        assert_eq!(block.header.hash, block.header.calculate_hash());
+        // Every earlier nonce falls short.
+        for nonce in 0..block.header.nonce {
+            let earlier = Block::new(block.header.index, block.header.timestamp, block.header.previous_hash, block.header.difficulty, nonce, block.transactions.clone());
+            assert!(!earlier.header.meets_difficulty());
+        }
+    }
+
+    #[test]
+    fn mined_blocks_extend_the_chain() {
+        let mut chain = chain(10);
+        let miner = Miner::new(4);
+
+        for index in 1..4 {
+            let block = miner.mine(&chain.next_block(index * 60, Vec::new()), &AtomicBool::new(false)).unwrap();
+            chain.append(block).unwrap();
+        }
+
+        assert_eq!(chain.len(), 4);
+        assert!(chain.verify().is_ok());
+    }
+
+    #[test]
+    fn mining_stops_when_cancelled() {
+        // No nonce is likely to give this many leading zero bits.
+        let template = chain(200).next_block(10, Vec::new());
+
+        let job = Miner::new(2).start(template);
+        sleep(Duration::from_millis(20));
+        assert!(!job.is_finished());
+
+        let start = Instant::now();
+        job.cancel();
+        assert_eq!(job.wait(), None);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn background_jobs_return_the_mined_block() {
+        let template = chain(8).next_block(10, Vec::new());
+
+        let block = Miner::new(2).start(template.clone()).wait().unwrap();
+
+        assert!(block.header.meets_difficulty());
+        assert_eq!((block.header.index, block.header.previous_hash, block.header.difficulty), (template.header.index, template.header.previous_hash, 8));
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::{Authentication, Authenticator};
+use crate::handler::{ConnectionInfo, HttpRequest, HttpResponse};
+use crate::metrics;
+use crate::middleware::{Middleware, Next};
+use crate::servererror::Result;
+
+const ERROR_PAGE_429: &str = "./src/html/429.html";
+// The wait suggested to clients rejected for having too many open connections, since we cannot
+// know when one will close.
+const CONNECTION_LIMIT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// A sustained rate, plus a burst that may be used up all at once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/// Limits applied to each client IP as its connections are accepted.
+#[derive(Clone)]
+pub struct ClientLimits {
+    // How quickly each client may open connections. None disables the check.
+    pub connection_rate: Option<RateLimit>,
+    // How many connections each client may have open at once. None disables the check.
+    pub max_connections_per_ip: Option<usize>,
+    // Clients that are never limited, e.g. load balancers and probes.
+    pub allowlist: Vec<IpAddr>,
+    // The most clients whose rates are tracked at once. Beyond this, the least recently seen
+    // client is forgotten.
+    pub max_tracked_clients: usize,
+    // How long a client must be idle before it is forgotten.
+    pub idle_expiry: Duration,
+}
+
+impl Default for ClientLimits {
+    fn default() -> ClientLimits {
+        return ClientLimits {
+            connection_rate: None,
+            max_connections_per_ip: None,
+            allowlist: Vec::new(),
+            max_tracked_clients: 10_000,
+            idle_expiry: Duration::from_secs(600),
+        };
+    }
+}
+
+/// The tokens remaining to a single client.
+struct TokenBucket {
+    tokens: f64,
+    last_seen: Instant,
+}
+
+impl TokenBucket {
+    /// Refills the bucket for the time elapsed, then takes a token if there is one. Otherwise,
+    /// returns how long until there will be.
+    fn take(&mut self, limit: RateLimit, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.last_seen).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.last_seen = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        return Some(Duration::from_secs_f64((1.0 - self.tokens) / limit.per_second));
+    }
+}
+
+/// Token buckets keyed by client. Memory is bounded: idle clients are forgotten, and once too
+/// many clients are tracked, the least recently seen is forgotten to make room.
+pub struct RateLimiter {
+    limit: RateLimit,
+    max_tracked_clients: usize,
+    idle_expiry: Duration,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit, max_tracked_clients: usize, idle_expiry: Duration) -> RateLimiter {
+        return RateLimiter { limit, max_tracked_clients, idle_expiry, buckets: Mutex::new(HashMap::new()) };
+    }
+
+    /// Takes a token for the given client. Returns how long the client should wait before
+    /// retrying if it has been limited.
+    pub fn check(&self, client: &str) -> Result<Option<Duration>> {
+        return self.check_at(client, Instant::now());
+    }
+
+    fn check_at(&self, client: &str, now: Instant) -> Result<Option<Duration>> {
+        let mut buckets = self.buckets.lock()?;
+
+        if !buckets.contains_key(client) && buckets.len() >= self.max_tracked_clients {
+            let idle_expiry = self.idle_expiry;
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.last_seen) < idle_expiry);
+
+            if buckets.len() >= self.max_tracked_clients {
+                let least_recent = buckets.iter()
+                    .min_by_key(|(_, bucket)| bucket.last_seen)
+                    .map(|(client, _)| client.clone());
+                if let Some(least_recent) = least_recent {
+                    buckets.remove(&least_recent);
+                }
+            }
+        }
+
+        let limit = self.limit;
+        let bucket = buckets.entry(client.into())
+            .or_insert_with(|| TokenBucket { tokens: limit.burst as f64, last_seen: now });
+        return Ok(bucket.take(limit, now));
+    }
+
+    #[cfg(test)]
+    fn tracked_clients(&self) -> usize {
+        return self.buckets.lock().unwrap().len();
+    }
+}
+
+/// Counts each client's open connections.
+pub struct ConnectionCounter {
+    max_per_ip: usize,
+    // Clients are removed once their last connection closes, so this only holds open connections.
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionCounter {
+    pub fn new(max_per_ip: usize) -> ConnectionCounter {
+        return ConnectionCounter { max_per_ip, counts: Arc::new(Mutex::new(HashMap::new())) };
+    }
+
+    /// Counts a new connection from the client, unless it already has too many open. The
+    /// connection is counted until the returned permit is dropped.
+    pub fn try_acquire(&self, ip: IpAddr) -> Result<Option<ConnectionPermit>> {
+        let mut counts = self.counts.lock()?;
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return Ok(None);
+        }
+
+        *count += 1;
+        return Ok(Some(ConnectionPermit { ip, counts: self.counts.clone() }));
+    }
+}
+
+/// Represents one of a client's open connections.
+pub struct ConnectionPermit {
+    ip: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Ok(mut counts) = self.counts.lock() {
+            if let Some(count) = counts.get_mut(&self.ip) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(&self.ip);
+                }
+            }
+        }
+    }
+}
+
+/// Whether a new connection may be handled.
+pub enum Admission {
+    // Any permit must be held for as long as the connection is open.
+    Admitted(Option<ConnectionPermit>),
+    Rejected { retry_after: Duration },
+}
+
+/// Applies the client limits to new connections.
+pub struct ClientLimiter {
+    allowlist: Vec<IpAddr>,
+    rate_limiter: Option<RateLimiter>,
+    connection_counter: Option<ConnectionCounter>,
+}
+
+impl ClientLimiter {
+    pub fn new(limits: &ClientLimits) -> ClientLimiter {
+        return ClientLimiter {
+            allowlist: limits.allowlist.clone(),
+            rate_limiter: limits.connection_rate
+                .map(|rate| RateLimiter::new(rate, limits.max_tracked_clients, limits.idle_expiry)),
+            connection_counter: limits.max_connections_per_ip.map(ConnectionCounter::new),
+        };
+    }
+
+    /// Decides whether to handle a new connection from the given client.
+    pub fn admit(&self, ip: IpAddr) -> Result<Admission> {
+        if self.allowlist.contains(&ip) {
+            return Ok(Admission::Admitted(None));
+        }
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            if let Some(retry_after) = rate_limiter.check(&ip.to_string())? {
+                metrics::global().rate_limited_total.with(&["rate"], |counter| counter.inc());
+                return Ok(Admission::Rejected { retry_after });
+            }
+        }
+
+        return match &self.connection_counter {
+            None => Ok(Admission::Admitted(None)),
+            Some(connection_counter) => match connection_counter.try_acquire(ip)? {
+                Some(permit) => Ok(Admission::Admitted(Some(permit))),
+                None => {
+                    metrics::global().rate_limited_total.with(&["connections"], |counter| counter.inc());
+                    Ok(Admission::Rejected { retry_after: CONNECTION_LIMIT_RETRY_AFTER })
+                }
+            }
+        };
+    }
+}
+
+/// Middleware that rate-limits requests by the principal their API key authenticates, or by
+/// client IP for requests without a valid key. Keys are checked first, so that clients cannot get
+/// fresh limits, or push real clients out of the tracked ones, by sending made-up keys. Added for
+/// a specific route, it limits that route alone.
+pub struct RateLimitLayer {
+    limiter: RateLimiter,
+    // Checks API keys, if requests are limited by key.
+    key_authenticator: Option<Arc<dyn Authenticator>>,
+    allowlist: Vec<IpAddr>,
+}
+
+impl RateLimitLayer {
+    pub fn new(limit: RateLimit, key_authenticator: Option<Arc<dyn Authenticator>>, limits: &ClientLimits) -> RateLimitLayer {
+        return RateLimitLayer {
+            limiter: RateLimiter::new(limit, limits.max_tracked_clients, limits.idle_expiry),
+            key_authenticator,
+            allowlist: limits.allowlist.clone(),
+        };
+    }
+}
+
+impl Middleware for RateLimitLayer {
+    fn handle(&self, request: &mut HttpRequest, connection: &ConnectionInfo, next: Next) -> Result<HttpResponse> {
+        if let Some(peer_address) = connection.peer_address {
+            if self.allowlist.contains(&peer_address.ip()) {
+                return next.run(request, connection);
+            }
+        }
+
+        let authentication = self.key_authenticator.as_ref().map(|authenticator| authenticator.authenticate(request));
+        let client = match authentication {
+            Some(Authentication::Authenticated(principal)) => format!("principal {}", principal.name),
+            _ => format!("ip {}", connection.peer_ip_string())
+        };
+
+        return match self.limiter.check(&client)? {
+            None => next.run(request, connection),
+            Some(retry_after) => {
+                metrics::global().rate_limited_total.with(&["requests"], |counter| counter.inc());
+                too_many_requests_response(retry_after)
+            }
+        };
+    }
+}
+
+/// A 429 response telling the client how many seconds to wait before retrying.
+pub fn too_many_requests_response(retry_after: Duration) -> Result<HttpResponse> {
+    let mut response = HttpResponse::from_file("429 TOO MANY REQUESTS", ERROR_PAGE_429)?;
+    let retry_after_secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    response.set_header("Retry-After", &retry_after_secs.to_string());
+    return Ok(response);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, BufWriter};
+    use std::net::IpAddr;
+    use std::str::from_utf8;
+    use std::time::{Duration, Instant};
+
+    use std::sync::Arc;
+
+    use crate::auth::TokenAuthenticator;
+    use crate::handler::{ConnectionInfo, HttpHandler, HttpRequest, HttpResponse};
+    use crate::middleware::MiddlewareChain;
+    use crate::ratelimit::{Admission, ClientLimiter, ClientLimits, RateLimit, RateLimitLayer, RateLimiter};
+    use crate::servererror::Result;
+
+    const LIMIT: RateLimit = RateLimit { per_second: 2.0, burst: 3 };
+
+    fn ip(address: &str) -> IpAddr {
+        return address.parse().unwrap();
+    }
+
+    #[test]
+    fn rate_limiter_allows_bursts_then_refills() {
+        let limiter = RateLimiter::new(LIMIT, 100, Duration::from_secs(60));
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at("client", start).unwrap(), None);
+        }
+        assert_eq!(limiter.check_at("client", start).unwrap(), Some(Duration::from_millis(500)));
+        // Other clients have their own buckets.
+        assert_eq!(limiter.check_at("other", start).unwrap(), None);
+
+        // Half a second refills one token.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check_at("client", later).unwrap(), None);
+        assert!(limiter.check_at("client", later).unwrap().is_some());
+    }
+
+    #[test]
+    fn rate_limiter_bounds_tracked_clients() {
+        let limiter = RateLimiter::new(LIMIT, 2, Duration::from_secs(60));
+        let start = Instant::now();
+
+        limiter.check_at("first", start).unwrap();
+        limiter.check_at("second", start + Duration::from_secs(1)).unwrap();
+        limiter.check_at("third", start + Duration::from_secs(2)).unwrap();
+        assert_eq!(limiter.tracked_clients(), 2);
+
+        // The least recently seen client was forgotten, and so starts with a full bucket.
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at("first", start + Duration::from_secs(3)).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn rate_limiter_expires_idle_clients() {
+        let limiter = RateLimiter::new(LIMIT, 2, Duration::from_secs(60));
+        let start = Instant::now();
+
+        limiter.check_at("first", start).unwrap();
+        limiter.check_at("second", start).unwrap();
+        limiter.check_at("third", start + Duration::from_secs(61)).unwrap();
+
+        assert_eq!(limiter.tracked_clients(), 1);
+    }
+
+    #[test]
+    fn client_limiter_caps_connections_per_ip() {
+        let limiter = ClientLimiter::new(&ClientLimits { max_connections_per_ip: Some(2), ..ClientLimits::default() });
+
+        let first = limiter.admit(ip("10.0.0.1")).unwrap();
+        let _second = limiter.admit(ip("10.0.0.1")).unwrap();
+        assert!(matches!(limiter.admit(ip("10.0.0.1")).unwrap(), Admission::Rejected { .. }));
+        assert!(matches!(limiter.admit(ip("10.0.0.2")).unwrap(), Admission::Admitted(Some(_))));
+
+        // Closing a connection frees up a slot.
+        drop(first);
+        assert!(matches!(limiter.admit(ip("10.0.0.1")).unwrap(), Admission::Admitted(Some(_))));
+    }
+
+    #[test]
+    fn client_limiter_skips_allowlisted_ips() {
+        let limits = ClientLimits {
+            connection_rate: Some(RateLimit { per_second: 1.0, burst: 1 }),
+            max_connections_per_ip: Some(1),
+            allowlist: vec![ip("10.0.0.1")],
+            ..ClientLimits::default()
+        };
+        let limiter = ClientLimiter::new(&limits);
+
+        for _ in 0..5 {
+            assert!(matches!(limiter.admit(ip("10.0.0.1")).unwrap(), Admission::Admitted(None)));
+        }
+        assert!(matches!(limiter.admit(ip("10.0.0.2")).unwrap(), Admission::Admitted(_)));
+        assert!(matches!(limiter.admit(ip("10.0.0.2")).unwrap(), Admission::Rejected { .. }));
+    }
+
+    fn ok(_request: &HttpRequest) -> Result<HttpResponse> {
+        return Ok(HttpResponse::new("200 OK", "text/plain", "OK".into()));
+    }
+
+    fn handle(chain: &MiddlewareChain, request: &str) -> String {
+        let mut reader = BufReader::new(request.as_bytes());
+        let mut response = Vec::<u8>::new();
+        let connection = ConnectionInfo { peer_address: Some("10.0.0.1:1234".parse().unwrap()), ..ConnectionInfo::default() };
+
+        let mut http_request = HttpHandler::read_http_request(&mut reader, &ConnectionInfo::default()).unwrap();
+        chain.run(&mut http_request, &connection, &ok).unwrap().write(BufWriter::new(&mut response)).unwrap();
+
+        return from_utf8(&response).unwrap().into();
+    }
+
+    #[test]
+    fn rate_limit_layer_limits_by_api_key() {
+        let mut authenticator = TokenAuthenticator::new("test", "X-API-Key");
+        authenticator.add_token("one", "alice");
+        authenticator.add_token("two", "bob");
+        authenticator.add_token("three", "bob");
+        let mut chain = MiddlewareChain::new();
+        let limit = RateLimit { per_second: 0.1, burst: 1 };
+        chain.add(RateLimitLayer::new(limit, Some(Arc::new(authenticator)), &ClientLimits::default()));
+
+        assert!(handle(&chain, "GET / HTTP/1.1\r\nX-API-Key: one\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(handle(&chain, "GET / HTTP/1.1\r\nX-API-Key: two\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+        // Requests without a key are limited by IP.
+        assert!(handle(&chain, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let response = handle(&chain, "GET / HTTP/1.1\r\nX-API-Key: one\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 429 TOO MANY REQUESTS\r\n"));
+        assert!(response.contains("\r\nRetry-After: 10\r\n"));
+        // Keys for the same principal share its limit.
+        assert!(handle(&chain, "GET / HTTP/1.1\r\nX-API-Key: three\r\n\r\n").starts_with("HTTP/1.1 429 TOO MANY REQUESTS\r\n"));
+    }
+
+    #[test]
+    fn rate_limit_layer_limits_unknown_api_keys_by_ip() {
+        let mut chain = MiddlewareChain::new();
+        let limit = RateLimit { per_second: 0.1, burst: 1 };
+        chain.add(RateLimitLayer::new(limit, Some(Arc::new(TokenAuthenticator::new("test", "X-API-Key"))), &ClientLimits::default()));
+
+        assert!(handle(&chain, "GET / HTTP/1.1\r\nX-API-Key: made-up\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+        // A different made-up key does not earn a fresh limit.
+        assert!(handle(&chain, "GET / HTTP/1.1\r\nX-API-Key: also-made-up\r\n\r\n").starts_with("HTTP/1.1 429 TOO MANY REQUESTS\r\n"));
+        assert!(handle(&chain, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 429 TOO MANY REQUESTS\r\n"));
+    }
+}
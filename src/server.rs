@@ -1,28 +1,85 @@
 use std::io::{ErrorKind::WouldBlock};
-use std::io::{BufReader, BufWriter};
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread::spawn;
-
-use crate::handler::{Handler, HttpHandler};
-use crate::servererror::Result;
+use std::thread::{JoinHandle, sleep, spawn};
+use std::time::{Duration, Instant};
+
+use crate::api::ApiRoutes;
+use crate::database::Database;
+use crate::eventstream::EventStreamRoutes;
+use crate::handler::{ConnectionInfo, Handler, HttpHandler, RedirectHandler};
+use crate::limits::{ConnectionLimits, TimeoutReader};
+use crate::logger;
+use crate::metrics::{self, CountingReader, CountingWriter, MetricsHandler};
+use crate::middleware::MiddlewareChain;
+use crate::ratelimit::{too_many_requests_response, Admission, ClientLimiter, ClientLimits, ConnectionPermit};
+use crate::servererror::{Result, ServerError};
+use crate::tls::TlsConfig;
+use crate::websocket::WebSocketRoutes;
 use std::collections::HashMap;
 
+// How often a graceful shutdown checks whether in-flight connections have finished.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Options controlling how a server accepts and serves connections.
+#[derive(Clone, Default)]
+pub struct ServerOptions {
+    // If set, TLS is terminated on each connection before it is passed to the handler.
+    pub tls_config: Option<TlsConfig>,
+    // The timeouts and size limits applied to each connection.
+    pub limits: ConnectionLimits,
+    // The middleware wrapped around the HTTP handler's routes.
+    pub middleware: MiddlewareChain,
+    // The routes the HTTP handler serves by code rather than by pages.
+    pub api_routes: ApiRoutes,
+    // The routes the HTTP handler upgrades to WebSocket connections.
+    pub websocket_routes: WebSocketRoutes,
+    // The routes the HTTP handler streams events on.
+    pub event_stream_routes: EventStreamRoutes,
+    // The per-client limits applied as connections are accepted.
+    pub client_limits: ClientLimits,
+}
+
 /// A TCP server.
 pub struct Server { }
 
 impl Server {
-    /// Listens for and handles incoming TCP connections on the given address. Does not block the
+    /// Listens for and handles incoming TCP connections on the given address, with the given
+    /// options, e.g. to serve HTTPS. Does not block the main thread.
+    pub fn start_with_options(port: &str, database: Arc<dyn Database>, routes: HashMap<String, String>, options: ServerOptions) -> Result<ServerHandle> {
+        let handler = HttpHandler::new(database, routes)
+            .with_middleware(options.middleware.clone())
+            .with_api_routes(options.api_routes.clone())
+            .with_websocket_routes(options.websocket_routes.clone())
+            .with_event_stream_routes(options.event_stream_routes.clone());
+        let server_handle = ServerInternal::start_with_options(port, handler, options)?;
+        return Ok(server_handle);
+    }
+
+    /// Listens for incoming HTTP connections on the given address, and serves the server's metrics
+    /// in the Prometheus text format on the given path. Intended to be kept off the public
+    /// network. Does not block the main thread.
+    pub fn start_admin(port: &str, metrics_path: &str) -> Result<ServerHandle> {
+        let handler = MetricsHandler::new(metrics_path);
+        let server_handle = ServerInternal::start(port, handler)?;
+        return Ok(server_handle);
+    }
+
+    /// Listens for incoming HTTP connections on the given address, and redirects each request to
+    /// the same path on the given HTTPS authority (e.g. `example.com:443`). Does not block the
     /// main thread.
-    pub fn start(port: &str, db_connection_string: &str, routes: HashMap<String, String>) -> Result<ServerHandle> {
-        let handler = HttpHandler::new(db_connection_string, routes)?;
+    pub fn start_redirect(port: &str, https_authority: &str) -> Result<ServerHandle> {
+        let handler = RedirectHandler::new(https_authority);
         let server_handle = ServerInternal::start(port, handler)?;
         return Ok(server_handle);
     }
 }
 
-/// The class wrapped by `Server` that allows a custom handler to be injected for testing.
+/// The class wrapped by `Server` that allows a custom handler to be injected, e.g. for testing or
+/// by the key-value store.
 pub struct ServerInternal {
 
 }
@@ -31,33 +88,79 @@ impl ServerInternal {
     /// Listens for and handles incoming TCP connections on the given port, using the handler
     /// provided. Does not block the main thread. Returns a handler for stopping the server.
     pub fn start<T: Handler + Sync + Send + 'static>(port: &str, handler: T) -> Result<ServerHandle> {
+        return ServerInternal::start_with_options(port, handler, ServerOptions::default());
+    }
+
+    /// As `start`, but terminates TLS on each incoming connection before passing it to the
+    /// handler.
+    #[cfg(test)]
+    pub fn start_tls<T: Handler + Sync + Send + 'static>(port: &str, handler: T, tls_config: TlsConfig) -> Result<ServerHandle> {
+        let options = ServerOptions { tls_config: Some(tls_config), ..ServerOptions::default() };
+        return ServerInternal::start_with_options(port, handler, options);
+    }
+
+    /// As `start`, but with the given options.
+    pub fn start_with_options<T: Handler + Sync + Send + 'static>(port: &str, handler: T, options: ServerOptions) -> Result<ServerHandle> {
         // This channel is used to interrupt the TCP listening thread.
         let (interrupt_sender, interrupt_receiver)  = channel::<u8>();
-        ServerInternal::listen::<T>(port, handler, interrupt_receiver)?;
-        let server_handle = ServerHandle { interrupt_sender };
+        // We create a reference to the handler that can be shared across threads.
+        let handler_arc = Arc::new(handler);
+        // Used to wait for in-flight connections during a graceful shutdown.
+        let active_connections = Arc::new(AtomicUsize::new(0));
+
+        let listener_thread = ServerInternal::listen::<T>(port, handler_arc.clone(), options, interrupt_receiver, active_connections.clone())?;
+
+        let server_handle = ServerHandle {
+            interrupt_sender,
+            listener_thread: Some(listener_thread),
+            active_connections,
+            begin_shutdown: Box::new(move || handler_arc.begin_shutdown())
+        };
         return Ok(server_handle);
     }
 
     /// Listens for and handles incoming TCP connections on the given port, using the handler
     /// provided. Does not block the main thread. Stops listening if an interrupt is received.
-    fn listen<T: Handler + Sync + Send + 'static>(port: &str, handler: T, interrupt_receiver: Receiver<u8>) -> Result<()> {
+    fn listen<T: Handler + Sync + Send + 'static>(port: &str, handler_arc: Arc<T>, options: ServerOptions, interrupt_receiver: Receiver<u8>, active_connections: Arc<AtomicUsize>) -> Result<JoinHandle<()>> {
         let address = format!("0.0.0.0:{}", port);
         let tcp_listener = TcpListener::bind(address)?;
 
         // We set the listener to non-blocking so that we can check for interrupts, below.
         tcp_listener.set_nonblocking(true)?;
-
-        // We create a reference to the handler that can be shared across threads.
-        let handler_arc = Arc::new(handler);
+        let client_limiter = ClientLimiter::new(&options.client_limits);
 
         // We listen on a separate thread.
-        spawn(move || {
+        let listener_thread = spawn(move || {
             for maybe_stream in tcp_listener.incoming() {
                 match maybe_stream {
                     // We spin up a new thread to handle each incoming stream.
                     Ok(stream) => {
+                        let connection_permit = match ServerInternal::admit(&client_limiter, &stream, &options) {
+                            Some(connection_permit) => connection_permit,
+                            None => continue
+                        };
+
                         let handler_arc_clone = handler_arc.clone();
-                        spawn(move || ServerInternal::handle_tcp_stream::<T>(stream, handler_arc_clone));
+                        let options_clone = options.clone();
+                        let connection_guard = ConnectionGuard::new(active_connections.clone());
+                        spawn(move || {
+                            let _connection_guard = connection_guard;
+                            let _connection_permit = connection_permit;
+                            metrics::global().handler_threads_active.inc();
+                            let peer_address = stream.peer_addr().ok();
+                            if let Some(peer_address) = peer_address {
+                                logger::global().debug(&format!("Accepted connection from {}.", peer_address));
+                            }
+                            let result = ServerInternal::handle_tcp_stream::<T>(stream, handler_arc_clone, options_clone);
+
+                            if let Err(e) = result {
+                                let peer_address = peer_address.map(|address| address.to_string()).unwrap_or_else(|| "-".into());
+                                let message = format!("Failed to handle connection from {}", peer_address);
+                                logger::global().error(&ServerError::with_cause(message, e));
+                            }
+
+                            metrics::global().handler_threads_active.dec();
+                        });
                     }
                     // The listener has not received a new connection yet.
                     Err(e) if e.kind() == WouldBlock => {
@@ -67,35 +170,166 @@ impl ServerInternal {
                         }
                     }
                     // We choose to panic, rather than passing the error back to the main thread.
-                    Err(e) => panic!(e)
+                    Err(e) => {
+                        logger::global().error(&ServerError::with_cause("Listener failed".into(), ServerError::new(e.to_string())));
+                        panic!("{}", e)
+                    }
                 }
             }
         });
 
-        return Ok(());
+        return Ok(listener_thread);
     }
 
-    /// Handles an incoming TCP connection, using the handler provided.
-    fn handle_tcp_stream<T: Handler>(stream: TcpStream, handler: Arc<T>) -> Result<()> {
+    /// Applies the client limits to a new connection. If it is rejected, makes a best-effort
+    /// attempt to send a 429 without blocking the listener, and returns None. Otherwise, returns
+    /// any permit that must be held while the connection is open.
+    fn admit(client_limiter: &ClientLimiter, stream: &TcpStream, options: &ServerOptions) -> Option<Option<ConnectionPermit>> {
+        let peer_ip = match stream.peer_addr() {
+            Ok(peer_address) => peer_address.ip(),
+            // The client has already gone.
+            Err(_) => return None
+        };
+
+        let retry_after = match client_limiter.admit(peer_ip) {
+            Ok(Admission::Admitted(connection_permit)) => return Some(connection_permit),
+            Ok(Admission::Rejected { retry_after }) => retry_after,
+            Err(e) => {
+                logger::global().error(&ServerError::with_cause(format!("Failed to apply client limits to {}", peer_ip), e));
+                return None;
+            }
+        };
+
+        logger::global().debug(&format!("Rate limited connection from {}.", peer_ip));
+        // Responding over TLS would need a handshake, so TLS connections are simply closed.
+        if options.tls_config.is_none() && stream.set_nonblocking(true).is_ok() {
+            if let Ok(response) = too_many_requests_response(retry_after) {
+                let _ = response.write(stream);
+            }
+        }
+        return None;
+    }
+
+    /// Returns a reader for an upgraded connection, which bypasses the request timeouts. It first
+    /// yields anything the client sent after the handshake that has already been buffered.
+    fn upgraded_reader<B, R: Read>(buffered: &BufReader<B>, stream: R) -> impl Read {
+        return Cursor::new(buffered.buffer().to_vec()).chain(stream);
+    }
+
+    /// Handles an incoming TCP connection, using the handler provided. Terminates TLS first if a
+    /// TLS config is provided, and applies the connection limits until the connection is upgraded
+    /// to a WebSocket or event stream, if it is.
+    fn handle_tcp_stream<T: Handler>(stream: TcpStream, handler: Arc<T>, options: ServerOptions) -> Result<()> {
         // We reverse the non-blocking behaviour set at the listener level.
         stream.set_nonblocking(false)?;
+        stream.set_write_timeout(Some(options.limits.write_timeout))?;
+        // Used by the timeout reader to adjust the socket's read timeout as the request progresses.
+        let socket = stream.try_clone()?;
+
+        let connection = ConnectionInfo {
+            peer_address: stream.peer_addr().ok(),
+            max_request_line_length: options.limits.max_request_line_length,
+            max_header_line_length: options.limits.max_header_line_length,
+            max_header_count: options.limits.max_header_count,
+            max_body_length: options.limits.max_body_length
+        };
+
+        return match options.tls_config {
+            None => {
+                // Used to hand the socket over to an upgraded connection, as the timeout reader takes its own.
+                let upgrade_socket = stream.try_clone()?;
+                let mut reader = BufReader::new(CountingReader::new(TimeoutReader::new(&stream, socket, options.limits)));
+                let mut writer = BufWriter::new(CountingWriter::new(&stream));
+                let upgrade = handler.handle(&mut reader, &mut writer, &connection)?;
+                writer.flush()?;
+                if let Some(upgrade) = upgrade {
+                    upgrade.run(&upgrade_socket, ServerInternal::upgraded_reader(&reader, CountingReader::new(&stream)), writer)?;
+                }
+                Ok(())
+            }
+            Some(tls_config) => {
+                let upgrade_socket = stream.try_clone()?;
+                let tls_stream = tls_config.accept(stream)?;
+                let mut reader = BufReader::new(CountingReader::new(TimeoutReader::new(tls_stream.clone(), socket, options.limits)));
+                let mut writer = BufWriter::new(CountingWriter::new(tls_stream.clone()));
+                let upgrade = handler.handle(&mut reader, &mut writer, &connection)?;
+                writer.flush()?;
+                if let Some(upgrade) = upgrade {
+                    upgrade.run(&upgrade_socket, ServerInternal::upgraded_reader(&reader, CountingReader::new(tls_stream.clone())), writer)?;
+                }
+                tls_stream.close()
+            }
+        };
+    }
+}
 
-        let reader = BufReader::new(&stream);
-        let writer = BufWriter::new(&stream);
-        return handler.handle(reader, writer);
+/// Tracks a connection being handled, for as long as it is alive.
+struct ConnectionGuard {
+    // The server's count of in-flight connections.
+    active_connections: Arc<AtomicUsize>
+}
+
+impl ConnectionGuard {
+    fn new(active_connections: Arc<AtomicUsize>) -> ConnectionGuard {
+        active_connections.fetch_add(1, Ordering::SeqCst);
+        metrics::global().connections_total.inc();
+        metrics::global().connections_active.inc();
+        return ConnectionGuard { active_connections };
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+        metrics::global().connections_active.dec();
     }
 }
 
 /// The handle returned when starting a TCP server, allowing the server to be brought to a halt.
 pub struct ServerHandle {
     // Used to interrupt the TCP listening thread.
-    interrupt_sender: Sender<u8>
+    interrupt_sender: Sender<u8>,
+    // Joined when stopping, so that the port is released before we return.
+    listener_thread: Option<JoinHandle<()>>,
+    // The number of connections currently being handled.
+    active_connections: Arc<AtomicUsize>,
+    // Notifies the handler that a graceful shutdown has begun.
+    begin_shutdown: Box<dyn Fn() + Send>
 }
 
 impl ServerHandle {
-    /// Brings the corresponding TCP server to a halt.
+    /// Brings the corresponding TCP server to a halt. Connections already accepted are allowed to
+    /// finish in the background.
     pub fn stop_listening(&mut self) -> Result<()> {
-        self.interrupt_sender.send(0)?;
+        return match self.listener_thread.take() {
+            // We've already stopped listening.
+            None => Ok(()),
+            Some(listener_thread) => {
+                self.interrupt_sender.send(0)?;
+                listener_thread.join()
+                    .map_err(|_| ServerError::new("The listening thread panicked.".into()))
+            }
+        };
+    }
+
+    /// Gracefully brings the corresponding TCP server to a halt. The handler is told that the
+    /// shutdown has begun (e.g. so that it reports itself as not ready), then the server keeps
+    /// serving for the drain period so that load balancers can stop routing to it, then it stops
+    /// listening and waits up to the timeout for in-flight connections to finish.
+    pub fn shutdown(&mut self, drain_period: Duration, timeout: Duration) -> Result<()> {
+        (self.begin_shutdown)();
+        sleep(drain_period);
+        self.stop_listening()?;
+
+        let deadline = Instant::now() + timeout;
+        while self.active_connections.load(Ordering::SeqCst) > 0 {
+            if Instant::now() >= deadline {
+                let message = format!("{} connections still active after shutdown timeout.", self.active_connections.load(Ordering::SeqCst));
+                return Err(ServerError::new(message));
+            }
+            sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+
         return Ok(());
     }
 }
@@ -105,9 +339,13 @@ mod tests {
     use std::io::{BufRead, BufReader, BufWriter, Write};
     use std::net::TcpStream;
     use std::sync::atomic::{AtomicU16, Ordering};
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
 
-    use crate::handler::DummyHandler;
-    use crate::server::{ServerInternal, ServerHandle};
+    use crate::handler::{DummyHandler, RedirectHandler};
+    use crate::limits::ConnectionLimits;
+    use crate::ratelimit::ClientLimits;
+    use crate::server::{ServerInternal, ServerHandle, ServerOptions};
 
     // Used to allocate different ports for the listeners across tests.
     static PORT: AtomicU16 = AtomicU16::new(10000);
@@ -122,7 +360,7 @@ mod tests {
 
     fn write_to_stream(stream: &TcpStream, packet_to_write: &[u8]) {
         let mut buf_writer = BufWriter::new(stream);
-        buf_writer.write(packet_to_write).unwrap();
+        buf_writer.write_all(packet_to_write).unwrap();
         buf_writer.flush().unwrap();
     }
 
@@ -241,4 +479,104 @@ mod tests {
 
         server_handle.stop_listening().unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn server_can_be_shut_down_gracefully() {
+        let port = get_port();
+        let mut server_handle = start_server(&port);
+        let address = format!("0.0.0.0:{}", port);
+
+        // The connection is in flight when the shutdown begins, and finishes during it.
+        let stream = TcpStream::connect(address.to_string()).unwrap();
+        let client = spawn(move || {
+            sleep(Duration::from_millis(100));
+            write_to_stream(&stream, b" ");
+            return get_response(&stream);
+        });
+
+        server_handle.shutdown(Duration::from_millis(0), Duration::from_secs(5)).unwrap();
+
+        assert_eq!(client.join().unwrap(), "DUMMY\n");
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn server_shutdown_times_out_on_hung_connections() {
+        let port = get_port();
+        let mut server_handle = start_server(&port);
+        let address = format!("0.0.0.0:{}", port);
+
+        // Hangs the connection using the '#' special character.
+        let stream = TcpStream::connect(address).unwrap();
+        write_to_stream(&stream, b"#");
+        sleep(Duration::from_millis(50));
+
+        let result = server_handle.shutdown(Duration::from_millis(0), Duration::from_millis(100));
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn server_closes_idle_connections() {
+        let port = get_port();
+        let limits = ConnectionLimits { idle_timeout: Duration::from_millis(100), ..ConnectionLimits::default() };
+        let options = ServerOptions { limits, ..ServerOptions::default() };
+        let mut server_handle = ServerInternal::start_with_options(&port, DummyHandler {}, options).unwrap();
+        let address = format!("0.0.0.0:{}", port);
+
+        // The hung connection is dropped once the client has been idle for too long.
+        let stream = TcpStream::connect(address).unwrap();
+        write_to_stream(&stream, b"#");
+        sleep(Duration::from_millis(50));
+
+        let result = server_handle.shutdown(Duration::from_millis(0), Duration::from_secs(2));
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn server_responds_408_to_slow_requests() {
+        let port = get_port();
+        let limits = ConnectionLimits {
+            header_read_timeout: Duration::from_millis(200),
+            idle_timeout: Duration::from_millis(100),
+            ..ConnectionLimits::default()
+        };
+        let options = ServerOptions { limits, ..ServerOptions::default() };
+        let mut server_handle = ServerInternal::start_with_options(&port, RedirectHandler::new("example.com"), options).unwrap();
+        let address = format!("0.0.0.0:{}", port);
+
+        // The request line is never finished.
+        let stream = TcpStream::connect(address).unwrap();
+        write_to_stream(&stream, b"GET / HT");
+
+        assert_eq!(get_response(&stream), "HTTP/1.1 408 REQUEST TIMEOUT\r\n");
+        server_handle.stop_listening().unwrap();
+    }
+
+    #[test]
+    fn server_caps_connections_per_ip() {
+        let port = get_port();
+        let client_limits = ClientLimits { max_connections_per_ip: Some(1), ..ClientLimits::default() };
+        let options = ServerOptions { client_limits, ..ServerOptions::default() };
+        let mut server_handle = ServerInternal::start_with_options(&port, DummyHandler {}, options).unwrap();
+        let address = format!("0.0.0.0:{}", port);
+
+        // Hangs the first connection using the '#' special character.
+        let first_stream = TcpStream::connect(&address).unwrap();
+        write_to_stream(&first_stream, b"#");
+        sleep(Duration::from_millis(50));
+
+        let second_stream = TcpStream::connect(&address).unwrap();
+        assert_eq!(get_response(&second_stream), "HTTP/1.1 429 TOO MANY REQUESTS\r\n");
+
+        // Once the first connection closes, the client may connect again.
+        drop(first_stream);
+        sleep(Duration::from_millis(50));
+        let third_stream = TcpStream::connect(&address).unwrap();
+        write_to_stream(&third_stream, b"a");
+        assert_eq!(get_response(&third_stream), "DUMMY\n");
+
+        server_handle.stop_listening().unwrap();
+    }
+}
@@ -1,21 +1,78 @@
+use std::fmt;
 use std::str::Utf8Error;
 use std::io::Error;
 use std::num::ParseIntError;
+use std::sync::PoisonError;
 use std::sync::mpsc::SendError;
+use std::time::SystemTimeError;
+
+/// The categories of error that the server responds to differently.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    // The request is malformed in a way the client should be told about.
+    BadRequest,
+    // The client was too slow to send or receive data.
+    TimedOut,
+    // The request line exceeded the maximum length.
+    RequestLineTooLong,
+    // A header line, or the number of headers, exceeded the maximum.
+    HeadersTooLarge,
+    // The request body exceeded the maximum length.
+    PayloadTooLarge,
+    Other,
+}
 
 /// A common class for errors generated by the server.
 #[derive(Debug)]
 pub struct ServerError {
-    pub(crate) message: String
+    pub(crate) message: String,
+    pub(crate) kind: ErrorKind,
+    // The lower-level error that caused this one, if any.
+    pub(crate) cause: Option<Box<ServerError>>
 }
 
 impl ServerError {
     pub fn new(message: String) -> ServerError {
-        ServerError { message }
+        ServerError { message, kind: ErrorKind::Other, cause: None }
+    }
+
+    pub fn with_kind(kind: ErrorKind, message: String) -> ServerError {
+        ServerError { message, kind, cause: None }
+    }
+
+    /// Creates an error that adds context to a lower-level error. The lower-level error's kind is
+    /// kept.
+    pub fn with_cause(message: String, cause: ServerError) -> ServerError {
+        ServerError { message, kind: cause.kind, cause: Some(Box::new(cause)) }
+    }
+
+    /// Returns the messages of this error and each of its causes, outermost first.
+    pub fn chain(&self) -> Vec<&str> {
+        let mut messages = vec![self.message.as_str()];
+        let mut current = self;
+
+        while let Some(cause) = &current.cause {
+            messages.push(cause.message.as_str());
+            current = cause;
+        }
+
+        return messages;
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f.write_str(&self.message);
     }
 }
 
-pub(crate) type Result<T> = std::result::Result<T, ServerError>;
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        return self.cause.as_ref().map(|cause| cause.as_ref() as &(dyn std::error::Error + 'static));
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ServerError>;
 
 impl From<Utf8Error> for ServerError {
     fn from(err: Utf8Error) -> Self {
@@ -25,7 +82,12 @@ impl From<Utf8Error> for ServerError {
 
 impl From<Error> for ServerError {
     fn from(err: Error) -> Self {
-        return ServerError::new(err.to_string());
+        let kind = match err.kind() {
+            // Reads and writes that exceed a socket timeout fail with one of these kinds.
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => ErrorKind::TimedOut,
+            _ => ErrorKind::Other
+        };
+        return ServerError::with_kind(kind, err.to_string());
     }
 }
 
@@ -39,4 +101,28 @@ impl From<ParseIntError> for ServerError {
     fn from(err: ParseIntError) -> Self {
         return ServerError::new(err.to_string());
     }
-}
\ No newline at end of file
+}
+
+impl From<rustls::Error> for ServerError {
+    fn from(err: rustls::Error) -> Self {
+        return ServerError::new(err.to_string());
+    }
+}
+
+impl From<serde_json::Error> for ServerError {
+    fn from(err: serde_json::Error) -> Self {
+        return ServerError::new(err.to_string());
+    }
+}
+
+impl From<SystemTimeError> for ServerError {
+    fn from(err: SystemTimeError) -> Self {
+        return ServerError::new(err.to_string());
+    }
+}
+
+impl<T> From<PoisonError<T>> for ServerError {
+    fn from(err: PoisonError<T>) -> Self {
+        return ServerError::new(err.to_string());
+    }
+}
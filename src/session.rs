@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::json;
+
+use crate::api::{ApiError, ApiResult};
+use crate::auth::{Authentication, Authenticator, Principal};
+use crate::cookie::SetCookie;
+use crate::handler::{ConnectionInfo, HttpRequest, HttpResponse};
+use crate::middleware::{Middleware, Next};
+use crate::servererror::{Result, ServerError};
+
+// The number of random bytes in a session ID.
+const SESSION_ID_LENGTH: usize = 32;
+// The session key holding the name of the principal that logged in.
+const PRINCIPAL_KEY: &str = "principal";
+
+/// The state of a client's session, available to routes via `HttpRequest::session`. Changes are
+/// saved once the response has been produced. Concurrent requests in the same session each see
+/// the state as it was when they arrived, and the last to finish wins.
+pub struct Session {
+    // The ID the client sent, if it named a live session.
+    id: Option<String>,
+    state: Mutex<SessionState>,
+}
+
+#[derive(Default)]
+struct SessionState {
+    data: HashMap<String, String>,
+    // Whether to move the data to a new ID, e.g. after logging in, so that an ID planted on the
+    // client before then is useless.
+    regenerate: bool,
+    destroyed: bool,
+}
+
+impl Session {
+    fn new(id: Option<String>, data: HashMap<String, String>) -> Session {
+        return Session { id, state: Mutex::new(SessionState { data, ..SessionState::default() }) };
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        return self.state.lock().ok()?.data.get(key).cloned();
+    }
+
+    pub fn insert(&self, key: &str, value: &str) -> Result<()> {
+        self.state.lock()?.data.insert(key.into(), value.into());
+        return Ok(());
+    }
+
+    /// Moves the session to a new ID when the response is sent.
+    pub fn regenerate(&self) -> Result<()> {
+        self.state.lock()?.regenerate = true;
+        return Ok(());
+    }
+
+    /// Deletes the session, and its cookie, when the response is sent.
+    pub fn destroy(&self) -> Result<()> {
+        let mut state = self.state.lock()?;
+        state.data.clear();
+        state.destroyed = true;
+        return Ok(());
+    }
+}
+
+impl HttpRequest {
+    /// Returns the client's session, if the route is wrapped in a `SessionLayer`.
+    pub fn session(&self) -> Option<&Session> {
+        return self.session.as_ref();
+    }
+}
+
+/// A session's data, and when it expires.
+struct StoredSession {
+    data: HashMap<String, String>,
+    expires_at: Instant,
+}
+
+/// Sessions kept in memory, which expire once idle. Memory is bounded: once too many sessions are
+/// stored, expired sessions are removed, and then those closest to expiring.
+pub struct SessionStore {
+    idle_timeout: Duration,
+    max_sessions: usize,
+    // Keyed by the SHA-256 of the session ID, so that lookups do not leak IDs through timing.
+    sessions: Mutex<HashMap<Vec<u8>, StoredSession>>,
+}
+
+impl SessionStore {
+    pub fn new(idle_timeout: Duration, max_sessions: usize) -> SessionStore {
+        return SessionStore { idle_timeout, max_sessions, sessions: Mutex::new(HashMap::new()) };
+    }
+
+    /// Returns the session's data, unless it is unknown or has expired.
+    fn load(&self, id: &str, now: Instant) -> Result<Option<HashMap<String, String>>> {
+        let mut sessions = self.sessions.lock()?;
+        let key = session_key(id);
+
+        return match sessions.get(&key) {
+            Some(session) if session.expires_at > now => Ok(Some(session.data.clone())),
+            Some(_) => {
+                sessions.remove(&key);
+                Ok(None)
+            }
+            None => Ok(None)
+        };
+    }
+
+    /// Stores the session's data, and restarts its idle timeout.
+    fn save(&self, id: &str, data: HashMap<String, String>, now: Instant) -> Result<()> {
+        let mut sessions = self.sessions.lock()?;
+        sessions.insert(session_key(id), StoredSession { data, expires_at: now + self.idle_timeout });
+
+        if sessions.len() > self.max_sessions {
+            sessions.retain(|_, session| session.expires_at > now);
+        }
+        while sessions.len() > self.max_sessions {
+            let soonest = sessions.iter().min_by_key(|(_, session)| session.expires_at).map(|(key, _)| key.clone());
+            if let Some(key) = soonest {
+                sessions.remove(&key);
+            }
+        }
+        return Ok(());
+    }
+
+    fn remove(&self, id: &str) -> Result<()> {
+        self.sessions.lock()?.remove(&session_key(id));
+        return Ok(());
+    }
+}
+
+fn session_key(id: &str) -> Vec<u8> {
+    return digest::digest(&digest::SHA256, id.as_bytes()).as_ref().to_vec();
+}
+
+fn new_session_id() -> Result<String> {
+    let mut id = [0u8; SESSION_ID_LENGTH];
+    SystemRandom::new().fill(&mut id).map_err(|_| ServerError::new("Failed to generate a session ID.".into()))?;
+    return Ok(URL_SAFE_NO_PAD.encode(id));
+}
+
+/// Middleware that gives each request the session named by its session cookie, and saves the
+/// session once the response has been produced. Sessions are only created, and cookies only set,
+/// once a route stores something in them.
+pub struct SessionLayer {
+    store: SessionStore,
+    // The session cookie's name and attributes. Its value and Max-Age are set per session.
+    cookie: SetCookie,
+}
+
+impl SessionLayer {
+    pub fn new(cookie: SetCookie, idle_timeout: Duration, max_sessions: usize) -> SessionLayer {
+        return SessionLayer { store: SessionStore::new(idle_timeout, max_sessions), cookie };
+    }
+
+    fn session_cookie(&self, id: &str) -> SetCookie {
+        return SetCookie { value: id.into(), max_age: Some(self.store.idle_timeout), ..self.cookie.clone() };
+    }
+}
+
+impl Middleware for SessionLayer {
+    fn handle(&self, request: &mut HttpRequest, connection: &ConnectionInfo, next: Next) -> Result<HttpResponse> {
+        let now = Instant::now();
+        let sent_id = request.cookie(&self.cookie.name).map(|id| id.to_string());
+        let session = match sent_id {
+            None => Session::new(None, HashMap::new()),
+            Some(id) => match self.store.load(&id, now)? {
+                None => Session::new(None, HashMap::new()),
+                Some(data) => Session::new(Some(id), data)
+            }
+        };
+        request.session = Some(session);
+
+        let mut response = next.run(request, connection)?;
+
+        let session = match request.session.take() {
+            None => return Ok(response),
+            Some(session) => session
+        };
+        let state = session.state.into_inner()?;
+
+        if state.destroyed {
+            if let Some(id) = &session.id {
+                self.store.remove(id)?;
+                response.add_cookie(&self.cookie.expired());
+            }
+            return Ok(response);
+        }
+        if session.id.is_none() && state.data.is_empty() {
+            return Ok(response);
+        }
+
+        let id = match (session.id, state.regenerate) {
+            (Some(id), false) => id,
+            (old_id, _) => {
+                if let Some(old_id) = old_id {
+                    self.store.remove(&old_id)?;
+                }
+                new_session_id()?
+            }
+        };
+        self.store.save(&id, state.data, now)?;
+        response.add_cookie(&self.session_cookie(&id));
+
+        return Ok(response);
+    }
+}
+
+/// Authenticates requests whose session records a login via the session route.
+pub struct SessionAuthenticator;
+
+impl Authenticator for SessionAuthenticator {
+    fn authenticate(&self, request: &HttpRequest) -> Authentication {
+        return match request.session().and_then(|session| session.get(PRINCIPAL_KEY)) {
+            None => Authentication::Missing,
+            Some(principal_name) => Authentication::Authenticated(Principal::new(&principal_name))
+        };
+    }
+
+    // Clients log in via the session route rather than in response to a challenge.
+    fn challenge(&self) -> String {
+        return String::new();
+    }
+
+    fn accepts_issued_credentials(&self) -> bool {
+        return true;
+    }
+}
+
+/// Returns an API route that logs the authenticated principal in to the session on POST, describes
+/// the session on GET, and logs out on DELETE. The route must be protected by an `AuthLayer`, and
+/// wrapped in a `SessionLayer`.
+pub fn session_route() -> impl Fn(&HttpRequest) -> ApiResult + Send + Sync {
+    return move |request: &HttpRequest| {
+        let principal = request.principal().ok_or_else(ApiError::unauthorized)?;
+        let session = request.session()
+            .ok_or_else(|| ServerError::new("The session route has no session layer.".into()))?;
+
+        return match request.method.as_str() {
+            "POST" => {
+                session.regenerate()?;
+                session.insert(PRINCIPAL_KEY, &principal.name)?;
+                Ok(HttpResponse::json("200 OK", &json!({ "principal": principal.name }))?)
+            }
+            "DELETE" => {
+                session.destroy()?;
+                Ok(HttpResponse::empty("204 NO CONTENT"))
+            }
+            _ => Ok(HttpResponse::json("200 OK", &json!({ "principal": session.get(PRINCIPAL_KEY) }))?)
+        };
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::BufReader;
+    use std::str::from_utf8;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::auth::{AuthLayer, TokenAuthenticator};
+    use crate::cookie::SetCookie;
+    use crate::handler::{ConnectionInfo, HttpHandler, HttpRequest, HttpResponse};
+    use crate::middleware::MiddlewareChain;
+    use crate::servererror::Result;
+    use crate::session::{session_route, SessionAuthenticator, SessionLayer, SessionStore};
+
+    /// Counts visits in the session, logs in on /login and logs out on /logout.
+    fn visits(request: &HttpRequest) -> Result<HttpResponse> {
+        let session = request.session().unwrap();
+        match request.request_uri.as_str() {
+            "/logout" => session.destroy()?,
+            "/login" => session.regenerate()?,
+            "/anonymous" => {}
+            _ => {
+                let visits = session.get("visits").map(|visits| visits.parse::<u32>().unwrap()).unwrap_or(0) + 1;
+                session.insert("visits", &visits.to_string())?;
+            }
+        }
+        let body = session.get("visits").unwrap_or_else(|| "none".into());
+        return Ok(HttpResponse::new("200 OK", "text/plain", body.into()));
+    }
+
+    /// Sends a request with the given session cookie, returning the body and any Set-Cookie header.
+    fn send(chain: &MiddlewareChain, path: &str, session_id: Option<&str>) -> (String, Option<String>) {
+        let cookie_header = session_id.map(|id| format!("Cookie: theme=dark; sid={}\r\n", id)).unwrap_or_default();
+        let request = format!("GET {} HTTP/1.1\r\n{}\r\n", path, cookie_header);
+        let mut http_request = HttpHandler::read_http_request(&mut BufReader::new(request.as_bytes()), &ConnectionInfo::default()).unwrap();
+
+        let response = chain.run(&mut http_request, &ConnectionInfo::default(), &visits).unwrap();
+
+        return (from_utf8(&response.body).unwrap().into(), response.header("Set-Cookie").map(|value| value.into()));
+    }
+
+    fn session_id(set_cookie: &str) -> &str {
+        return set_cookie.strip_prefix("sid=").unwrap().split(';').next().unwrap();
+    }
+
+    fn chain() -> MiddlewareChain {
+        let mut chain = MiddlewareChain::new();
+        chain.add(SessionLayer::new(SetCookie { secure: true, ..SetCookie::new("sid", "") }, Duration::from_secs(60), 100));
+        return chain;
+    }
+
+    #[test]
+    fn sessions_persist_between_requests() {
+        let chain = chain();
+
+        assert_eq!(send(&chain, "/anonymous", None), ("none".into(), None));
+
+        let (body, set_cookie) = send(&chain, "/", None);
+        let set_cookie = set_cookie.unwrap();
+        assert_eq!(body, "1");
+        assert!(set_cookie.ends_with("; Max-Age=60; Path=/; Secure; HttpOnly; SameSite=Lax"));
+        let id = session_id(&set_cookie);
+        assert_eq!(id.len(), 43);
+
+        let (body, set_cookie) = send(&chain, "/", Some(id));
+        assert_eq!(body, "2");
+        assert_eq!(session_id(&set_cookie.unwrap()), id);
+    }
+
+    #[test]
+    fn unknown_session_ids_start_new_sessions() {
+        let chain = chain();
+
+        let (body, set_cookie) = send(&chain, "/", Some("forged"));
+
+        assert_eq!(body, "1");
+        assert_ne!(session_id(&set_cookie.unwrap()), "forged");
+        assert_eq!(send(&chain, "/anonymous", Some("forged")), ("none".into(), None));
+    }
+
+    #[test]
+    fn sessions_can_be_regenerated_and_destroyed() {
+        let chain = chain();
+        let (_, set_cookie) = send(&chain, "/", None);
+        let old_id = session_id(&set_cookie.unwrap()).to_string();
+
+        let (body, set_cookie) = send(&chain, "/login", Some(&old_id));
+        let new_id = session_id(&set_cookie.unwrap()).to_string();
+        assert_eq!(body, "1");
+        assert_ne!(new_id, old_id);
+        assert_eq!(send(&chain, "/anonymous", Some(&old_id)).0, "none");
+
+        let (body, set_cookie) = send(&chain, "/logout", Some(&new_id));
+        assert_eq!(body, "none");
+        assert!(set_cookie.unwrap().starts_with("sid=; Max-Age=0;"));
+        assert_eq!(send(&chain, "/anonymous", Some(&new_id)).0, "none");
+    }
+
+    #[test]
+    fn session_store_expires_and_bounds_sessions() {
+        let store = SessionStore::new(Duration::from_secs(60), 2);
+        let start = Instant::now();
+        let data: HashMap<String, String> = vec![("k".to_string(), "v".to_string())].into_iter().collect();
+
+        store.save("a", data.clone(), start).unwrap();
+        assert_eq!(store.load("a", start + Duration::from_secs(59)).unwrap(), Some(data.clone()));
+        assert_eq!(store.load("a", start + Duration::from_secs(60)).unwrap(), None);
+
+        store.save("b", data.clone(), start).unwrap();
+        store.save("c", data.clone(), start + Duration::from_secs(1)).unwrap();
+        store.save("d", data.clone(), start + Duration::from_secs(2)).unwrap();
+        assert_eq!(store.load("b", start).unwrap(), None);
+        assert!(store.load("c", start).unwrap().is_some());
+        assert!(store.load("d", start).unwrap().is_some());
+    }
+
+    #[test]
+    fn session_route_logs_principals_in_and_out() {
+        let mut token_authenticator = TokenAuthenticator::new("test", "X-API-Key");
+        token_authenticator.add_token("secret", "alice");
+        let mut chain = chain();
+        chain.add(AuthLayer::new(vec![Arc::new(token_authenticator), Arc::new(SessionAuthenticator)], None));
+        let route = session_route();
+        let endpoint = |request: &HttpRequest| -> Result<HttpResponse> { return Ok(route(request).unwrap_or_else(|e| e.to_response())); };
+        let send = |method: &str, header: &str| {
+            let request = format!("{} /api/session HTTP/1.1\r\n{}\r\n\r\n", method, header);
+            let mut http_request = HttpHandler::read_http_request(&mut BufReader::new(request.as_bytes()), &ConnectionInfo::default()).unwrap();
+            return chain.run(&mut http_request, &ConnectionInfo::default(), &endpoint).unwrap();
+        };
+
+        let response = send("POST", "Authorization: Bearer secret");
+        assert_eq!(from_utf8(&response.body).unwrap(), "{\"principal\":\"alice\"}");
+        let cookie = format!("Cookie: sid={}", session_id(response.header("Set-Cookie").unwrap()));
+
+        let response = send("GET", &cookie);
+        assert_eq!(from_utf8(&response.body).unwrap(), "{\"principal\":\"alice\"}");
+
+        let response = send("DELETE", &cookie);
+        assert_eq!(response.status_code, "204 NO CONTENT");
+        assert!(response.header("Set-Cookie").unwrap().starts_with("sid=;"));
+
+        let response = send("GET", &cookie);
+        assert_eq!(response.status_code, "401 UNAUTHORIZED");
+        assert_eq!(response.header("WWW-Authenticate"), Some("Bearer realm=\"test\""));
+    }
+}
@@ -0,0 +1,189 @@
+use std::convert::TryInto;
+
+// The length of a digest, in bytes.
+pub const DIGEST_LENGTH: usize = 32;
+// The length of the blocks the message is processed in, in bytes.
+const BLOCK_LENGTH: usize = 64;
+
+// The first 32 bits of the fractional parts of the square roots of the first eight primes.
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+// The first 32 bits of the fractional parts of the cube roots of the first 64 primes.
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Returns the SHA-256 digest of the data.
+pub fn sha256(data: &[u8]) -> [u8; DIGEST_LENGTH] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    return hasher.finish();
+}
+
+/// Formats bytes as lower-case hexadecimal.
+pub fn to_hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+/// A SHA-256 hash computed incrementally, as defined in FIPS 180-4, so that a message can be
+/// hashed in pieces without first being copied into one buffer.
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    // Bytes not yet processed, as they do not fill a block.
+    buffer: [u8; BLOCK_LENGTH],
+    buffered: usize,
+    // The length of the message so far, in bytes.
+    length: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Sha256 {
+        return Sha256 { state: INITIAL_STATE, buffer: [0; BLOCK_LENGTH], buffered: 0, length: 0 };
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        return Sha256::default();
+    }
+
+    /// Appends the data to the message.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length = self.length.wrapping_add(data.len() as u64);
+
+        if self.buffered > 0 {
+            let taken = data.len().min(BLOCK_LENGTH - self.buffered);
+            self.buffer[self.buffered..self.buffered + taken].copy_from_slice(&data[..taken]);
+            self.buffered += taken;
+            data = &data[taken..];
+            if self.buffered < BLOCK_LENGTH {
+                return;
+            }
+            let block = self.buffer;
+            self.compress(&block);
+            self.buffered = 0;
+        }
+
+        let mut blocks = data.chunks_exact(BLOCK_LENGTH);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+        let remainder = blocks.remainder();
+        self.buffer[..remainder.len()].copy_from_slice(remainder);
+        self.buffered = remainder.len();
+    }
+
+    /// Pads the message and returns its digest.
+    pub fn finish(mut self) -> [u8; DIGEST_LENGTH] {
+        let bit_length = self.length.wrapping_mul(8);
+
+        // The padding is a one bit, then zeroes up to eight bytes short of a block boundary, then
+        // the message's length in bits.
+        let mut padding = [0u8; BLOCK_LENGTH + 8];
+        padding[0] = 0x80;
+        let zeroes = (BLOCK_LENGTH + 56 - (self.buffered + 1) % BLOCK_LENGTH) % BLOCK_LENGTH;
+        padding[1 + zeroes..9 + zeroes].copy_from_slice(&bit_length.to_be_bytes());
+        self.update(&padding[..9 + zeroes]);
+
+        let mut digest = [0u8; DIGEST_LENGTH];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        return digest;
+    }
+
+    /// Mixes a 64-byte block into the state.
+    fn compress(&mut self, block: &[u8]) {
+        let mut schedule = [0u32; 64];
+        for (word, bytes) in schedule.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = schedule[i - 15].rotate_right(7) ^ schedule[i - 15].rotate_right(18) ^ (schedule[i - 15] >> 3);
+            let s1 = schedule[i - 2].rotate_right(17) ^ schedule[i - 2].rotate_right(19) ^ (schedule[i - 2] >> 10);
+            schedule[i] = schedule[i - 16].wrapping_add(s0).wrapping_add(schedule[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for (constant, word) in ROUND_CONSTANTS.iter().zip(schedule.iter()) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(*constant).wrapping_add(*word);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (word, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *word = word.wrapping_add(*value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::digest;
+
+    use crate::sha256::{sha256, to_hex, Sha256};
+
+    #[test]
+    fn digests_match_known_answers() {
+        // From FIPS 180-4's examples and the NIST test vectors.
+        let vectors: [(&[u8], &str); 4] = [
+            (b"", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            (b"abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            (b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq", "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"),
+            (b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+             "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1"),
+        ];
+
+        for (message, expected) in vectors.iter() {
+            assert_eq!(to_hex(&sha256(message)), *expected);
+        }
+    }
+
+    #[test]
+    fn digests_of_a_million_bytes_match_the_known_answer() {
+        let mut hasher = Sha256::new();
+        for _ in 0..1000 {
+            hasher.update(&[b'a'; 1000]);
+        }
+
+        assert_eq!(to_hex(&hasher.finish()), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+
+    #[test]
+    fn digests_match_ring_across_block_boundaries_and_split_updates() {
+        let message: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+
+        for length in 0..message.len() {
+            let expected = digest::digest(&digest::SHA256, &message[..length]);
+            assert_eq!(&sha256(&message[..length])[..], expected.as_ref());
+
+            let mut hasher = Sha256::new();
+            let (first, second) = message[..length].split_at(length / 3);
+            hasher.update(first);
+            hasher.update(second);
+            assert_eq!(&hasher.finish()[..], expected.as_ref());
+        }
+    }
+}
@@ -0,0 +1,502 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::OnceLock;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::handler::HttpResponse;
+use crate::servererror::{Result, ServerError};
+
+// The directory templates are loaded from by default.
+pub const TEMPLATE_DIR: &str = "./src/html";
+// The deepest that includes and layouts may nest, to catch templates that include themselves.
+const MAX_DEPTH: usize = 16;
+
+// Used by `global` until `init` is called.
+static GLOBAL_TEMPLATES: OnceLock<Templates> = OnceLock::new();
+
+/// Installs the process-wide templates. Can only be called once, before the first call to `global`.
+pub fn init(templates: Templates) -> Result<()> {
+    return GLOBAL_TEMPLATES.set(templates)
+        .map_err(|_| ServerError::new("The global templates are already initialised.".into()));
+}
+
+/// Returns the process-wide templates. Defaults to the templates in `TEMPLATE_DIR`.
+pub fn global() -> &'static Templates {
+    return GLOBAL_TEMPLATES.get_or_init(|| Templates::load(TEMPLATE_DIR).unwrap());
+}
+
+/// A part of a compiled template.
+#[derive(Debug, PartialEq)]
+enum Node {
+    Text(String),
+    // `{{ path }}`, which is HTML-escaped unless followed by `| raw`.
+    Variable { path: Vec<String>, escape: bool },
+    // `{% if [not] path %} ... {% else %} ... {% endif %}`.
+    If { path: Vec<String>, negate: bool, then: Vec<Node>, otherwise: Vec<Node> },
+    // `{% for name in path %} ... {% endfor %}`.
+    For { name: String, path: Vec<String>, body: Vec<Node> },
+    // `{% include "name" %}`.
+    Include(String),
+    // `{% block name %} ... {% endblock %}`, which templates extending this one may replace.
+    Block { name: String, body: Vec<Node> },
+}
+
+/// A piece of template source, before parsing.
+enum Token<'a> {
+    Text(&'a str),
+    Expression(&'a str),
+    Tag(&'a str),
+}
+
+/// A template, compiled once and rendered many times.
+#[derive(Debug, PartialEq)]
+pub struct Template {
+    // The template this one fills in the blocks of, if it begins with `{% extends "name" %}`.
+    extends: Option<String>,
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    /// Compiles the template source. Fails on unknown or unbalanced tags.
+    pub fn compile(source: &str) -> Result<Template> {
+        let tokens = Template::tokenize(source)?;
+        let mut position = 0;
+
+        let mut extends = None;
+        let first_tag = tokens.iter().position(|token| !matches!(token, Token::Text(text) if text.trim().is_empty()));
+        if let Some(Token::Tag(tag)) = first_tag.map(|index| &tokens[index]) {
+            if let Some(("extends", name)) = split_tag(tag) {
+                extends = Some(parse_name(name)?);
+                position = first_tag.unwrap() + 1;
+            }
+        }
+
+        let (nodes, end) = Template::parse(&tokens, &mut position, &[])?;
+        if let Some(end) = end {
+            return Err(ServerError::new(format!("Unexpected {{% {} %}}.", end)));
+        }
+        return Ok(Template { extends, nodes });
+    }
+
+    /// Splits the source into text, `{{ expressions }}` and `{% tags %}`.
+    fn tokenize(source: &str) -> Result<Vec<Token<'_>>> {
+        let mut tokens = Vec::new();
+        let mut rest = source;
+
+        while let Some(start) = rest.find("{{").into_iter().chain(rest.find("{%")).min() {
+            if start > 0 {
+                tokens.push(Token::Text(&rest[..start]));
+            }
+            let closing = match &rest[start..start + 2] {
+                "{{" => "}}",
+                _ => "%}"
+            };
+            let end = rest[start + 2..].find(closing)
+                .ok_or_else(|| ServerError::new(format!("Unclosed {}.", &rest[start..start + 2])))?;
+            let inner = rest[start + 2..start + 2 + end].trim();
+            tokens.push(match closing {
+                "}}" => Token::Expression(inner),
+                _ => Token::Tag(inner)
+            });
+            rest = &rest[start + 2 + end + 2..];
+        }
+        if !rest.is_empty() {
+            tokens.push(Token::Text(rest));
+        }
+
+        return Ok(tokens);
+    }
+
+    /// Parses nodes until one of the terminating tags, which is returned along with the nodes.
+    /// Returns `None` as the terminator if the tokens run out.
+    fn parse(tokens: &[Token], position: &mut usize, terminators: &[&str]) -> Result<(Vec<Node>, Option<String>)> {
+        let mut nodes = Vec::new();
+
+        while *position < tokens.len() {
+            let token = &tokens[*position];
+            *position += 1;
+
+            match token {
+                Token::Text(text) => nodes.push(Node::Text(text.to_string())),
+                Token::Expression(expression) => nodes.push(parse_variable(expression)?),
+                Token::Tag(tag) => {
+                    let (keyword, arguments) = split_tag(tag)
+                        .ok_or_else(|| ServerError::new("Empty tag.".into()))?;
+                    if terminators.contains(&keyword) {
+                        return Ok((nodes, Some(keyword.into())));
+                    }
+                    nodes.push(Template::parse_tag(tokens, position, keyword, arguments)?);
+                }
+            }
+        }
+
+        if !terminators.is_empty() {
+            return Err(ServerError::new(format!("Missing {{% {} %}}.", terminators.last().unwrap())));
+        }
+        return Ok((nodes, None));
+    }
+
+    /// Parses a tag, and for tags with a body, the nodes up to its end tag.
+    fn parse_tag(tokens: &[Token], position: &mut usize, keyword: &str, arguments: &str) -> Result<Node> {
+        return match keyword {
+            "if" => {
+                let (negate, path) = match arguments.strip_prefix("not ") {
+                    None => (false, arguments),
+                    Some(path) => (true, path)
+                };
+                let (then, end) = Template::parse(tokens, position, &["else", "endif"])?;
+                let otherwise = match end.as_deref() {
+                    Some("else") => Template::parse(tokens, position, &["endif"])?.0,
+                    _ => Vec::new()
+                };
+                Ok(Node::If { path: parse_path(path)?, negate, then, otherwise })
+            }
+            "for" => {
+                let parts = arguments.split_whitespace().collect::<Vec<_>>();
+                if parts.len() != 3 || parts[1] != "in" {
+                    return Err(ServerError::new(format!("Malformed {{% for {} %}}.", arguments)));
+                }
+                let body = Template::parse(tokens, position, &["endfor"])?.0;
+                Ok(Node::For { name: parse_path(parts[0])?.join("."), path: parse_path(parts[2])?, body })
+            }
+            "include" => Ok(Node::Include(parse_name(arguments)?)),
+            "block" => {
+                let body = Template::parse(tokens, position, &["endblock"])?.0;
+                Ok(Node::Block { name: parse_path(arguments)?.join("."), body })
+            }
+            _ => Err(ServerError::new(format!("Unexpected {{% {} %}}.", keyword)))
+        };
+    }
+}
+
+/// Splits a tag into its keyword and arguments.
+fn split_tag(tag: &str) -> Option<(&str, &str)> {
+    let mut parts = tag.splitn(2, char::is_whitespace);
+    let keyword = parts.next().filter(|keyword| !keyword.is_empty())?;
+    return Some((keyword, parts.next().unwrap_or("").trim()));
+}
+
+/// Parses a quoted template name, as used by `include` and `extends`.
+fn parse_name(argument: &str) -> Result<String> {
+    return argument.strip_prefix('"').and_then(|name| name.strip_suffix('"'))
+        .filter(|name| !name.is_empty())
+        .map(|name| name.into())
+        .ok_or_else(|| ServerError::new(format!("Expected a quoted template name, got {}.", argument)));
+}
+
+/// Parses a dotted path into a value, e.g. `block.header.height`.
+fn parse_path(path: &str) -> Result<Vec<String>> {
+    let segments = path.trim().split('.').map(|segment| segment.to_string()).collect::<Vec<_>>();
+    let is_valid = segments.iter().all(|segment| {
+        !segment.is_empty() && segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    });
+    if !is_valid {
+        return Err(ServerError::new(format!("Malformed variable: {}", path)));
+    }
+    return Ok(segments);
+}
+
+/// Parses the contents of `{{ ... }}`: a path, optionally followed by `| raw`.
+fn parse_variable(expression: &str) -> Result<Node> {
+    return match expression.split_once('|') {
+        None => Ok(Node::Variable { path: parse_path(expression)?, escape: true }),
+        Some((path, "raw")) | Some((path, " raw")) => Ok(Node::Variable { path: parse_path(path)?, escape: false }),
+        Some((_, filter)) => Err(ServerError::new(format!("Unknown filter: {}", filter.trim())))
+    };
+}
+
+/// The values visible while rendering: the loop variables in scope, innermost last, and then the
+/// context.
+struct Scope<'a> {
+    context: &'a Value,
+    locals: Vec<(&'a str, &'a Value)>,
+}
+
+impl<'a> Scope<'a> {
+    /// Looks up a path, or returns `None` if any part of it is missing.
+    fn lookup(&self, path: &[String]) -> Option<&'a Value> {
+        let local = self.locals.iter().rev().find(|(name, _)| *name == path[0]).map(|(_, value)| *value);
+        let (mut value, rest) = match local {
+            Some(value) => (value, &path[1..]),
+            None => (self.context, path)
+        };
+
+        for segment in rest {
+            value = match value {
+                Value::Object(fields) => fields.get(segment)?,
+                Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+                _ => return None
+            };
+        }
+        return Some(value);
+    }
+}
+
+/// Whether a value counts as true in an `if`. Missing values, `null`, `false`, zero and empty
+/// strings, arrays and objects are false.
+fn is_truthy(value: Option<&Value>) -> bool {
+    return match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(value)) => *value,
+        Some(Value::Number(number)) => number.as_f64() != Some(0.0),
+        Some(Value::String(string)) => !string.is_empty(),
+        Some(Value::Array(items)) => !items.is_empty(),
+        Some(Value::Object(fields)) => !fields.is_empty()
+    };
+}
+
+/// Escapes the characters that are significant in HTML text and attribute values.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c)
+        }
+    }
+    return escaped;
+}
+
+/// A set of compiled templates, which may include and extend one another by name.
+#[derive(Default)]
+pub struct Templates {
+    // Keyed by name, which for loaded templates is the file name.
+    templates: HashMap<String, Template>,
+}
+
+impl Templates {
+    pub fn new() -> Templates {
+        return Templates::default();
+    }
+
+    /// Compiles every `.html` file in the directory, named by file name.
+    pub fn load(dir: &str) -> Result<Templates> {
+        let mut templates = Templates::new();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("html") {
+                continue;
+            }
+            let name = path.file_name().and_then(|name| name.to_str())
+                .ok_or_else(|| ServerError::new(format!("Invalid template name: {}", path.display())))?;
+            templates.add(name, &fs::read_to_string(&path)?)?;
+        }
+
+        return Ok(templates);
+    }
+
+    /// Compiles the template and adds it under the given name.
+    pub fn add(&mut self, name: &str, source: &str) -> Result<()> {
+        let template = Template::compile(source)
+            .map_err(|e| ServerError::with_cause(format!("Could not compile template {}", name), e))?;
+        self.templates.insert(name.into(), template);
+        return Ok(());
+    }
+
+    /// Renders the named template with the values in the context, which is usually a struct or a
+    /// `serde_json::json!` object. Missing values render as nothing.
+    pub fn render<T: Serialize>(&self, name: &str, context: &T) -> Result<String> {
+        let context = serde_json::to_value(context)?;
+        let mut scope = Scope { context: &context, locals: Vec::new() };
+        let mut output = String::new();
+
+        self.render_template(name, &mut scope, 0, &mut output)?;
+        return Ok(output);
+    }
+
+    /// Renders a template by name, following its chain of layouts. The blocks it defines replace
+    /// those of the same name in its layouts, with the innermost definition winning.
+    fn render_template<'a>(&'a self, name: &str, scope: &mut Scope<'a>, depth: usize, output: &mut String) -> Result<()> {
+        let mut blocks = HashMap::new();
+        let mut template = self.get(name, depth)?;
+        let mut depth = depth;
+
+        while let Some(layout) = &template.extends {
+            collect_blocks(&template.nodes, &mut blocks);
+            depth += 1;
+            template = self.get(layout, depth)?;
+        }
+
+        return self.render_nodes(&template.nodes, scope, &blocks, depth, output);
+    }
+
+    fn get(&self, name: &str, depth: usize) -> Result<&Template> {
+        if depth > MAX_DEPTH {
+            return Err(ServerError::new(format!("Templates nested too deeply at {}.", name)));
+        }
+        return self.templates.get(name)
+            .ok_or_else(|| ServerError::new(format!("Unknown template: {}", name)));
+    }
+
+    fn render_nodes<'a>(&'a self, nodes: &'a [Node], scope: &mut Scope<'a>, blocks: &HashMap<&'a str, &'a [Node]>,
+                        depth: usize, output: &mut String) -> Result<()> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => output.push_str(text),
+                Node::Variable { path, escape } => {
+                    let text = match scope.lookup(path) {
+                        None | Some(Value::Null) => String::new(),
+                        Some(Value::String(string)) => string.clone(),
+                        Some(value) => value.to_string()
+                    };
+                    match escape {
+                        true => output.push_str(&escape_html(&text)),
+                        false => output.push_str(&text)
+                    }
+                }
+                Node::If { path, negate, then, otherwise } => {
+                    let branch = match is_truthy(scope.lookup(path)) != *negate {
+                        true => then,
+                        false => otherwise
+                    };
+                    self.render_nodes(branch, scope, blocks, depth, output)?;
+                }
+                Node::For { name, path, body } => {
+                    let items = match scope.lookup(path) {
+                        None | Some(Value::Null) => continue,
+                        Some(Value::Array(items)) => items,
+                        Some(_) => return Err(ServerError::new(format!("Cannot loop over {}.", path.join("."))))
+                    };
+                    for item in items {
+                        scope.locals.push((name, item));
+                        let result = self.render_nodes(body, scope, blocks, depth, output);
+                        scope.locals.pop();
+                        result?;
+                    }
+                }
+                Node::Include(name) => self.render_template(name, scope, depth + 1, output)?,
+                Node::Block { name, body } => {
+                    let body = blocks.get(name.as_str()).copied().unwrap_or(body);
+                    self.render_nodes(body, scope, blocks, depth, output)?;
+                }
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+/// Records the blocks defined by a template, unless a template extending it already defined them.
+fn collect_blocks<'a>(nodes: &'a [Node], blocks: &mut HashMap<&'a str, &'a [Node]>) {
+    for node in nodes {
+        if let Node::Block { name, body } = node {
+            blocks.entry(name.as_str()).or_insert(body.as_slice());
+            collect_blocks(body, blocks);
+        }
+    }
+}
+
+impl HttpResponse {
+    /// Creates an HTML response by rendering one of the global templates.
+    pub fn from_template<T: Serialize>(status_code: &str, name: &str, context: &T) -> Result<HttpResponse> {
+        let html = global().render(name, context)?;
+        return Ok(HttpResponse::new(status_code, "text/html", html.into()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+    use serde_json::json;
+
+    use crate::template::{escape_html, Template, Templates, TEMPLATE_DIR};
+
+    #[derive(Serialize)]
+    struct Block {
+        height: u64,
+        hash: String,
+        transactions: Vec<String>,
+    }
+
+    fn templates(sources: &[(&str, &str)]) -> Templates {
+        let mut templates = Templates::new();
+        for (name, source) in sources.iter() {
+            templates.add(name, source).unwrap();
+        }
+        return templates;
+    }
+
+    fn render(source: &str, context: serde_json::Value) -> String {
+        return templates(&[("page", source)]).render("page", &context).unwrap();
+    }
+
+    #[test]
+    fn variables_are_substituted_and_escaped() {
+        let context = json!({ "name": "<b>Bob & 'Alice'</b>", "block": { "height": 7 }, "missing": null });
+
+        assert_eq!(render("Hi {{ name }}!", context.clone()), "Hi &lt;b&gt;Bob &amp; &#39;Alice&#39;&lt;/b&gt;!");
+        assert_eq!(render("Hi {{ name | raw }}!", context.clone()), "Hi <b>Bob & 'Alice'</b>!");
+        assert_eq!(render("{{block.height}}, {{ missing }}{{ unknown.field }}.", context), "7, .");
+    }
+
+    #[test]
+    fn conditionals_choose_a_branch() {
+        let source = "{% if items %}some{% else %}none{% endif %}{% if not flag %}!{% endif %}";
+
+        assert_eq!(render(source, json!({ "items": [1], "flag": true })), "some");
+        assert_eq!(render(source, json!({ "items": [], "flag": 0 })), "none!");
+        assert_eq!(render(source, json!({})), "none!");
+    }
+
+    #[test]
+    fn loops_repeat_their_body_for_each_item() {
+        let block = Block { height: 3, hash: "00ab".into(), transactions: vec!["tx1".into(), "<tx2>".into()] };
+        let source = "<h1>{{ height }}</h1><ul>{% for tx in transactions %}<li>{{ tx }} in {{ hash }}</li>{% endfor %}</ul>";
+
+        let html = templates(&[("block", source)]).render("block", &block).unwrap();
+
+        assert_eq!(html, "<h1>3</h1><ul><li>tx1 in 00ab</li><li>&lt;tx2&gt; in 00ab</li></ul>");
+    }
+
+    #[test]
+    fn templates_include_and_extend_others() {
+        let templates = templates(&[
+            ("layout", "<title>{% block title %}Blockchain{% endblock %}</title>{% include \"nav\" %}{% block body %}{% endblock %}"),
+            ("nav", "<nav>{{ user }}</nav>"),
+            ("base_page", "{% extends \"layout\" %}{% block title %}Page{% endblock %}{% block body %}<p>base</p>{% endblock %}"),
+            ("page", "\n{% extends \"base_page\" %}{% block body %}<p>{{ user }}</p>{% endblock %}"),
+        ]);
+
+        let html = templates.render("page", &json!({ "user": "bob" })).unwrap();
+
+        assert_eq!(html, "<title>Page</title><nav>bob</nav><p>bob</p>");
+    }
+
+    #[test]
+    fn malformed_templates_fail_to_compile() {
+        let malformed_sources = [
+            "{{ name", "{% if x %}", "{% endif %}", "{% for x of xs %}{% endfor %}", "{% unknown %}",
+            "{{ name | upper }}", "{{ <script> }}", "{% include page %}", "{% if x %}{% endfor %}",
+        ];
+
+        for source in malformed_sources.iter() {
+            assert!(Template::compile(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn missing_and_recursive_templates_fail_to_render() {
+        let templates = templates(&[("missing", "{% include \"other\" %}"), ("loop", "{% include \"loop\" %}")]);
+
+        assert!(templates.render("missing", &json!({})).is_err());
+        assert!(templates.render("loop", &json!({})).is_err());
+        assert!(templates.render("unknown", &json!({})).is_err());
+    }
+
+    #[test]
+    fn bundled_templates_compile() {
+        let templates = Templates::load(TEMPLATE_DIR).unwrap();
+
+        let html = templates.render("404.html", &json!({ "path": "/<script>" })).unwrap();
+
+        assert!(html.contains("/&lt;script&gt;"));
+        assert_eq!(escape_html("a\"b"), "a&quot;b");
+    }
+}
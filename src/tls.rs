@@ -0,0 +1,349 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+
+use rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::servererror::{Result, ServerError};
+
+/// The locations of a PEM-encoded certificate chain and its PEM-encoded private key.
+#[derive(Clone)]
+pub struct CertificatePaths {
+    pub cert_chain_path: String,
+    pub private_key_path: String,
+}
+
+impl CertificatePaths {
+    pub fn new(cert_chain_path: &str, private_key_path: &str) -> CertificatePaths {
+        return CertificatePaths {
+            cert_chain_path: cert_chain_path.into(),
+            private_key_path: private_key_path.into(),
+        };
+    }
+}
+
+/// The TLS settings used to terminate HTTPS connections. Cheap to clone; clones share the same
+/// certificates, so reloading through one clone affects all of them.
+#[derive(Clone)]
+pub struct TlsConfig {
+    // Used to pick a certificate for each incoming connection.
+    resolver: Arc<CertificateResolver>,
+    // The rustls configuration handed to each new connection.
+    server_config: Arc<ServerConfig>,
+}
+
+impl TlsConfig {
+    /// Loads the default certificate, presented to clients that do not send a known server name.
+    pub fn new(default_certificate: CertificatePaths) -> Result<TlsConfig> {
+        let resolver = Arc::new(CertificateResolver::new(default_certificate)?);
+
+        let server_config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+
+        return Ok(TlsConfig { resolver, server_config: Arc::new(server_config) });
+    }
+
+    /// Loads an additional certificate, presented to clients that request the given server name
+    /// via SNI.
+    pub fn add_certificate(&self, server_name: &str, certificate: CertificatePaths) -> Result<()> {
+        return self.resolver.add(server_name, certificate);
+    }
+
+    /// Re-reads every certificate and private key from disk. If any of them fails to load, the
+    /// previously-loaded certificates are kept and an error is returned.
+    pub fn reload(&self) -> Result<()> {
+        return self.resolver.reload();
+    }
+
+    /// Wraps an accepted stream in TLS. The handshake is performed lazily, on first use.
+    pub(crate) fn accept(&self, stream: TcpStream) -> Result<TlsStream> {
+        let connection = ServerConnection::new(self.server_config.clone())?;
+        let stream_owned = StreamOwned::new(connection, stream);
+        return Ok(TlsStream { inner: Rc::new(RefCell::new(stream_owned)) });
+    }
+}
+
+/// A server-side TLS stream. Clones share the same underlying connection, allowing the stream to
+/// be passed to a handler as both its reader and its writer.
+#[derive(Clone)]
+pub(crate) struct TlsStream {
+    inner: Rc<RefCell<StreamOwned<ServerConnection, TcpStream>>>,
+}
+
+impl TlsStream {
+    /// Notifies the client that no more data will be sent, and flushes any pending TLS records.
+    pub(crate) fn close(&self) -> Result<()> {
+        let mut stream = self.inner.borrow_mut();
+        stream.conn.send_close_notify();
+        stream.flush()?;
+        return Ok(());
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.inner.borrow_mut().read(buf);
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        return self.inner.borrow_mut().write(buf);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.inner.borrow_mut().flush();
+    }
+}
+
+/// A certificate, along with the paths it was loaded from so that it can be reloaded.
+struct LoadedCertificate {
+    paths: CertificatePaths,
+    certified_key: Arc<CertifiedKey>,
+}
+
+impl LoadedCertificate {
+    fn load(paths: CertificatePaths) -> Result<LoadedCertificate> {
+        let description = format!("Failed to load certificate {} with key {}", paths.cert_chain_path, paths.private_key_path);
+        return LoadedCertificate::load_inner(paths)
+            .map_err(|e| ServerError::with_cause(description, e));
+    }
+
+    fn load_inner(paths: CertificatePaths) -> Result<LoadedCertificate> {
+        let mut cert_chain_reader = BufReader::new(File::open(&paths.cert_chain_path)?);
+        let cert_chain = rustls_pemfile::certs(&mut cert_chain_reader)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        if cert_chain.is_empty() {
+            return Err(ServerError::new(format!("No certificates found in {}.", paths.cert_chain_path)));
+        }
+
+        let mut private_key_reader = BufReader::new(File::open(&paths.private_key_path)?);
+        let private_key = rustls_pemfile::private_key(&mut private_key_reader)?
+            .ok_or_else(|| ServerError::new(format!("No private key found in {}.", paths.private_key_path)))?;
+        let signing_key = any_supported_type(&private_key)?;
+
+        let certified_key = Arc::new(CertifiedKey::new(cert_chain, signing_key));
+        return Ok(LoadedCertificate { paths, certified_key });
+    }
+}
+
+/// Selects the certificate to present based on the server name requested via SNI.
+struct CertificateResolver {
+    // Presented when the client sends no server name, or one we have no certificate for.
+    default_certificate: RwLock<LoadedCertificate>,
+    // Keyed by lower-case server name.
+    sni_certificates: RwLock<HashMap<String, LoadedCertificate>>,
+}
+
+impl CertificateResolver {
+    fn new(default_certificate: CertificatePaths) -> Result<CertificateResolver> {
+        return Ok(CertificateResolver {
+            default_certificate: RwLock::new(LoadedCertificate::load(default_certificate)?),
+            sni_certificates: RwLock::new(HashMap::new()),
+        });
+    }
+
+    fn add(&self, server_name: &str, certificate: CertificatePaths) -> Result<()> {
+        let loaded_certificate = LoadedCertificate::load(certificate)?;
+        self.sni_certificates.write()?.insert(server_name.to_lowercase(), loaded_certificate);
+        return Ok(());
+    }
+
+    fn reload(&self) -> Result<()> {
+        // We load everything before swapping anything in, so that a failure leaves us unchanged.
+        let default_paths = self.default_certificate.read()?.paths.clone();
+        let reloaded_default = LoadedCertificate::load(default_paths)?;
+
+        let mut reloaded_sni_certificates = HashMap::new();
+        for (server_name, loaded_certificate) in self.sni_certificates.read()?.iter() {
+            let reloaded = LoadedCertificate::load(loaded_certificate.paths.clone())?;
+            reloaded_sni_certificates.insert(server_name.clone(), reloaded);
+        }
+
+        *self.default_certificate.write()? = reloaded_default;
+        *self.sni_certificates.write()? = reloaded_sni_certificates;
+        return Ok(());
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if let Some(server_name) = client_hello.server_name() {
+            let sni_certificates = self.sni_certificates.read().ok()?;
+            if let Some(loaded_certificate) = sni_certificates.get(&server_name.to_lowercase()) {
+                return Some(loaded_certificate.certified_key.clone());
+            }
+        }
+
+        let default_certificate = self.default_certificate.read().ok()?;
+        return Some(default_certificate.certified_key.clone());
+    }
+}
+
+impl fmt::Debug for CertificateResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f.write_str("CertificateResolver");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::env::temp_dir;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+
+    use rustls::crypto::ring::default_provider;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    use crate::handler::DummyHandler;
+    use crate::server::{ServerHandle, ServerInternal};
+    use crate::tls::{CertificatePaths, TlsConfig};
+
+    // Used to allocate different ports for the listeners across tests.
+    static PORT: AtomicU16 = AtomicU16::new(10500);
+    // Used to give each generated certificate its own files.
+    static CERTIFICATE_ID: AtomicUsize = AtomicUsize::new(0);
+
+    fn get_port() -> String {
+        return PORT.fetch_add(1, Ordering::Relaxed).to_string();
+    }
+
+    /// Generates a self-signed certificate for the given server name and writes it to disk.
+    /// Returns the paths and the DER-encoded certificate.
+    fn generate_certificate(server_name: &str) -> (CertificatePaths, CertificateDer<'static>) {
+        let id = CERTIFICATE_ID.fetch_add(1, Ordering::Relaxed);
+        let directory = temp_dir();
+        let cert_chain_path = directory.join(format!("blockchain-test-{}-{}.crt", std::process::id(), id));
+        let private_key_path = directory.join(format!("blockchain-test-{}-{}.key", std::process::id(), id));
+
+        let generated = rcgen::generate_simple_self_signed(vec![server_name.into()]).unwrap();
+        fs::write(&cert_chain_path, generated.cert.pem()).unwrap();
+        fs::write(&private_key_path, generated.key_pair.serialize_pem()).unwrap();
+
+        let paths = CertificatePaths::new(cert_chain_path.to_str().unwrap(), private_key_path.to_str().unwrap());
+        return (paths, generated.cert.der().clone());
+    }
+
+    /// Connects to the server over TLS, trusting only the given certificate. Returns the response
+    /// line and the certificate presented by the server.
+    fn request_over_tls(port: &str, server_name: &str, trusted: &CertificateDer<'static>) -> (String, CertificateDer<'static>) {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted.clone()).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        let connection = ClientConnection::new(Arc::new(config), server_name).unwrap();
+        let tcp_stream = TcpStream::connect(format!("localhost:{}", port)).unwrap();
+        let mut tls_stream = StreamOwned::new(connection, tcp_stream);
+
+        tls_stream.write_all(b" ").unwrap();
+        tls_stream.flush().unwrap();
+        let mut response = String::new();
+        BufReader::new(&mut tls_stream).read_line(&mut response).unwrap();
+
+        let presented = tls_stream.conn.peer_certificates().unwrap()[0].clone();
+        return (response, presented);
+    }
+
+    fn start_server(port: &str, tls_config: TlsConfig) -> ServerHandle {
+        return ServerInternal::start_tls(port, DummyHandler {}, tls_config).unwrap();
+    }
+
+    #[test]
+    fn server_responds_over_tls() {
+        let port = get_port();
+        let (paths, certificate) = generate_certificate("localhost");
+        let mut server_handle = start_server(&port, TlsConfig::new(paths).unwrap());
+
+        let (response, presented) = request_over_tls(&port, "localhost", &certificate);
+
+        assert_eq!(response, "DUMMY\n");
+        assert_eq!(presented, certificate);
+
+        server_handle.stop_listening().unwrap();
+    }
+
+    #[test]
+    fn server_selects_certificate_using_sni() {
+        let port = get_port();
+        let (default_paths, default_certificate) = generate_certificate("localhost");
+        let (other_paths, other_certificate) = generate_certificate("other.test");
+        let tls_config = TlsConfig::new(default_paths).unwrap();
+        tls_config.add_certificate("OTHER.test", other_paths).unwrap();
+        let mut server_handle = start_server(&port, tls_config);
+
+        let (_, presented_for_default) = request_over_tls(&port, "localhost", &default_certificate);
+        let (_, presented_for_other) = request_over_tls(&port, "other.test", &other_certificate);
+
+        assert_eq!(presented_for_default, default_certificate);
+        assert_eq!(presented_for_other, other_certificate);
+
+        server_handle.stop_listening().unwrap();
+    }
+
+    #[test]
+    fn server_presents_reloaded_certificate() {
+        let port = get_port();
+        let (paths, old_certificate) = generate_certificate("localhost");
+        let tls_config = TlsConfig::new(paths.clone()).unwrap();
+        let mut server_handle = start_server(&port, tls_config.clone());
+
+        let (new_paths, new_certificate) = generate_certificate("localhost");
+        fs::copy(&new_paths.cert_chain_path, &paths.cert_chain_path).unwrap();
+        fs::copy(&new_paths.private_key_path, &paths.private_key_path).unwrap();
+        tls_config.reload().unwrap();
+
+        let (response, presented) = request_over_tls(&port, "localhost", &new_certificate);
+
+        assert_eq!(response, "DUMMY\n");
+        assert_eq!(presented, new_certificate);
+        assert_ne!(presented, old_certificate);
+
+        server_handle.stop_listening().unwrap();
+    }
+
+    #[test]
+    fn failed_reload_keeps_previous_certificate() {
+        let port = get_port();
+        let (paths, certificate) = generate_certificate("localhost");
+        let tls_config = TlsConfig::new(paths.clone()).unwrap();
+        let mut server_handle = start_server(&port, tls_config.clone());
+
+        fs::write(&paths.private_key_path, "not a key").unwrap();
+        assert!(tls_config.reload().is_err());
+
+        let (_, presented) = request_over_tls(&port, "localhost", &certificate);
+        assert_eq!(presented, certificate);
+
+        server_handle.stop_listening().unwrap();
+    }
+
+    #[test]
+    fn config_rejects_missing_or_invalid_files() {
+        let (paths, _) = generate_certificate("localhost");
+
+        let missing = CertificatePaths::new("./does/not/exist.crt", &paths.private_key_path);
+        assert!(TlsConfig::new(missing).is_err());
+
+        let swapped = CertificatePaths::new(&paths.private_key_path, &paths.cert_chain_path);
+        assert!(TlsConfig::new(swapped).is_err());
+    }
+}
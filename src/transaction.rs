@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use crate::ledger::Hash;
+use crate::sha256::{sha256, to_hex};
+use crate::wallet::Address;
+
+// The lengths of an Ed25519 public key and signature, in bytes.
+pub const PUBLIC_KEY_LENGTH: usize = 32;
+pub const SIGNATURE_LENGTH: usize = 64;
+
+/// The ways a transaction can be malformed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransactionError {
+    // The transaction pays nobody.
+    NoOutputs,
+    // An output pays nothing.
+    ZeroOutput { output: usize },
+    // The outputs and fee add up to more than an amount can hold.
+    AmountOverflow,
+    // The transaction spends the same output twice.
+    DuplicateInput { input: usize },
+    // The input's signature does not match its public key and the transaction.
+    InvalidSignature { input: usize },
+    // A transaction without inputs that is not the first in its block.
+    MisplacedCoinbase,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            TransactionError::NoOutputs => f.write_str("The transaction has no outputs."),
+            TransactionError::ZeroOutput { output } => write!(f, "Output {} pays nothing.", output),
+            TransactionError::AmountOverflow => f.write_str("The transaction's amounts overflow."),
+            TransactionError::DuplicateInput { input } => write!(f, "Input {} spends an output already spent by the transaction.", input),
+            TransactionError::InvalidSignature { input } => write!(f, "Input {} has an invalid signature.", input),
+            TransactionError::MisplacedCoinbase => f.write_str("Only the first transaction in a block may create coins."),
+        };
+    }
+}
+
+pub type TransactionResult<T> = std::result::Result<T, TransactionError>;
+
+/// A reference to an output of an earlier transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: Hash,
+    // The position of the output in the transaction's outputs.
+    pub index: u32,
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}:{}", to_hex(&self.txid), self.index);
+    }
+}
+
+/// Spends an earlier output, proving ownership by signing the transaction with the key the
+/// output's address was derived from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxInput {
+    pub previous_output: OutPoint,
+    pub public_key: [u8; PUBLIC_KEY_LENGTH],
+    // Signs the transaction's signing hash.
+    pub signature: [u8; SIGNATURE_LENGTH],
+}
+
+/// Pays an amount to an address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxOutput {
+    pub amount: u64,
+    pub address: Address,
+}
+
+/// A transfer of value. The inputs spend earlier outputs, whose amounts must cover the outputs
+/// and the fee, which goes to the block's miner. A transaction without inputs is a coinbase, which
+/// creates coins as a block's reward.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transaction {
+    pub inputs: Vec<TxInput>,
+    pub outputs: Vec<TxOutput>,
+    pub fee: u64,
+    // Distinguishes otherwise identical transactions, such as coinbases paying the same address.
+    pub nonce: u64,
+}
+
+impl Transaction {
+    /// Creates a coinbase paying the amount to the address.
+    pub fn coinbase(address: Address, amount: u64, nonce: u64) -> Transaction {
+        return Transaction { inputs: Vec::new(), outputs: vec![TxOutput { amount, address }], fee: 0, nonce };
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        return self.inputs.is_empty();
+    }
+
+    /// Encodes the transaction for hashing. Counts are four-byte and amounts eight-byte big-endian
+    /// integers. Signatures are left out of the signing hash, as they sign it.
+    fn encode(&self, with_signatures: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.inputs.len() as u32).to_be_bytes());
+        for input in self.inputs.iter() {
+            bytes.extend_from_slice(&input.previous_output.txid);
+            bytes.extend_from_slice(&input.previous_output.index.to_be_bytes());
+            bytes.extend_from_slice(&input.public_key);
+            if with_signatures {
+                bytes.extend_from_slice(&input.signature);
+            }
+        }
+        bytes.extend_from_slice(&(self.outputs.len() as u32).to_be_bytes());
+        for output in self.outputs.iter() {
+            bytes.extend_from_slice(&output.amount.to_be_bytes());
+            bytes.extend_from_slice(output.address.as_bytes());
+        }
+        bytes.extend_from_slice(&self.fee.to_be_bytes());
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        return bytes;
+    }
+
+    /// The transaction's ID: the hash of the whole transaction.
+    pub fn txid(&self) -> Hash {
+        return sha256(&self.encode(true));
+    }
+
+    /// The transaction's size when encoded, in bytes.
+    pub fn size(&self) -> usize {
+        return self.encode(true).len();
+    }
+
+    /// The hash each input signs: the hash of everything but the signatures.
+    pub fn signing_hash(&self) -> Hash {
+        return sha256(&self.encode(false));
+    }
+
+    /// The total paid to the outputs.
+    pub fn output_total(&self) -> TransactionResult<u64> {
+        return self.outputs.iter()
+            .try_fold(0u64, |total, output| total.checked_add(output.amount))
+            .ok_or(TransactionError::AmountOverflow);
+    }
+
+    /// Checks the transaction's form and signatures. Whether the inputs exist, belong to their
+    /// signers and cover the outputs depends on the outputs spent so far, so is checked
+    /// elsewhere.
+    pub fn validate(&self) -> TransactionResult<()> {
+        if self.outputs.is_empty() {
+            return Err(TransactionError::NoOutputs);
+        }
+        if let Some(output) = self.outputs.iter().position(|output| output.amount == 0) {
+            return Err(TransactionError::ZeroOutput { output });
+        }
+        self.output_total()?.checked_add(self.fee).ok_or(TransactionError::AmountOverflow)?;
+
+        let mut spent = HashSet::new();
+        let signing_hash = self.signing_hash();
+        for (index, input) in self.inputs.iter().enumerate() {
+            if !spent.insert(input.previous_output) {
+                return Err(TransactionError::DuplicateInput { input: index });
+            }
+            UnparsedPublicKey::new(&ED25519, &input.public_key).verify(&signing_hash, &input.signature)
+                .map_err(|_| TransactionError::InvalidSignature { input: index })?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction::{OutPoint, Transaction, TransactionError, TxOutput};
+    use crate::wallet::Wallet;
+
+    fn spend(wallet: &Wallet, outputs: Vec<TxOutput>) -> Transaction {
+        let inputs = vec![OutPoint { txid: [1; 32], index: 0 }, OutPoint { txid: [1; 32], index: 1 }];
+        return wallet.create_transaction(inputs, outputs, 10, 7);
+    }
+
+    #[test]
+    fn signed_transactions_are_valid() {
+        let wallet = Wallet::generate().unwrap();
+        let recipient = Wallet::generate().unwrap();
+
+        let transaction = spend(&wallet, vec![TxOutput { amount: 90, address: recipient.address() }]);
+
+        assert_eq!(transaction.validate(), Ok(()));
+        assert_eq!(transaction.output_total(), Ok(90));
+        assert!(!transaction.is_coinbase());
+    }
+
+    #[test]
+    fn tampered_transactions_fail_verification() {
+        let wallet = Wallet::generate().unwrap();
+        let transaction = spend(&wallet, vec![TxOutput { amount: 90, address: wallet.address() }]);
+
+        let mut tampered = transaction.clone();
+        tampered.outputs[0].amount = 95;
+        assert_eq!(tampered.validate(), Err(TransactionError::InvalidSignature { input: 0 }));
+
+        let mut tampered = transaction.clone();
+        tampered.inputs[1].public_key = Wallet::generate().unwrap().public_key();
+        assert_eq!(tampered.validate(), Err(TransactionError::InvalidSignature { input: 0 }));
+
+        let mut tampered = transaction;
+        tampered.inputs[1].signature[0] ^= 1;
+        assert_eq!(tampered.validate(), Err(TransactionError::InvalidSignature { input: 1 }));
+    }
+
+    #[test]
+    fn malformed_transactions_are_rejected() {
+        let wallet = Wallet::generate().unwrap();
+        let address = wallet.address();
+
+        assert_eq!(spend(&wallet, Vec::new()).validate(), Err(TransactionError::NoOutputs));
+        assert_eq!(spend(&wallet, vec![TxOutput { amount: 0, address }]).validate(), Err(TransactionError::ZeroOutput { output: 0 }));
+        assert_eq!(spend(&wallet, vec![TxOutput { amount: u64::MAX, address }]).validate(), Err(TransactionError::AmountOverflow));
+
+        let outpoint = OutPoint { txid: [1; 32], index: 0 };
+        let duplicate = wallet.create_transaction(vec![outpoint, outpoint], vec![TxOutput { amount: 1, address }], 0, 0);
+        assert_eq!(duplicate.validate(), Err(TransactionError::DuplicateInput { input: 1 }));
+    }
+
+    #[test]
+    fn transaction_ids_cover_signatures_but_signing_hashes_do_not() {
+        let wallet = Wallet::generate().unwrap();
+        let transaction = spend(&wallet, vec![TxOutput { amount: 90, address: wallet.address() }]);
+
+        let mut resigned = transaction.clone();
+        resigned.inputs[0].signature = [0; 64];
+        assert_eq!(resigned.signing_hash(), transaction.signing_hash());
+        assert_ne!(resigned.txid(), transaction.txid());
+
+        let coinbase = Transaction::coinbase(wallet.address(), 50, 1);
+        assert!(coinbase.is_coinbase());
+        assert_eq!(coinbase.validate(), Ok(()));
+        assert_ne!(coinbase.txid(), Transaction::coinbase(wallet.address(), 50, 2).txid());
+    }
+}
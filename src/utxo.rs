@@ -0,0 +1,403 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use crate::ledger::{Block, Hash};
+use crate::sha256::to_hex;
+use crate::transaction::{OutPoint, Transaction, TxOutput};
+use crate::wallet::Address;
+
+/// The ways a block or transaction can fail to spend from the set.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UtxoError {
+    // The output does not exist, or has already been spent.
+    MissingOutput { output: OutPoint },
+    // The output is spent twice in the same transaction or block.
+    DoubleSpend { output: OutPoint },
+    // The input's public key does not match the address the output pays.
+    WrongOwner { output: OutPoint },
+    // The inputs do not cover the outputs and fee.
+    Overspend { txid: Hash, available: u64, required: u64 },
+    // The inputs exceed the outputs and fee, so the difference would be lost.
+    UnclaimedSurplus { txid: Hash, available: u64, required: u64 },
+    // The transaction's outputs already exist.
+    DuplicateTransaction { txid: Hash },
+    // The coinbase pays more than the block reward and the block's fees.
+    ExcessiveCoinbase { claimed: u64, allowed: u64 },
+    // The amounts add up to more than an amount can hold.
+    AmountOverflow,
+    // The block does not follow the latest block applied, or is not the latest when reverted.
+    UnexpectedBlock { index: u64 },
+}
+
+impl fmt::Display for UtxoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            UtxoError::MissingOutput { output } => write!(f, "Output {} does not exist or is already spent.", output),
+            UtxoError::DoubleSpend { output } => write!(f, "Output {} is spent twice.", output),
+            UtxoError::WrongOwner { output } => write!(f, "Output {} is not paid to the spender's key.", output),
+            UtxoError::Overspend { txid, available, required } => write!(f, "Transaction {} spends {} but has only {}.", to_hex(txid), required, available),
+            UtxoError::UnclaimedSurplus { txid, available, required } => write!(f, "Transaction {} spends {} of its {}.", to_hex(txid), required, available),
+            UtxoError::DuplicateTransaction { txid } => write!(f, "Transaction {} already has unspent outputs.", to_hex(txid)),
+            UtxoError::ExcessiveCoinbase { claimed, allowed } => write!(f, "The coinbase claims {} but only {} is allowed.", claimed, allowed),
+            UtxoError::AmountOverflow => f.write_str("The amounts overflow."),
+            UtxoError::UnexpectedBlock { index } => write!(f, "Block {} is not next in the set's chain.", index),
+        };
+    }
+}
+
+pub type UtxoResult<T> = std::result::Result<T, UtxoError>;
+
+/// What applying a block changed, so that it can be reverted.
+#[derive(Clone, Debug)]
+struct BlockUndo {
+    hash: Hash,
+    // The outputs the block spent.
+    spent: Vec<(OutPoint, TxOutput)>,
+    // The outputs the block created.
+    created: Vec<OutPoint>,
+}
+
+/// The unspent transaction outputs: everything that can be spent, as of the latest block applied.
+/// Blocks are applied and reverted in chain order, and the balance of each address is kept as they
+/// are.
+#[derive(Clone, Debug)]
+pub struct UtxoSet {
+    // The coins each block's coinbase may create, on top of the block's fees.
+    block_reward: u64,
+    outputs: HashMap<OutPoint, TxOutput>,
+    balances: HashMap<Address, u64>,
+    // One entry per block applied, the latest last.
+    undo: Vec<BlockUndo>,
+}
+
+impl UtxoSet {
+    pub fn new(block_reward: u64) -> UtxoSet {
+        return UtxoSet { block_reward, outputs: HashMap::new(), balances: HashMap::new(), undo: Vec::new() };
+    }
+
+    /// Builds the set from scratch by applying the blocks in order, e.g. to check a set kept up to
+    /// date incrementally against its snapshot.
+    pub fn rebuild(block_reward: u64, blocks: &[Block]) -> UtxoResult<UtxoSet> {
+        let mut set = UtxoSet::new(block_reward);
+        for block in blocks.iter() {
+            set.apply_block(block)?;
+        }
+        return Ok(set);
+    }
+
+    pub fn get(&self, output: &OutPoint) -> Option<&TxOutput> {
+        return self.outputs.get(output);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.outputs.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.outputs.is_empty();
+    }
+
+    /// The total of the unspent outputs paying the address.
+    pub fn balance(&self, address: &Address) -> u64 {
+        return self.balances.get(address).copied().unwrap_or(0);
+    }
+
+    /// The unspent outputs paying the address, in order.
+    pub fn outputs_for(&self, address: &Address) -> Vec<(OutPoint, TxOutput)> {
+        let mut outputs: Vec<_> = self.outputs.iter()
+            .filter(|(_, output)| output.address == *address)
+            .map(|(outpoint, output)| (*outpoint, output.clone()))
+            .collect();
+        outputs.sort_by_key(|(outpoint, _)| *outpoint);
+        return outputs;
+    }
+
+    /// A copy of the unspent outputs, ordered so that snapshots can be compared.
+    pub fn snapshot(&self) -> BTreeMap<OutPoint, TxOutput> {
+        return self.outputs.iter().map(|(outpoint, output)| (*outpoint, output.clone())).collect();
+    }
+
+    /// Checks that the transaction can spend from the set as it stands: its inputs exist, belong to
+    /// their signers, and exactly cover its outputs and fee. Signatures are checked by the
+    /// transaction itself.
+    pub fn validate_transaction(&self, transaction: &Transaction) -> UtxoResult<()> {
+        let mut spent = HashSet::new();
+        let mut available: u64 = 0;
+        for input in transaction.inputs.iter() {
+            let outpoint = input.previous_output;
+            if !spent.insert(outpoint) {
+                return Err(UtxoError::DoubleSpend { output: outpoint });
+            }
+            let output = self.outputs.get(&outpoint).ok_or(UtxoError::MissingOutput { output: outpoint })?;
+            if output.address != Address::from_public_key(&input.public_key) {
+                return Err(UtxoError::WrongOwner { output: outpoint });
+            }
+            available = available.checked_add(output.amount).ok_or(UtxoError::AmountOverflow)?;
+        }
+
+        let required = transaction.output_total().ok()
+            .and_then(|total| total.checked_add(transaction.fee))
+            .ok_or(UtxoError::AmountOverflow)?;
+        if available < required {
+            return Err(UtxoError::Overspend { txid: transaction.txid(), available, required });
+        }
+        if available > required {
+            return Err(UtxoError::UnclaimedSurplus { txid: transaction.txid(), available, required });
+        }
+        return Ok(());
+    }
+
+    /// Spends the block's inputs and adds its outputs, in transaction order, so that a
+    /// transaction may spend an output created earlier in the same block. The block must follow
+    /// the latest block applied. If any transaction is invalid, the set is left unchanged.
+    pub fn apply_block(&mut self, block: &Block) -> UtxoResult<()> {
+        if let Some(latest) = self.undo.last() {
+            if block.header.previous_hash != latest.hash {
+                return Err(UtxoError::UnexpectedBlock { index: block.header.index });
+            }
+        }
+
+        let mut undo = BlockUndo { hash: block.header.hash, spent: Vec::new(), created: Vec::new() };
+        if let Err(e) = self.apply_transactions(block, &mut undo) {
+            self.restore(undo);
+            return Err(e);
+        }
+        self.undo.push(undo);
+        return Ok(());
+    }
+
+    /// Undoes the latest block applied, which must be the given block, restoring the outputs it
+    /// spent.
+    pub fn revert_block(&mut self, block: &Block) -> UtxoResult<()> {
+        match self.undo.last() {
+            Some(latest) if latest.hash == block.header.hash => {}
+            _ => return Err(UtxoError::UnexpectedBlock { index: block.header.index })
+        }
+        // Checked above.
+        let undo = self.undo.pop().unwrap();
+        self.restore(undo);
+        return Ok(());
+    }
+
+    /// Applies the transactions, recording each change in the undo entry as it is made.
+    fn apply_transactions(&mut self, block: &Block, undo: &mut BlockUndo) -> UtxoResult<()> {
+        let mut fees: u64 = 0;
+        for transaction in block.transactions.iter() {
+            if !transaction.is_coinbase() {
+                // An output spent earlier in the block is gone from the set by now.
+                if let Some(input) = transaction.inputs.iter().find(|input| undo.spent.iter().any(|(spent, _)| *spent == input.previous_output)) {
+                    return Err(UtxoError::DoubleSpend { output: input.previous_output });
+                }
+                self.validate_transaction(transaction)?;
+                fees = fees.checked_add(transaction.fee).ok_or(UtxoError::AmountOverflow)?;
+
+                for input in transaction.inputs.iter() {
+                    // Checked to exist above.
+                    let output = self.outputs.remove(&input.previous_output).unwrap();
+                    self.debit(&output);
+                    undo.spent.push((input.previous_output, output));
+                }
+            }
+
+            let txid = transaction.txid();
+            for (index, output) in transaction.outputs.iter().enumerate() {
+                let outpoint = OutPoint { txid, index: index as u32 };
+                if self.outputs.contains_key(&outpoint) {
+                    return Err(UtxoError::DuplicateTransaction { txid });
+                }
+                self.credit(output);
+                self.outputs.insert(outpoint, output.clone());
+                undo.created.push(outpoint);
+            }
+        }
+
+        if let Some(coinbase) = block.transactions.first().filter(|transaction| transaction.is_coinbase()) {
+            let claimed = coinbase.output_total().map_err(|_| UtxoError::AmountOverflow)?;
+            let allowed = self.block_reward.checked_add(fees).ok_or(UtxoError::AmountOverflow)?;
+            if claimed > allowed {
+                return Err(UtxoError::ExcessiveCoinbase { claimed, allowed });
+            }
+        }
+        return Ok(());
+    }
+
+    /// Reverses the changes recorded in the undo entry. The spent outputs are restored first, so
+    /// that those the block both created and spent are then removed with the rest it created.
+    fn restore(&mut self, undo: BlockUndo) {
+        for (outpoint, output) in undo.spent.into_iter() {
+            self.credit(&output);
+            self.outputs.insert(outpoint, output);
+        }
+        for outpoint in undo.created.iter() {
+            if let Some(output) = self.outputs.remove(outpoint) {
+                self.debit(&output);
+            }
+        }
+    }
+
+    fn credit(&mut self, output: &TxOutput) {
+        let balance = self.balances.entry(output.address).or_insert(0);
+        *balance = balance.saturating_add(output.amount);
+    }
+
+    fn debit(&mut self, output: &TxOutput) {
+        if let Some(balance) = self.balances.get_mut(&output.address) {
+            *balance = balance.saturating_sub(output.amount);
+            if *balance == 0 {
+                self.balances.remove(&output.address);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ledger::{Block, Hash};
+    use crate::transaction::{OutPoint, Transaction, TxOutput};
+    use crate::utxo::{UtxoError, UtxoSet};
+    use crate::wallet::Wallet;
+
+    const REWARD: u64 = 50;
+
+    /// Builds a block following the previous one. The set does not check hashes or work.
+    fn block(previous: &Block, transactions: Vec<Transaction>) -> Block {
+        return Block::new(previous.header.index + 1, previous.header.timestamp + 10, previous.header.hash, 0, 0, transactions);
+    }
+
+    fn pay(wallet: &Wallet, spent: Vec<OutPoint>, payments: Vec<(&Wallet, u64)>, fee: u64) -> Transaction {
+        let outputs = payments.into_iter().map(|(payee, amount)| TxOutput { amount, address: payee.address() }).collect();
+        return wallet.create_transaction(spent, outputs, fee, 0);
+    }
+
+    fn outpoint(txid: Hash, index: u32) -> OutPoint {
+        return OutPoint { txid, index };
+    }
+
+    /// A set in which Alice has mined one block.
+    fn funded(alice: &Wallet) -> (UtxoSet, Block, Transaction) {
+        let mut set = UtxoSet::new(REWARD);
+        let genesis = Block::genesis();
+        set.apply_block(&genesis).unwrap();
+        let coinbase = Transaction::coinbase(alice.address(), REWARD, 1);
+        let mined = block(&genesis, vec![coinbase.clone()]);
+        set.apply_block(&mined).unwrap();
+        return (set, mined, coinbase);
+    }
+
+    #[test]
+    fn blocks_move_coins_between_addresses() {
+        let (alice, bob) = (Wallet::generate().unwrap(), Wallet::generate().unwrap());
+        let (mut set, mined, coinbase) = funded(&alice);
+        assert_eq!(set.balance(&alice.address()), 50);
+
+        let payment = pay(&alice, vec![outpoint(coinbase.txid(), 0)], vec![(&bob, 30), (&alice, 15)], 5);
+        let next = block(&mined, vec![Transaction::coinbase(bob.address(), 55, 2), payment.clone()]);
+        set.apply_block(&next).unwrap();
+
+        assert_eq!(set.balance(&alice.address()), 15);
+        assert_eq!(set.balance(&bob.address()), 85);
+        assert_eq!(set.get(&outpoint(coinbase.txid(), 0)), None);
+        assert_eq!(set.outputs_for(&alice.address()), vec![(outpoint(payment.txid(), 1), payment.outputs[1].clone())]);
+        assert_eq!(set.len(), 3);
+    }
+
+    #[test]
+    fn double_spends_and_overspends_are_rejected() {
+        let (alice, bob) = (Wallet::generate().unwrap(), Wallet::generate().unwrap());
+        let (mut set, mined, coinbase) = funded(&alice);
+        let spent = outpoint(coinbase.txid(), 0);
+
+        let overspend = pay(&alice, vec![spent], vec![(&bob, 50)], 1);
+        assert_eq!(set.validate_transaction(&overspend), Err(UtxoError::Overspend { txid: overspend.txid(), available: 50, required: 51 }));
+        let surplus = pay(&alice, vec![spent], vec![(&bob, 40)], 1);
+        assert_eq!(set.validate_transaction(&surplus), Err(UtxoError::UnclaimedSurplus { txid: surplus.txid(), available: 50, required: 41 }));
+        let stolen = pay(&bob, vec![spent], vec![(&bob, 50)], 0);
+        assert_eq!(set.validate_transaction(&stolen), Err(UtxoError::WrongOwner { output: spent }));
+        let missing = pay(&alice, vec![outpoint(coinbase.txid(), 1)], vec![(&bob, 50)], 0);
+        assert_eq!(set.validate_transaction(&missing), Err(UtxoError::MissingOutput { output: outpoint(coinbase.txid(), 1) }));
+
+        let first = pay(&alice, vec![spent], vec![(&bob, 50)], 0);
+        let second = pay(&alice, vec![spent], vec![(&alice, 50)], 0);
+        let snapshot = set.snapshot();
+        assert_eq!(set.apply_block(&block(&mined, vec![first.clone(), second])), Err(UtxoError::DoubleSpend { output: spent }));
+        // A rejected block leaves the set as it was.
+        assert_eq!(set.snapshot(), snapshot);
+        assert_eq!(set.balance(&alice.address()), 50);
+        assert_eq!(set.balance(&bob.address()), 0);
+
+        let next = block(&mined, vec![first]);
+        set.apply_block(&next).unwrap();
+        let replay = pay(&alice, vec![spent], vec![(&alice, 50)], 0);
+        assert_eq!(set.apply_block(&block(&next, vec![replay])), Err(UtxoError::MissingOutput { output: spent }));
+    }
+
+    #[test]
+    fn coinbases_may_claim_only_the_reward_and_fees() {
+        let (alice, bob) = (Wallet::generate().unwrap(), Wallet::generate().unwrap());
+        let (mut set, mined, coinbase) = funded(&alice);
+        let payment = pay(&alice, vec![outpoint(coinbase.txid(), 0)], vec![(&bob, 45)], 5);
+
+        let greedy = block(&mined, vec![Transaction::coinbase(bob.address(), 56, 2), payment.clone()]);
+        assert_eq!(set.apply_block(&greedy), Err(UtxoError::ExcessiveCoinbase { claimed: 56, allowed: 55 }));
+        assert_eq!(set.balance(&alice.address()), 50);
+
+        let repeated = block(&mined, vec![coinbase]);
+        assert_eq!(set.apply_block(&repeated), Err(UtxoError::DuplicateTransaction { txid: repeated.transactions[0].txid() }));
+        assert_eq!(set.balance(&alice.address()), 50);
+    }
+
+    #[test]
+    fn reverting_blocks_restores_spent_outputs() {
+        let (alice, bob) = (Wallet::generate().unwrap(), Wallet::generate().unwrap());
+        let (mut set, mined, coinbase) = funded(&alice);
+        let before = set.snapshot();
+
+        let payment = pay(&alice, vec![outpoint(coinbase.txid(), 0)], vec![(&bob, 50)], 0);
+        let next = block(&mined, vec![payment]);
+        set.apply_block(&next).unwrap();
+
+        // Only the latest block can be reverted.
+        assert_eq!(set.revert_block(&mined), Err(UtxoError::UnexpectedBlock { index: 1 }));
+        set.revert_block(&next).unwrap();
+        assert_eq!(set.snapshot(), before);
+        assert_eq!(set.balance(&alice.address()), 50);
+        assert_eq!(set.balance(&bob.address()), 0);
+
+        set.revert_block(&mined).unwrap();
+        assert_eq!(set.balance(&alice.address()), 0);
+        assert!(set.is_empty());
+        assert_eq!(set.apply_block(&next), Err(UtxoError::UnexpectedBlock { index: 2 }));
+    }
+
+    #[test]
+    fn reverting_blocks_removes_outputs_created_and_spent_within_them() {
+        let (alice, bob) = (Wallet::generate().unwrap(), Wallet::generate().unwrap());
+        let (mut set, mined, coinbase) = funded(&alice);
+        let before = set.snapshot();
+
+        let payment = pay(&alice, vec![outpoint(coinbase.txid(), 0)], vec![(&alice, 50)], 0);
+        let onward = pay(&alice, vec![outpoint(payment.txid(), 0)], vec![(&bob, 50)], 0);
+        let next = block(&mined, vec![payment, onward]);
+        set.apply_block(&next).unwrap();
+        assert_eq!(set.balance(&bob.address()), 50);
+
+        set.revert_block(&next).unwrap();
+        assert_eq!(set.snapshot(), before);
+        assert_eq!(set.balance(&alice.address()), 50);
+        assert_eq!(set.balance(&bob.address()), 0);
+    }
+
+    #[test]
+    fn rebuilt_sets_match_incrementally_applied_ones() {
+        let (alice, bob) = (Wallet::generate().unwrap(), Wallet::generate().unwrap());
+        let (mut set, mined, coinbase) = funded(&alice);
+        let payment = pay(&alice, vec![outpoint(coinbase.txid(), 0)], vec![(&bob, 20), (&alice, 30)], 0);
+        let next = block(&mined, vec![Transaction::coinbase(bob.address(), 50, 2), payment]);
+        set.apply_block(&next).unwrap();
+
+        let rebuilt = UtxoSet::rebuild(REWARD, &[Block::genesis(), mined, next]).unwrap();
+
+        assert_eq!(rebuilt.snapshot(), set.snapshot());
+        assert_eq!(rebuilt.balance(&bob.address()), 70);
+    }
+}
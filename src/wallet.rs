@@ -0,0 +1,192 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+
+use crate::servererror::{Result, ServerError};
+use crate::sha256::{sha256, to_hex};
+use crate::transaction::{OutPoint, Transaction, TxInput, TxOutput, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+
+// The length of an address, in bytes.
+pub const ADDRESS_LENGTH: usize = 20;
+// The length of the checksum appended to an address when it is written out, in bytes.
+const CHECKSUM_LENGTH: usize = 4;
+
+/// Where coins are paid: the first 20 bytes of the SHA-256 digest of the owner's public key.
+/// Written as hexadecimal followed by a four-byte checksum, so that mistyped addresses are caught.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Address([u8; ADDRESS_LENGTH]);
+
+impl Address {
+    pub fn from_public_key(public_key: &[u8]) -> Address {
+        let mut address = [0u8; ADDRESS_LENGTH];
+        address.copy_from_slice(&sha256(public_key)[..ADDRESS_LENGTH]);
+        return Address(address);
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        return &self.0;
+    }
+
+    fn checksum(&self) -> [u8; CHECKSUM_LENGTH] {
+        let mut checksum = [0u8; CHECKSUM_LENGTH];
+        checksum.copy_from_slice(&sha256(&self.0)[..CHECKSUM_LENGTH]);
+        return checksum;
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}{}", to_hex(&self.0), to_hex(&self.checksum()));
+    }
+}
+
+impl FromStr for Address {
+    type Err = ServerError;
+
+    fn from_str(text: &str) -> Result<Address> {
+        let invalid = || ServerError::new(format!("Invalid address: {}", text));
+        if text.len() != (ADDRESS_LENGTH + CHECKSUM_LENGTH) * 2 || !text.is_ascii() {
+            return Err(invalid());
+        }
+
+        let mut bytes = [0u8; ADDRESS_LENGTH + CHECKSUM_LENGTH];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&text[index * 2..index * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        let mut address = Address([0u8; ADDRESS_LENGTH]);
+        address.0.copy_from_slice(&bytes[..ADDRESS_LENGTH]);
+        if address.checksum()[..] != bytes[ADDRESS_LENGTH..] {
+            return Err(invalid());
+        }
+        return Ok(address);
+    }
+}
+
+/// An Ed25519 key pair, which receives coins at its address and signs the transactions that spend
+/// them. Stored as a PKCS#8 document.
+pub struct Wallet {
+    key_pair: Ed25519KeyPair,
+    pkcs8: Vec<u8>,
+}
+
+impl Wallet {
+    /// Generates a new key pair.
+    pub fn generate() -> Result<Wallet> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| ServerError::new("Failed to generate a key pair.".into()))?;
+        return Wallet::from_pkcs8(pkcs8.as_ref());
+    }
+
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Wallet> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8)
+            .map_err(|e| ServerError::new(format!("Invalid key pair: {}", e)))?;
+        return Ok(Wallet { key_pair, pkcs8: pkcs8.to_vec() });
+    }
+
+    /// Reads a wallet saved by `save`.
+    pub fn load(path: &Path) -> Result<Wallet> {
+        return Wallet::from_pkcs8(&fs::read(path)?);
+    }
+
+    /// Saves the key pair to a new file, readable only by its owner. Fails rather than overwrite
+    /// an existing wallet.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(path)?;
+        file.write_all(&self.pkcs8)?;
+        file.sync_all()?;
+        return Ok(());
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LENGTH] {
+        let mut public_key = [0u8; PUBLIC_KEY_LENGTH];
+        public_key.copy_from_slice(self.key_pair.public_key().as_ref());
+        return public_key;
+    }
+
+    pub fn address(&self) -> Address {
+        return Address::from_public_key(self.key_pair.public_key().as_ref());
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; SIGNATURE_LENGTH] {
+        let mut signature = [0u8; SIGNATURE_LENGTH];
+        signature.copy_from_slice(self.key_pair.sign(message).as_ref());
+        return signature;
+    }
+
+    /// Creates a transaction spending the given outputs, which must be paid to this wallet's
+    /// address, and signs each input.
+    pub fn create_transaction(&self, spent: Vec<OutPoint>, outputs: Vec<TxOutput>, fee: u64, nonce: u64) -> Transaction {
+        let public_key = self.public_key();
+        let inputs = spent.into_iter()
+            .map(|previous_output| TxInput { previous_output, public_key, signature: [0; SIGNATURE_LENGTH] })
+            .collect();
+        let mut transaction = Transaction { inputs, outputs, fee, nonce };
+
+        let signature = self.sign(&transaction.signing_hash());
+        for input in transaction.inputs.iter_mut() {
+            input.signature = signature;
+        }
+        return transaction;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs;
+
+    use crate::sha256::sha256;
+    use crate::wallet::{Address, Wallet};
+
+    #[test]
+    fn addresses_are_derived_from_public_keys() {
+        let wallet = Wallet::generate().unwrap();
+
+        assert_eq!(wallet.address().as_bytes(), &sha256(&wallet.public_key())[..20]);
+        assert_ne!(Wallet::generate().unwrap().address(), wallet.address());
+    }
+
+    #[test]
+    fn addresses_round_trip_through_text_with_a_checksum() {
+        let address = Wallet::generate().unwrap().address();
+        let text = address.to_string();
+
+        assert_eq!(text.len(), 48);
+        assert_eq!(text.parse::<Address>().unwrap(), address);
+
+        // Changing any character breaks the checksum.
+        let last = if text.ends_with('0') { "1" } else { "0" };
+        assert!(format!("{}{}", &text[..47], last).parse::<Address>().is_err());
+        assert!(text[..40].parse::<Address>().is_err());
+        assert!(format!("{}é", &text[..46]).parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn wallets_are_saved_and_loaded() {
+        let path = temp_dir().join(format!("blockchain-test-{}-wallet", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let wallet = Wallet::generate().unwrap();
+
+        wallet.save(&path).unwrap();
+        let loaded = Wallet::load(&path).unwrap();
+
+        assert_eq!(loaded.address(), wallet.address());
+        assert!(wallet.save(&path).is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,931 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::digest;
+
+use crate::handler::{HttpRequest, HttpResponse};
+use crate::logger;
+use crate::metrics;
+use crate::servererror::{Result, ServerError};
+
+// Appended to the client's key to produce Sec-WebSocket-Accept, per RFC 6455.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const WEBSOCKET_VERSION: &str = "13";
+// The methods accepted by WebSocket routes.
+pub const WEBSOCKET_ALLOWED_METHODS: &str = "GET, OPTIONS";
+// How often an open connection checks for messages to send while waiting for the client.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Outgoing messages are split into frames of at most this size.
+const MAX_OUTGOING_FRAME_SIZE: usize = 64 * 1024;
+// How many outgoing messages may be queued for a connection before it is closed as too slow.
+const OUTBOX_CAPACITY: usize = 256;
+// Control frames may not carry more than this.
+const MAX_CONTROL_PAYLOAD_LENGTH: usize = 125;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+pub const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+
+/// Limits applied to each WebSocket connection.
+#[derive(Clone, Debug)]
+pub struct WebSocketLimits {
+    // The largest message accepted from a client, in bytes, across all its fragments.
+    pub max_message_size: usize,
+    // How long a client may be silent before it is pinged, and then how long it has to answer
+    // before the connection is dropped.
+    pub ping_interval: Duration,
+    // How long to wait for the client to answer a close frame.
+    pub close_timeout: Duration,
+}
+
+impl Default for WebSocketLimits {
+    fn default() -> WebSocketLimits {
+        return WebSocketLimits {
+            max_message_size: 1024 * 1024,
+            ping_interval: Duration::from_secs(30),
+            close_timeout: Duration::from_secs(5),
+        };
+    }
+}
+
+/// A complete message, reassembled from its fragments.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Something queued to be sent on a connection.
+enum Outgoing {
+    Message(Message),
+    Close(u16, String),
+}
+
+/// Queues messages to be sent on a connection. Clones can be kept and used from other threads,
+/// e.g. to push events to the client. Sending fails once the connection has closed, or if the
+/// client has fallen too far behind, in which case the connection is closed.
+#[derive(Clone)]
+pub struct WebSocketSender {
+    outbox: SyncSender<Outgoing>,
+    // Set if the outbox fills up.
+    overflowed: Arc<AtomicBool>,
+    // Set once the connection has ended.
+    closed: Arc<AtomicBool>,
+}
+
+impl WebSocketSender {
+    pub fn send(&self, message: Message) -> Result<()> {
+        return self.queue(Outgoing::Message(message));
+    }
+
+    /// Starts the close handshake, once the messages queued so far have been sent.
+    pub fn close(&self, code: u16, reason: &str) -> Result<()> {
+        return self.queue(Outgoing::Close(code, reason.into()));
+    }
+
+    /// Whether the connection has ended, so that nothing more can be sent.
+    pub fn is_closed(&self) -> bool {
+        return self.closed.load(Ordering::SeqCst);
+    }
+
+    fn queue(&self, outgoing: Outgoing) -> Result<()> {
+        return match self.outbox.try_send(outgoing) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.overflowed.store(true, Ordering::SeqCst);
+                Err(ServerError::new("The WebSocket client is too slow.".into()))
+            }
+            Err(TrySendError::Disconnected(_)) => Err(ServerError::new("The WebSocket connection is closed.".into()))
+        };
+    }
+}
+
+/// Serves the connections to a WebSocket route. Each connection is served on its own thread,
+/// which calls the handler as messages arrive.
+pub trait WebSocketHandler: Send + Sync {
+    // Called once the handshake has completed, before any messages are received.
+    fn on_open(&self, request: &HttpRequest, sender: WebSocketSender) -> Result<()>;
+
+    // Called for each message received. Errors close the connection.
+    fn on_message(&self, message: Message, sender: &WebSocketSender) -> Result<()>;
+}
+
+/// The WebSocket routes, keyed by request URI, and the limits applied to their connections.
+#[derive(Clone, Default)]
+pub struct WebSocketRoutes {
+    routes: HashMap<String, Arc<dyn WebSocketHandler>>,
+    limits: WebSocketLimits,
+}
+
+impl WebSocketRoutes {
+    pub fn new(limits: WebSocketLimits) -> WebSocketRoutes {
+        return WebSocketRoutes { routes: HashMap::new(), limits };
+    }
+
+    pub fn add<H: WebSocketHandler + 'static>(&mut self, path: &str, handler: Arc<H>) {
+        self.routes.insert(path.into(), handler);
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Arc<dyn WebSocketHandler>> {
+        return self.routes.get(path);
+    }
+
+    pub fn limits(&self) -> &WebSocketLimits {
+        return &self.limits;
+    }
+}
+
+/// Checks that the request is a valid WebSocket handshake, and returns the 101 response accepting
+/// it. Otherwise, returns a 400, or a 426 if the client must use another protocol version.
+pub fn handshake_response(request: &HttpRequest) -> HttpResponse {
+    let has_token = |header: &str, token: &str| request.header(header)
+        .map(|value| value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token)))
+        .unwrap_or(false);
+
+    if request.method != "GET" || !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        let mut response = HttpResponse::new("426 UPGRADE REQUIRED", "text/plain", "Expected a WebSocket handshake.".into());
+        response.set_header("Upgrade", "websocket");
+        return response;
+    }
+    if request.header("Sec-WebSocket-Version") != Some(WEBSOCKET_VERSION) {
+        let mut response = HttpResponse::new("426 UPGRADE REQUIRED", "text/plain", "Unsupported WebSocket version.".into());
+        response.set_header("Sec-WebSocket-Version", WEBSOCKET_VERSION);
+        return response;
+    }
+
+    let key = request.header("Sec-WebSocket-Key").unwrap_or("");
+    if STANDARD.decode(key).map(|nonce| nonce.len()).ok() != Some(16) {
+        return HttpResponse::new("400 BAD REQUEST", "text/plain", "Invalid Sec-WebSocket-Key.".into());
+    }
+
+    let mut response = HttpResponse::empty("101 SWITCHING PROTOCOLS");
+    response.set_header("Upgrade", "websocket");
+    response.set_header("Connection", "Upgrade");
+    response.set_header("Sec-WebSocket-Accept", &accept_key(key));
+    return response;
+}
+
+/// Derives Sec-WebSocket-Accept from Sec-WebSocket-Key.
+fn accept_key(key: &str) -> String {
+    let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, format!("{}{}", key, WEBSOCKET_GUID).as_bytes());
+    return STANDARD.encode(hash.as_ref());
+}
+
+/// A connection that has completed the handshake, and is to be served by a WebSocket handler.
+pub struct WebSocketUpgrade {
+    pub(crate) handler: Arc<dyn WebSocketHandler>,
+    pub(crate) request: HttpRequest,
+    pub(crate) limits: WebSocketLimits,
+    // Set once the server begins shutting down, to close the connection.
+    pub(crate) shutting_down: Arc<AtomicBool>,
+}
+
+impl WebSocketUpgrade {
+    /// Serves the connection until it closes. The socket is the one underlying the reader, and
+    /// has its read timeout set so that the connection can send messages while waiting for the
+    /// client.
+    pub fn run<R: Read, W: Write>(self, socket: &TcpStream, reader: R, writer: W) -> Result<()> {
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        metrics::global().websocket_connections_active.inc();
+        let result = WebSocketConnection::new(reader, writer, self.limits).run(self.handler.as_ref(), &self.request, &self.shutting_down);
+        metrics::global().websocket_connections_active.dec();
+        return result;
+    }
+}
+
+/// A single frame, with its payload unmasked.
+#[derive(Debug, PartialEq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// The reason a connection must be closed, sent to the client in the close frame.
+#[derive(Debug, PartialEq)]
+struct CloseReason {
+    code: u16,
+    reason: String,
+}
+
+impl CloseReason {
+    fn new(code: u16, reason: &str) -> CloseReason {
+        return CloseReason { code, reason: reason.into() };
+    }
+}
+
+/// Encodes an unmasked frame, as sent by servers.
+fn encode_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(if fin { 0x80 } else { 0x00 } | opcode);
+
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+
+    frame.extend_from_slice(payload);
+    return frame;
+}
+
+/// Decodes the masked frames sent by clients from the bytes received so far.
+struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_payload_length: usize,
+}
+
+impl FrameDecoder {
+    /// Returns the next frame, or None if it has not been fully received.
+    fn decode(&mut self) -> std::result::Result<Option<Frame>, CloseReason> {
+        if self.buffer.len() < 2 {
+            return Ok(None);
+        }
+        let (first, second) = (self.buffer[0], self.buffer[1]);
+        let fin = first & 0x80 != 0;
+        let opcode = first & 0x0F;
+        let is_control = opcode & 0x08 != 0;
+
+        if first & 0x70 != 0 {
+            return Err(CloseReason::new(CLOSE_PROTOCOL_ERROR, "Reserved bits must not be set."));
+        }
+        if ![OPCODE_CONTINUATION, OPCODE_TEXT, OPCODE_BINARY, OPCODE_CLOSE, OPCODE_PING, OPCODE_PONG].contains(&opcode) {
+            return Err(CloseReason::new(CLOSE_PROTOCOL_ERROR, "Unknown opcode."));
+        }
+        if second & 0x80 == 0 {
+            return Err(CloseReason::new(CLOSE_PROTOCOL_ERROR, "Client frames must be masked."));
+        }
+        if is_control && !fin {
+            return Err(CloseReason::new(CLOSE_PROTOCOL_ERROR, "Control frames must not be fragmented."));
+        }
+
+        let (payload_length, mut offset) = match second & 0x7F {
+            126 if self.buffer.len() >= 4 => (u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as u64, 4),
+            127 if self.buffer.len() >= 10 => {
+                let mut length = [0u8; 8];
+                length.copy_from_slice(&self.buffer[2..10]);
+                (u64::from_be_bytes(length), 10)
+            }
+            126 | 127 => return Ok(None),
+            length => (length as u64, 2)
+        };
+        if is_control && payload_length > MAX_CONTROL_PAYLOAD_LENGTH as u64 {
+            return Err(CloseReason::new(CLOSE_PROTOCOL_ERROR, "Control frame is too long."));
+        }
+        if payload_length > self.max_payload_length as u64 {
+            return Err(CloseReason::new(CLOSE_MESSAGE_TOO_BIG, "Message is too big."));
+        }
+
+        let payload_length = payload_length as usize;
+        if self.buffer.len() < offset + 4 + payload_length {
+            return Ok(None);
+        }
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(&self.buffer[offset..offset + 4]);
+        offset += 4;
+
+        let mut payload: Vec<u8> = self.buffer.drain(..offset + payload_length).skip(offset).collect();
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+        return Ok(Some(Frame { fin, opcode, payload }));
+    }
+}
+
+/// The outcome of waiting for a frame.
+enum Incoming {
+    Frame(Frame),
+    // Nothing arrived before the read timed out.
+    Idle,
+    // The client closed the TCP connection.
+    Disconnected,
+    Invalid(CloseReason),
+}
+
+/// The state of an open WebSocket connection.
+struct WebSocketConnection<R, W> {
+    reader: R,
+    writer: W,
+    decoder: FrameDecoder,
+    limits: WebSocketLimits,
+    // The opcode and payload so far of a fragmented message.
+    fragments: Option<(u8, Vec<u8>)>,
+    last_received: Instant,
+    ping_sent: Option<Instant>,
+    close_sent: Option<Instant>,
+}
+
+impl<R: Read, W: Write> WebSocketConnection<R, W> {
+    fn new(reader: R, writer: W, limits: WebSocketLimits) -> WebSocketConnection<R, W> {
+        return WebSocketConnection {
+            reader,
+            writer,
+            decoder: FrameDecoder { buffer: Vec::new(), max_payload_length: limits.max_message_size },
+            limits,
+            fragments: None,
+            last_received: Instant::now(),
+            ping_sent: None,
+            close_sent: None,
+        };
+    }
+
+    /// Alternates between sending queued messages and receiving frames, until the close handshake
+    /// completes, the client disconnects or stops responding, or the close handshake times out.
+    fn run(mut self, handler: &dyn WebSocketHandler, request: &HttpRequest, shutting_down: &AtomicBool) -> Result<()> {
+        let (outbox, queued) = sync_channel(OUTBOX_CAPACITY);
+        let sender = WebSocketSender { outbox, overflowed: Arc::new(AtomicBool::new(false)), closed: Arc::new(AtomicBool::new(false)) };
+
+        let result = handler.on_open(request, sender.clone())
+            .and_then(|_| self.serve(handler, &sender, &queued, shutting_down));
+        sender.closed.store(true, Ordering::SeqCst);
+        return result;
+    }
+
+    fn serve(&mut self, handler: &dyn WebSocketHandler, sender: &WebSocketSender, queued: &Receiver<Outgoing>,
+             shutting_down: &AtomicBool) -> Result<()> {
+        loop {
+            self.send_queued(queued)?;
+
+            if self.close_sent.is_none() {
+                if shutting_down.load(Ordering::SeqCst) {
+                    self.send_close(CLOSE_GOING_AWAY, "Server is shutting down.")?;
+                } else if sender.overflowed.load(Ordering::SeqCst) {
+                    self.send_close(CLOSE_POLICY_VIOLATION, "Client is too slow.")?;
+                }
+            }
+            if self.close_sent.map(|sent| sent.elapsed() >= self.limits.close_timeout).unwrap_or(false) {
+                return Ok(());
+            }
+
+            match self.read_frame()? {
+                Incoming::Disconnected => return Ok(()),
+                // The rest of the stream cannot be framed, so we cannot wait for the client's close
+                // frame, and fail the connection instead.
+                Incoming::Invalid(close_reason) => {
+                    self.send_close(close_reason.code, &close_reason.reason)?;
+                    return Ok(());
+                }
+                Incoming::Idle => {
+                    match self.ping_sent {
+                        // The client has not answered the last ping.
+                        Some(ping_sent) if ping_sent.elapsed() >= self.limits.ping_interval => return Ok(()),
+                        Some(_) => {}
+                        None if self.last_received.elapsed() >= self.limits.ping_interval => {
+                            self.write_frame(true, OPCODE_PING, &[])?;
+                            self.ping_sent = Some(Instant::now());
+                        }
+                        None => {}
+                    }
+                }
+                Incoming::Frame(frame) => {
+                    // Any frame shows that the client is alive.
+                    self.last_received = Instant::now();
+                    self.ping_sent = None;
+                    if !self.handle_frame(frame, handler, sender)? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the messages queued by the handler, unless the connection is closing.
+    fn send_queued(&mut self, queued: &Receiver<Outgoing>) -> Result<()> {
+        while self.close_sent.is_none() {
+            match queued.try_recv() {
+                Err(_) => break,
+                Ok(Outgoing::Message(message)) => self.send_message(&message)?,
+                Ok(Outgoing::Close(code, reason)) => self.send_close(code, &reason)?
+            }
+        }
+        return Ok(());
+    }
+
+    /// Waits up to the socket's read timeout for a complete frame.
+    fn read_frame(&mut self) -> Result<Incoming> {
+        let mut chunk = [0u8; 8 * 1024];
+
+        loop {
+            match self.decoder.decode() {
+                Err(close_reason) => return Ok(Incoming::Invalid(close_reason)),
+                Ok(Some(frame)) => return Ok(Incoming::Frame(frame)),
+                Ok(None) => {}
+            }
+
+            match self.reader.read(&mut chunk) {
+                Ok(0) => return Ok(Incoming::Disconnected),
+                Ok(bytes_read) => self.decoder.buffer.extend_from_slice(&chunk[..bytes_read]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return Ok(Incoming::Idle),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into())
+            }
+        }
+    }
+
+    /// Handles a frame from the client. Returns false once the connection should end.
+    fn handle_frame(&mut self, frame: Frame, handler: &dyn WebSocketHandler, sender: &WebSocketSender) -> Result<bool> {
+        match frame.opcode {
+            OPCODE_CLOSE => {
+                if self.close_sent.is_none() {
+                    let (code, reason) = match close_code(&frame.payload) {
+                        Ok(code) => (code, ""),
+                        Err(close_reason) => (close_reason.code, "Invalid close frame.")
+                    };
+                    self.send_close(code, reason)?;
+                }
+                return Ok(false);
+            }
+            OPCODE_PING if self.close_sent.is_none() => self.write_frame(true, OPCODE_PONG, &frame.payload)?,
+            OPCODE_PING | OPCODE_PONG => {}
+            // Once closing, the client's remaining messages are discarded.
+            _ if self.close_sent.is_some() => {}
+            opcode => {
+                if let Err(close_reason) = self.add_fragment(opcode, frame) {
+                    self.send_close(close_reason.code, &close_reason.reason)?;
+                    return Ok(true);
+                }
+                if let Some(message) = self.complete_message()? {
+                    metrics::global().websocket_messages_total.with(&["received"], |counter| counter.inc());
+                    if let Err(e) = handler.on_message(message, sender) {
+                        logger::global().error(&ServerError::with_cause("WebSocket handler failed".into(), e));
+                        self.send_close(CLOSE_INTERNAL_ERROR, "Internal error.")?;
+                    }
+                }
+            }
+        }
+        return Ok(true);
+    }
+
+    /// Adds a data frame to the message being received.
+    fn add_fragment(&mut self, opcode: u8, frame: Frame) -> std::result::Result<(), CloseReason> {
+        match (&mut self.fragments, opcode) {
+            (None, OPCODE_CONTINUATION) => return Err(CloseReason::new(CLOSE_PROTOCOL_ERROR, "Unexpected continuation frame.")),
+            (None, _) => self.fragments = Some((opcode, frame.payload)),
+            (Some(_), OPCODE_TEXT) | (Some(_), OPCODE_BINARY) => {
+                return Err(CloseReason::new(CLOSE_PROTOCOL_ERROR, "Expected a continuation frame."));
+            }
+            (Some((_, payload)), _) => {
+                if payload.len() + frame.payload.len() > self.limits.max_message_size {
+                    return Err(CloseReason::new(CLOSE_MESSAGE_TOO_BIG, "Message is too big."));
+                }
+                payload.extend_from_slice(&frame.payload);
+            }
+        }
+
+        if !frame.fin {
+            return Ok(());
+        }
+        // Marks the message as complete for `complete_message`.
+        if let Some((opcode, _)) = &mut self.fragments {
+            *opcode |= 0x80;
+        }
+        return Ok(());
+    }
+
+    /// Takes the message being received, if its last fragment has arrived.
+    fn complete_message(&mut self) -> Result<Option<Message>> {
+        let is_complete = matches!(self.fragments, Some((opcode, _)) if opcode & 0x80 != 0);
+        if !is_complete {
+            return Ok(None);
+        }
+
+        let (opcode, payload) = self.fragments.take().unwrap();
+        return match opcode & 0x0F {
+            OPCODE_TEXT => match String::from_utf8(payload) {
+                Ok(text) => Ok(Some(Message::Text(text))),
+                Err(_) => {
+                    self.send_close(CLOSE_INVALID_DATA, "Text must be valid UTF-8.")?;
+                    Ok(None)
+                }
+            },
+            _ => Ok(Some(Message::Binary(payload)))
+        };
+    }
+
+    fn send_message(&mut self, message: &Message) -> Result<()> {
+        let (opcode, payload) = match message {
+            Message::Text(text) => (OPCODE_TEXT, text.as_bytes()),
+            Message::Binary(bytes) => (OPCODE_BINARY, bytes.as_slice())
+        };
+
+        let mut chunks = payload.chunks(MAX_OUTGOING_FRAME_SIZE).peekable();
+        let mut frame_opcode = opcode;
+        if chunks.peek().is_none() {
+            self.write_frame(true, opcode, &[])?;
+        }
+        while let Some(chunk) = chunks.next() {
+            self.write_frame(chunks.peek().is_none(), frame_opcode, chunk)?;
+            frame_opcode = OPCODE_CONTINUATION;
+        }
+
+        metrics::global().websocket_messages_total.with(&["sent"], |counter| counter.inc());
+        return Ok(());
+    }
+
+    /// Sends a close frame, unless one has been sent already.
+    fn send_close(&mut self, code: u16, reason: &str) -> Result<()> {
+        if self.close_sent.is_some() {
+            return Ok(());
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        // The reason must fit in a control frame, so is truncated at a character boundary.
+        let mut reason_length = reason.len().min(MAX_CONTROL_PAYLOAD_LENGTH - 2);
+        while !reason.is_char_boundary(reason_length) {
+            reason_length -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..reason_length]);
+
+        self.write_frame(true, OPCODE_CLOSE, &payload)?;
+        self.close_sent = Some(Instant::now());
+        return Ok(());
+    }
+
+    fn write_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> Result<()> {
+        self.writer.write_all(&encode_frame(fin, opcode, payload))?;
+        self.writer.flush()?;
+        return Ok(());
+    }
+}
+
+/// Reads the status code from a close frame's payload. An empty payload means a normal closure.
+fn close_code(payload: &[u8]) -> std::result::Result<u16, CloseReason> {
+    if payload.is_empty() {
+        return Ok(CLOSE_NORMAL);
+    }
+    if payload.len() < 2 || std::str::from_utf8(&payload[2..]).is_err() {
+        return Err(CloseReason::new(CLOSE_PROTOCOL_ERROR, "Invalid close frame."));
+    }
+
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    // Codes below 1000, reserved codes, and those that must not be sent, are invalid.
+    let is_valid = matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999);
+    return match is_valid {
+        true => Ok(code),
+        false => Err(CloseReason::new(CLOSE_PROTOCOL_ERROR, "Invalid close code."))
+    };
+}
+
+/// Relays every message received to every connected client, including its sender. Messages can
+/// also be broadcast by the server.
+pub struct WebSocketHub {
+    senders: Mutex<Vec<WebSocketSender>>,
+    // Clients connecting beyond this are asked to try again later.
+    max_clients: usize,
+}
+
+impl WebSocketHub {
+    pub fn new(max_clients: usize) -> WebSocketHub {
+        return WebSocketHub { senders: Mutex::new(Vec::new()), max_clients };
+    }
+
+    /// Sends the message to every connected client, forgetting those that have gone.
+    pub fn broadcast(&self, message: &Message) -> Result<()> {
+        self.senders.lock()?.retain(|sender| sender.send(message.clone()).is_ok());
+        return Ok(());
+    }
+}
+
+impl WebSocketHandler for WebSocketHub {
+    fn on_open(&self, _request: &HttpRequest, sender: WebSocketSender) -> Result<()> {
+        let mut senders = self.senders.lock()?;
+        // Clients that have gone are only noticed when broadcasting, so are forgotten first.
+        senders.retain(|sender| !sender.is_closed());
+        if senders.len() >= self.max_clients {
+            return sender.close(CLOSE_TRY_AGAIN_LATER, "Too many clients.");
+        }
+        senders.push(sender);
+        return Ok(());
+    }
+
+    fn on_message(&self, message: Message, _sender: &WebSocketSender) -> Result<()> {
+        return self.broadcast(&message);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashMap;
+    use std::convert::TryInto;
+    use std::io::{BufRead, BufReader, Cursor, Read, Write};
+    use std::net::TcpStream;
+    use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+    use std::sync::mpsc::{sync_channel, Receiver};
+    use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    use crate::database::LocalDatabase;
+    use crate::handler::{HttpHandler, HttpRequest};
+    use crate::server::ServerInternal;
+    use crate::servererror::{Result, ServerError};
+    use crate::websocket::{accept_key, encode_frame, handshake_response, Frame, FrameDecoder, Message, Outgoing,
+                           WebSocketConnection, WebSocketHandler, WebSocketHub, WebSocketLimits, WebSocketRoutes, WebSocketSender};
+
+    // Used to allocate different ports for the listeners across tests.
+    static PORT: AtomicU16 = AtomicU16::new(10600);
+
+    fn get_port() -> String {
+        return PORT.fetch_add(1, Ordering::Relaxed).to_string();
+    }
+
+    /// Encodes a masked frame, as sent by clients.
+    pub fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = encode_frame(fin, opcode, payload);
+        let header_length = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        let masked_payload: Vec<u8> = payload.iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]).collect();
+        frame.truncate(header_length);
+        frame.extend_from_slice(&mask);
+        frame.extend_from_slice(&masked_payload);
+        return frame;
+    }
+
+    /// Decodes the unmasked frames sent by the server.
+    pub fn server_frames(mut bytes: &[u8]) -> Vec<(bool, u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while !bytes.is_empty() {
+            let (length, offset) = match bytes[1] {
+                126 => (u16::from_be_bytes([bytes[2], bytes[3]]) as usize, 4),
+                127 => (u64::from_be_bytes(bytes[2..10].try_into().unwrap()) as usize, 10),
+                length => (length as usize, 2)
+            };
+            frames.push((bytes[0] & 0x80 != 0, bytes[0] & 0x0F, bytes[offset..offset + length].to_vec()));
+            bytes = &bytes[offset + length..];
+        }
+        return frames;
+    }
+
+    /// Echoes messages, and fails on the message "fail".
+    struct Echo {
+        opened: Mutex<Vec<String>>,
+    }
+
+    impl WebSocketHandler for Echo {
+        fn on_open(&self, request: &HttpRequest, sender: WebSocketSender) -> Result<()> {
+            self.opened.lock()?.push(request.request_uri.clone());
+            return sender.send(Message::Text("hello".into()));
+        }
+
+        fn on_message(&self, message: Message, sender: &WebSocketSender) -> Result<()> {
+            if message == Message::Text("fail".into()) {
+                return Err(ServerError::new("Failed.".into()));
+            }
+            return sender.send(message);
+        }
+    }
+
+    /// Runs a connection over the client's frames, and returns the frames the server sent.
+    fn converse(client_frames: &[Vec<u8>], limits: WebSocketLimits) -> Vec<(bool, u8, Vec<u8>)> {
+        let echo = Echo { opened: Mutex::new(Vec::new()) };
+        let input: Vec<u8> = client_frames.concat();
+        let mut output = Vec::new();
+        let request = HttpRequest::new("GET", "/ws", "HTTP/1.1", HashMap::new());
+
+        WebSocketConnection::new(Cursor::new(input), &mut output, limits).run(&echo, &request, &AtomicBool::new(false)).unwrap();
+
+        assert_eq!(*echo.opened.lock().unwrap(), vec!["/ws"]);
+        return server_frames(&output);
+    }
+
+    fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+        return [code.to_be_bytes().to_vec(), reason.as_bytes().to_vec()].concat();
+    }
+
+    fn handshake_request(headers: &[(&str, &str)]) -> HttpRequest {
+        let headers = headers.iter().map(|(name, value)| (name.to_lowercase(), value.to_string())).collect();
+        return HttpRequest::new("GET", "/ws", "HTTP/1.1", headers);
+    }
+
+    #[test]
+    fn handshakes_are_accepted() {
+        // The example from RFC 6455.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let valid_headers = [("Upgrade", "websocket"), ("Connection", "keep-alive, Upgrade"),
+            ("Sec-WebSocket-Version", "13"), ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")];
+        let response = handshake_response(&handshake_request(&valid_headers));
+
+        assert_eq!(response.status_code, "101 SWITCHING PROTOCOLS");
+        assert_eq!(response.header("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert_eq!(response.header("Connection"), Some("Upgrade"));
+
+        let invalid_handshakes = [
+            (0, "", "426 UPGRADE REQUIRED"),
+            (2, "8", "426 UPGRADE REQUIRED"),
+            (3, "c2hvcnQ=", "400 BAD REQUEST"),
+        ];
+        for (index, value, status) in invalid_handshakes.iter() {
+            let mut headers = valid_headers;
+            headers[*index].1 = value;
+            assert_eq!(handshake_response(&handshake_request(&headers)).status_code, *status);
+        }
+    }
+
+    #[test]
+    fn frames_are_decoded_and_unmasked() {
+        let payloads = [vec![], b"hi".to_vec(), vec![7; 200], vec![9; 70_000]];
+        for payload in payloads.iter() {
+            let mut decoder = FrameDecoder { buffer: client_frame(true, 0x2, payload), max_payload_length: 100_000 };
+            let expected = Frame { fin: true, opcode: 0x2, payload: payload.clone() };
+
+            assert_eq!(decoder.decode(), Ok(Some(expected)));
+            assert!(decoder.buffer.is_empty());
+        }
+
+        let frame = client_frame(true, 0x1, b"partial");
+        let mut decoder = FrameDecoder { buffer: frame[..frame.len() - 1].to_vec(), max_payload_length: 100 };
+        assert_eq!(decoder.decode(), Ok(None));
+    }
+
+    #[test]
+    fn invalid_frames_are_rejected() {
+        let invalid_frames = [
+            (encode_frame(true, 0x1, b"unmasked"), 1002),
+            (client_frame(true, 0x3, b""), 1002),
+            (client_frame(false, 0x9, b""), 1002),
+            (client_frame(true, 0x9, &[0; 126]), 1002),
+            (client_frame(true, 0x2, &[0; 11]), 1009),
+            ([vec![0xC1], client_frame(true, 0x1, b"")[1..].to_vec()].concat(), 1002),
+        ];
+
+        for (frame, code) in invalid_frames.iter() {
+            let mut decoder = FrameDecoder { buffer: frame.clone(), max_payload_length: 10 };
+            assert_eq!(decoder.decode().unwrap_err().code, *code);
+        }
+    }
+
+    #[test]
+    fn connections_echo_messages_and_answer_pings() {
+        let frames = converse(&[
+            client_frame(true, 0x9, b"ping"),
+            client_frame(false, 0x1, b"Hel"),
+            client_frame(true, 0xA, b""),
+            client_frame(false, 0x0, b"lo, "),
+            client_frame(true, 0x0, "wörld".as_bytes()),
+            client_frame(true, 0x2, &[1, 2, 3]),
+            client_frame(true, 0x8, &close_payload(1000, "bye")),
+        ], WebSocketLimits::default());
+
+        assert_eq!(frames, vec![
+            (true, 0x1, b"hello".to_vec()),
+            (true, 0xA, b"ping".to_vec()),
+            (true, 0x1, "Hello, wörld".as_bytes().to_vec()),
+            (true, 0x2, vec![1, 2, 3]),
+            (true, 0x8, close_payload(1000, "")),
+        ]);
+    }
+
+    #[test]
+    fn connections_close_on_protocol_errors() {
+        let limits = WebSocketLimits { max_message_size: 8, close_timeout: Duration::from_millis(0), ..WebSocketLimits::default() };
+        let cases = [
+            (vec![client_frame(true, 0x0, b"orphan")], 1002),
+            (vec![client_frame(false, 0x1, b"a"), client_frame(true, 0x1, b"b")], 1002),
+            (vec![client_frame(false, 0x2, b"12345"), client_frame(true, 0x0, b"6789")], 1009),
+            (vec![client_frame(true, 0x1, &[0xFF, 0xFE])], 1007),
+            (vec![client_frame(true, 0x1, b"fail")], 1011),
+            (vec![client_frame(true, 0x8, &close_payload(1005, ""))], 1002),
+            (vec![client_frame(true, 0x8, &[0x03])], 1002),
+        ];
+
+        for (client_frames, code) in cases.iter() {
+            let frames = converse(client_frames, limits.clone());
+
+            let (_, opcode, payload) = frames.last().unwrap();
+            assert_eq!((*opcode, u16::from_be_bytes([payload[0], payload[1]])), (0x8, *code));
+        }
+    }
+
+    #[test]
+    fn connections_fail_once_on_malformed_frames() {
+        let cases = [
+            // Unmasked.
+            vec![encode_frame(true, 0x1, b"hi")],
+            // An unknown opcode, after a message.
+            vec![client_frame(true, 0x1, b"hi"), client_frame(true, 0x3, b"")],
+        ];
+
+        for client_frames in cases.iter() {
+            let start = Instant::now();
+            let frames = converse(client_frames, WebSocketLimits::default());
+
+            let closes: Vec<&(bool, u8, Vec<u8>)> = frames.iter().filter(|(_, opcode, _)| *opcode == 0x8).collect();
+            assert_eq!(closes.len(), 1);
+            assert_eq!(u16::from_be_bytes([closes[0].2[0], closes[0].2[1]]), 1002);
+            // The connection ends at once, rather than waiting for the close timeout.
+            assert!(start.elapsed() < WebSocketLimits::default().close_timeout);
+        }
+    }
+
+    #[test]
+    fn large_messages_are_sent_in_fragments() {
+        let message = vec![5u8; 100_000];
+        let frames = converse(&[client_frame(false, 0x2, &message[..60_000]), client_frame(true, 0x0, &message[60_000..])],
+                              WebSocketLimits::default());
+
+        let echoed: Vec<&(bool, u8, Vec<u8>)> = frames.iter().filter(|(_, opcode, _)| *opcode == 0x2 || *opcode == 0x0).collect();
+        assert_eq!(echoed.iter().map(|(fin, opcode, payload)| (*fin, *opcode, payload.len())).collect::<Vec<_>>(),
+                   vec![(false, 0x2, 65_536), (true, 0x0, 34_464)]);
+    }
+
+    fn test_sender() -> (WebSocketSender, Receiver<Outgoing>) {
+        let (outbox, queued) = sync_channel(4);
+        let sender = WebSocketSender { outbox, overflowed: Arc::new(AtomicBool::new(false)), closed: Arc::new(AtomicBool::new(false)) };
+        return (sender, queued);
+    }
+
+    #[test]
+    fn hubs_relay_messages_to_every_client() {
+        let hub = WebSocketHub::new(2);
+        let request = HttpRequest::new("GET", "/ws", "HTTP/1.1", HashMap::new());
+        let (first, first_queue) = test_sender();
+        let (second, second_queue) = test_sender();
+        let (third, third_queue) = test_sender();
+        hub.on_open(&request, first.clone()).unwrap();
+        hub.on_open(&request, second).unwrap();
+        hub.on_open(&request, third).unwrap();
+        drop(second_queue);
+
+        hub.on_message(Message::Text("hi".into()), &first).unwrap();
+
+        assert!(matches!(first_queue.try_recv(), Ok(Outgoing::Message(Message::Text(text))) if text == "hi"));
+        assert!(matches!(third_queue.try_recv(), Ok(Outgoing::Close(1013, _))));
+        assert_eq!(hub.senders.lock().unwrap().len(), 1);
+    }
+
+    /// Sends a handshake to the server, and returns the connection once it has been accepted.
+    fn connect(port: &str) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
+        stream.write_all(b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").unwrap();
+
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 101 SWITCHING PROTOCOLS\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(!head.contains("Content-Length") && !head.contains("Connection: Closed"));
+        return reader;
+    }
+
+    /// Reads a single unmasked frame sent by the server.
+    fn read_server_frame(reader: &mut BufReader<TcpStream>) -> (bool, u8, Vec<u8>) {
+        let mut header = [0u8; 2];
+        reader.read_exact(&mut header).unwrap();
+        let mut payload = vec![0u8; (header[1] & 0x7F) as usize];
+        reader.read_exact(&mut payload).unwrap();
+        return server_frames(&[header.to_vec(), payload].concat()).remove(0);
+    }
+
+    #[test]
+    fn server_upgrades_connections_to_websocket_routes() {
+        let port = &get_port();
+        let mut websocket_routes = WebSocketRoutes::new(WebSocketLimits::default());
+        websocket_routes.add("/ws", Arc::new(WebSocketHub::new(10)));
+        let handler = HttpHandler::new(Arc::new(LocalDatabase::new()), HashMap::new()).with_websocket_routes(websocket_routes);
+        let mut server_handle = ServerInternal::start(port, handler).unwrap();
+
+        let mut first = connect(port);
+        let mut second = connect(port);
+        // Gives the second connection time to join the hub.
+        sleep(Duration::from_millis(200));
+        first.get_mut().write_all(&client_frame(true, 0x1, b"new block")).unwrap();
+
+        assert_eq!(read_server_frame(&mut first), (true, 0x1, b"new block".to_vec()));
+        assert_eq!(read_server_frame(&mut second), (true, 0x1, b"new block".to_vec()));
+
+        first.get_mut().write_all(&client_frame(true, 0x8, &close_payload(1000, ""))).unwrap();
+        assert_eq!(read_server_frame(&mut first), (true, 0x8, close_payload(1000, "")));
+
+        server_handle.stop_listening().unwrap();
+    }
+}